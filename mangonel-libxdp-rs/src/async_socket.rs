@@ -0,0 +1,214 @@
+//! Sockets that await readiness from a tokio runtime instead of being polled
+//! in a loop, for low-rate sockets living in async services.
+//!
+//! The application still fills the UMEM and collects completed frames with
+//! [`crate::umem::Umem::fill`] and [`crate::umem::Umem::complete`]; a socket
+//! only becomes readable once it has frames to receive into.
+
+use std::io;
+
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::{
+    buffer::Buffer,
+    descriptor::Descriptor,
+    socket::{RxSocket, TxSocket},
+    umem::Umem,
+};
+
+pub struct AsyncRxSocket {
+    inner: AsyncFd<RxSocket>,
+}
+
+impl AsyncRxSocket {
+    /// Register the socket with the reactor of the current runtime.
+    pub fn new(socket: RxSocket) -> io::Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::with_interest(socket, Interest::READABLE)?,
+        })
+    }
+
+    #[inline(always)]
+    pub fn umem(&self) -> Umem {
+        self.inner.get_ref().umem()
+    }
+
+    #[inline(always)]
+    pub fn get_ref(&self) -> &RxSocket {
+        self.inner.get_ref()
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut RxSocket {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> RxSocket {
+        self.inner.into_inner()
+    }
+
+    /// Wait until descriptors are received, move them into the buffer and
+    /// return their number. Return 0 without waiting if the buffer is full.
+    pub async fn recv_batch<T>(&mut self, buffer: &mut T) -> io::Result<u32>
+    where
+        T: Buffer<Descriptor>,
+    {
+        if buffer.free() == 0 {
+            return Ok(0);
+        }
+
+        loop {
+            let mut guard = self.inner.readable_mut().await?;
+            let received = guard.get_inner_mut().rx_burst(buffer);
+            if received > 0 {
+                return Ok(received);
+            }
+            guard.clear_ready();
+        }
+    }
+}
+
+pub struct AsyncTxSocket {
+    inner: AsyncFd<TxSocket>,
+}
+
+impl AsyncTxSocket {
+    /// Register the socket with the reactor of the current runtime.
+    pub fn new(socket: TxSocket) -> io::Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::with_interest(socket, Interest::WRITABLE)?,
+        })
+    }
+
+    #[inline(always)]
+    pub fn umem(&self) -> Umem {
+        self.inner.get_ref().umem()
+    }
+
+    #[inline(always)]
+    pub fn get_ref(&self) -> &TxSocket {
+        self.inner.get_ref()
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut TxSocket {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> TxSocket {
+        self.inner.into_inner()
+    }
+
+    /// Wait until the TX ring has room, move descriptors from the buffer out
+    /// for transmission and return their number. Return 0 without waiting if
+    /// the buffer is empty.
+    pub async fn send_batch<T>(&mut self, buffer: &mut T) -> io::Result<u32>
+    where
+        T: Buffer<Descriptor>,
+    {
+        if buffer.count() == 0 {
+            return Ok(0);
+        }
+
+        loop {
+            let mut guard = self.inner.writable_mut().await?;
+            let sent = guard.get_inner_mut().tx_burst(buffer);
+            if sent > 0 {
+                return Ok(sent);
+            }
+            guard.clear_ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, future::Future, io::Write, thread, time::Duration};
+
+    use libc::xdp_desc;
+
+    use super::*;
+    use crate::socket::TestSockets;
+
+    const FRAME_SIZE: u32 = 2048;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn recv_batch_waits_for_descriptors() {
+        let sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        let TestSockets {
+            rx_socket,
+            mut rx_ring,
+            mut peer,
+            ..
+        } = sockets;
+
+        let kernel = thread::spawn(move || {
+            // Readiness without descriptors is waited out.
+            thread::sleep(Duration::from_millis(20));
+            peer.write_all(&[0]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            let descriptor = xdp_desc {
+                addr: FRAME_SIZE as u64,
+                len: 60,
+                options: 0,
+            };
+            assert!(rx_ring.reserve(1).push(descriptor).is_ok());
+            peer.write_all(&[0]).unwrap();
+        });
+
+        let mut buffer = VecDeque::with_capacity(4);
+        let received = block_on(async {
+            let mut socket = AsyncRxSocket::new(rx_socket).unwrap();
+            socket.recv_batch(&mut buffer).await.unwrap()
+        });
+        kernel.join().unwrap();
+        assert_eq!(received, 1);
+        assert_eq!(buffer[0].address(), FRAME_SIZE as u64);
+        assert_eq!(buffer[0].length(), 60);
+    }
+
+    #[test]
+    fn send_batch_submits_descriptors() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        let umem = sockets.tx_socket.umem();
+        let mut buffer = VecDeque::from([
+            Descriptor::new(0, 60, umem.clone()),
+            Descriptor::new(FRAME_SIZE as u64, 70, umem),
+        ]);
+
+        let sent = block_on(async {
+            let mut socket = AsyncTxSocket::new(sockets.tx_socket).unwrap();
+            socket.send_batch(&mut buffer).await.unwrap()
+        });
+        assert_eq!(sent, 2);
+        assert!(buffer.is_empty());
+        let peek = sockets.tx_ring.peek(4);
+        let lengths: Vec<u32> = peek.iter().map(|descriptor| descriptor.len).collect();
+        assert_eq!(lengths, [60, 70]);
+    }
+
+    #[test]
+    fn nothing_to_wait_for() {
+        let sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+
+        block_on(async {
+            // The sockets share their fd, which registers only once.
+            let mut socket = AsyncRxSocket::new(sockets.rx_socket).unwrap();
+            let mut full = VecDeque::new();
+            assert_eq!(socket.recv_batch(&mut full).await.unwrap(), 0);
+            drop(socket);
+
+            let mut socket = AsyncTxSocket::new(sockets.tx_socket).unwrap();
+            let mut empty = VecDeque::with_capacity(4);
+            assert_eq!(socket.send_batch(&mut empty).await.unwrap(), 0);
+        });
+    }
+}
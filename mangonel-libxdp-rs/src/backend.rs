@@ -0,0 +1,83 @@
+use crate::{
+    buffer::Buffer,
+    descriptor::Descriptor,
+    socket::{RxSocket, SocketError, TxSocket},
+    umem::Umem,
+};
+
+/// The datapath of a socket: receiving and transmitting descriptors, and
+/// handing frames to and taking them back from the UMEM.
+///
+/// Code written against this trait runs on [`SocketPair`] in production and
+/// on [`crate::loopback::Loopback`] in tests.
+pub trait Backend: Sender {
+    fn umem(&self) -> Umem;
+
+    /// Move received descriptors into the buffer and return their number.
+    fn rx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32;
+
+    /// Move descriptors from the buffer out for transmission and return their
+    /// number.
+    fn tx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32;
+
+    /// Hand frame addresses from the buffer to the backend to receive into.
+    fn fill<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32;
+
+    /// Move the addresses of transmitted frames back into the buffer.
+    fn complete<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32;
+}
+
+/// Transmitting frames built by the application rather than received, from
+/// frames of the sender's own.
+pub trait Sender {
+    /// Copy the payload into a free frame and transmit it.
+    fn send(&mut self, payload: &[u8]) -> Result<(), SocketError>;
+}
+
+impl Sender for TxSocket {
+    #[inline(always)]
+    fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        TxSocket::send(self, payload)
+    }
+}
+
+/// The sockets returned by [`crate::socket::SocketBuilder::build`] driven
+/// together.
+pub struct SocketPair {
+    pub rx_socket: RxSocket,
+    pub tx_socket: TxSocket,
+}
+
+impl Backend for SocketPair {
+    #[inline(always)]
+    fn umem(&self) -> Umem {
+        self.rx_socket.umem()
+    }
+
+    #[inline(always)]
+    fn rx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        self.rx_socket.rx_burst(buffer)
+    }
+
+    #[inline(always)]
+    fn tx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        self.tx_socket.tx_burst(buffer)
+    }
+
+    #[inline(always)]
+    fn fill<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        self.rx_socket.umem().fill(buffer)
+    }
+
+    #[inline(always)]
+    fn complete<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        self.tx_socket.complete(buffer)
+    }
+}
+
+impl Sender for SocketPair {
+    #[inline(always)]
+    fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        self.tx_socket.send(payload)
+    }
+}
@@ -40,6 +40,6 @@ impl<T> Buffer<T> for std::collections::VecDeque<T> {
 
         self.push_back(value);
 
-        return None;
+        None
     }
 }
@@ -1,4 +1,4 @@
-use mangonel_libxdp_sys::xdp_desc;
+use libc::xdp_desc;
 
 use crate::umem::Umem;
 
@@ -8,20 +8,29 @@ pub struct Descriptor {
     umem: Umem,
 }
 
-impl From<(*const xdp_desc, &Umem)> for Descriptor {
+impl From<(&xdp_desc, &Umem)> for Descriptor {
     #[inline(always)]
-    fn from(value: (*const xdp_desc, &Umem)) -> Self {
-        unsafe {
-            Self {
-                address: (*value.0).addr,
-                length: (*value.0).len,
-                umem: value.1.clone(),
-            }
+    fn from(value: (&xdp_desc, &Umem)) -> Self {
+        Self {
+            address: value.0.addr,
+            length: value.0.len,
+            umem: value.1.clone(),
         }
     }
 }
 
 impl Descriptor {
+    /// Describe `length` bytes at `address` in the UMEM, e.g. a frame built
+    /// by the application rather than received.
+    #[inline(always)]
+    pub fn new(address: u64, length: u32, umem: Umem) -> Self {
+        Self {
+            address,
+            length,
+            umem,
+        }
+    }
+
     #[inline(always)]
     pub fn address(&self) -> u64 {
         self.address
@@ -32,6 +41,36 @@ impl Descriptor {
         self.length
     }
 
+    /// Return the frame without its headroom.
+    #[inline(always)]
+    pub fn payload(&self) -> &[u8] {
+        let offset = self.umem.get_data(self.address) as *const u8;
+
+        unsafe { std::slice::from_raw_parts(offset, self.length as usize) }
+    }
+
+    /// Return a mutable slice from the start of the data to the end of the
+    /// frame, for writing data of another length in place. Set the new length
+    /// with [`Descriptor::set_length`].
+    #[inline(always)]
+    pub fn get_buffer(&mut self) -> &mut [u8] {
+        let frame_size = self.umem.frame_size() as u64;
+        let end = (self.address / frame_size + 1) * frame_size;
+        let offset = self.umem.get_data(self.address) as *mut u8;
+
+        unsafe { std::slice::from_raw_parts_mut(offset, (end - self.address) as usize) }
+    }
+
+    /// Set the length of the data, at most the length of
+    /// [`Descriptor::get_buffer`].
+    #[inline(always)]
+    pub fn set_length(&mut self, length: u32) {
+        let frame_size = self.umem.frame_size() as u64;
+        assert!(self.address % frame_size + length as u64 <= frame_size);
+
+        self.length = length;
+    }
+
     /// Return a mutable slice of the frame including its headroom.
     #[inline(always)]
     pub fn get_data(&mut self) -> &mut [u8] {
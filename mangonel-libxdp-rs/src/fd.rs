@@ -0,0 +1,187 @@
+use std::{
+    io::ErrorKind,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+
+use libc::{
+    c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN,
+    CMSG_NXTHDR, CMSG_SPACE, MSG_CMSG_CLOEXEC, MSG_CTRUNC, SCM_RIGHTS, SOL_SOCKET,
+};
+
+/// The maximum number of file descriptors in a single message. The kernel
+/// limit is 253.
+pub const MAX_FDS: usize = 253;
+
+/// Send `data` along with the file descriptors over a Unix socket with
+/// `SCM_RIGHTS`. `data` must not be empty, as the descriptors travel with
+/// its first byte.
+pub fn send_fds(stream: &UnixStream, data: &[u8], fds: &[BorrowedFd]) -> std::io::Result<()> {
+    if data.is_empty() || fds.len() > MAX_FDS {
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let fds_length = std::mem::size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { CMSG_SPACE(fds_length) } as usize];
+    let mut iov = iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let mut message: msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    if !fds.is_empty() {
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = control.len();
+
+        unsafe {
+            let header = CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = SOL_SOCKET;
+            (*header).cmsg_type = SCM_RIGHTS;
+            (*header).cmsg_len = CMSG_LEN(fds_length) as usize;
+            let data = CMSG_DATA(header) as *mut RawFd;
+            for (index, fd) in fds.iter().enumerate() {
+                data.add(index).write_unaligned(fd.as_raw_fd());
+            }
+        }
+    }
+
+    let value = unsafe { sendmsg(stream.as_raw_fd(), &message, 0) };
+    if value.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+    // The rest cannot be sent on its own, as it would arrive without the
+    // descriptors.
+    if (value as usize) < data.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::WriteZero,
+            format!("Sent {} of {} bytes", value, data.len()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Receive a message sent with [`send_fds`]. Returns the number of bytes
+/// written to `buffer` and the received file descriptors, which are closed
+/// on exec. Fails if the message carried more than [`MAX_FDS`] descriptors,
+/// as the rest would be lost.
+pub fn recv_fds(stream: &UnixStream, buffer: &mut [u8]) -> std::io::Result<(usize, Vec<OwnedFd>)> {
+    recv_at_most(stream, buffer, MAX_FDS)
+}
+
+fn recv_at_most(
+    stream: &UnixStream,
+    buffer: &mut [u8],
+    max_fds: usize,
+) -> std::io::Result<(usize, Vec<OwnedFd>)> {
+    let mut control =
+        vec![0u8; unsafe { CMSG_SPACE((max_fds * size_of::<RawFd>()) as u32) } as usize];
+    let mut iov = iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    let mut message: msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = control.len();
+
+    let value = unsafe { recvmsg(stream.as_raw_fd(), &mut message, MSG_CMSG_CLOEXEC) };
+    if value.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    let mut header: *mut cmsghdr = unsafe { CMSG_FIRSTHDR(&message) };
+    while !header.is_null() {
+        unsafe {
+            if (*header).cmsg_level == SOL_SOCKET && (*header).cmsg_type == SCM_RIGHTS {
+                let data = CMSG_DATA(header) as *const RawFd;
+                let count = ((*header).cmsg_len - CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                for index in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(index).read_unaligned()));
+                }
+            }
+            header = CMSG_NXTHDR(&message, header);
+        }
+    }
+    // The descriptors received are closed on return.
+    if message.msg_flags & MSG_CTRUNC != 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "More file descriptors were sent than can be received",
+        ));
+    }
+
+    Ok((value as usize, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        os::fd::AsFd,
+    };
+
+    use super::*;
+
+    /// Return both ends of a pipe.
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let (mut reader, mut writer) = pipe();
+
+        send_fds(&sender, b"pipe", &[reader.as_fd(), writer.as_fd()]).unwrap();
+        let mut buffer = [0; 16];
+        let (length, fds) = recv_fds(&receiver, &mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"pipe");
+        assert_eq!(fds.len(), 2);
+
+        // The received descriptors refer to the same pipe.
+        let mut received_writer = File::from(fds.into_iter().nth(1).unwrap());
+        received_writer.write_all(b"through").unwrap();
+        let mut data = [0; 7];
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"through");
+        writer.write_all(b"!").unwrap();
+    }
+
+    #[test]
+    fn without_fds() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        send_fds(&sender, b"data", &[]).unwrap();
+
+        let mut buffer = [0; 4];
+        let (length, fds) = recv_fds(&receiver, &mut buffer).unwrap();
+        assert_eq!((length, fds.len()), (4, 0));
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let (reader, _writer) = pipe();
+
+        let error = send_fds(&sender, b"", &[reader.as_fd()]).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+        let fds = vec![reader.as_fd(); MAX_FDS + 1];
+        let error = send_fds(&sender, b"data", &fds).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+
+        // Room for one descriptor is padded to room for two.
+        send_fds(&sender, b"data", &fds[..4]).unwrap();
+        let error = recv_at_most(&receiver, &mut [0; 4], 1).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,272 @@
+//! Hand XSK sockets, their UMEMs and the XSKMAP over to another process with
+//! `SCM_RIGHTS`, so that a new version of a program can take over the
+//! datapath without dropping traffic or detaching the XDP program.
+//!
+//! The UMEMs must be backed by a shared memfd (see
+//! [`crate::mmap::MmapBuilder::shared`]) and the sockets must be registered in
+//! an [`XskMap`] (see [`crate::socket::SocketBuilder::xskmap`]).
+
+use std::{
+    io::{Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    fd::{recv_fds, send_fds},
+    mmap::{Mmap, MmapError},
+    socket::{RxSocket, Socket, SocketError, TxSocket},
+    umem::{Umem, UmemError},
+    xskmap::XskMap,
+};
+
+const HEADER: &str = "mangonel-handoff 1";
+
+/// A socket handed over along with the UMEM it is bound to.
+pub struct HandoffSocket {
+    pub queue_id: u32,
+    pub rx_socket: RxSocket,
+    pub tx_socket: TxSocket,
+    /// Addresses of the frames held by the application rather than by the
+    /// rings. They would be lost to the receiving process otherwise.
+    pub free_frames: Vec<u64>,
+}
+
+pub struct Handoff {
+    pub xskmap: Arc<XskMap>,
+    pub sockets: Vec<HandoffSocket>,
+}
+
+impl Handoff {
+    /// Send everything to the process on the other end of the stream.
+    ///
+    /// The sockets keep working in this process until they are dropped, so the
+    /// datapath should be stopped before sending. Dropping them afterwards
+    /// does not affect the receiving process.
+    pub fn send(&self, mut stream: &UnixStream) -> Result<(), HandoffError> {
+        let umems: Vec<Umem> = self
+            .sockets
+            .iter()
+            .map(|socket| socket.rx_socket.umem())
+            .collect();
+
+        let mut payload = format!("{} {}\n", HEADER, self.sockets.len());
+        let mut fds: Vec<BorrowedFd> = vec![self.xskmap.as_fd()];
+        for (socket, umem) in self.sockets.iter().zip(&umems) {
+            fds.push(umem.mmap().fd().ok_or(HandoffError::UmemNotShared)?);
+            fds.push(socket.rx_socket.socket().as_fd());
+
+            payload.push_str(&format!(
+                "{} {} {} {}",
+                socket.queue_id,
+                umem.frame_size(),
+                umem.headroom_size(),
+                umem.ring_size()
+            ));
+            // The frames held for `TxSocket::send` are handed over as free
+            // frames; frames are interchangeable.
+            socket
+                .free_frames
+                .iter()
+                .copied()
+                .chain(socket.tx_socket.frames())
+                .for_each(|address| payload.push_str(&format!(" {}", address)));
+            payload.push('\n');
+        }
+
+        let length = (payload.len() as u64).to_le_bytes();
+        send_fds(stream, &length, &fds).map_err(HandoffError::Io)?;
+        stream
+            .write_all(payload.as_bytes())
+            .map_err(HandoffError::Io)?;
+
+        Ok(())
+    }
+
+    /// Receive everything sent with [`Handoff::send`] and adopt the sockets.
+    pub fn receive(mut stream: &UnixStream) -> Result<Self, HandoffError> {
+        let mut length = [0u8; 8];
+        let (received, fds) = recv_fds(stream, &mut length).map_err(HandoffError::Io)?;
+        if received != length.len() {
+            return Err(HandoffError::Protocol);
+        }
+
+        let mut payload = vec![0u8; u64::from_le_bytes(length) as usize];
+        stream.read_exact(&mut payload).map_err(HandoffError::Io)?;
+        let payload = String::from_utf8(payload).map_err(|_| HandoffError::Protocol)?;
+
+        let mut lines = payload.lines();
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.strip_prefix(HEADER))
+            .and_then(|count| count.trim().parse().ok())
+            .ok_or(HandoffError::Protocol)?;
+        if fds.len() != 1 + 2 * count {
+            return Err(HandoffError::Protocol);
+        }
+
+        let mut fds = fds.into_iter();
+        let mut next_fd =
+            || -> Result<OwnedFd, HandoffError> { fds.next().ok_or(HandoffError::Protocol) };
+        let xskmap = Arc::new(XskMap::from_fd(next_fd()?));
+
+        let mut sockets = Vec::with_capacity(count);
+        for line in lines.take(count) {
+            let mut fields = line.split_whitespace();
+            let queue_id = parse(fields.next())?;
+            let frame_size = parse(fields.next())?;
+            let frame_headroom_size = parse(fields.next())?;
+            let ring_size = parse(fields.next())?;
+            let free_frames = fields
+                .map(|field| parse(Some(field)))
+                .collect::<Result<_, _>>()?;
+
+            let mmap = Mmap::from_fd(next_fd()?)?;
+            let socket_fd = next_fd()?;
+            let umem = Umem::adopt(
+                mmap,
+                socket_fd.as_fd(),
+                frame_size,
+                frame_headroom_size,
+                ring_size,
+            )?;
+            let (rx_socket, tx_socket) = Socket::adopt(socket_fd, umem)?;
+
+            sockets.push(HandoffSocket {
+                queue_id,
+                rx_socket,
+                tx_socket,
+                free_frames,
+            });
+        }
+        if sockets.len() != count {
+            return Err(HandoffError::Protocol);
+        }
+
+        Ok(Self { xskmap, sockets })
+    }
+}
+
+fn parse<T: FromStr>(field: Option<&str>) -> Result<T, HandoffError> {
+    field
+        .and_then(|field| field.parse().ok())
+        .ok_or(HandoffError::Protocol)
+}
+
+#[derive(Debug)]
+pub enum HandoffError {
+    Io(std::io::Error),
+    Protocol,
+    UmemNotShared,
+    Mmap(MmapError),
+    Umem(UmemError),
+    Socket(SocketError),
+}
+
+impl std::fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Handoff failed: {}", error),
+            Self::Protocol => write!(f, "Malformed handoff message"),
+            Self::UmemNotShared => write!(
+                f,
+                "The UMEM is not backed by a memfd. Build it with `MmapBuilder::shared`"
+            ),
+            Self::Mmap(error) => write!(f, "{}", error),
+            Self::Umem(error) => write!(f, "{}", error),
+            Self::Socket(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for HandoffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Mmap(error) => Some(error),
+            Self::Umem(error) => Some(error),
+            Self::Socket(error) => Some(error),
+            Self::Protocol | Self::UmemNotShared => None,
+        }
+    }
+}
+
+impl From<MmapError> for HandoffError {
+    fn from(value: MmapError) -> Self {
+        Self::Mmap(value)
+    }
+}
+
+impl From<UmemError> for HandoffError {
+    fn from(value: UmemError) -> Self {
+        Self::Umem(value)
+    }
+}
+
+impl From<SocketError> for HandoffError {
+    fn from(value: SocketError) -> Self {
+        Self::Socket(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(payload: &str, fds: &[BorrowedFd]) -> Result<Handoff, HandoffError> {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let length = (payload.len() as u64).to_le_bytes();
+        send_fds(&sender, &length, fds).unwrap();
+        sender.write_all(payload.as_bytes()).unwrap();
+
+        Handoff::receive(&receiver)
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_fd();
+
+        for payload in ["", "mangonel-handoff 2 0\n", "mangonel-handoff 1 x\n"] {
+            let result = receive(payload, &[fd]);
+            assert!(
+                matches!(result, Err(HandoffError::Protocol)),
+                "{:?}",
+                payload
+            );
+        }
+        // Every socket needs the fds of its UMEM and of itself.
+        let result = receive("mangonel-handoff 1 1\n0 4096 0 2048\n", &[fd]);
+        assert!(matches!(result, Err(HandoffError::Protocol)));
+        let result = receive("mangonel-handoff 1 0\n", &[fd, fd]);
+        assert!(matches!(result, Err(HandoffError::Protocol)));
+    }
+
+    #[test]
+    fn nothing_to_adopt() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let handoff = receive("mangonel-handoff 1 0\n", &[file.as_fd()]).unwrap();
+        assert!(handoff.sockets.is_empty());
+    }
+
+    #[test]
+    fn disconnected_sender() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        drop(sender);
+        assert!(Handoff::receive(&receiver).is_err());
+
+        // The length arrives but the payload does not.
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = std::fs::File::open("/dev/null").unwrap();
+        send_fds(&sender, &64u64.to_le_bytes(), &[file.as_fd()]).unwrap();
+        drop(sender);
+        assert!(matches!(
+            Handoff::receive(&receiver),
+            Err(HandoffError::Io(_))
+        ));
+    }
+}
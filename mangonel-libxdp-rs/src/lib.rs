@@ -1,7 +1,23 @@
+#[cfg(not(any(feature = "libxdp", feature = "native")))]
+compile_error!("Enable either the `libxdp` or the `native` feature.");
+
+#[cfg(feature = "tokio")]
+pub mod async_socket;
+pub mod backend;
 pub mod buffer;
 pub mod descriptor;
+pub mod fd;
+pub mod handoff;
+pub mod loopback;
 pub mod mmap;
+pub mod numa;
+pub mod pcap;
+pub mod preflight;
+pub mod replay;
 pub mod ring;
 pub mod socket;
 pub mod umem;
 pub mod util;
+#[cfg(feature = "native")]
+mod xsk;
+pub mod xskmap;
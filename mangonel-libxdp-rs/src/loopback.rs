@@ -0,0 +1,259 @@
+//! An in-memory [`Backend`] for exercising packet processing code without a
+//! NIC or privileges.
+//!
+//! Frames injected with [`Loopback::inject`] are received into frames handed
+//! over with [`Backend::fill`], like the kernel does, and frames transmitted
+//! with [`Backend::tx_burst`] are held until [`Loopback::transmitted`]
+//! collects them and makes them available to [`Backend::complete`]. Frames
+//! sent with [`Sender::send`] are copied into filled frames and transmitted
+//! the same way.
+
+use std::collections::VecDeque;
+
+use libc::xdp_desc;
+
+use crate::{
+    backend::{Backend, Sender},
+    buffer::Buffer,
+    descriptor::Descriptor,
+    socket::SocketError,
+    umem::{Umem, UmemError},
+};
+
+pub struct Loopback {
+    umem: Umem,
+    fill_queue: VecDeque<u64>,
+    rx_queue: VecDeque<xdp_desc>,
+    tx_queue: VecDeque<xdp_desc>,
+    completion_queue: VecDeque<u64>,
+}
+
+impl Loopback {
+    /// Allocate a UMEM of `frame_count` frames. None of them can receive
+    /// until the application fills them, as with a real socket.
+    pub fn new(
+        frame_size: u32,
+        frame_headroom_size: u32,
+        frame_count: u32,
+    ) -> Result<Self, UmemError> {
+        let umem = Umem::detached(frame_size, frame_headroom_size, frame_count)?;
+        let capacity = frame_count as usize;
+
+        Ok(Self {
+            umem,
+            fill_queue: VecDeque::with_capacity(capacity),
+            rx_queue: VecDeque::with_capacity(capacity),
+            tx_queue: VecDeque::with_capacity(capacity),
+            completion_queue: VecDeque::with_capacity(capacity),
+        })
+    }
+
+    /// Receive a frame and return the address of its descriptor. Return
+    /// [`None`] when it is dropped because no frame is filled or it does not
+    /// fit in one.
+    pub fn inject(&mut self, data: &[u8]) -> Option<u64> {
+        let headroom_size = self.umem.headroom_size();
+        if data.len() > self.max_frame_length() {
+            return None;
+        }
+        let frame = self.fill_queue.pop_front()?;
+
+        let address = frame + headroom_size as u64;
+        let frame = self.umem.get_data(address) as *mut u8;
+        unsafe { frame.copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+        self.rx_queue.push_back(xdp_desc {
+            addr: address,
+            len: data.len() as u32,
+            options: 0,
+        });
+
+        Some(address)
+    }
+
+    /// Return the frames transmitted since the last call and make them
+    /// available to [`Backend::complete`].
+    pub fn transmitted(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::with_capacity(self.tx_queue.len());
+        self.drain_transmitted(|_, data| frames.push(data.to_vec()));
+
+        frames
+    }
+
+    /// Pass the address and data of each frame transmitted since the last
+    /// call to `f` and make them available to [`Backend::complete`].
+    pub fn drain_transmitted(&mut self, mut f: impl FnMut(u64, &[u8])) {
+        while let Some(descriptor) = self.tx_queue.pop_front() {
+            let frame = self.umem.get_data(descriptor.addr) as *const u8;
+            let data = unsafe { std::slice::from_raw_parts(frame, descriptor.len as usize) };
+            f(descriptor.addr, data);
+            self.completion_queue.push_back(descriptor.addr);
+        }
+    }
+
+    /// Return the length of the largest frame that can be received.
+    pub fn max_frame_length(&self) -> usize {
+        (self.umem.frame_size() - self.umem.headroom_size()) as usize
+    }
+
+    /// Return the number of frames that can receive.
+    pub fn filled(&self) -> usize {
+        self.fill_queue.len()
+    }
+}
+
+impl Backend for Loopback {
+    fn umem(&self) -> Umem {
+        self.umem.clone()
+    }
+
+    fn rx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        let size = std::cmp::min(buffer.free() as usize, self.rx_queue.len());
+        self.rx_queue.drain(..size).for_each(|descriptor| {
+            buffer.push(Descriptor::from((&descriptor, &self.umem)));
+        });
+
+        size as u32
+    }
+
+    fn tx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        let mut sent = 0;
+        while let Some(descriptor) = buffer.pop() {
+            self.tx_queue.push_back(xdp_desc {
+                addr: descriptor.address(),
+                len: descriptor.length(),
+                options: 0,
+            });
+            sent += 1;
+        }
+
+        sent
+    }
+
+    fn fill<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        let frame_size = self.umem.frame_size() as u64;
+        let mut filled = 0;
+        while let Some(address) = buffer.pop() {
+            // Like the kernel in aligned mode, ignore the offset within the
+            // frame.
+            self.fill_queue.push_back(address - address % frame_size);
+            filled += 1;
+        }
+
+        filled
+    }
+
+    fn complete<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        let size = std::cmp::min(buffer.free() as usize, self.completion_queue.len());
+        self.completion_queue.drain(..size).for_each(|address| {
+            buffer.push(address);
+        });
+
+        size as u32
+    }
+}
+
+impl Sender for Loopback {
+    fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        if payload.len() > self.max_frame_length() {
+            return Err(SocketError::PayloadTooLarge {
+                length: payload.len(),
+                capacity: self.max_frame_length() as u32,
+            });
+        }
+        let frame = self.fill_queue.pop_front().ok_or(SocketError::TxFull)?;
+
+        let address = frame + self.umem.headroom_size() as u64;
+        let data = self.umem.get_data(address) as *mut u8;
+        unsafe { data.copy_from_nonoverlapping(payload.as_ptr(), payload.len()) };
+        self.tx_queue.push_back(xdp_desc {
+            addr: address,
+            len: payload.len() as u32,
+            options: 0,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_SIZE: u32 = 2048;
+    const HEADROOM_SIZE: u32 = 256;
+
+    fn loopback(frame_count: u32) -> Loopback {
+        let mut loopback = Loopback::new(FRAME_SIZE, HEADROOM_SIZE, frame_count).unwrap();
+        let mut frames: VecDeque<u64> = (0..frame_count as u64)
+            .map(|index| index * FRAME_SIZE as u64)
+            .collect();
+        assert_eq!(loopback.fill(&mut frames), frame_count);
+
+        loopback
+    }
+
+    #[test]
+    fn inject_needs_filled_frames() {
+        let mut loopback = Loopback::new(FRAME_SIZE, HEADROOM_SIZE, 1).unwrap();
+        assert_eq!(loopback.inject(&[1, 2, 3]), None);
+
+        let mut frames = VecDeque::from([100]);
+        loopback.fill(&mut frames);
+        // The offset within the frame is ignored, as in aligned mode.
+        assert_eq!(loopback.inject(&[1, 2, 3]), Some(HEADROOM_SIZE as u64));
+        assert_eq!(loopback.inject(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn inject_rejects_frames_too_long() {
+        let mut loopback = loopback(1);
+        let data = vec![0; loopback.max_frame_length() + 1];
+        assert_eq!(loopback.inject(&data), None);
+        assert_eq!(loopback.filled(), 1);
+    }
+
+    #[test]
+    fn rx_burst_is_bounded_by_the_buffer() {
+        let mut loopback = loopback(4);
+        for index in 0..3 {
+            loopback.inject(&[index; 60]).unwrap();
+        }
+
+        let mut buffer = VecDeque::with_capacity(2);
+        let received = loopback.rx_burst(&mut buffer);
+        assert_eq!(received as usize, buffer.capacity().min(3));
+        assert_eq!(buffer[0].payload(), &[0; 60]);
+        buffer.clear();
+        assert_eq!(loopback.rx_burst(&mut buffer), 3 - received);
+        assert_eq!(buffer.back().unwrap().payload(), &[2; 60]);
+    }
+
+    #[test]
+    fn transmitted_frames_complete() {
+        let mut loopback = loopback(2);
+        let address = loopback.inject(b"frame").unwrap();
+        let mut buffer = VecDeque::with_capacity(2);
+        loopback.rx_burst(&mut buffer);
+
+        assert_eq!(loopback.tx_burst(&mut buffer), 1);
+        let mut completed = VecDeque::with_capacity(2);
+        // Nothing completes before the frames are collected.
+        assert_eq!(loopback.complete(&mut completed), 0);
+        assert_eq!(loopback.transmitted(), vec![b"frame".to_vec()]);
+        assert_eq!(loopback.complete(&mut completed), 1);
+        assert_eq!(completed, VecDeque::from([address]));
+    }
+
+    #[test]
+    fn sent_frames_take_filled_frames() {
+        let mut loopback = loopback(1);
+        assert!(loopback.send(b"built").is_ok());
+        assert!(matches!(loopback.send(b"built"), Err(SocketError::TxFull)));
+        assert_eq!(loopback.filled(), 0);
+
+        assert_eq!(loopback.transmitted(), vec![b"built".to_vec()]);
+        let mut completed = VecDeque::with_capacity(1);
+        assert_eq!(loopback.complete(&mut completed), 1);
+        assert_eq!(completed, VecDeque::from([HEADROOM_SIZE as u64]));
+    }
+}
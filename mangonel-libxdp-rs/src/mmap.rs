@@ -1,18 +1,246 @@
 use std::{
     ffi::c_void,
+    mem::MaybeUninit,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
     ptr::{null_mut, NonNull},
 };
 
 use libc::{
-    mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+    fstat, fstatfs, ftruncate, memfd_create, mlock, mmap, munmap, sysconf, HUGETLBFS_MAGIC,
+    MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_HUGE_1GB, MAP_HUGE_2MB, MAP_POPULATE, MAP_PRIVATE,
+    MAP_SHARED, MFD_CLOEXEC, MFD_HUGETLB, MFD_HUGE_1GB, MFD_HUGE_2MB, PROT_READ, PROT_WRITE,
+    _SC_PAGESIZE,
 };
 
+use crate::{numa, util::round_up};
+
+const HUGEPAGES_PATH: &str = "/sys/kernel/mm/hugepages";
+const MEMFD_NAME: &[u8] = b"mangonel-umem\0";
+
+/// The size of the pages backing a [`Mmap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// The base page size of the system, usually 4 KiB.
+    Regular,
+    Huge2MiB,
+    Huge1GiB,
+}
+
+impl PageSize {
+    /// Return the page size in bytes.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Regular => unsafe { sysconf(_SC_PAGESIZE) as usize },
+            Self::Huge2MiB => 1 << 21,
+            Self::Huge1GiB => 1 << 30,
+        }
+    }
+
+    /// Return the number of free pages of this size in the system-wide
+    /// hugepage pool. Returns [`None`] for [`PageSize::Regular`] and for sizes
+    /// the kernel does not support.
+    pub fn free_pages(&self) -> Option<usize> {
+        self.read_count("free_hugepages")
+    }
+
+    /// Return the number of pages of this size that can be mapped: the free
+    /// pages of the pool and the surplus pages the kernel may still allocate
+    /// beyond it with `nr_overcommit_hugepages`.
+    pub fn available_pages(&self) -> Option<usize> {
+        let free = self.free_pages()?;
+        let overcommit = self.read_count("nr_overcommit_hugepages").unwrap_or(0);
+        let surplus = self.read_count("surplus_hugepages").unwrap_or(0);
+
+        Some(free + overcommit.saturating_sub(surplus))
+    }
+
+    /// Read a counter of the hugepage pool of this size.
+    fn read_count(&self, name: &str) -> Option<usize> {
+        if *self == Self::Regular {
+            return None;
+        }
+
+        let path = format!(
+            "{}/hugepages-{}kB/{}",
+            HUGEPAGES_PATH,
+            self.bytes() >> 10,
+            name
+        );
+        parse_count(&std::fs::read_to_string(path).ok()?)
+    }
+
+    /// Return the hugepage sizes supported by the kernel from the smallest to
+    /// the largest.
+    pub fn hugepage_sizes() -> Vec<Self> {
+        [Self::Huge2MiB, Self::Huge1GiB]
+            .into_iter()
+            .filter(|page_size| page_size.free_pages().is_some())
+            .collect()
+    }
+
+    fn from_bytes(bytes: usize) -> Option<Self> {
+        [Self::Regular, Self::Huge2MiB, Self::Huge1GiB]
+            .into_iter()
+            .find(|page_size| page_size.bytes() == bytes)
+    }
+
+    fn flags(&self) -> i32 {
+        match self {
+            Self::Regular => 0,
+            Self::Huge2MiB => MAP_HUGETLB | MAP_HUGE_2MB,
+            Self::Huge1GiB => MAP_HUGETLB | MAP_HUGE_1GB,
+        }
+    }
+
+    fn memfd_flags(&self) -> u32 {
+        match self {
+            Self::Regular => 0,
+            Self::Huge2MiB => MFD_HUGETLB | MFD_HUGE_2MB,
+            Self::Huge1GiB => MFD_HUGETLB | MFD_HUGE_1GB,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MmapBuilder {
+    pub page_size: PageSize,
+    /// Pre-fault the whole mapping with `MAP_POPULATE`.
+    pub populate: bool,
+    /// Lock the mapping in memory with `mlock`.
+    pub lock: bool,
+    /// Bind the mapping to the NUMA node before any page is faulted in.
+    pub numa_node: Option<u32>,
+    /// Back the mapping with a memfd instead of anonymous memory so that
+    /// [`Mmap::fd`] can be passed to another process and mapped there with
+    /// [`Mmap::from_fd`].
+    pub shared: bool,
+    /// Fall back to regular pages when the requested hugepages cannot be
+    /// mapped. The reason is kept in [`Mmap::hugepage_fallback`].
+    pub fallback: bool,
+}
+
+impl Default for MmapBuilder {
+    fn default() -> Self {
+        Self {
+            page_size: PageSize::Regular,
+            populate: false,
+            lock: false,
+            numa_node: None,
+            shared: false,
+            fallback: true,
+        }
+    }
+}
+
+impl MmapBuilder {
+    /// Map at least `length` bytes. The length is rounded up to a multiple of
+    /// the page size.
+    pub fn build(&self, length: usize) -> Result<Mmap, MmapError> {
+        let mmap = match self.map(length, self.page_size) {
+            Ok(mmap) => mmap,
+            Err(error) if self.fallback && self.page_size != PageSize::Regular => {
+                let mut mmap = self.map(length, PageSize::Regular)?;
+                mmap.hugepage_fallback = Some(error);
+                mmap
+            }
+            Err(error) => return Err(error),
+        };
+
+        if let Some(node) = self.numa_node {
+            numa::bind(mmap.as_ptr(), mmap.length, node)
+                .map_err(|error| MmapError::Bind(node, error))?;
+            if self.populate {
+                mmap.populate();
+            }
+        }
+
+        if self.lock {
+            mmap.lock()?;
+        }
+
+        Ok(mmap)
+    }
+
+    fn map(&self, length: usize, page_size: PageSize) -> Result<Mmap, MmapError> {
+        let length = round_up(length, page_size.bytes());
+        if page_size != PageSize::Regular {
+            let required = length / page_size.bytes();
+            let available = page_size.available_pages().unwrap_or(0);
+            if available < required {
+                return Err(MmapError::HugepageUnavailable {
+                    page_size,
+                    required,
+                    available,
+                });
+            }
+        }
+
+        let fd = match self.shared {
+            true => Some(memfd(length, page_size)?),
+            false => None,
+        };
+
+        let protection_mode = PROT_READ | PROT_WRITE;
+        let (mut flags, raw_fd) = match &fd {
+            Some(fd) => (MAP_SHARED, fd.as_raw_fd()),
+            None => (MAP_PRIVATE | MAP_ANONYMOUS | page_size.flags(), -1),
+        };
+        // `MAP_POPULATE` faults pages in on the local node, so it has to wait
+        // until the mapping is bound to a node.
+        if self.populate && self.numa_node.is_none() {
+            flags |= MAP_POPULATE;
+        }
+
+        let address = unsafe { mmap(null_mut(), length, protection_mode, flags, raw_fd, 0) };
+        if address == MAP_FAILED {
+            return Err(MmapError::Initialize(std::io::Error::last_os_error()));
+        }
+
+        Ok(Mmap {
+            address: NonNull::new(address).ok_or(MmapError::MmapIsNull)?,
+            length,
+            page_size,
+            fd,
+            hugepage_fallback: None,
+        })
+    }
+}
+
+/// Parse a counter of sysfs, a decimal number on a line.
+fn parse_count(text: &str) -> Option<usize> {
+    text.trim().parse().ok()
+}
+
+/// Create a memfd of `length` bytes backed by pages of `page_size`.
+fn memfd(length: usize, page_size: PageSize) -> Result<OwnedFd, MmapError> {
+    let flags = MFD_CLOEXEC | page_size.memfd_flags();
+    let value = unsafe { memfd_create(MEMFD_NAME.as_ptr().cast(), flags) };
+    if value.is_negative() {
+        return Err(MmapError::Initialize(std::io::Error::last_os_error()));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(value) };
+
+    let value = unsafe { ftruncate(fd.as_raw_fd(), length as i64) };
+    if value.is_negative() {
+        return Err(MmapError::Initialize(std::io::Error::last_os_error()));
+    }
+
+    Ok(fd)
+}
+
 #[derive(Debug)]
 pub struct Mmap {
     address: NonNull<c_void>,
     length: usize,
+    page_size: PageSize,
+    fd: Option<OwnedFd>,
+    hugepage_fallback: Option<MmapError>,
 }
 
+unsafe impl Send for Mmap {}
+
+unsafe impl Sync for Mmap {}
+
 impl Drop for Mmap {
     /// # Panics
     ///
@@ -22,20 +250,85 @@ impl Drop for Mmap {
     fn drop(&mut self) {
         let value = unsafe { munmap(self.address.as_ptr(), self.length) };
         if value.is_negative() {
-            panic!("{:?}", MmapError::Free(std::io::Error::last_os_error()));
+            panic!("{}", MmapError::Free(std::io::Error::last_os_error()));
         }
     }
 }
 
 impl Mmap {
-    pub fn new(length: usize, hugetlb: bool) -> Result<Self, MmapError> {
+    pub fn new(length: usize, page_size: PageSize) -> Result<Self, MmapError> {
+        let builder = MmapBuilder {
+            page_size,
+            ..Default::default()
+        };
+
+        builder.build(length)
+    }
+
+    /// Map the whole shared memory file, typically the [`Mmap::fd`] of a
+    /// mapping created in another process and received over a Unix socket.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, MmapError> {
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        let value = unsafe { fstat(fd.as_raw_fd(), stat.as_mut_ptr()) };
+        if value.is_negative() {
+            return Err(MmapError::Initialize(std::io::Error::last_os_error()));
+        }
+        let length = unsafe { stat.assume_init() }.st_size as usize;
+
+        let mut statfs = MaybeUninit::<libc::statfs>::uninit();
+        let value = unsafe { fstatfs(fd.as_raw_fd(), statfs.as_mut_ptr()) };
+        if value.is_negative() {
+            return Err(MmapError::Initialize(std::io::Error::last_os_error()));
+        }
+        let statfs = unsafe { statfs.assume_init() };
+        let page_size = match statfs.f_type as u32 == HUGETLBFS_MAGIC as u32 {
+            true => PageSize::from_bytes(statfs.f_bsize as usize).unwrap_or(PageSize::Regular),
+            false => PageSize::Regular,
+        };
+
         let protection_mode = PROT_READ | PROT_WRITE;
-        let mut flags = MAP_PRIVATE | MAP_ANONYMOUS;
-        if hugetlb {
-            flags |= MAP_HUGETLB;
+        let address = unsafe {
+            mmap(
+                null_mut(),
+                length,
+                protection_mode,
+                MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if address == MAP_FAILED {
+            return Err(MmapError::Initialize(std::io::Error::last_os_error()));
         }
 
-        let address = unsafe { mmap(null_mut(), length, protection_mode, flags, -1, 0) };
+        Ok(Self {
+            address: NonNull::new(address).ok_or(MmapError::MmapIsNull)?,
+            length,
+            page_size,
+            fd: Some(fd),
+            hugepage_fallback: None,
+        })
+    }
+
+    /// Map `length` bytes of a ring of an XSK socket at the page offset
+    /// defined by the kernel. The socket file is not owned by the mapping.
+    pub(crate) fn from_socket(
+        fd: BorrowedFd,
+        length: usize,
+        offset: i64,
+    ) -> Result<Self, MmapError> {
+        let protection_mode = PROT_READ | PROT_WRITE;
+        let flags = MAP_SHARED | MAP_POPULATE;
+        let address = unsafe {
+            mmap(
+                null_mut(),
+                length,
+                protection_mode,
+                flags,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
         if address == MAP_FAILED {
             return Err(MmapError::Initialize(std::io::Error::last_os_error()));
         }
@@ -43,9 +336,29 @@ impl Mmap {
         Ok(Self {
             address: NonNull::new(address).ok_or(MmapError::MmapIsNull)?,
             length,
+            page_size: PageSize::Regular,
+            fd: None,
+            hugepage_fallback: None,
         })
     }
 
+    /// Fault in every page by writing to it.
+    fn populate(&self) {
+        let base = self.as_ptr() as *mut u8;
+        for offset in (0..self.length).step_by(self.page_size.bytes()) {
+            unsafe { base.add(offset).write_volatile(0) };
+        }
+    }
+
+    fn lock(&self) -> Result<(), MmapError> {
+        let value = unsafe { mlock(self.as_ptr(), self.length) };
+        if value.is_negative() {
+            return Err(MmapError::Lock(std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn as_ptr(&self) -> *mut c_void {
         self.address.as_ptr()
@@ -60,28 +373,195 @@ impl Mmap {
     pub fn length(&self) -> usize {
         self.length
     }
+
+    /// Return the page size actually backing the mapping.
+    #[inline(always)]
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// Return the memfd backing a shared mapping.
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.fd.as_ref().map(|fd| fd.as_fd())
+    }
+
+    /// Return the reason the requested hugepages could not be mapped when the
+    /// mapping fell back to regular pages.
+    pub fn hugepage_fallback(&self) -> Option<&MmapError> {
+        self.hugepage_fallback.as_ref()
+    }
 }
 
+#[derive(Debug)]
 pub enum MmapError {
     Initialize(std::io::Error),
+    HugepageUnavailable {
+        page_size: PageSize,
+        required: usize,
+        available: usize,
+    },
     MmapIsNull,
+    Bind(u32, std::io::Error),
+    Lock(std::io::Error),
     Free(std::io::Error),
 }
 
-impl std::fmt::Debug for MmapError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl MmapError {
+    /// Return how to fix the error when the cause is well known.
+    pub fn hint(&self) -> Option<&'static str> {
         match self {
-            Self::Initialize(error) => write!(f, "Failed to initialize `Mmap`: {:?}", error),
-            Self::MmapIsNull => write!(f, "Mmap address is null"),
-            Self::Free(error) => write!(f, "Failed to free `Mmap`: {:?}", error),
+            Self::Initialize(error) if error.raw_os_error() == Some(libc::ENOMEM) => {
+                Some("Reserve more hugepages or map fewer frames")
+            }
+            Self::HugepageUnavailable { .. } => Some(
+                "Reserve hugepages through /sys/kernel/mm/hugepages or enable the fallback to \
+                 regular pages",
+            ),
+            Self::Bind(..) => Some("Check that the NUMA node exists and has free memory"),
+            Self::Lock(error)
+                if matches!(
+                    error.raw_os_error(),
+                    Some(libc::ENOMEM | libc::EAGAIN | libc::EPERM)
+                ) =>
+            {
+                Some("Raise RLIMIT_MEMLOCK (`ulimit -l`) or grant CAP_IPC_LOCK")
+            }
+            _ => None,
         }
     }
 }
 
 impl std::fmt::Display for MmapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::Initialize(error) => write!(f, "Failed to map memory: {}", error)?,
+            Self::HugepageUnavailable {
+                page_size,
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough {:?} hugepages: {} required, {} available",
+                page_size, required, available
+            )?,
+            Self::MmapIsNull => write!(f, "The mapped address is null")?,
+            Self::Bind(node, error) => {
+                write!(f, "Failed to bind memory to NUMA node {}: {}", node, error)?
+            }
+            Self::Lock(error) => write!(f, "Failed to lock memory: {}", error)?,
+            Self::Free(error) => write!(f, "Failed to unmap memory: {}", error)?,
+        }
+
+        match self.hint() {
+            Some(hint) => write!(f, ". {}", hint),
+            None => Ok(()),
+        }
     }
 }
 
-impl std::error::Error for MmapError {}
+impl std::error::Error for MmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Initialize(error)
+            | Self::Bind(_, error)
+            | Self::Lock(error)
+            | Self::Free(error) => Some(error),
+            Self::HugepageUnavailable { .. } | Self::MmapIsNull => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_is_rounded_up_to_pages() {
+        let page = PageSize::Regular.bytes();
+        for (length, expected) in [(1, page), (page, page), (page + 1, 2 * page)] {
+            let mmap = Mmap::new(length, PageSize::Regular).unwrap();
+            assert_eq!(mmap.length(), expected);
+            assert_eq!(mmap.page_size(), PageSize::Regular);
+            assert!(mmap.hugepage_fallback().is_none());
+        }
+    }
+
+    #[test]
+    fn page_sizes() {
+        assert_eq!(PageSize::Huge2MiB.bytes(), 2 << 20);
+        assert_eq!(PageSize::Huge1GiB.bytes(), 1 << 30);
+        assert_eq!(PageSize::from_bytes(2 << 20), Some(PageSize::Huge2MiB));
+        assert_eq!(
+            PageSize::from_bytes(PageSize::Regular.bytes()),
+            Some(PageSize::Regular)
+        );
+        assert_eq!(PageSize::from_bytes(12345), None);
+        assert_eq!(PageSize::Regular.free_pages(), None);
+        assert_eq!(PageSize::Regular.available_pages(), None);
+
+        assert_eq!(parse_count("16\n"), Some(16));
+        assert_eq!(parse_count("0"), Some(0));
+        assert_eq!(parse_count(""), None);
+        assert_eq!(parse_count("-1\n"), None);
+    }
+
+    #[test]
+    fn hugepages_fall_back_to_regular_pages() {
+        let page_size = PageSize::Huge2MiB;
+        // More pages than can be mapped.
+        let required = page_size.available_pages().unwrap_or(0) + 1;
+        let mut builder = MmapBuilder {
+            page_size,
+            ..Default::default()
+        };
+
+        let mmap = builder.build(required * page_size.bytes()).unwrap();
+        assert_eq!(mmap.page_size(), PageSize::Regular);
+        assert_eq!(mmap.length(), required * page_size.bytes());
+        assert!(matches!(
+            mmap.hugepage_fallback(),
+            Some(MmapError::HugepageUnavailable { required: fallback, .. }) if *fallback == required
+        ));
+
+        builder.fallback = false;
+        let error = builder.build(required * page_size.bytes()).unwrap_err();
+        assert!(matches!(error, MmapError::HugepageUnavailable { .. }));
+        assert!(error.hint().is_some());
+    }
+
+    #[test]
+    fn shared_mappings() {
+        let mmap = MmapBuilder {
+            shared: true,
+            ..Default::default()
+        }
+        .build(100)
+        .unwrap();
+        unsafe { (mmap.as_ptr() as *mut u8).add(99).write(7) };
+
+        let fd = mmap.fd().unwrap().try_clone_to_owned().unwrap();
+        let other = Mmap::from_fd(fd).unwrap();
+        assert_eq!(other.length(), mmap.length());
+        assert_eq!(other.page_size(), PageSize::Regular);
+        assert_eq!(unsafe { *(other.as_ptr() as *const u8).add(99) }, 7);
+
+        assert!(Mmap::new(100, PageSize::Regular).unwrap().fd().is_none());
+    }
+
+    #[test]
+    fn populate_and_lock() {
+        let mmap = MmapBuilder {
+            populate: true,
+            ..Default::default()
+        }
+        .build(1 << 16)
+        .unwrap();
+        mmap.populate();
+        assert_eq!(unsafe { *(mmap.as_ptr() as *const u8) }, 0);
+
+        // Locking may exceed RLIMIT_MEMLOCK, which has a hint.
+        if let Err(error) = mmap.lock() {
+            assert!(matches!(error, MmapError::Lock(_)));
+        }
+    }
+}
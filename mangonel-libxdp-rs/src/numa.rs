@@ -0,0 +1,127 @@
+use std::{ffi::c_void, path::Path};
+
+use libc::{syscall, SYS_mbind, MPOL_BIND};
+
+const NODE_PATH: &str = "/sys/devices/system/node";
+
+/// Return the NUMA node the device behind the network interface is attached
+/// to. Returns [`None`] on single-node systems and for virtual interfaces.
+pub fn interface_node(interface_name: impl AsRef<str>) -> Option<u32> {
+    let path = format!(
+        "/sys/class/net/{}/device/numa_node",
+        interface_name.as_ref()
+    );
+
+    parse_node(&std::fs::read_to_string(path).ok()?)
+}
+
+/// Parse a `numa_node` file, which holds -1 when the node is unknown.
+fn parse_node(text: &str) -> Option<u32> {
+    let node: i32 = text.trim().parse().ok()?;
+
+    node.try_into().ok()
+}
+
+/// Order `items` with the ones running on `local_cpus` first, keeping their
+/// order otherwise.
+pub fn prefer_local<T>(items: &mut [T], local_cpus: &[usize], cpu: impl Fn(&T) -> usize) {
+    items.sort_by_key(|item| !local_cpus.contains(&cpu(item)));
+}
+
+/// Return the CPUs that belong to the NUMA node.
+pub fn node_cpus(node: u32) -> Vec<usize> {
+    let path = Path::new(NODE_PATH)
+        .join(format!("node{}", node))
+        .join("cpulist");
+
+    std::fs::read_to_string(path)
+        .map(|cpu_list| parse_cpu_list(cpu_list.trim()))
+        .unwrap_or_default()
+}
+
+/// Parse a kernel cpu list such as "0-3,8,10-11".
+fn parse_cpu_list(cpu_list: &str) -> Vec<usize> {
+    cpu_list
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                Some(cpu..=cpu)
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+/// Bind the memory range to the NUMA node. Pages that are already faulted in
+/// are not moved, so this must be called before the range is touched.
+pub(crate) fn bind(address: *mut c_void, length: usize, node: u32) -> std::io::Result<()> {
+    let bits = u64::BITS as usize;
+    let mut node_mask = vec![0u64; node as usize / bits + 1];
+    node_mask[node as usize / bits] |= 1 << (node as usize % bits);
+
+    let value = unsafe {
+        syscall(
+            SYS_mbind,
+            address,
+            length,
+            MPOL_BIND,
+            node_mask.as_ptr(),
+            node_mask.len() * bits + 1,
+            0,
+        )
+    };
+    if value.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::{Mmap, PageSize};
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("1\n"), Some(1));
+        assert_eq!(parse_node("0"), Some(0));
+        // Unknown, e.g. on single-node systems.
+        assert_eq!(parse_node("-1\n"), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11"), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5"), [5]);
+        assert_eq!(parse_cpu_list(""), Vec::<usize>::new());
+        // Invalid ranges are skipped.
+        assert_eq!(parse_cpu_list("a-b,2,3-x"), [2]);
+    }
+
+    #[test]
+    fn local_cpus_first() {
+        let mut cpus = vec![0, 1, 2, 3, 4, 5];
+        prefer_local(&mut cpus, &[4, 1, 5], |cpu| *cpu);
+        assert_eq!(cpus, [1, 4, 5, 0, 2, 3]);
+
+        prefer_local(&mut cpus, &[], |cpu| *cpu);
+        assert_eq!(cpus, [1, 4, 5, 0, 2, 3]);
+    }
+
+    #[test]
+    fn bind_to_a_node() {
+        if !Path::new(NODE_PATH).join("node0").exists() {
+            return;
+        }
+        assert!(!node_cpus(0).is_empty());
+
+        let mmap = Mmap::new(1 << 16, PageSize::Regular).unwrap();
+        bind(mmap.as_ptr(), mmap.length(), 0).unwrap();
+        // A node beyond the possible ones.
+        assert!(bind(mmap.as_ptr(), mmap.length(), 1 << 16).is_err());
+    }
+}
@@ -0,0 +1,739 @@
+//! Reading pcap and pcapng files and writing pcap and pcapng files of Ethernet
+//! frames.
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x00000003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x00000006;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_COMMENT: u16 = 1;
+const PCAPNG_OPTION_NAME: u16 = 2;
+const PCAPNG_OPTION_FLAGS: u16 = 2;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+const SNAPLEN: u32 = 262144;
+/// The longest pcapng block read, as in Wireshark.
+const MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+/// The finest decimal resolution whose units still fit in 64 bits.
+const MAX_DECIMAL_EXPONENT: u8 = 19;
+
+/// A frame read from a capture file.
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// The capture time since the Unix epoch.
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+enum Format {
+    Pcap {
+        nanoseconds: bool,
+    },
+    /// Timestamp resolutions of the interfaces of the current section.
+    Pcapng {
+        resolutions: Vec<Resolution>,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Resolution {
+    /// Units of 10^-n seconds.
+    Decimal(u8),
+    /// Units of 2^-n seconds.
+    Binary(u8),
+}
+
+impl Resolution {
+    fn duration(self, units: u64) -> Duration {
+        let nanoseconds = match self {
+            Self::Decimal(exponent) if exponent <= 9 => {
+                units as u128 * 10u128.pow(9 - exponent as u32)
+            }
+            Self::Decimal(exponent) => units as u128 / 10u128.pow(exponent as u32 - 9),
+            Self::Binary(exponent) => (units as u128 * 1_000_000_000) >> exponent,
+        };
+
+        Duration::new(
+            (nanoseconds / 1_000_000_000) as u64,
+            (nanoseconds % 1_000_000_000) as u32,
+        )
+    }
+}
+
+/// Read the records of a pcap or pcapng file, detected by its magic number.
+pub struct PcapReader<R> {
+    reader: R,
+    format: Format,
+    big_endian: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, PcapError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut pcap_reader = Self {
+                reader,
+                format: Format::Pcapng {
+                    resolutions: Vec::new(),
+                },
+                big_endian: false,
+            };
+            pcap_reader.read_section_header()?;
+
+            return Ok(pcap_reader);
+        }
+
+        let (big_endian, nanoseconds) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic))
+        {
+            (PCAP_MAGIC_MICROSECONDS, _) => (false, false),
+            (PCAP_MAGIC_NANOSECONDS, _) => (false, true),
+            (_, PCAP_MAGIC_MICROSECONDS) => (true, false),
+            (_, PCAP_MAGIC_NANOSECONDS) => (true, true),
+            _ => return Err(PcapError::Format("unknown magic number")),
+        };
+        let mut pcap_reader = Self {
+            reader,
+            format: Format::Pcap { nanoseconds },
+            big_endian,
+        };
+
+        // Version, time zone, accuracy and snapshot length.
+        pcap_reader.skip_bytes(16)?;
+        let link_type = pcap_reader.read_u32()? & 0xffff;
+        if link_type != LINKTYPE_ETHERNET {
+            return Err(PcapError::LinkType(link_type));
+        }
+
+        Ok(pcap_reader)
+    }
+
+    /// Return [`None`] at the end of the file.
+    pub fn next_record(&mut self) -> Result<Option<Record>, PcapError> {
+        match self.format {
+            Format::Pcap { nanoseconds } => self.next_pcap_record(nanoseconds),
+            Format::Pcapng { .. } => self.next_pcapng_record(),
+        }
+    }
+
+    fn next_pcap_record(&mut self, nanoseconds: bool) -> Result<Option<Record>, PcapError> {
+        let Some(seconds) = self.read_u32_or_eof()? else {
+            return Ok(None);
+        };
+        let fraction = self.read_u32()?;
+        let captured_length = self.read_u32()?;
+        let _original_length = self.read_u32()?;
+
+        if captured_length > SNAPLEN {
+            return Err(PcapError::Format("record longer than the snapshot length"));
+        }
+        let fraction = match nanoseconds {
+            true => fraction,
+            false => fraction.checked_mul(1000).unwrap_or(u32::MAX),
+        };
+        if fraction >= 1_000_000_000 {
+            return Err(PcapError::Format("invalid timestamp"));
+        }
+        let data = self.read_vec(captured_length as usize)?;
+
+        Ok(Some(Record {
+            timestamp: Duration::new(seconds as u64, fraction),
+            data,
+        }))
+    }
+
+    fn next_pcapng_record(&mut self) -> Result<Option<Record>, PcapError> {
+        loop {
+            let Some(block_type) = self.read_u32_or_eof()? else {
+                return Ok(None);
+            };
+            if block_type == PCAPNG_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let total_length = self.read_u32()? as usize;
+            if !(12..=MAX_BLOCK_LENGTH).contains(&total_length) || total_length % 4 != 0 {
+                return Err(PcapError::Format("invalid block length"));
+            }
+            let body = self.read_vec(total_length - 12)?;
+            let _total_length = self.read_u32()?;
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => self.read_interface_description(&body)?,
+                PCAPNG_ENHANCED_PACKET => return self.read_enhanced_packet(&body).map(Some),
+                PCAPNG_SIMPLE_PACKET => {
+                    let original_length = self.u32_at(&body, 0)? as usize;
+                    let length = std::cmp::min(original_length, body.len() - 4);
+
+                    return Ok(Some(Record {
+                        timestamp: Duration::ZERO,
+                        data: body[4..4 + length].to_vec(),
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Read the rest of a section header block, whose type is already read.
+    fn read_section_header(&mut self) -> Result<(), PcapError> {
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        let mut byte_order_magic = [0u8; 4];
+        self.reader.read_exact(&mut byte_order_magic)?;
+
+        self.big_endian = match u32::from_le_bytes(byte_order_magic) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            _ if u32::from_be_bytes(byte_order_magic) == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(PcapError::Format("unknown byte order magic")),
+        };
+        let total_length = match self.big_endian {
+            true => u32::from_be_bytes(length),
+            false => u32::from_le_bytes(length),
+        } as usize;
+        if total_length < 28 {
+            return Err(PcapError::Format("invalid section header length"));
+        }
+
+        // Version, section length, options and the trailing length.
+        self.skip_bytes(total_length - 12)?;
+        self.format = Format::Pcapng {
+            resolutions: Vec::new(),
+        };
+
+        Ok(())
+    }
+
+    fn read_interface_description(&mut self, body: &[u8]) -> Result<(), PcapError> {
+        let link_type = self.u16_at(body, 0)? as u32;
+        if link_type != LINKTYPE_ETHERNET {
+            return Err(PcapError::LinkType(link_type));
+        }
+
+        let mut resolution = Resolution::Decimal(6);
+        let mut offset = 8;
+        while offset + 4 <= body.len() {
+            let code = self.u16_at(body, offset)?;
+            let length = self.u16_at(body, offset + 2)? as usize;
+            if code == PCAPNG_OPTION_END {
+                break;
+            }
+            if code == PCAPNG_OPTION_TSRESOL && length == 1 {
+                let value = *body.get(offset + 4).ok_or(PcapError::Truncated)?;
+                resolution = match value & 0x80 {
+                    0 if value <= MAX_DECIMAL_EXPONENT => Resolution::Decimal(value),
+                    0 => return Err(PcapError::Format("invalid timestamp resolution")),
+                    _ => Resolution::Binary(value & 0x7f),
+                };
+            }
+            offset += 4 + (length + 3) / 4 * 4;
+        }
+
+        if let Format::Pcapng { resolutions } = &mut self.format {
+            resolutions.push(resolution);
+        }
+
+        Ok(())
+    }
+
+    fn read_enhanced_packet(&self, body: &[u8]) -> Result<Record, PcapError> {
+        let interface_id = self.u32_at(body, 0)? as usize;
+        let high = self.u32_at(body, 4)? as u64;
+        let low = self.u32_at(body, 8)? as u64;
+        let captured_length = self.u32_at(body, 12)? as usize;
+        let data = body
+            .get(20..20 + captured_length)
+            .ok_or(PcapError::Truncated)?;
+
+        let resolution = match &self.format {
+            Format::Pcapng { resolutions } => resolutions.get(interface_id).copied(),
+            Format::Pcap { .. } => None,
+        }
+        .ok_or(PcapError::Format("packet of an undescribed interface"))?;
+
+        Ok(Record {
+            timestamp: resolution.duration(high << 32 | low),
+            data: data.to_vec(),
+        })
+    }
+
+    fn read_vec(&mut self, length: usize) -> Result<Vec<u8>, PcapError> {
+        let mut data = vec![0u8; length];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+
+    fn skip_bytes(&mut self, length: usize) -> Result<(), PcapError> {
+        let skipped = std::io::copy(
+            &mut self.reader.by_ref().take(length as u64),
+            &mut std::io::sink(),
+        )?;
+        if skipped != length as u64 {
+            return Err(PcapError::Truncated);
+        }
+
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, PcapError> {
+        self.read_u32_or_eof()?.ok_or(PcapError::Truncated)
+    }
+
+    /// Return [`None`] when the file ends before the first byte.
+    fn read_u32_or_eof(&mut self) -> Result<Option<u32>, PcapError> {
+        let mut bytes = [0u8; 4];
+        let mut read = 0;
+        while read < bytes.len() {
+            match self.reader.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(PcapError::Truncated),
+                Ok(length) => read += length,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(Some(self.u32_at(&bytes, 0)?))
+    }
+
+    fn u16_at(&self, bytes: &[u8], offset: usize) -> Result<u16, PcapError> {
+        let bytes: [u8; 2] = bytes
+            .get(offset..offset + 2)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(PcapError::Truncated)?;
+
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> Result<u32, PcapError> {
+        let bytes: [u8; 4] = bytes
+            .get(offset..offset + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(PcapError::Truncated)?;
+
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<Record, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Write Ethernet frames to a pcap file with nanosecond timestamps.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, PcapError> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC_NANOSECONDS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, timestamp: Duration, data: &[u8]) -> Result<(), PcapError> {
+        let captured_length = std::cmp::min(data.len(), SNAPLEN as usize);
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&timestamp.subsec_nanos().to_le_bytes());
+        header[8..12].copy_from_slice(&(captured_length as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&data[..captured_length])?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), PcapError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// The direction of a frame, recorded in the flags of a pcapng packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Write Ethernet frames to a pcapng file with nanosecond timestamps, along
+/// with their direction and a comment.
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    interface_count: u32,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, PcapError> {
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // The section length is unknown.
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, PCAPNG_SECTION_HEADER, &body)?;
+
+        Ok(Self {
+            writer,
+            interface_count: 0,
+        })
+    }
+
+    /// Describe an interface and return the identifier to write its frames
+    /// with.
+    pub fn add_interface(&mut self, name: &str) -> Result<u32, PcapError> {
+        let mut body = Vec::with_capacity(32 + name.len());
+        body.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&SNAPLEN.to_le_bytes());
+        push_option(&mut body, PCAPNG_OPTION_NAME, name.as_bytes());
+        push_option(&mut body, PCAPNG_OPTION_TSRESOL, &[9]);
+        push_option(&mut body, PCAPNG_OPTION_END, &[]);
+        write_block(&mut self.writer, PCAPNG_INTERFACE_DESCRIPTION, &body)?;
+
+        let interface_id = self.interface_count;
+        self.interface_count += 1;
+
+        Ok(interface_id)
+    }
+
+    pub fn write(
+        &mut self,
+        interface_id: u32,
+        timestamp: Duration,
+        data: &[u8],
+        direction: Option<Direction>,
+        comment: Option<&str>,
+    ) -> Result<(), PcapError> {
+        if interface_id >= self.interface_count {
+            return Err(PcapError::Format("unknown interface"));
+        }
+
+        let captured_length = std::cmp::min(data.len(), SNAPLEN as usize);
+        let timestamp = timestamp.as_nanos() as u64;
+        let mut body = Vec::with_capacity(64 + captured_length);
+        body.extend_from_slice(&interface_id.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(captured_length as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&data[..captured_length]);
+        body.resize(padded(body.len()), 0);
+        if let Some(comment) = comment {
+            push_option(&mut body, PCAPNG_OPTION_COMMENT, comment.as_bytes());
+        }
+        if let Some(direction) = direction {
+            let flags: u32 = match direction {
+                Direction::Inbound => 1,
+                Direction::Outbound => 2,
+            };
+            push_option(&mut body, PCAPNG_OPTION_FLAGS, &flags.to_le_bytes());
+        }
+        if comment.is_some() || direction.is_some() {
+            push_option(&mut body, PCAPNG_OPTION_END, &[]);
+        }
+        write_block(&mut self.writer, PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> Result<(), PcapError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[inline(always)]
+fn padded(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(padded(body.len()), 0);
+}
+
+/// Write a block around a body padded to 32 bits.
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> Result<(), PcapError> {
+    let total_length = (12 + body.len()) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_length.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_length.to_le_bytes())?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum PcapError {
+    Io(std::io::Error),
+    Format(&'static str),
+    LinkType(u32),
+    Truncated,
+}
+
+impl std::fmt::Display for PcapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Failed to access the capture: {}", error),
+            Self::Format(reason) => write!(f, "Invalid capture: {}", reason),
+            Self::LinkType(link_type) => write!(
+                f,
+                "Unsupported link type {}. Only Ethernet captures are supported",
+                link_type
+            ),
+            Self::Truncated => write!(f, "The capture is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for PcapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Format(_) | Self::LinkType(_) | Self::Truncated => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PcapError {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcap_header(magic: u32) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&magic.to_le_bytes());
+        file.extend_from_slice(&[0; 16]);
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+        file
+    }
+
+    fn pcap_record(file: &mut Vec<u8>, seconds: u32, fraction: u32, data: &[u8]) {
+        file.extend_from_slice(&seconds.to_le_bytes());
+        file.extend_from_slice(&fraction.to_le_bytes());
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(data);
+    }
+
+    /// A pcapng section with an interface of the given `if_tsresol`.
+    fn pcapng_file(tsresol: u8) -> Vec<u8> {
+        let mut file = PcapngWriter::new(Vec::new()).unwrap().into_inner();
+        let mut body = Vec::new();
+        body.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&SNAPLEN.to_le_bytes());
+        push_option(&mut body, PCAPNG_OPTION_TSRESOL, &[tsresol]);
+        push_option(&mut body, PCAPNG_OPTION_END, &[]);
+        write_block(&mut file, PCAPNG_INTERFACE_DESCRIPTION, &body).unwrap();
+
+        file
+    }
+
+    fn enhanced_packet(file: &mut Vec<u8>, units: u64, data: &[u8]) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((units >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(units as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        body.resize(padded(body.len()), 0);
+        write_block(file, PCAPNG_ENHANCED_PACKET, &body).unwrap();
+    }
+
+    fn read_all(file: &[u8]) -> Result<Vec<Record>, PcapError> {
+        PcapReader::new(file)?.collect()
+    }
+
+    #[test]
+    fn pcap_round_trip() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write(Duration::new(5, 123_456_789), b"first")
+            .unwrap();
+        writer.write(Duration::new(6, 0), b"second").unwrap();
+
+        let records = read_all(&writer.into_inner()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, Duration::new(5, 123_456_789));
+        assert_eq!(records[0].data, b"first");
+        assert_eq!(records[1].timestamp, Duration::new(6, 0));
+        assert_eq!(records[1].data, b"second");
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        let interface_id = writer.add_interface("eth0").unwrap();
+        writer
+            .write(
+                interface_id,
+                Duration::new(7, 1),
+                b"odd",
+                Some(Direction::Inbound),
+                Some("comment"),
+            )
+            .unwrap();
+        assert!(writer
+            .write(interface_id + 1, Duration::ZERO, b"", None, None)
+            .is_err());
+
+        let records = read_all(&writer.into_inner()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].timestamp, Duration::new(7, 1));
+        assert_eq!(records[0].data, b"odd");
+    }
+
+    #[test]
+    fn big_endian_microseconds() {
+        let mut file = Vec::new();
+        file.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_be_bytes());
+        file.extend_from_slice(&[0; 16]);
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+        for field in [1u32, 500_000, 2, 2] {
+            file.extend_from_slice(&field.to_be_bytes());
+        }
+        file.extend_from_slice(b"ab");
+
+        let records = read_all(&file).unwrap();
+        assert_eq!(records[0].timestamp, Duration::new(1, 500_000_000));
+        assert_eq!(records[0].data, b"ab");
+    }
+
+    #[test]
+    fn invalid_pcap_records() {
+        // A fraction of microseconds that would overflow in nanoseconds.
+        let mut file = pcap_header(PCAP_MAGIC_MICROSECONDS);
+        pcap_record(&mut file, 0, u32::MAX, b"");
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+
+        let mut file = pcap_header(PCAP_MAGIC_NANOSECONDS);
+        pcap_record(&mut file, 0, 1_000_000_000, b"");
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+
+        // A length beyond the snapshot length, not allocated.
+        let mut file = pcap_header(PCAP_MAGIC_NANOSECONDS);
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+
+        let mut file = pcap_header(PCAP_MAGIC_NANOSECONDS);
+        pcap_record(&mut file, 0, 0, b"frame");
+        file.truncate(file.len() - 1);
+        assert!(matches!(read_all(&file), Err(PcapError::Truncated)));
+
+        let mut file = pcap_header(PCAP_MAGIC_NANOSECONDS);
+        file[20] = 101;
+        assert!(matches!(read_all(&file), Err(PcapError::LinkType(101))));
+
+        assert!(matches!(
+            read_all(&[0; 24]),
+            Err(PcapError::Format("unknown magic number"))
+        ));
+    }
+
+    #[test]
+    fn timestamp_resolutions() {
+        let mut file = pcapng_file(3);
+        enhanced_packet(&mut file, 1_500, b"");
+        assert_eq!(
+            read_all(&file).unwrap()[0].timestamp,
+            Duration::new(1, 500_000_000)
+        );
+
+        let mut file = pcapng_file(MAX_DECIMAL_EXPONENT);
+        enhanced_packet(&mut file, u64::MAX, b"");
+        assert_eq!(read_all(&file).unwrap()[0].timestamp.as_secs(), 1);
+
+        // Units of 2^-10 seconds.
+        let mut file = pcapng_file(0x80 | 10);
+        enhanced_packet(&mut file, 3 << 9, b"");
+        assert_eq!(
+            read_all(&file).unwrap()[0].timestamp,
+            Duration::new(1, 500_000_000)
+        );
+
+        let mut file = pcapng_file(0x80 | 0x7f);
+        enhanced_packet(&mut file, u64::MAX, b"");
+        assert_eq!(read_all(&file).unwrap()[0].timestamp, Duration::ZERO);
+
+        let mut file = pcapng_file(MAX_DECIMAL_EXPONENT + 1);
+        enhanced_packet(&mut file, 1, b"");
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+    }
+
+    #[test]
+    fn invalid_pcapng_blocks() {
+        // A block too long to be read at once.
+        let mut file = pcapng_file(9);
+        file.extend_from_slice(&PCAPNG_ENHANCED_PACKET.to_le_bytes());
+        file.extend_from_slice(&(u32::MAX & !3).to_le_bytes());
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+
+        // A captured length beyond the block.
+        let mut file = pcapng_file(9);
+        enhanced_packet(&mut file, 0, b"data");
+        let length_offset = file.len() - 4 - 4 - 4 - 4;
+        file[length_offset..length_offset + 4].copy_from_slice(&64u32.to_le_bytes());
+        assert!(matches!(read_all(&file), Err(PcapError::Truncated)));
+
+        // A packet without any interface description.
+        let mut file = PcapngWriter::new(Vec::new()).unwrap().into_inner();
+        enhanced_packet(&mut file, 0, b"");
+        assert!(matches!(read_all(&file), Err(PcapError::Format(_))));
+    }
+}
@@ -0,0 +1,590 @@
+//! Check the prerequisites of AF_XDP before creating any socket, so that
+//! operators can see why startup would fail.
+
+use std::{
+    ffi::CStr,
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+#[cfg(feature = "libxdp")]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+use libc::{
+    getrlimit, ifreq, ioctl, socket, uname, utsname, AF_INET, IFNAMSIZ, RLIMIT_MEMLOCK,
+    RLIM_INFINITY, SIOCETHTOOL, SOCK_CLOEXEC, SOCK_DGRAM,
+};
+#[cfg(feature = "libxdp")]
+use mangonel_libxdp_sys::{libbpf_version_string, xsk_socket__create, LIBXDP_VERSION};
+
+use crate::{mmap::PageSize, socket::SocketBuilder};
+
+const ETHTOOL_GDRVINFO: u32 = 0x00000003;
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_SYS_RESOURCE: u32 = 24;
+const CAP_BPF: u32 = 39;
+
+/// Drivers with native XDP support in mainline kernels. The driver checks go
+/// by name only, a heuristic that out-of-tree drivers and other kernel
+/// versions may not follow.
+const NATIVE_XDP_DRIVERS: &[&str] = &[
+    "bnxt_en",
+    "ena",
+    "enetc",
+    "fec",
+    "hv_netvsc",
+    "i40e",
+    "ice",
+    "igb",
+    "igc",
+    "ixgbe",
+    "ixgbevf",
+    "mlx4_en",
+    "mlx4_core",
+    "mlx5_core",
+    "mvneta",
+    "mvpp2",
+    "nfp",
+    "qede",
+    "sfc",
+    "stmmac",
+    "tun",
+    "veth",
+    "virtio_net",
+];
+
+/// Drivers with AF_XDP zero-copy support in mainline kernels.
+const ZERO_COPY_DRIVERS: &[&str] = &[
+    "i40e",
+    "ice",
+    "igb",
+    "igc",
+    "ixgbe",
+    "mlx5_core",
+    "stmmac",
+    "virtio_net",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "[{}] {}: {}", status, check.name, check.detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Report {
+    /// Check everything [`SocketBuilder::build`] needs to succeed with the
+    /// given configuration.
+    pub fn new(interface_name: impl AsRef<str>, queue_id: u32, builder: &SocketBuilder) -> Self {
+        let mut report = Self { checks: Vec::new() };
+        let interface_name = interface_name.as_ref();
+        let capabilities = effective_capabilities();
+
+        report.check_kernel();
+        report.check_libraries();
+        report.check_capabilities(capabilities);
+        report.check_memlock(capabilities, builder);
+        report.check_hugepages(builder);
+        if report.check_interface(interface_name) {
+            report.check_driver(interface_name, builder);
+            report.check_queue(interface_name, queue_id);
+        }
+
+        report
+    }
+
+    /// Return true when no check failed.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.status != Status::Fail)
+    }
+
+    fn push(&mut self, name: &'static str, status: Status, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    fn check_kernel(&mut self) {
+        let Some((release, version)) = kernel_version() else {
+            self.push("kernel", Status::Warn, "Failed to read the kernel release");
+            return;
+        };
+
+        let (status, detail) = match version {
+            version if version < (4, 18) => (Status::Fail, "AF_XDP requires 4.18 or later"),
+            version if version < (5, 4) => (
+                Status::Warn,
+                "need_wakeup requires 5.4 or later and is disabled",
+            ),
+            _ => (Status::Pass, "AF_XDP supported"),
+        };
+        self.push("kernel", status, format!("{} ({})", release, detail));
+    }
+
+    #[cfg(feature = "libxdp")]
+    fn check_libraries(&mut self) {
+        let libbpf = unsafe { CStr::from_ptr(libbpf_version_string()) };
+        let rings = match cfg!(feature = "native") {
+            true => "native",
+            false => "libxdp",
+        };
+        let (status, libxdp) = match libxdp_version() {
+            Some(version) => (Status::Pass, version),
+            None => (
+                Status::Warn,
+                format!("of unknown version, built against {}", LIBXDP_VERSION),
+            ),
+        };
+        self.push(
+            "libraries",
+            status,
+            format!(
+                "libbpf {}, libxdp {}, {} rings",
+                libbpf.to_string_lossy(),
+                libxdp,
+                rings
+            ),
+        );
+    }
+
+    #[cfg(not(feature = "libxdp"))]
+    fn check_libraries(&mut self) {
+        self.push(
+            "libraries",
+            Status::Warn,
+            "Built without libxdp: native rings, the XDP program must be loaded by another tool",
+        );
+    }
+
+    fn check_capabilities(&mut self, capabilities: Option<u64>) {
+        let Some(capabilities) = capabilities else {
+            self.push(
+                "capabilities",
+                Status::Warn,
+                "Failed to read the capabilities from /proc/self/status",
+            );
+            return;
+        };
+
+        let has = |capability: u32| capabilities & (1 << capability) != 0;
+        // Kernels before 5.8 do not know CAP_BPF and require CAP_SYS_ADMIN.
+        let bpf = match kernel_version() {
+            Some((_, version)) if version < (5, 8) => ("CAP_SYS_ADMIN", has(CAP_SYS_ADMIN)),
+            _ => ("CAP_BPF", has(CAP_BPF) || has(CAP_SYS_ADMIN)),
+        };
+        let required = [
+            ("CAP_NET_ADMIN", has(CAP_NET_ADMIN)),
+            ("CAP_NET_RAW", has(CAP_NET_RAW)),
+            bpf,
+        ];
+
+        let missing: Vec<&str> = required
+            .iter()
+            .filter(|(_, present)| !present)
+            .map(|(name, _)| *name)
+            .collect();
+        match missing.is_empty() {
+            true => self.push(
+                "capabilities",
+                Status::Pass,
+                "CAP_NET_ADMIN, CAP_NET_RAW and CAP_BPF are effective",
+            ),
+            false => self.push(
+                "capabilities",
+                Status::Fail,
+                format!("Missing {}; run as root or grant them", missing.join(", ")),
+            ),
+        }
+    }
+
+    fn check_memlock(&mut self, capabilities: Option<u64>, builder: &SocketBuilder) {
+        let mut rlimit = MaybeUninit::<libc::rlimit>::uninit();
+        let value = unsafe { getrlimit(RLIMIT_MEMLOCK, rlimit.as_mut_ptr()) };
+        if value.is_negative() {
+            self.push(
+                "memlock",
+                Status::Warn,
+                format!("{}", std::io::Error::last_os_error()),
+            );
+            return;
+        }
+        let rlimit = unsafe { rlimit.assume_init() };
+
+        let required =
+            builder.frame_size as u64 * (builder.ring_size as u64 + builder.tx_frame_count as u64);
+        let has = |capability: u32| capabilities.unwrap_or(0) & (1 << capability) != 0;
+        let format_limit = |limit: u64| match limit {
+            RLIM_INFINITY => "unlimited".to_owned(),
+            limit => format!("{} bytes", limit),
+        };
+        let detail = format!(
+            "soft limit {}, hard limit {}, UMEM needs {} bytes",
+            format_limit(rlimit.rlim_cur),
+            format_limit(rlimit.rlim_max),
+            required
+        );
+
+        let status = match rlimit.rlim_cur >= required
+            || rlimit.rlim_max == RLIM_INFINITY
+            || has(CAP_SYS_RESOURCE)
+            || has(CAP_IPC_LOCK)
+        {
+            true => Status::Pass,
+            false => Status::Fail,
+        };
+        self.push("memlock", status, detail);
+    }
+
+    fn check_hugepages(&mut self, builder: &SocketBuilder) {
+        let page_size = builder.mmap.page_size;
+        if page_size == PageSize::Regular {
+            return;
+        }
+
+        let length = builder.frame_size as usize
+            * (builder.ring_size as usize + builder.tx_frame_count as usize);
+        let required = length.div_ceil(page_size.bytes());
+        let available = page_size.available_pages().unwrap_or(0);
+        let detail = format!(
+            "{:?}: {} required, {} available",
+            page_size, required, available
+        );
+        let status = match (available >= required, builder.mmap.fallback) {
+            (true, _) => Status::Pass,
+            (false, true) => Status::Warn,
+            (false, false) => Status::Fail,
+        };
+        self.push("hugepages", status, detail);
+    }
+
+    /// Return true when the interface exists.
+    fn check_interface(&mut self, interface_name: &str) -> bool {
+        let path = Path::new("/sys/class/net").join(interface_name);
+        if !path.exists() {
+            self.push(
+                "interface",
+                Status::Fail,
+                format!("{} does not exist", interface_name),
+            );
+            return false;
+        }
+
+        let state = std::fs::read_to_string(path.join("operstate")).unwrap_or_default();
+        let state = state.trim();
+        let status = match state {
+            "up" => Status::Pass,
+            _ => Status::Warn,
+        };
+        self.push(
+            "interface",
+            status,
+            format!("{} is {}", interface_name, state),
+        );
+
+        true
+    }
+
+    fn check_driver(&mut self, interface_name: &str, builder: &SocketBuilder) {
+        let Some(driver) = driver_name(interface_name) else {
+            self.push("driver", Status::Warn, "Failed to query the driver");
+            return;
+        };
+
+        match NATIVE_XDP_DRIVERS.contains(&driver.as_str()) {
+            true => self.push(
+                "driver",
+                Status::Pass,
+                format!("{} is known to support native XDP (by driver name)", driver),
+            ),
+            false => self.push(
+                "driver",
+                Status::Warn,
+                format!(
+                    "{} is not known to support native XDP (by driver name); generic XDP is slower",
+                    driver
+                ),
+            ),
+        }
+
+        let zero_copy = ZERO_COPY_DRIVERS.contains(&driver.as_str());
+        let status = match (zero_copy, builder.force_zero_copy) {
+            (true, _) => Status::Pass,
+            (false, false) => Status::Warn,
+            (false, true) => Status::Fail,
+        };
+        let detail = match zero_copy {
+            true => format!("{} is known to support zero-copy (by driver name)", driver),
+            false => format!(
+                "{} is not known to support zero-copy (by driver name)",
+                driver
+            ),
+        };
+        self.push("zero-copy", status, detail);
+    }
+
+    fn check_queue(&mut self, interface_name: &str, queue_id: u32) {
+        let path = Path::new("/sys/class/net")
+            .join(interface_name)
+            .join("queues");
+        let count = std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+                    .count()
+            })
+            .unwrap_or(0);
+
+        let status = match (queue_id as usize) < count {
+            true => Status::Pass,
+            false => Status::Fail,
+        };
+        self.push(
+            "queue",
+            status,
+            format!("queue {} of {} rx queues", queue_id, count),
+        );
+    }
+}
+
+/// Return the kernel release and its major and minor version.
+fn kernel_version() -> Option<(String, (u32, u32))> {
+    let mut name = MaybeUninit::<utsname>::uninit();
+    let value = unsafe { uname(name.as_mut_ptr()) };
+    if value.is_negative() {
+        return None;
+    }
+    let name = unsafe { name.assume_init() };
+    let release = unsafe { CStr::from_ptr(name.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+    let version = parse_version(&release)?;
+
+    Some((release, version))
+}
+
+/// Return the major and minor version of a kernel release, e.g. `6.1.0-13`.
+fn parse_version(release: &str) -> Option<(u32, u32)> {
+    let mut numbers = release
+        .split(|character: char| !character.is_ascii_digit())
+        .filter_map(|number| number.parse().ok());
+
+    Some((numbers.next()?, numbers.next()?))
+}
+
+/// Return the version of the libxdp loaded into the process, from the file
+/// name of the shared object, e.g. `libxdp.so.1.4.2`. Returns `None` when
+/// linked statically or installed without the full version in its name.
+#[cfg(feature = "libxdp")]
+fn libxdp_version() -> Option<String> {
+    let mut info = MaybeUninit::<libc::Dl_info>::uninit();
+    let value =
+        unsafe { libc::dladdr(xsk_socket__create as *const libc::c_void, info.as_mut_ptr()) };
+    if value == 0 {
+        return None;
+    }
+    let info = unsafe { info.assume_init() };
+    if info.dli_fname.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(info.dli_fname) };
+    let path = std::fs::canonicalize(OsStr::from_bytes(path.to_bytes())).ok()?;
+    let version = path.file_name()?.to_str()?.strip_prefix("libxdp.so.")?;
+
+    Some(version.to_owned())
+}
+
+/// Return the effective capability set of the process.
+fn effective_capabilities() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    parse_capabilities(&status)
+}
+
+/// Return the effective capability set from the contents of
+/// `/proc/<pid>/status`.
+fn parse_capabilities(status: &str) -> Option<u64> {
+    let capabilities = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+
+    u64::from_str_radix(capabilities.trim(), 16).ok()
+}
+
+#[repr(C)]
+struct EthtoolDriverInfo {
+    command: u32,
+    driver: [u8; 32],
+    version: [u8; 32],
+    firmware_version: [u8; 32],
+    bus_info: [u8; 32],
+    expansion_rom_version: [u8; 32],
+    reserved: [u8; 12],
+    private_flags_count: u32,
+    stats_count: u32,
+    test_info_length: u32,
+    eeprom_dump_length: u32,
+    register_dump_length: u32,
+}
+
+/// Query the driver of the interface with `ETHTOOL_GDRVINFO`, which also works
+/// for virtual interfaces without a device in sysfs.
+fn driver_name(interface_name: &str) -> Option<String> {
+    if interface_name.len() >= IFNAMSIZ {
+        return None;
+    }
+
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    if fd.is_negative() {
+        return None;
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut driver_info: EthtoolDriverInfo = unsafe { std::mem::zeroed() };
+    driver_info.command = ETHTOOL_GDRVINFO;
+    let mut request: ifreq = unsafe { std::mem::zeroed() };
+    for (target, source) in request.ifr_name.iter_mut().zip(interface_name.bytes()) {
+        *target = source as libc::c_char;
+    }
+    request.ifr_ifru.ifru_data = (&mut driver_info as *mut EthtoolDriverInfo).cast();
+
+    let value = unsafe { ioctl(fd.as_raw_fd(), SIOCETHTOOL, &mut request) };
+    if value.is_negative() {
+        return None;
+    }
+
+    let driver = CStr::from_bytes_until_nul(&driver_info.driver).ok()?;
+
+    Some(driver.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmap::MmapBuilder;
+
+    fn statuses(report: &Report) -> Vec<(&str, Status)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect()
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("6.1.0-13-amd64"), Some((6, 1)));
+        assert_eq!(parse_version("4.18.0-553.el8_10.x86_64"), Some((4, 18)));
+        assert_eq!(
+            parse_version("5.15.133.1-microsoft-standard-WSL2"),
+            Some((5, 15))
+        );
+        assert_eq!(parse_version("6"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn capabilities() {
+        let status = "Name:\tmangonel\nCapInh:\t0000000000000000\nCapPrm:\t000001ffffffffff\nCapEff:\t0000000000003000\n";
+        let capabilities = parse_capabilities(status).unwrap();
+        assert_eq!(capabilities, (1 << CAP_NET_ADMIN) | (1 << CAP_NET_RAW));
+
+        assert_eq!(parse_capabilities("Name:\tmangonel\n"), None);
+        assert_eq!(parse_capabilities("CapEff:\tnot hex\n"), None);
+    }
+
+    #[test]
+    fn missing_capabilities_fail() {
+        let mut report = Report { checks: Vec::new() };
+        report.check_capabilities(Some(1 << CAP_NET_ADMIN));
+        report.check_capabilities(None);
+        assert_eq!(
+            statuses(&report),
+            [
+                ("capabilities", Status::Fail),
+                ("capabilities", Status::Warn)
+            ]
+        );
+        assert!(report.checks[0].detail.starts_with("Missing CAP_NET_RAW, "));
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn hugepages() {
+        let mut report = Report { checks: Vec::new() };
+        // Nothing to check for regular pages.
+        report.check_hugepages(&SocketBuilder::default());
+        assert!(report.checks.is_empty());
+
+        for fallback in [true, false] {
+            let builder = SocketBuilder {
+                // More than any test machine reserves.
+                ring_size: 1 << 20,
+                mmap: MmapBuilder {
+                    page_size: PageSize::Huge2MiB,
+                    fallback,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            report.check_hugepages(&builder);
+        }
+        assert_eq!(
+            statuses(&report),
+            [("hugepages", Status::Warn), ("hugepages", Status::Fail)]
+        );
+    }
+
+    #[test]
+    fn missing_interface() {
+        let mut report = Report { checks: Vec::new() };
+        assert!(!report.check_interface("mangonel-none"));
+        assert_eq!(statuses(&report), [("interface", Status::Fail)]);
+        assert_eq!(driver_name("an-interface-name-too-long"), None);
+    }
+
+    #[test]
+    fn display() {
+        let mut report = Report { checks: Vec::new() };
+        report.push("kernel", Status::Pass, "6.1.0 (AF_XDP supported)");
+        report.push("queue", Status::Warn, "queue 0 of 1 rx queues");
+        assert!(report.is_ok());
+        assert_eq!(
+            report.to_string(),
+            "[PASS] kernel: 6.1.0 (AF_XDP supported)\n[WARN] queue: queue 0 of 1 rx queues\n"
+        );
+    }
+}
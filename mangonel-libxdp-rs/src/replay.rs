@@ -0,0 +1,202 @@
+//! A [`Backend`] receiving the frames of a capture file and writing the
+//! transmitted frames to another, for reproducible runs of packet processing
+//! code on captured traffic.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Sink, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    backend::{Backend, Sender},
+    buffer::Buffer,
+    descriptor::Descriptor,
+    loopback::Loopback,
+    pcap::{PcapError, PcapReader, PcapWriter, Record},
+    socket::SocketError,
+    umem::Umem,
+};
+
+pub struct Replay<R: Read, W: Write = Sink> {
+    loopback: Loopback,
+    source: PcapReader<R>,
+    sink: Option<PcapWriter<W>>,
+    /// Receive the frames at the pace they were captured at rather than as
+    /// fast as frames are filled.
+    pub pace: bool,
+    /// A record that could not be received yet for lack of filled frames.
+    pending: Option<Record>,
+    /// The first capture timestamp and when it was received.
+    start: Option<(Duration, Instant)>,
+    /// Capture timestamps of the frames received, by frame.
+    timestamps: HashMap<u64, Duration>,
+    finished: bool,
+    dropped: usize,
+    error: Option<PcapError>,
+}
+
+impl<R: Read> Replay<R> {
+    /// Replay the capture into the loopback, discarding transmitted frames.
+    pub fn new(loopback: Loopback, source: PcapReader<R>) -> Self {
+        Self {
+            loopback,
+            source,
+            sink: None,
+            pace: false,
+            pending: None,
+            start: None,
+            timestamps: HashMap::new(),
+            finished: false,
+            dropped: 0,
+            error: None,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Replay<R, W> {
+    /// Write the transmitted frames to `sink`. A frame keeps the timestamp of
+    /// the frame received into the same UMEM frame, and gets the current time
+    /// otherwise.
+    pub fn with_sink<V: Write>(self, sink: PcapWriter<V>) -> Replay<R, V> {
+        Replay {
+            loopback: self.loopback,
+            source: self.source,
+            sink: Some(sink),
+            pace: self.pace,
+            pending: self.pending,
+            start: self.start,
+            timestamps: self.timestamps,
+            finished: self.finished,
+            dropped: self.dropped,
+            error: self.error,
+        }
+    }
+
+    /// Return [`true`] once every record is read and received.
+    pub fn is_finished(&self) -> bool {
+        self.finished && self.pending.is_none()
+    }
+
+    /// Return the number of records dropped because they do not fit in a
+    /// frame.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Return the first error reading the source or writing the sink. The
+    /// replay stops reading after an error.
+    pub fn take_error(&mut self) -> Option<PcapError> {
+        self.error.take()
+    }
+
+    pub fn into_sink(self) -> Option<PcapWriter<W>> {
+        self.sink
+    }
+
+    /// Receive records until `count` are received, the frames run out or, when
+    /// pacing, a record is not due yet.
+    fn receive(&mut self, count: u32) {
+        let mut received = 0;
+        while received < count {
+            let record = match self.pending.take() {
+                Some(record) => record,
+                None if self.finished => return,
+                None => match self.source.next_record() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => {
+                        self.finished = true;
+                        return;
+                    }
+                    Err(error) => {
+                        self.error.get_or_insert(error);
+                        self.finished = true;
+                        return;
+                    }
+                },
+            };
+
+            if record.data.len() > self.loopback.max_frame_length() {
+                self.dropped += 1;
+                continue;
+            }
+
+            let (first_timestamp, start) = *self
+                .start
+                .get_or_insert_with(|| (record.timestamp, Instant::now()));
+            let due = record.timestamp.saturating_sub(first_timestamp);
+            if self.pace && start.elapsed() < due {
+                self.pending = Some(record);
+                return;
+            }
+
+            match self.loopback.inject(&record.data) {
+                Some(address) => {
+                    self.timestamps
+                        .insert(self.frame(address), record.timestamp);
+                    received += 1;
+                }
+                None => {
+                    self.pending = Some(record);
+                    return;
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn frame(&self, address: u64) -> u64 {
+        let frame_size = self.loopback.umem().frame_size() as u64;
+
+        address - address % frame_size
+    }
+}
+
+impl<R: Read, W: Write> Backend for Replay<R, W> {
+    fn umem(&self) -> Umem {
+        self.loopback.umem()
+    }
+
+    fn rx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        self.receive(buffer.free());
+
+        self.loopback.rx_burst(buffer)
+    }
+
+    fn tx_burst<T: Buffer<Descriptor>>(&mut self, buffer: &mut T) -> u32 {
+        let sent = self.loopback.tx_burst(buffer);
+
+        let frame_size = self.loopback.umem().frame_size() as u64;
+        let timestamps = &mut self.timestamps;
+        let sink = &mut self.sink;
+        let error = &mut self.error;
+        self.loopback.drain_transmitted(|address, data| {
+            let timestamp = timestamps
+                .remove(&(address - address % frame_size))
+                .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+            if let (Some(writer), None) = (sink.as_mut(), error.as_ref()) {
+                if let Err(write_error) = writer.write(timestamp, data) {
+                    *error = Some(write_error);
+                }
+            }
+        });
+
+        sent
+    }
+
+    fn fill<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        self.loopback.fill(buffer)
+    }
+
+    fn complete<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        self.loopback.complete(buffer)
+    }
+}
+
+impl<R: Read, W: Write> Sender for Replay<R, W> {
+    /// Send the payload from a filled frame. It is written to the sink along
+    /// with the frames of the next [`Backend::tx_burst`].
+    fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        self.loopback.send(payload)
+    }
+}
@@ -1,19 +1,87 @@
-use std::{mem::MaybeUninit, ptr::NonNull};
+use std::{
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+    os::fd::{AsRawFd, BorrowedFd},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
+use libc::{
+    getsockopt, socklen_t, xdp_desc, xdp_mmap_offsets, xdp_ring_offset, SOL_XDP, XDP_MMAP_OFFSETS,
+    XDP_RING_NEED_WAKEUP,
+};
+#[cfg(not(feature = "native"))]
 use mangonel_libxdp_sys::{
-    xdp_desc, xsk_ring_cons, xsk_ring_cons__cancel, xsk_ring_cons__comp_addr, xsk_ring_cons__peek,
-    xsk_ring_cons__release, xsk_ring_cons__rx_desc, xsk_ring_prod, xsk_ring_prod__fill_addr,
-    xsk_ring_prod__needs_wakeup, xsk_ring_prod__reserve, xsk_ring_prod__submit,
-    xsk_ring_prod__tx_desc,
+    xsk_ring_cons, xsk_ring_cons__cancel, xsk_ring_cons__peek, xsk_ring_cons__release,
+    xsk_ring_prod, xsk_ring_prod__needs_wakeup, xsk_ring_prod__reserve, xsk_ring_prod__submit,
+};
+
+#[cfg(feature = "native")]
+use crate::xsk::{
+    xsk_ring_cons, xsk_ring_cons__cancel, xsk_ring_cons__peek, xsk_ring_cons__release,
+    xsk_ring_prod, xsk_ring_prod__needs_wakeup, xsk_ring_prod__reserve, xsk_ring_prod__submit,
+};
+use crate::{
+    mmap::{Mmap, MmapError},
+    util::is_power_of_two,
 };
 
-use crate::util::is_power_of_two;
+/// Return the offsets of the rings of a bound XSK socket within their
+/// mappings.
+pub(crate) fn mmap_offsets(fd: BorrowedFd) -> Result<xdp_mmap_offsets, RingError> {
+    let mut offsets = MaybeUninit::<xdp_mmap_offsets>::zeroed();
+    let mut length = size_of::<xdp_mmap_offsets>() as socklen_t;
+    let value = unsafe {
+        getsockopt(
+            fd.as_raw_fd(),
+            SOL_XDP,
+            XDP_MMAP_OFFSETS,
+            offsets.as_mut_ptr().cast(),
+            &mut length,
+        )
+    };
+    if value.is_negative() {
+        return Err(RingError::Offsets(std::io::Error::last_os_error()));
+    }
+
+    Ok(unsafe { offsets.assume_init() })
+}
+
+/// Map a ring of a bound XSK socket and return the mapping with pointers to
+/// its producer, consumer, entries of type `T` and flags.
+fn map_ring<T>(
+    fd: BorrowedFd,
+    page_offset: i64,
+    offset: &xdp_ring_offset,
+    size: u32,
+) -> Result<(Mmap, [*mut u8; 4]), RingError> {
+    if !is_power_of_two(size) {
+        return Err(RingError::Size(size));
+    }
 
+    let length = offset.desc as usize + size as usize * size_of::<T>();
+    let mmap = Mmap::from_socket(fd, length, page_offset)?;
+    let base = mmap.as_ptr() as *mut u8;
+    let pointers = unsafe {
+        [
+            base.add(offset.producer as usize),
+            base.add(offset.consumer as usize),
+            base.add(offset.desc as usize),
+            base.add(offset.flags as usize),
+        ]
+    };
+
+    Ok((mmap, pointers))
+}
+
+/// A ring to be set up by libxdp.
+#[cfg(not(feature = "native"))]
 pub struct ConsumerRingUninit {
     size: u32,
     ring: Box<MaybeUninit<xsk_ring_cons>>,
 }
 
+#[cfg(not(feature = "native"))]
 impl ConsumerRingUninit {
     pub fn new(size: u32) -> Result<Self, RingError> {
         if !is_power_of_two(size) {
@@ -30,7 +98,7 @@ impl ConsumerRingUninit {
         self.ring.as_mut_ptr()
     }
 
-    pub fn init(self) -> Result<ConsumerRing, RingError> {
+    pub fn init<T>(self) -> Result<ConsumerRing<T>, RingError> {
         let ring: Box<xsk_ring_cons> =
             unsafe { MaybeUninit::<xsk_ring_cons>::assume_init(*self.ring).into() };
         if ring.size != self.size {
@@ -39,15 +107,22 @@ impl ConsumerRingUninit {
 
         let ring_ptr = NonNull::new(Box::into_raw(ring)).ok_or(RingError::RingIsNull)?;
 
-        Ok(ConsumerRing(ring_ptr))
+        Ok(ConsumerRing {
+            ring: ring_ptr,
+            _mmap: None,
+            _entry: PhantomData,
+        })
     }
 }
 
+/// A ring to be set up by libxdp.
+#[cfg(not(feature = "native"))]
 pub struct ProducerRingUninit {
     size: u32,
     ring: Box<MaybeUninit<xsk_ring_prod>>,
 }
 
+#[cfg(not(feature = "native"))]
 impl ProducerRingUninit {
     pub fn new(size: u32) -> Result<Self, RingError> {
         if !is_power_of_two(size) {
@@ -64,7 +139,7 @@ impl ProducerRingUninit {
         self.ring.as_mut_ptr()
     }
 
-    pub fn init(self) -> Result<ProducerRing, RingError> {
+    pub fn init<T>(self) -> Result<ProducerRing<T>, RingError> {
         let ring: Box<xsk_ring_prod> =
             unsafe { MaybeUninit::<xsk_ring_prod>::assume_init(*self.ring).into() };
         if ring.size != self.size {
@@ -73,123 +148,635 @@ impl ProducerRingUninit {
 
         let ring_ptr = NonNull::new(Box::into_raw(ring)).ok_or(RingError::RingIsNull)?;
 
-        Ok(ProducerRing(ring_ptr))
+        Ok(ProducerRing {
+            ring: ring_ptr,
+            _mmap: None,
+            _entry: PhantomData,
+        })
     }
 }
 
-pub struct ConsumerRing(NonNull<xsk_ring_cons>);
+/// A ring the kernel produces entries of type `T` into: frame addresses
+/// (`u64`) for the completion ring and descriptors (`xdp_desc`) for the RX
+/// ring.
+///
+/// Entries are read as the type of the ring only:
+///
+/// ```compile_fail
+/// fn peek(completion_ring: &mut mangonel_libxdp_rs::ring::ConsumerRing<u64>) {
+///     completion_ring.peek_rx(1);
+/// }
+/// ```
+pub struct ConsumerRing<T> {
+    ring: NonNull<xsk_ring_cons>,
+    /// The mapping of a ring set up natively or adopted from another
+    /// process. Rings set up by libxdp are unmapped by libxdp.
+    _mmap: Option<Mmap>,
+    _entry: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for ConsumerRing<T> {}
 
-impl std::fmt::Debug for ConsumerRing {
+impl<T> std::fmt::Debug for ConsumerRing<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", unsafe { self.0.as_ref() })
+        write!(f, "{:?}", unsafe { self.ring.as_ref() })
     }
 }
 
-impl std::ops::Deref for ConsumerRing {
+impl<T> std::ops::Deref for ConsumerRing<T> {
     type Target = xsk_ring_cons;
 
     #[inline(always)]
     fn deref(&self) -> &Self::Target {
-        unsafe { self.0.as_ref() }
+        unsafe { self.ring.as_ref() }
     }
 }
 
-impl ConsumerRing {
-    pub fn complete_address(&self, index: u32) -> *const u64 {
-        unsafe { xsk_ring_cons__comp_addr(self.0.as_ptr(), index) }
+impl<T> ConsumerRing<T> {
+    /// Map the consumer ring of an XSK socket, either set up natively or
+    /// bound by another process.
+    pub(crate) fn map(
+        fd: BorrowedFd,
+        page_offset: i64,
+        offset: &xdp_ring_offset,
+        size: u32,
+    ) -> Result<Self, RingError> {
+        let (mmap, [producer, consumer, descriptors, flags]) =
+            map_ring::<T>(fd, page_offset, offset, size)?;
+        let producer = producer as *mut u32;
+        let consumer = consumer as *mut u32;
+        let ring = Box::new(unsafe {
+            xsk_ring_cons {
+                cached_prod: producer.read_volatile(),
+                cached_cons: consumer.read_volatile(),
+                mask: size - 1,
+                size,
+                producer,
+                consumer,
+                ring: descriptors.cast(),
+                flags: flags.cast(),
+            }
+        });
+
+        Ok(Self {
+            ring: NonNull::new(Box::into_raw(ring)).ok_or(RingError::RingIsNull)?,
+            _mmap: Some(mmap),
+            _entry: PhantomData,
+        })
     }
 
     #[inline(always)]
-    pub fn peek(&self, size: u32, index: &mut u32) -> u32 {
-        unsafe { xsk_ring_cons__peek(self.0.as_ptr(), size, index) }
+    fn entries(&self) -> *mut T {
+        unsafe { self.ring.as_ref().ring.cast() }
     }
 
+    /// Peek at up to `size` entries.
     #[inline(always)]
-    pub fn release(&self, size: u32) {
-        unsafe { xsk_ring_cons__release(self.0.as_ptr(), size) }
+    pub fn peek(&mut self, size: u32) -> ConsumerPeek<'_, T> {
+        ConsumerPeek::new(self, size)
     }
+}
 
+impl ConsumerRing<u64> {
+    /// Peek at up to `size` completed frame addresses.
     #[inline(always)]
-    pub fn rx_descriptor(&self, index: u32) -> *const xdp_desc {
-        unsafe { xsk_ring_cons__rx_desc(self.0.as_ptr(), index) }
+    pub fn peek_complete(&mut self, size: u32) -> ConsumerPeek<'_, u64> {
+        self.peek(size)
     }
+}
 
+impl ConsumerRing<xdp_desc> {
+    /// Peek at up to `size` received descriptors.
     #[inline(always)]
-    pub fn cancel(&self, size: u32) {
-        unsafe { xsk_ring_cons__cancel(self.0.as_ptr(), size) }
+    pub fn peek_rx(&mut self, size: u32) -> ConsumerPeek<'_, xdp_desc> {
+        self.peek(size)
     }
 }
 
-pub struct ProducerRing(NonNull<xsk_ring_prod>);
+/// Entries peeked from a [`ConsumerRing`]. They are released back to the
+/// kernel when the peek is dropped, unless it is cancelled.
+pub struct ConsumerPeek<'a, T> {
+    ring: &'a mut ConsumerRing<T>,
+    index: u32,
+    length: u32,
+}
+
+impl<'a, T> ConsumerPeek<'a, T> {
+    #[inline(always)]
+    fn new(ring: &'a mut ConsumerRing<T>, size: u32) -> Self {
+        let mut index = 0;
+        let length = unsafe { xsk_ring_cons__peek(ring.ring.as_ptr(), size, &mut index) };
+
+        Self {
+            ring,
+            index,
+            length,
+        }
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        let (head, tail) = self.as_slices();
+        match index < head.len() {
+            true => head.get(index),
+            false => tail.get(index - head.len()),
+        }
+    }
 
-impl std::fmt::Debug for ProducerRing {
+    /// Return the entries as two slices since they may wrap around the end
+    /// of the ring.
+    #[inline(always)]
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let (start, head, tail) = split(self.index, self.length, self.ring.mask);
+        let entries = self.ring.entries() as *const T;
+        unsafe {
+            (
+                std::slice::from_raw_parts(entries.add(start), head),
+                std::slice::from_raw_parts(entries, tail),
+            )
+        }
+    }
+
+    #[inline(always)]
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let (head, tail) = self.as_slices();
+        head.iter().chain(tail)
+    }
+
+    /// Release the entries back to the kernel and return their number.
+    #[inline(always)]
+    pub fn release(self) -> u32 {
+        self.length
+    }
+
+    /// Leave the entries in the ring to be peeked again.
+    #[inline(always)]
+    pub fn cancel(mut self) {
+        unsafe { xsk_ring_cons__cancel(self.ring.ring.as_ptr(), self.length) };
+        self.length = 0;
+    }
+}
+
+impl<T> Drop for ConsumerPeek<'_, T> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.length > 0 {
+            unsafe { xsk_ring_cons__release(self.ring.ring.as_ptr(), self.length) }
+        }
+    }
+}
+
+/// A ring the kernel consumes entries of type `T` from: frame addresses
+/// (`u64`) for the fill ring and descriptors (`xdp_desc`) for the TX ring.
+/// The flags of a producer ring, written by the kernel.
+#[derive(Clone, Copy)]
+pub(crate) struct RingFlags(NonNull<u32>);
+
+unsafe impl Send for RingFlags {}
+
+unsafe impl Sync for RingFlags {}
+
+impl RingFlags {
+    #[inline(always)]
+    pub fn needs_wakeup(&self) -> bool {
+        let flags = unsafe { &*(self.0.as_ptr() as *const AtomicU32) };
+        flags.load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0
+    }
+}
+
+pub struct ProducerRing<T> {
+    ring: NonNull<xsk_ring_prod>,
+    /// The mapping of a ring set up natively or adopted from another
+    /// process. Rings set up by libxdp are unmapped by libxdp.
+    _mmap: Option<Mmap>,
+    _entry: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for ProducerRing<T> {}
+
+impl<T> std::fmt::Debug for ProducerRing<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", unsafe { self.0.as_ref() })
+        write!(f, "{:?}", unsafe { self.ring.as_ref() })
     }
 }
 
-impl std::ops::Deref for ProducerRing {
+impl<T> std::ops::Deref for ProducerRing<T> {
     type Target = xsk_ring_prod;
 
     #[inline(always)]
     fn deref(&self) -> &Self::Target {
-        unsafe { self.0.as_ref() }
+        unsafe { self.ring.as_ref() }
     }
 }
 
-impl ProducerRing {
-    #[inline(always)]
-    pub fn fill_address(&self, index: u32) -> *mut u64 {
-        unsafe { xsk_ring_prod__fill_addr(self.0.as_ptr(), index) }
+impl<T> ProducerRing<T> {
+    /// Map the producer ring of an XSK socket, either set up natively or
+    /// bound by another process.
+    pub(crate) fn map(
+        fd: BorrowedFd,
+        page_offset: i64,
+        offset: &xdp_ring_offset,
+        size: u32,
+    ) -> Result<Self, RingError> {
+        let (mmap, [producer, consumer, descriptors, flags]) =
+            map_ring::<T>(fd, page_offset, offset, size)?;
+        let producer = producer as *mut u32;
+        let consumer = consumer as *mut u32;
+        let ring = Box::new(unsafe {
+            xsk_ring_prod {
+                cached_prod: producer.read_volatile(),
+                cached_cons: consumer.read_volatile().wrapping_add(size),
+                mask: size - 1,
+                size,
+                producer,
+                consumer,
+                ring: descriptors.cast(),
+                flags: flags.cast(),
+            }
+        });
+
+        Ok(Self {
+            ring: NonNull::new(Box::into_raw(ring)).ok_or(RingError::RingIsNull)?,
+            _mmap: Some(mmap),
+            _entry: PhantomData,
+        })
     }
 
     #[inline(always)]
     pub fn needs_wakeup(&self) -> bool {
-        let value = unsafe { xsk_ring_prod__needs_wakeup(self.0.as_ptr()) };
+        let value = unsafe { xsk_ring_prod__needs_wakeup(self.ring.as_ptr()) };
         match value {
             0 => false,
             _other_values => true,
         }
     }
 
+    /// Return the flags of the ring, which stay readable from any thread for
+    /// as long as the ring is mapped.
+    pub(crate) fn flags(&self) -> Option<RingFlags> {
+        NonNull::new(unsafe { self.ring.as_ref().flags }).map(RingFlags)
+    }
+
+    #[inline(always)]
+    fn entries(&self) -> *mut T {
+        unsafe { self.ring.as_ref().ring.cast() }
+    }
+
+    /// Reserve up to `size` entries.
     #[inline(always)]
-    pub fn reserve(&self, size: u32, index: &mut u32) -> u32 {
-        unsafe { xsk_ring_prod__reserve(self.0.as_ptr(), size, index) }
+    pub fn reserve(&mut self, size: u32) -> ProducerReservation<'_, T> {
+        ProducerReservation::new(self, size)
     }
+}
 
+impl ProducerRing<u64> {
+    /// Reserve up to `size` entries for frame addresses to hand to the
+    /// kernel.
     #[inline(always)]
-    pub fn submit(&self, size: u32) {
-        unsafe { xsk_ring_prod__submit(self.0.as_ptr(), size) }
+    pub fn reserve_fill(&mut self, size: u32) -> ProducerReservation<'_, u64> {
+        self.reserve(size)
     }
+}
 
+impl ProducerRing<xdp_desc> {
+    /// Reserve up to `size` entries for descriptors to transmit.
     #[inline(always)]
-    pub fn tx_descriptor(&self, index: u32) -> *mut xdp_desc {
-        unsafe { xsk_ring_prod__tx_desc(self.0.as_ptr(), index) }
+    pub fn reserve_tx(&mut self, size: u32) -> ProducerReservation<'_, xdp_desc> {
+        self.reserve(size)
     }
 }
 
+/// Entries reserved in a [`ProducerRing`]. The entries pushed are submitted to
+/// the kernel when the reservation is dropped and the rest are given back to
+/// the ring.
+pub struct ProducerReservation<'a, T> {
+    ring: &'a mut ProducerRing<T>,
+    index: u32,
+    length: u32,
+    filled: u32,
+}
+
+impl<'a, T> ProducerReservation<'a, T> {
+    #[inline(always)]
+    fn new(ring: &'a mut ProducerRing<T>, size: u32) -> Self {
+        let mut index = 0;
+        let length = unsafe { xsk_ring_prod__reserve(ring.ring.as_ptr(), size, &mut index) };
+
+        Self {
+            ring,
+            index,
+            length,
+            filled: 0,
+        }
+    }
+
+    /// Return the number of entries reserved.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Return the number of entries pushed so far.
+    #[inline(always)]
+    pub fn filled(&self) -> usize {
+        self.filled as usize
+    }
+
+    #[inline(always)]
+    pub fn is_full(&self) -> bool {
+        self.filled == self.length
+    }
+
+    /// Write the next entry. Return the value back when every reserved entry
+    /// is already filled.
+    #[inline(always)]
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+
+        let index = (self.index.wrapping_add(self.filled) & self.ring.mask) as usize;
+        unsafe { self.ring.entries().add(index).write(value) };
+        self.filled += 1;
+
+        Ok(())
+    }
+
+    /// Submit the entries pushed so far and return their number.
+    #[inline(always)]
+    pub fn submit(self) -> u32 {
+        self.filled
+    }
+}
+
+impl<T> Drop for ProducerReservation<'_, T> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let ring = self.ring.ring.as_ptr();
+        unsafe {
+            // Give back the entries that were reserved but not filled.
+            (*ring).cached_prod = (*ring).cached_prod.wrapping_sub(self.length - self.filled);
+            if self.filled > 0 {
+                xsk_ring_prod__submit(ring, self.filled);
+            }
+        }
+    }
+}
+
+/// Return the position of the first of `length` entries starting at `index`
+/// and the number of them before and after the end of the ring.
+#[inline(always)]
+fn split(index: u32, length: u32, mask: u32) -> (usize, usize, usize) {
+    let start = index & mask;
+    let head = std::cmp::min(length, mask + 1 - start);
+
+    (start as usize, head as usize, (length - head) as usize)
+}
+
+#[derive(Debug)]
 pub enum RingError {
     Size(u32),
     Initialize,
     RingIsNull,
+    Offsets(std::io::Error),
+    Map(MmapError),
 }
 
-impl std::fmt::Debug for RingError {
+impl std::fmt::Display for RingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Size(ring_size) => {
-                write!(f, "The ring size ({}) is not the power of two.", ring_size)
-            }
-            Self::Initialize => write!(f, "Failed to initialize the ring."),
-            Self::RingIsNull => write!(f, "The ring pointer returned null."),
+            Self::Size(ring_size) => write!(
+                f,
+                "The ring size ({}) is not a power of two. Use a size such as 2048",
+                ring_size
+            ),
+            Self::Initialize => write!(f, "The ring was not initialized with the requested size"),
+            Self::RingIsNull => write!(f, "The ring pointer is null"),
+            Self::Offsets(error) => write!(f, "Failed to get the ring offsets: {}", error),
+            Self::Map(error) => write!(f, "Failed to map the ring: {}", error),
         }
     }
 }
 
-impl std::fmt::Display for RingError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl std::error::Error for RingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Offsets(error) => Some(error),
+            Self::Map(error) => Some(error),
+            Self::Size(_) | Self::Initialize | Self::RingIsNull => None,
+        }
+    }
+}
+
+impl From<MmapError> for RingError {
+    fn from(value: MmapError) -> Self {
+        Self::Map(value)
+    }
+}
+
+/// The memory of a ring, kept by a test instead of mapped from a socket. The
+/// test plays the kernel on one end of the ring.
+#[cfg(test)]
+pub(crate) struct RingMemory<T> {
+    /// The producer, the consumer and the flags.
+    indices: Box<[AtomicU32; 3]>,
+    entries: Vec<T>,
+}
+
+#[cfg(test)]
+impl<T: Copy> RingMemory<T> {
+    pub fn new(size: u32) -> Self {
+        Self {
+            indices: Box::new([AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)]),
+            // Both frame addresses and descriptors are plain integers.
+            entries: vec![unsafe { std::mem::zeroed() }; size as usize],
+        }
+    }
+
+    pub fn producer(&self) -> u32 {
+        self.indices[0].load(Ordering::SeqCst)
+    }
+
+    pub fn consumer(&self) -> u32 {
+        self.indices[1].load(Ordering::SeqCst)
+    }
+
+    pub fn set_consumer(&self, consumer: u32) {
+        self.indices[1].store(consumer, Ordering::SeqCst);
+    }
+
+    pub fn set_flags(&self, flags: u32) {
+        self.indices[2].store(flags, Ordering::SeqCst);
+    }
+
+    /// Return the producer end of the ring, which must not outlive the
+    /// memory.
+    pub fn producer_ring(&mut self) -> ProducerRing<T> {
+        let size = self.entries.len() as u32;
+        let ring = xsk_ring_prod {
+            cached_prod: self.producer(),
+            cached_cons: self.consumer().wrapping_add(size),
+            mask: size - 1,
+            size,
+            producer: self.indices[0].as_ptr(),
+            consumer: self.indices[1].as_ptr(),
+            ring: self.entries.as_mut_ptr().cast(),
+            flags: self.indices[2].as_ptr(),
+        };
+
+        ProducerRing {
+            ring: NonNull::from(Box::leak(Box::new(ring))),
+            _mmap: None,
+            _entry: PhantomData,
+        }
+    }
+
+    /// Return the consumer end of the ring, which must not outlive the
+    /// memory.
+    pub fn consumer_ring(&mut self) -> ConsumerRing<T> {
+        let size = self.entries.len() as u32;
+        let ring = xsk_ring_cons {
+            cached_prod: self.producer(),
+            cached_cons: self.consumer(),
+            mask: size - 1,
+            size,
+            producer: self.indices[0].as_ptr(),
+            consumer: self.indices[1].as_ptr(),
+            ring: self.entries.as_mut_ptr().cast(),
+            flags: self.indices[2].as_ptr(),
+        };
+
+        ConsumerRing {
+            ring: NonNull::from(Box::leak(Box::new(ring))),
+            _mmap: None,
+            _entry: PhantomData,
+        }
     }
 }
 
-impl std::error::Error for RingError {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 4;
+
+    #[test]
+    fn unfilled_entries_are_given_back() {
+        let mut memory = RingMemory::<u64>::new(SIZE);
+        let mut ring = memory.producer_ring();
+
+        let mut reservation = ring.reserve_fill(3);
+        assert_eq!(reservation.len(), 3);
+        assert_eq!(reservation.push(1), Ok(()));
+        assert_eq!(reservation.push(2), Ok(()));
+        assert_eq!(reservation.filled(), 2);
+        assert_eq!(reservation.submit(), 2);
+        assert_eq!(memory.producer(), 2);
+
+        // The third entry was given back, so all four can be reserved again
+        // once the kernel consumes the first two.
+        memory.set_consumer(2);
+        let mut reservation = ring.reserve_fill(SIZE);
+        assert_eq!(reservation.len(), SIZE as usize);
+        (0..SIZE as u64).for_each(|value| reservation.push(value).unwrap());
+        assert!(reservation.is_full());
+        assert_eq!(reservation.push(4), Err(4));
+    }
+
+    #[test]
+    fn reservations_are_all_or_nothing() {
+        let mut memory = RingMemory::<u64>::new(SIZE);
+        let mut ring = memory.producer_ring();
+
+        ring.reserve(3).push(1).unwrap();
+        assert!(ring.reserve(SIZE).is_empty());
+        assert_eq!(ring.reserve(SIZE - 1).len(), SIZE as usize - 1);
+        assert_eq!(memory.producer(), 1);
+    }
+
+    #[test]
+    fn entries_wrap_around() {
+        let mut memory = RingMemory::<u64>::new(SIZE);
+        let mut producer = memory.producer_ring();
+        let mut consumer = memory.consumer_ring();
+
+        let mut reservation = producer.reserve(3);
+        (1..=3).for_each(|value| reservation.push(value).unwrap());
+        drop(reservation);
+        assert_eq!(consumer.peek(SIZE).release(), 3);
+
+        let mut reservation = producer.reserve(SIZE);
+        (4..=7).for_each(|value| reservation.push(value).unwrap());
+        drop(reservation);
+
+        let peek = consumer.peek(SIZE);
+        assert_eq!(peek.len(), 4);
+        assert_eq!(peek.as_slices(), (&[4][..], &[5, 6, 7][..]));
+        assert_eq!(peek.get(3), Some(&7));
+        assert_eq!(peek.get(4), None);
+        assert_eq!(peek.iter().copied().collect::<Vec<_>>(), [4, 5, 6, 7]);
+        peek.release();
+        assert_eq!(memory.consumer(), 7);
+    }
+
+    #[test]
+    fn cancelled_entries_are_peeked_again() {
+        let mut memory = RingMemory::<xdp_desc>::new(SIZE);
+        let mut producer = memory.producer_ring();
+        let mut consumer = memory.consumer_ring();
+
+        let descriptor = xdp_desc {
+            addr: 4096,
+            len: 60,
+            options: 0,
+        };
+        assert!(producer.reserve_tx(1).push(descriptor).is_ok());
+
+        let peek = consumer.peek_rx(SIZE);
+        assert_eq!(peek.len(), 1);
+        peek.cancel();
+        assert_eq!(memory.consumer(), 0);
+
+        let peek = consumer.peek_rx(SIZE);
+        assert_eq!(peek.get(0).map(|descriptor| descriptor.addr), Some(4096));
+        drop(peek);
+        assert_eq!(memory.consumer(), 1);
+        assert!(consumer.peek_rx(SIZE).is_empty());
+    }
+
+    #[test]
+    fn wakeup_flag() {
+        let mut memory = RingMemory::<u64>::new(SIZE);
+        let ring = memory.producer_ring();
+        let flags = ring.flags().unwrap();
+        assert!(!ring.needs_wakeup());
+        assert!(!flags.needs_wakeup());
+
+        memory.set_flags(XDP_RING_NEED_WAKEUP);
+        assert!(ring.needs_wakeup());
+        assert!(flags.needs_wakeup());
+    }
+
+    #[test]
+    fn splits() {
+        assert_eq!(split(0, 4, 3), (0, 4, 0));
+        assert_eq!(split(6, 3, 3), (2, 2, 1));
+        assert_eq!(split(u32::MAX, 2, 3), (3, 1, 1));
+        assert_eq!(split(5, 0, 3), (1, 0, 0));
+    }
+}
@@ -1,32 +1,111 @@
+#[cfg(not(feature = "native"))]
+use std::ptr::NonNull;
 use std::{
     collections::VecDeque,
     ffi::{CString, NulError},
-    ptr::{null_mut, NonNull},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    ptr::null_mut,
     sync::Arc,
 };
+#[cfg(feature = "native")]
+use std::{mem::size_of, os::fd::FromRawFd};
 
-use libc::{poll, pollfd, sendto, MSG_DONTWAIT, POLLIN};
+#[cfg(feature = "native")]
+use libc::{
+    bind, if_nametoindex, sockaddr_xdp, socket, socklen_t, AF_XDP, SOCK_CLOEXEC, SOCK_RAW,
+    XDP_RX_RING, XDP_SHARED_UMEM, XDP_TX_RING,
+};
+use libc::{
+    c_int, poll, pollfd, recvfrom, sendto, xdp_desc, MSG_DONTWAIT, POLLIN, SOL_SOCKET,
+    SO_BUSY_POLL, XDP_COPY, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING, XDP_USE_NEED_WAKEUP,
+    XDP_ZEROCOPY,
+};
+#[cfg(not(feature = "native"))]
 use mangonel_libxdp_sys::{
     xsk_socket, xsk_socket__create, xsk_socket__delete, xsk_socket__fd, xsk_socket_config,
-    xsk_socket_config__bindgen_ty_1, XDP_COPY, XDP_ZEROCOPY, XSK_RING_PROD__DEFAULT_NUM_DESCS,
-    XSK_UMEM__DEFAULT_FRAME_HEADROOM, XSK_UMEM__DEFAULT_FRAME_SIZE,
+    xsk_socket_config__bindgen_ty_1, XDP_FLAGS_DRV_MODE, XDP_FLAGS_SKB_MODE,
+    XSK_LIBXDP_FLAGS__INHIBIT_PROG_LOAD,
+};
+#[cfg(not(feature = "native"))]
+use mangonel_libxdp_sys::{
+    XSK_RING_PROD__DEFAULT_NUM_DESCS, XSK_UMEM__DEFAULT_FRAME_HEADROOM,
+    XSK_UMEM__DEFAULT_FRAME_SIZE,
 };
 
+#[cfg(not(feature = "native"))]
+use crate::ring::{ConsumerRingUninit, ProducerRingUninit};
 use crate::{
     buffer::Buffer,
     descriptor::Descriptor,
-    ring::{ConsumerRing, ConsumerRingUninit, ProducerRing, ProducerRingUninit, RingError},
+    mmap::MmapBuilder,
+    numa,
+    ring::{mmap_offsets, ConsumerRing, ProducerRing, RingError},
     umem::{Umem, UmemError},
-    util::setrlimit,
+    util::{get_int_option, set_option, setrlimit},
+    xskmap::{XskMap, XskMapError},
+};
+#[cfg(feature = "native")]
+use crate::{
+    util::setsockopt,
+    xsk::{
+        XSK_RING_PROD__DEFAULT_NUM_DESCS, XSK_UMEM__DEFAULT_FRAME_HEADROOM,
+        XSK_UMEM__DEFAULT_FRAME_SIZE,
+    },
 };
 
+// Not defined by the libc crate yet.
+const SO_PREFER_BUSY_POLL: c_int = 69;
+const SO_BUSY_POLL_BUDGET: c_int = 70;
+
+/// Preferred busy polling: the application drives NAPI processing of the
+/// queue from [`RxSocket::rx_burst`] and [`TxSocket::tx_burst`] instead of
+/// relying on interrupts and softirqs.
+///
+/// Interrupts stay masked only while `napi_defer_hard_irqs` and
+/// `gro_flush_timeout` of the interface are set, e.g. to 2 and 200000.
+#[derive(Debug, Clone, Copy)]
+pub struct BusyPoll {
+    /// `SO_BUSY_POLL`, in microseconds.
+    pub timeout: u32,
+    /// `SO_BUSY_POLL_BUDGET`, the number of packets processed per poll.
+    pub budget: u32,
+}
+
+impl Default for BusyPoll {
+    fn default() -> Self {
+        Self {
+            timeout: 20,
+            budget: 64,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SocketBuilder {
     pub frame_size: u32,
     pub frame_headroom_size: u32,
     pub ring_size: u32,
-    pub use_hugetlb: bool,
+    pub mmap: MmapBuilder,
+    /// Bind the UMEM to the NUMA node of the interface unless
+    /// [`MmapBuilder::numa_node`] is already set.
+    pub numa_local: bool,
+    /// Index of the first UMEM frame handed to the fill ring. Processes
+    /// sharing a UMEM must own disjoint ranges of frames.
+    pub first_frame: u32,
+    /// Number of UMEM frames, following the ones handed to the fill ring,
+    /// that [`TxSocket::send`] copies payloads into.
+    pub tx_frame_count: u32,
+    /// Bind with `XDP_ZEROCOPY`, failing on drivers without it, instead of
+    /// `XDP_COPY`.
     pub force_zero_copy: bool,
+    /// Bind with `XDP_USE_NEED_WAKEUP`, so that transmitting only makes a
+    /// system call when the kernel asks for one. Requires Linux 5.4.
+    pub need_wakeup: bool,
+    pub busy_poll: Option<BusyPoll>,
+    /// Register the sockets in this map instead of letting libxdp load and
+    /// manage a program for them. Required for handing the sockets over to
+    /// another process.
+    pub xskmap: Option<Arc<XskMap>>,
 }
 
 impl Default for SocketBuilder {
@@ -35,50 +114,188 @@ impl Default for SocketBuilder {
             frame_size: XSK_UMEM__DEFAULT_FRAME_SIZE,
             frame_headroom_size: XSK_UMEM__DEFAULT_FRAME_HEADROOM,
             ring_size: XSK_RING_PROD__DEFAULT_NUM_DESCS,
-            use_hugetlb: false,
+            mmap: MmapBuilder::default(),
+            numa_local: true,
+            first_frame: 0,
+            tx_frame_count: 0,
             force_zero_copy: false,
+            need_wakeup: false,
+            busy_poll: None,
+            xskmap: None,
         }
     }
 }
 
 impl SocketBuilder {
-    /// # Panics
-    ///
-    /// The function panics when [`setrlimit()`] panic conditions are met.
+    /// Raise `RLIMIT_MEMLOCK`, register a new UMEM and create the sockets on
+    /// it.
     pub fn build(
-        self,
+        mut self,
         interface_name: impl AsRef<str>,
         queue_id: u32,
     ) -> Result<(RxSocket, TxSocket), SocketError> {
-        setrlimit();
+        setrlimit().map_err(SocketError::Memlock)?;
+
+        if self.numa_local && self.mmap.numa_node.is_none() {
+            self.mmap.numa_node = numa::interface_node(interface_name.as_ref());
+        }
 
-        let (rx_socket, tx_socket) = Socket::init(
+        let frame_count = self.ring_size as usize + self.tx_frame_count as usize;
+        let mmap = self
+            .mmap
+            .build(self.frame_size as usize * frame_count)
+            .map_err(UmemError::from)?;
+        let umem = Umem::from_mmap(
+            mmap,
             self.frame_size,
             self.frame_headroom_size,
             self.ring_size,
-            self.use_hugetlb,
-            self.force_zero_copy,
+        )?;
+
+        self.build_with_umem(umem, interface_name, queue_id)
+    }
+
+    /// Create the sockets on an existing UMEM, e.g. one registered with
+    /// [`Umem::from_mmap`] on memory shared by another process. Only
+    /// [`SocketBuilder::first_frame`], [`SocketBuilder::tx_frame_count`],
+    /// [`SocketBuilder::force_zero_copy`], [`SocketBuilder::need_wakeup`],
+    /// [`SocketBuilder::busy_poll`] and [`SocketBuilder::xskmap`] apply; the
+    /// rest of the configuration is taken from the UMEM.
+    pub fn build_with_umem(
+        self,
+        umem: Umem,
+        interface_name: impl AsRef<str>,
+        queue_id: u32,
+    ) -> Result<(RxSocket, TxSocket), SocketError> {
+        setrlimit().map_err(SocketError::Memlock)?;
+
+        let mut bind_flags = match self.force_zero_copy {
+            true => XDP_ZEROCOPY,
+            false => XDP_COPY,
+        };
+        if self.need_wakeup {
+            bind_flags |= XDP_USE_NEED_WAKEUP;
+        }
+        let (mut rx_socket, mut tx_socket) = Socket::init(
+            umem,
+            self.first_frame,
+            self.tx_frame_count,
+            bind_flags,
+            self.xskmap.as_deref(),
             interface_name,
             queue_id,
         )?;
+        if let Some(busy_poll) = self.busy_poll {
+            rx_socket
+                .socket
+                .set_busy_poll(busy_poll)
+                .map_err(SocketError::BusyPoll)?;
+            tx_socket.socket.busy_poll = true;
+        }
 
         Ok((rx_socket, tx_socket))
     }
+
+    /// Raise `RLIMIT_MEMLOCK`, register a new UMEM of
+    /// [`SocketBuilder::tx_frame_count`] frames and create a socket on it that
+    /// only transmits, e.g. to send frames out of another interface than the
+    /// one receiving them. No program is loaded or map given a socket, so the
+    /// queue keeps receiving into the kernel.
+    pub fn build_tx(
+        mut self,
+        interface_name: impl AsRef<str>,
+        queue_id: u32,
+    ) -> Result<TxSocket, SocketError> {
+        setrlimit().map_err(SocketError::Memlock)?;
+
+        if self.numa_local && self.mmap.numa_node.is_none() {
+            self.mmap.numa_node = numa::interface_node(interface_name.as_ref());
+        }
+
+        // The fill and completion rings are sized by the ring size, which
+        // the UMEM has to hold frames for.
+        let frame_count = std::cmp::max(self.ring_size, self.tx_frame_count) as usize;
+        let mmap = self
+            .mmap
+            .build(self.frame_size as usize * frame_count)
+            .map_err(UmemError::from)?;
+        let umem = Umem::from_mmap(
+            mmap,
+            self.frame_size,
+            self.frame_headroom_size,
+            self.ring_size,
+        )?;
+
+        let mut bind_flags = match self.force_zero_copy {
+            true => XDP_ZEROCOPY,
+            false => XDP_COPY,
+        };
+        if self.need_wakeup {
+            bind_flags |= XDP_USE_NEED_WAKEUP;
+        }
+        let mut tx_socket = Socket::init_tx(
+            umem,
+            self.tx_frame_count,
+            bind_flags,
+            interface_name,
+            queue_id,
+        )?;
+        if let Some(busy_poll) = self.busy_poll {
+            tx_socket
+                .socket
+                .set_busy_poll(busy_poll)
+                .map_err(SocketError::BusyPoll)?;
+        }
+
+        Ok(tx_socket)
+    }
 }
 
+/// A new socket with its RX ring, unless it only transmits, and its TX ring.
+type Rings = (
+    Socket,
+    Option<ConsumerRing<xdp_desc>>,
+    ProducerRing<xdp_desc>,
+);
+
 pub struct Socket {
     inner: Arc<SocketInner>,
+    need_wakeup: bool,
+    busy_poll: bool,
 }
 
-struct SocketInner(NonNull<xsk_socket>);
+enum SocketInner {
+    #[cfg(not(feature = "native"))]
+    Libxdp(NonNull<xsk_socket>),
+    /// A socket created natively or adopted from another process. Its rings
+    /// are mapped by [`RxSocket`], [`TxSocket`] and [`Umem`].
+    Fd(OwnedFd),
+}
 
 unsafe impl Send for SocketInner {}
 
 unsafe impl Sync for SocketInner {}
 
+#[cfg(not(feature = "native"))]
 impl Drop for SocketInner {
     fn drop(&mut self) {
-        unsafe { xsk_socket__delete(self.0.as_ptr()) }
+        if let Self::Libxdp(socket) = self {
+            unsafe { xsk_socket__delete(socket.as_ptr()) }
+        }
+    }
+}
+
+impl AsFd for Socket {
+    #[inline(always)]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.socket_fd()) }
+    }
+}
+
+impl AsRawFd for Socket {
+    #[inline(always)]
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket_fd()
     }
 }
 
@@ -87,40 +304,127 @@ impl Clone for Socket {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            need_wakeup: self.need_wakeup,
+            busy_poll: self.busy_poll,
         }
     }
 }
 
 impl Socket {
+    /// Create the sockets and bind them with `bind_flags`, e.g.
+    /// `XDP_COPY` and `XDP_USE_NEED_WAKEUP`. Without `XDP_ZEROCOPY` or
+    /// `XDP_COPY` the kernel tries zero-copy and falls back to copy mode;
+    /// [`SocketBuilder`] binds in copy mode unless zero-copy is forced.
     pub fn init(
-        frame_size: u32,
-        frame_headroom_size: u32,
-        ring_size: u32,
-        use_hugetlb: bool,
-        force_zero_copy: bool,
+        umem: Umem,
+        first_frame: u32,
+        tx_frame_count: u32,
+        bind_flags: u16,
+        xskmap: Option<&XskMap>,
         interface_name: impl AsRef<str>,
         queue_id: u32,
     ) -> Result<(RxSocket, TxSocket), SocketError> {
-        let umem = Umem::new(frame_size, frame_headroom_size, ring_size, use_hugetlb)?;
+        let ring_size = umem.ring_size();
+        let frame_count = umem.frame_count();
+        let length = ring_size.saturating_add(tx_frame_count);
+        if first_frame.saturating_add(length) > frame_count {
+            return Err(SocketError::FrameRange {
+                first_frame,
+                length,
+                frame_count,
+            });
+        }
 
-        let mut rx_ring = ConsumerRingUninit::new(ring_size)?;
-        let mut tx_ring = ProducerRingUninit::new(ring_size)?;
+        let interface_name =
+            CString::new(interface_name.as_ref()).map_err(SocketError::InvalidInterfaceName)?;
+        let (socket, Some(rx_ring), tx_ring) =
+            Self::create(&umem, bind_flags, xskmap, true, &interface_name, queue_id)?
+        else {
+            unreachable!("sockets are created with the RX ring asked for");
+        };
+
+        if let Some(xskmap) = xskmap {
+            xskmap.insert(queue_id, socket.as_fd())?;
+        }
+
+        // Pre-fill the buffer with addresses.
+        let mut prefilled_buffer = VecDeque::<u64>::with_capacity(ring_size as usize);
+        (first_frame..first_frame + ring_size).for_each(|descriptor_index: u32| {
+            let offset = descriptor_index as u64 * umem.frame_size() as u64;
+            prefilled_buffer.push(offset);
+        });
+        umem.fill(&mut prefilled_buffer);
+
+        let tx_frames = first_frame + ring_size..first_frame + length;
+        let rx_socket = RxSocket::new(socket.clone(), rx_ring, umem.clone());
+        let mut tx_socket = TxSocket::new(socket.clone(), tx_ring, umem.clone());
+        tx_socket.add_frames(tx_frames.map(|index| index as u64 * umem.frame_size() as u64));
+
+        Ok((rx_socket, tx_socket))
+    }
 
-        let mut xdp_flags = 0;
-        match force_zero_copy {
-            true => xdp_flags |= XDP_ZEROCOPY,
-            false => xdp_flags |= XDP_COPY,
+    /// Create a socket that only transmits, copying payloads into the first
+    /// `tx_frame_count` frames of the UMEM. No program is loaded for it.
+    pub fn init_tx(
+        umem: Umem,
+        tx_frame_count: u32,
+        bind_flags: u16,
+        interface_name: impl AsRef<str>,
+        queue_id: u32,
+    ) -> Result<TxSocket, SocketError> {
+        let frame_count = umem.frame_count();
+        if tx_frame_count > frame_count {
+            return Err(SocketError::FrameRange {
+                first_frame: 0,
+                length: tx_frame_count,
+                frame_count,
+            });
         }
 
         let interface_name =
             CString::new(interface_name.as_ref()).map_err(SocketError::InvalidInterfaceName)?;
+        let (socket, _, tx_ring) =
+            Self::create(&umem, bind_flags, None, false, &interface_name, queue_id)?;
+
+        let mut tx_socket = TxSocket::new(socket, tx_ring, umem.clone());
+        tx_socket
+            .add_frames((0..tx_frame_count).map(|index| index as u64 * umem.frame_size() as u64));
 
+        Ok(tx_socket)
+    }
+
+    /// Create and bind the socket with libxdp, which also loads its default
+    /// program unless an [`XskMap`] is given or the socket has no RX ring.
+    #[cfg(not(feature = "native"))]
+    fn create(
+        umem: &Umem,
+        bind_flags: u16,
+        xskmap: Option<&XskMap>,
+        rx: bool,
+        interface_name: &CString,
+        queue_id: u32,
+    ) -> Result<Rings, SocketError> {
+        let umem_ptr = umem.as_ptr().ok_or(SocketError::AdoptedUmem)?;
+        let ring_size = umem.ring_size();
+        let mut rx_ring = ConsumerRingUninit::new(ring_size)?;
+        let mut tx_ring = ProducerRingUninit::new(ring_size)?;
+
+        let libbpf_flags = match xskmap.is_some() || !rx {
+            true => XSK_LIBXDP_FLAGS__INHIBIT_PROG_LOAD,
+            false => 0,
+        };
+
+        // The default program is attached in the mode matching the socket.
+        let xdp_flags = match bind_flags & XDP_ZEROCOPY != 0 {
+            true => XDP_FLAGS_DRV_MODE,
+            false => XDP_FLAGS_SKB_MODE,
+        };
         let socket_config = xsk_socket_config {
             rx_size: ring_size,
             tx_size: ring_size,
-            __bindgen_anon_1: xsk_socket_config__bindgen_ty_1 { libbpf_flags: 0 },
+            __bindgen_anon_1: xsk_socket_config__bindgen_ty_1 { libbpf_flags },
             xdp_flags,
-            bind_flags: 0,
+            bind_flags,
         };
         let mut socket = null_mut();
 
@@ -129,40 +433,184 @@ impl Socket {
                 &mut socket,
                 interface_name.as_ptr(),
                 queue_id,
-                umem.as_ptr(),
-                rx_ring.as_mut_ptr(),
+                umem_ptr,
+                match rx {
+                    true => rx_ring.as_mut_ptr(),
+                    false => null_mut(),
+                },
                 tx_ring.as_mut_ptr(),
                 &socket_config,
             )
         };
         if value.is_negative() {
-            return Err(SocketError::Initialize(std::io::Error::from_raw_os_error(
-                -value,
-            )));
+            return Err(SocketError::from_create(
+                std::io::Error::from_raw_os_error(-value),
+                interface_name,
+                queue_id,
+                bind_flags & XDP_ZEROCOPY != 0,
+            ));
         }
 
-        let inner = SocketInner(NonNull::new(socket).unwrap());
+        let inner = SocketInner::Libxdp(NonNull::new(socket).ok_or(SocketError::SocketIsNull)?);
         let socket = Self {
             inner: Arc::new(inner),
+            need_wakeup: bind_flags & XDP_USE_NEED_WAKEUP != 0,
+            busy_poll: false,
         };
 
-        // Pre-fill the buffer with addresses.
-        let mut prefilled_buffer = VecDeque::<u64>::with_capacity(ring_size as usize);
-        (0..ring_size).for_each(|descriptor_index: u32| {
-            let offset = descriptor_index * (frame_headroom_size + frame_size);
-            prefilled_buffer.push(offset as u64);
-        });
-        umem.fill(&mut prefilled_buffer);
+        let rx_ring = match rx {
+            true => Some(rx_ring.init()?),
+            false => None,
+        };
+
+        Ok((socket, rx_ring, tx_ring.init()?))
+    }
+
+    /// Create and bind the socket with plain system calls, as
+    /// `xsk_socket__create()` does. The first socket is bound on the one the
+    /// UMEM is registered on and later ones share the UMEM.
+    ///
+    /// Without an [`XskMap`], the default program is loaded with libxdp when
+    /// the `libxdp` feature is enabled, unless the socket has no RX ring.
+    #[cfg(feature = "native")]
+    fn create(
+        umem: &Umem,
+        mut bind_flags: u16,
+        xskmap: Option<&XskMap>,
+        rx: bool,
+        interface_name: &CString,
+        queue_id: u32,
+    ) -> Result<Rings, SocketError> {
+        let force_zero_copy = bind_flags & XDP_ZEROCOPY != 0;
+        let error =
+            |error| SocketError::from_create(error, interface_name, queue_id, force_zero_copy);
+
+        let (umem_fd, shared) = umem.bind_fd().ok_or(SocketError::AdoptedUmem)?;
+        let interface_index = unsafe { if_nametoindex(interface_name.as_ptr()) };
+        if interface_index == 0 {
+            return Err(error(std::io::Error::last_os_error()));
+        }
+
+        let fd = match shared {
+            true => {
+                let fd = unsafe { socket(AF_XDP, SOCK_RAW | SOCK_CLOEXEC, 0) };
+                if fd.is_negative() {
+                    return Err(error(std::io::Error::last_os_error()));
+                }
+                bind_flags |= XDP_SHARED_UMEM;
+
+                unsafe { OwnedFd::from_raw_fd(fd) }
+            }
+            false => umem_fd.try_clone_to_owned().map_err(error)?,
+        };
 
-        let rx_socket = RxSocket::new(socket.clone(), rx_ring.init()?, umem.clone());
-        let tx_socket = TxSocket::new(socket.clone(), tx_ring.init()?, umem.clone());
+        let ring_size = umem.ring_size();
+        let (rx_ring, tx_ring) = match rx {
+            true => {
+                setsockopt(fd.as_fd(), XDP_RX_RING, &ring_size).map_err(error)?;
+                setsockopt(fd.as_fd(), XDP_TX_RING, &ring_size).map_err(error)?;
+                let (rx_ring, tx_ring) = map_rings(fd.as_fd(), ring_size)?;
+                (Some(rx_ring), tx_ring)
+            }
+            false => {
+                setsockopt(fd.as_fd(), XDP_TX_RING, &ring_size).map_err(error)?;
+                (None, map_tx_ring(fd.as_fd(), ring_size)?)
+            }
+        };
+
+        let address = sockaddr_xdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: bind_flags,
+            sxdp_ifindex: interface_index,
+            sxdp_queue_id: queue_id,
+            sxdp_shared_umem_fd: match shared {
+                true => umem_fd.as_raw_fd() as u32,
+                false => 0,
+            },
+        };
+        let value = unsafe {
+            bind(
+                fd.as_raw_fd(),
+                (&address as *const sockaddr_xdp).cast(),
+                size_of::<sockaddr_xdp>() as socklen_t,
+            )
+        };
+        if value.is_negative() {
+            return Err(error(std::io::Error::last_os_error()));
+        }
+        umem.set_bound();
+
+        let socket = Self {
+            inner: Arc::new(SocketInner::Fd(fd)),
+            need_wakeup: bind_flags & XDP_USE_NEED_WAKEUP != 0,
+            busy_poll: false,
+        };
+        if xskmap.is_none() && rx {
+            #[cfg(feature = "libxdp")]
+            XskMap::setup(interface_name.to_string_lossy())?.insert(queue_id, socket.as_fd())?;
+            #[cfg(not(feature = "libxdp"))]
+            return Err(SocketError::MissingXskMap);
+        }
+
+        Ok((socket, rx_ring, tx_ring))
+    }
+
+    /// Adopt a bound XSK socket from another process, typically received
+    /// through [`crate::handoff::Handoff`], along with the UMEM adopted on the
+    /// same socket with [`Umem::adopt`]. Busy polling stays enabled if the
+    /// other process enabled it. Whether the socket was bound with
+    /// `XDP_USE_NEED_WAKEUP` cannot be queried, so transmitting always makes a
+    /// system call.
+    pub fn adopt(fd: OwnedFd, umem: Umem) -> Result<(RxSocket, TxSocket), SocketError> {
+        let (rx_ring, tx_ring) = map_rings(fd.as_fd(), umem.ring_size())?;
+        let busy_poll = prefers_busy_poll(fd.as_fd()).map_err(SocketError::BusyPoll)?;
+
+        let socket = Self {
+            inner: Arc::new(SocketInner::Fd(fd)),
+            need_wakeup: false,
+            busy_poll,
+        };
+        let rx_socket = RxSocket::new(socket.clone(), rx_ring, umem.clone());
+        let tx_socket = TxSocket::new(socket, tx_ring, umem);
 
         Ok((rx_socket, tx_socket))
     }
 
+    fn set_busy_poll(&mut self, busy_poll: BusyPoll) -> std::io::Result<()> {
+        let fd = self.as_fd();
+        set_option(fd, SOL_SOCKET, SO_PREFER_BUSY_POLL, &(1 as c_int))?;
+        set_option(fd, SOL_SOCKET, SO_BUSY_POLL, &(busy_poll.timeout as c_int))?;
+        set_option(
+            fd,
+            SOL_SOCKET,
+            SO_BUSY_POLL_BUDGET,
+            &(busy_poll.budget as c_int),
+        )?;
+        self.busy_poll = true;
+
+        Ok(())
+    }
+
+    /// Whether the rx and tx paths drive NAPI processing, see [`BusyPoll`].
+    #[inline(always)]
+    pub fn busy_poll(&self) -> bool {
+        self.busy_poll
+    }
+
+    /// Whether the socket was bound with `XDP_USE_NEED_WAKEUP`. Without it
+    /// the kernel never asks for a wakeup and every transmission needs one.
+    #[inline(always)]
+    pub fn need_wakeup(&self) -> bool {
+        self.need_wakeup
+    }
+
     #[inline(always)]
     pub(crate) fn socket_fd(&self) -> i32 {
-        unsafe { xsk_socket__fd(self.inner.0.as_ptr()) }
+        match self.inner.as_ref() {
+            #[cfg(not(feature = "native"))]
+            SocketInner::Libxdp(socket) => unsafe { xsk_socket__fd(socket.as_ptr()) },
+            SocketInner::Fd(fd) => fd.as_raw_fd(),
+        }
     }
 
     #[inline(always)]
@@ -180,16 +628,60 @@ impl Socket {
     pub(crate) fn send_fd(&self) {
         unsafe { sendto(self.socket_fd(), null_mut(), 0, MSG_DONTWAIT, null_mut(), 0) };
     }
+
+    #[inline(always)]
+    pub(crate) fn recv_fd(&self) {
+        unsafe {
+            recvfrom(
+                self.socket_fd(),
+                null_mut(),
+                0,
+                MSG_DONTWAIT,
+                null_mut(),
+                null_mut(),
+            )
+        };
+    }
+}
+
+/// Return whether `SO_PREFER_BUSY_POLL` is set on the socket. Kernels before
+/// 5.11 do not know the option, so busy polling cannot be enabled on them.
+fn prefers_busy_poll(fd: BorrowedFd) -> std::io::Result<bool> {
+    match get_int_option(fd, SOL_SOCKET, SO_PREFER_BUSY_POLL) {
+        Ok(value) => Ok(value != 0),
+        Err(error) if error.raw_os_error() == Some(libc::ENOPROTOOPT) => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Map the RX and TX rings of the bound socket `fd`.
+fn map_rings(
+    fd: BorrowedFd,
+    ring_size: u32,
+) -> Result<(ConsumerRing<xdp_desc>, ProducerRing<xdp_desc>), RingError> {
+    let offsets = mmap_offsets(fd)?;
+    let rx_ring = ConsumerRing::map(fd, XDP_PGOFF_RX_RING, &offsets.rx, ring_size)?;
+    let tx_ring = ProducerRing::map(fd, XDP_PGOFF_TX_RING, &offsets.tx, ring_size)?;
+
+    Ok((rx_ring, tx_ring))
+}
+
+/// Map the TX ring of the bound socket `fd`, which has no RX ring.
+#[cfg(feature = "native")]
+fn map_tx_ring(fd: BorrowedFd, ring_size: u32) -> Result<ProducerRing<xdp_desc>, RingError> {
+    let offsets = mmap_offsets(fd)?;
+
+    ProducerRing::map(fd, XDP_PGOFF_TX_RING, &offsets.tx, ring_size)
 }
 
 pub struct RxSocket {
     socket: Socket,
-    rx_ring: ConsumerRing,
+    rx_ring: ConsumerRing<xdp_desc>,
     umem: Umem,
 }
 
 impl RxSocket {
-    pub fn new(socket: Socket, rx_ring: ConsumerRing, umem: Umem) -> Self {
+    pub fn new(socket: Socket, rx_ring: ConsumerRing<xdp_desc>, umem: Umem) -> Self {
         Self {
             socket,
             rx_ring,
@@ -202,46 +694,72 @@ impl RxSocket {
         self.umem.clone()
     }
 
+    #[inline(always)]
+    pub(crate) fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
     #[inline(always)]
     pub fn rx_burst<T>(&mut self, buffer: &mut T) -> u32
     where
         T: Buffer<Descriptor>,
     {
-        if self.umem.needs_wakeup() {
+        // A busy polling socket runs NAPI on every call, not only when the
+        // kernel asks for a wakeup.
+        if self.socket.busy_poll() {
+            self.socket.recv_fd();
+        } else if self.umem.needs_wakeup() {
             self.socket.poll_fd();
         }
 
-        let mut index: u32 = 0;
-        let size = std::cmp::min(buffer.free() as u32, self.rx_ring.size);
+        let size = std::cmp::min(buffer.free(), self.rx_ring.size);
 
-        let received = self.rx_ring.peek(size, &mut index);
-        if received > 0 {
-            for _ in 0..received {
-                let descriptor_ptr = self.rx_ring.rx_descriptor(index);
-                let descriptor = Descriptor::from((descriptor_ptr, &self.umem));
-                buffer.push(descriptor);
-                index += 1;
-            }
+        let peek = self.rx_ring.peek_rx(size);
+        peek.iter().for_each(|descriptor| {
+            buffer.push(Descriptor::from((descriptor, &self.umem)));
+        });
 
-            self.rx_ring.release(received);
-        }
+        peek.release()
+    }
+}
 
-        received
+impl AsFd for RxSocket {
+    #[inline(always)]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl AsRawFd for RxSocket {
+    #[inline(always)]
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
     }
 }
 
 pub struct TxSocket {
     socket: Socket,
-    tx_ring: ProducerRing,
+    tx_ring: ProducerRing<xdp_desc>,
     umem: Umem,
+    /// The free frames of the send pool, which [`TxSocket::send`] copies
+    /// payloads into.
+    frames: VecDeque<u64>,
+    /// Whether the frames, by index, belong to the send pool.
+    pool: Vec<bool>,
+    /// Transmitted frames outside the send pool, taken from the completion
+    /// ring until [`TxSocket::complete`] hands them out.
+    completed: VecDeque<u64>,
 }
 
 impl TxSocket {
-    pub fn new(socket: Socket, tx_ring: ProducerRing, umem: Umem) -> Self {
+    pub fn new(socket: Socket, tx_ring: ProducerRing<xdp_desc>, umem: Umem) -> Self {
         Self {
             socket,
             tx_ring,
             umem,
+            frames: VecDeque::new(),
+            pool: Vec::new(),
+            completed: VecDeque::new(),
         }
     }
 
@@ -255,28 +773,167 @@ impl TxSocket {
     where
         T: Buffer<Descriptor>,
     {
-        let mut index: u32 = 0;
-
-        let available = self.tx_ring.reserve(buffer.count(), &mut index);
-        if available > 0 {
-            for _ in 0..available {
-                let descriptor = buffer.pop().unwrap();
-                let descriptor_ptr = self.tx_ring.tx_descriptor(index);
-                unsafe {
-                    (*descriptor_ptr).addr = descriptor.address();
-                    (*descriptor_ptr).len = descriptor.length();
-                }
-                index += 1;
+        let mut reservation = self.tx_ring.reserve_tx(buffer.count());
+        while !reservation.is_full() {
+            let Some(descriptor) = buffer.pop() else {
+                break;
+            };
+            let _ = reservation.push(xdp_desc {
+                addr: descriptor.address(),
+                len: descriptor.length(),
+                options: 0,
+            });
+        }
+        let available = reservation.submit();
+
+        self.wake_up();
+
+        available
+    }
+
+    /// Hand frames to the socket for [`TxSocket::send`] to copy payloads
+    /// into. They join the send pool for good.
+    pub fn add_frames(&mut self, frames: impl IntoIterator<Item = u64>) {
+        let frame_size = self.umem.frame_size() as u64;
+        for frame in frames {
+            let index = (frame / frame_size) as usize;
+            if index >= self.pool.len() {
+                self.pool.resize(index + 1, false);
+            }
+            self.pool[index] = true;
+            self.frames.push_back(frame);
+        }
+    }
+
+    /// Return the addresses of the frames held for [`TxSocket::send`].
+    pub fn frames(&self) -> impl Iterator<Item = u64> + '_ {
+        self.frames.iter().copied()
+    }
+
+    /// Take the frames held for [`TxSocket::send`], e.g. to write frames in
+    /// place and transmit them with [`TxSocket::tx_burst`]. They stay in the
+    /// send pool, so [`TxSocket::complete`] returns them to it once they are
+    /// transmitted.
+    pub fn take_frames(&mut self) -> Vec<u64> {
+        self.frames.drain(..).collect()
+    }
+
+    /// Take the transmitted frames from the completion ring. The frames of
+    /// the send pool return to it for [`TxSocket::send`] to reuse; the others
+    /// move into the buffer. Return the number moved into the buffer.
+    ///
+    /// The completion ring has to be consumed only through this, or frames of
+    /// the send pool leak to other owners.
+    pub fn complete<T: Buffer<u64>>(&mut self, buffer: &mut T) -> u32 {
+        self.collect();
+
+        let count = std::cmp::min(buffer.free() as usize, self.completed.len());
+        self.completed.drain(..count).for_each(|address| {
+            buffer.push(address);
+        });
+
+        count as u32
+    }
+
+    /// Sort the frames in the completion ring into the send pool and
+    /// `completed`.
+    fn collect(&mut self) {
+        self.completed.reserve(self.umem.ring_size() as usize);
+        if self.umem.complete(&mut self.completed) == 0 {
+            return;
+        }
+
+        let frame_size = self.umem.frame_size() as u64;
+        let Self {
+            frames,
+            pool,
+            completed,
+            ..
+        } = self;
+        completed.retain(|&address| {
+            let pooled = pool
+                .get((address / frame_size) as usize)
+                .copied()
+                .unwrap_or(false);
+            if pooled {
+                frames.push_back(address - address % frame_size);
             }
 
-            self.tx_ring.submit(available);
+            !pooled
+        });
+    }
+
+    /// Copy the payload into a free frame and transmit it.
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+        match self.send_batch(&[payload])? {
+            0 => Err(SocketError::TxFull),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copy the payloads into free frames and transmit them in order. Return
+    /// the number of payloads sent, which is lower than their number when the
+    /// frames or the TX ring run out.
+    pub fn send_batch(&mut self, payloads: &[&[u8]]) -> Result<u32, SocketError> {
+        let headroom_size = self.umem.headroom_size();
+        let capacity = self.umem.frame_size() - headroom_size;
+        if let Some(payload) = payloads
+            .iter()
+            .find(|payload| payload.len() > capacity as usize)
+        {
+            return Err(SocketError::PayloadTooLarge {
+                length: payload.len(),
+                capacity,
+            });
+        }
+
+        if self.frames.len() < payloads.len() {
+            self.collect();
+        }
+
+        let size = std::cmp::min(payloads.len(), self.frames.len()) as u32;
+        let mut reservation = self.tx_ring.reserve_tx(size);
+        for payload in payloads.iter().take(reservation.len()) {
+            let frame = self.frames.pop_front().unwrap();
+            let address = frame + headroom_size as u64;
+            let data = self.umem.get_data(address) as *mut u8;
+            unsafe { data.copy_from_nonoverlapping(payload.as_ptr(), payload.len()) };
+            let _ = reservation.push(xdp_desc {
+                addr: address,
+                len: payload.len() as u32,
+                options: 0,
+            });
+        }
+        let sent = reservation.submit();
+
+        if sent > 0 {
+            self.wake_up();
         }
 
-        if self.tx_ring.needs_wakeup() {
+        Ok(sent)
+    }
+
+    /// Make the kernel transmit submitted descriptors, unless it does not
+    /// need a system call to.
+    #[inline(always)]
+    fn wake_up(&self) {
+        if self.socket.busy_poll() || !self.socket.need_wakeup() || self.tx_ring.needs_wakeup() {
             self.socket.send_fd();
         }
+    }
+}
 
-        available
+impl AsFd for TxSocket {
+    #[inline(always)]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl AsRawFd for TxSocket {
+    #[inline(always)]
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
     }
 }
 
@@ -284,18 +941,219 @@ impl TxSocket {
 pub enum SocketError {
     Umem(UmemError),
     Ring(RingError),
+    XskMap(XskMapError),
     InvalidInterfaceName(NulError),
+    AdoptedUmem,
+    /// The frames for the fill ring and [`TxSocket::send`] do not fit in
+    /// the UMEM.
+    FrameRange {
+        first_frame: u32,
+        length: u32,
+        frame_count: u32,
+    },
+    /// `RLIMIT_MEMLOCK` could not be raised.
+    Memlock(std::io::Error),
+    /// Another program or socket is already attached to the queue.
+    Busy {
+        interface_name: String,
+        queue_id: u32,
+    },
+    /// The driver does not support zero-copy on the interface.
+    ZeroCopyUnsupported {
+        interface_name: String,
+    },
+    /// The queue does not exist on the interface or the configuration is
+    /// rejected by the driver.
+    InvalidQueue {
+        interface_name: String,
+        queue_id: u32,
+        error: std::io::Error,
+    },
+    NoSuchInterface {
+        interface_name: String,
+    },
+    /// Sockets created natively without libxdp need an [`XskMap`] of a
+    /// program loaded by another tool.
+    MissingXskMap,
+    PermissionDenied(std::io::Error),
     Initialize(std::io::Error),
+    /// Setting or reading the busy polling options failed. They need Linux
+    /// 5.11 or later.
+    BusyPoll(std::io::Error),
     SocketIsNull,
+    /// The payload does not fit in a frame after its headroom.
+    PayloadTooLarge {
+        length: usize,
+        capacity: u32,
+    },
+    /// No frame is free or the TX ring is full.
+    TxFull,
+}
+
+impl SocketError {
+    /// Map the error from creating and binding the socket to a variant.
+    fn from_create(
+        error: std::io::Error,
+        interface_name: &CString,
+        queue_id: u32,
+        force_zero_copy: bool,
+    ) -> Self {
+        let interface_name = interface_name.to_string_lossy().into_owned();
+        match error.raw_os_error().unwrap_or_default() {
+            libc::EBUSY => Self::Busy {
+                interface_name,
+                queue_id,
+            },
+            libc::EOPNOTSUPP if force_zero_copy => Self::ZeroCopyUnsupported { interface_name },
+            libc::EINVAL => Self::InvalidQueue {
+                interface_name,
+                queue_id,
+                error,
+            },
+            libc::ENODEV | libc::ENXIO => Self::NoSuchInterface { interface_name },
+            libc::EPERM | libc::EACCES => Self::PermissionDenied(error),
+            libc::ENOMEM | libc::ENOBUFS => Self::Umem(UmemError::Memlock(error)),
+            _ => Self::Initialize(error),
+        }
+    }
+
+    /// Return how to fix the error when the cause is well known.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Umem(error) => error.hint(),
+            Self::AdoptedUmem => Some("Create the socket in the process that registered the UMEM"),
+            Self::FrameRange { .. } => {
+                Some("Lower `first_frame` or `tx_frame_count`, or map a UMEM with more frames")
+            }
+            Self::Memlock(_) => Some(
+                "The UMEM is locked in memory and counts against RLIMIT_MEMLOCK; run as root, \
+                 grant CAP_SYS_RESOURCE or raise the hard limit with `ulimit -l`",
+            ),
+            Self::Busy { .. } => Some(
+                "Detach the other program with `xdp-loader unload <interface> --all`, close the \
+                 other socket or use another queue",
+            ),
+            Self::ZeroCopyUnsupported { .. } => {
+                Some("Disable `force_zero_copy` to fall back to copy mode")
+            }
+            Self::InvalidQueue { .. } => Some(
+                "Check the number of queues with `ethtool -l <interface>` and that the ring and \
+                 frame sizes are supported by the driver",
+            ),
+            Self::NoSuchInterface { .. } => Some("Check the interface name with `ip link`"),
+            Self::MissingXskMap => Some(
+                "Load a program redirecting to an XSKMAP, e.g. with `xdp-loader`, and pass its \
+                 pinned map through `SocketBuilder::xskmap`",
+            ),
+            Self::PermissionDenied(_) => Some("Run as root or grant CAP_NET_ADMIN and CAP_NET_RAW"),
+            Self::BusyPoll(_) => Some(
+                "Upgrade to Linux 5.11 or later, and run as root or grant CAP_NET_ADMIN to raise \
+                 the budget above `net.core.busy_poll`",
+            ),
+            Self::PayloadTooLarge { .. } => {
+                Some("Raise `frame_size` or lower `frame_headroom_size`")
+            }
+            Self::TxFull => Some(
+                "Retry once the kernel has transmitted the queued frames, or raise \
+                 `tx_frame_count`",
+            ),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for SocketError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            // The wrapped errors print their own hints.
+            Self::Umem(error) => return write!(f, "{}", error),
+            Self::Ring(error) => return write!(f, "{}", error),
+            Self::XskMap(error) => return write!(f, "{}", error),
+            Self::InvalidInterfaceName(error) => write!(f, "Invalid interface name: {}", error)?,
+            Self::AdoptedUmem => write!(f, "Cannot create a socket on an adopted UMEM")?,
+            Self::FrameRange {
+                first_frame,
+                length,
+                frame_count,
+            } => write!(
+                f,
+                "Frames {}..{} are out of the UMEM of {} frames",
+                first_frame,
+                first_frame.saturating_add(*length),
+                frame_count
+            )?,
+            Self::Memlock(error) => write!(f, "Failed to raise RLIMIT_MEMLOCK: {}", error)?,
+            Self::Busy {
+                interface_name,
+                queue_id,
+            } => write!(
+                f,
+                "Queue {} of {} is busy: another XDP program or AF_XDP socket is attached",
+                queue_id, interface_name
+            )?,
+            Self::ZeroCopyUnsupported { interface_name } => write!(
+                f,
+                "The driver of {} does not support zero-copy",
+                interface_name
+            )?,
+            Self::InvalidQueue {
+                interface_name,
+                queue_id,
+                error,
+            } => write!(
+                f,
+                "Cannot bind to queue {} of {}: {}",
+                queue_id, interface_name, error
+            )?,
+            Self::NoSuchInterface { interface_name } => {
+                write!(f, "No such interface: {}", interface_name)?
+            }
+            Self::MissingXskMap => write!(f, "No XDP program redirects packets to the socket")?,
+            Self::PermissionDenied(error) => {
+                write!(f, "Not permitted to create the socket: {}", error)?
+            }
+            Self::Initialize(error) => write!(f, "Failed to create the socket: {}", error)?,
+            Self::BusyPoll(error) => write!(f, "Failed to set up busy polling: {}", error)?,
+            Self::SocketIsNull => write!(f, "The socket pointer is null")?,
+            Self::PayloadTooLarge { length, capacity } => write!(
+                f,
+                "The payload of {} bytes does not fit in a frame of {} bytes",
+                length, capacity
+            )?,
+            Self::TxFull => write!(f, "No frame or TX ring entry is free")?,
+        }
+
+        match self.hint() {
+            Some(hint) => write!(f, ". {}", hint),
+            None => Ok(()),
+        }
     }
 }
 
-impl std::error::Error for SocketError {}
+impl std::error::Error for SocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Umem(error) => Some(error),
+            Self::Ring(error) => Some(error),
+            Self::XskMap(error) => Some(error),
+            Self::InvalidInterfaceName(error) => Some(error),
+            Self::Memlock(error)
+            | Self::InvalidQueue { error, .. }
+            | Self::PermissionDenied(error)
+            | Self::Initialize(error)
+            | Self::BusyPoll(error) => Some(error),
+            Self::AdoptedUmem
+            | Self::FrameRange { .. }
+            | Self::Busy { .. }
+            | Self::ZeroCopyUnsupported { .. }
+            | Self::NoSuchInterface { .. }
+            | Self::MissingXskMap
+            | Self::SocketIsNull
+            | Self::PayloadTooLarge { .. }
+            | Self::TxFull => None,
+        }
+    }
+}
 
 impl From<UmemError> for SocketError {
     fn from(value: UmemError) -> Self {
@@ -308,3 +1166,302 @@ impl From<RingError> for SocketError {
         Self::Ring(value)
     }
 }
+
+impl From<XskMapError> for SocketError {
+    fn from(value: XskMapError) -> Self {
+        Self::XskMap(value)
+    }
+}
+
+/// An RX and a TX socket on rings in memory, along with the ends of the rings
+/// the kernel holds, to test the sockets without a NIC or privileges.
+#[cfg(test)]
+pub(crate) struct TestSockets {
+    pub rx_socket: RxSocket,
+    pub tx_socket: TxSocket,
+    pub rx_ring: ProducerRing<xdp_desc>,
+    pub tx_ring: ConsumerRing<xdp_desc>,
+    pub fill_ring: ConsumerRing<u64>,
+    pub completion_ring: ProducerRing<u64>,
+    /// The other end of the socket fd, which makes it readable by writing.
+    pub peer: std::os::unix::net::UnixStream,
+    _memory: (
+        crate::ring::RingMemory<xdp_desc>,
+        crate::ring::RingMemory<xdp_desc>,
+        crate::ring::RingMemory<u64>,
+        crate::ring::RingMemory<u64>,
+    ),
+}
+
+#[cfg(test)]
+impl TestSockets {
+    pub fn new(frame_size: u32, frame_headroom_size: u32, frame_count: u32) -> Self {
+        use crate::ring::RingMemory;
+
+        let ring_size = frame_count.next_power_of_two();
+        let mut rx_memory = RingMemory::new(ring_size);
+        let mut tx_memory = RingMemory::new(ring_size);
+        let mut fill_memory = RingMemory::new(ring_size);
+        let mut completion_memory = RingMemory::new(ring_size);
+
+        let umem = Umem::in_memory(
+            frame_size,
+            frame_headroom_size,
+            frame_count,
+            fill_memory.producer_ring(),
+            completion_memory.consumer_ring(),
+        );
+        let (fd, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let socket = Socket {
+            inner: Arc::new(SocketInner::Fd(fd.into())),
+            need_wakeup: false,
+            busy_poll: false,
+        };
+
+        Self {
+            rx_socket: RxSocket::new(socket.clone(), rx_memory.consumer_ring(), umem.clone()),
+            tx_socket: TxSocket::new(socket, tx_memory.producer_ring(), umem),
+            rx_ring: rx_memory.producer_ring(),
+            tx_ring: tx_memory.consumer_ring(),
+            fill_ring: fill_memory.consumer_ring(),
+            completion_ring: completion_memory.producer_ring(),
+            peer,
+            _memory: (rx_memory, tx_memory, fill_memory, completion_memory),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, Read};
+
+    use super::*;
+
+    fn from_create(errno: i32, force_zero_copy: bool) -> SocketError {
+        let interface_name = CString::new("eth0").unwrap();
+        SocketError::from_create(
+            Error::from_raw_os_error(errno),
+            &interface_name,
+            3,
+            force_zero_copy,
+        )
+    }
+
+    const FRAME_SIZE: u32 = 2048;
+
+    #[test]
+    fn frames_pass_through_the_rings() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        let umem = sockets.rx_socket.umem();
+
+        let mut frames = VecDeque::from([0, FRAME_SIZE as u64]);
+        assert_eq!(umem.fill(&mut frames), 2);
+        let peek = sockets.fill_ring.peek(4);
+        assert_eq!(peek.iter().copied().collect::<Vec<_>>(), [0, 2048]);
+        drop(peek);
+
+        let descriptor = xdp_desc {
+            addr: FRAME_SIZE as u64,
+            len: 60,
+            options: 0,
+        };
+        assert!(sockets.rx_ring.reserve(1).push(descriptor).is_ok());
+        let mut buffer = VecDeque::with_capacity(4);
+        assert_eq!(sockets.rx_socket.rx_burst(&mut buffer), 1);
+        assert_eq!(buffer[0].address(), FRAME_SIZE as u64);
+        assert_eq!(buffer[0].length(), 60);
+
+        assert_eq!(sockets.tx_socket.tx_burst(&mut buffer), 1);
+        assert_eq!(sockets.tx_ring.peek(4).release(), 1);
+        // Waking the kernel up sends no data.
+        sockets.peer.set_nonblocking(true).unwrap();
+        let error = sockets.peer.read(&mut [0]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(sockets
+            .completion_ring
+            .reserve(1)
+            .push(descriptor.addr)
+            .is_ok());
+        let mut completed = VecDeque::with_capacity(4);
+        assert_eq!(sockets.tx_socket.complete(&mut completed), 1);
+        assert_eq!(completed, [FRAME_SIZE as u64]);
+    }
+
+    /// Return the payloads of the descriptors the kernel takes from the TX
+    /// ring, along with their addresses.
+    fn transmitted(sockets: &mut TestSockets) -> Vec<(u64, Vec<u8>)> {
+        let umem = sockets.tx_socket.umem();
+        let peek = sockets.tx_ring.peek(8);
+        peek.iter()
+            .map(|descriptor| {
+                let data = umem.get_data(descriptor.addr) as *const u8;
+                let payload = unsafe { std::slice::from_raw_parts(data, descriptor.len as usize) };
+                (descriptor.addr, payload.to_vec())
+            })
+            .collect()
+    }
+
+    fn complete(sockets: &mut TestSockets, addresses: &[u64]) {
+        let mut reservation = sockets.completion_ring.reserve(addresses.len() as u32);
+        addresses
+            .iter()
+            .for_each(|&address| reservation.push(address).unwrap());
+    }
+
+    #[test]
+    fn sent_frames_return_to_the_pool() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 256, 4);
+        let frame_size = FRAME_SIZE as u64;
+        sockets
+            .tx_socket
+            .add_frames([2 * frame_size, 3 * frame_size]);
+
+        sockets.tx_socket.send(b"first").unwrap();
+        sockets.tx_socket.send(b"second").unwrap();
+        assert!(matches!(
+            sockets.tx_socket.send(b"third"),
+            Err(SocketError::TxFull)
+        ));
+        // Payloads are copied after the headroom.
+        let sent = transmitted(&mut sockets);
+        assert_eq!(
+            sent,
+            [
+                (2 * frame_size + 256, b"first".to_vec()),
+                (3 * frame_size + 256, b"second".to_vec()),
+            ]
+        );
+
+        // A completed frame is reused without reaching the application.
+        complete(&mut sockets, &[sent[1].0]);
+        sockets.tx_socket.send(b"third").unwrap();
+        assert_eq!(
+            transmitted(&mut sockets),
+            [(3 * frame_size + 256, b"third".to_vec())]
+        );
+        let mut completed = VecDeque::with_capacity(4);
+        assert_eq!(sockets.tx_socket.complete(&mut completed), 0);
+    }
+
+    #[test]
+    fn other_frames_complete_to_the_application() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        let frame_size = FRAME_SIZE as u64;
+        sockets.tx_socket.add_frames([3 * frame_size]);
+
+        // Frames taken from the pool return to it as well.
+        let taken = sockets.tx_socket.take_frames();
+        assert_eq!(taken, [3 * frame_size]);
+        assert_eq!(sockets.tx_socket.frames().count(), 0);
+        complete(&mut sockets, &[frame_size + 60, 3 * frame_size + 100]);
+
+        let mut completed = VecDeque::with_capacity(4);
+        assert_eq!(sockets.tx_socket.complete(&mut completed), 1);
+        assert_eq!(completed, [frame_size + 60]);
+        assert_eq!(
+            sockets.tx_socket.frames().collect::<Vec<_>>(),
+            [3 * frame_size]
+        );
+    }
+
+    #[test]
+    fn send_batch_stops_when_frames_run_out() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        sockets.tx_socket.add_frames([0, FRAME_SIZE as u64]);
+
+        let payloads: [&[u8]; 3] = [b"a", b"b", b"c"];
+        assert_eq!(sockets.tx_socket.send_batch(&payloads).unwrap(), 2);
+        let payloads: Vec<Vec<u8>> = transmitted(&mut sockets)
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect();
+        assert_eq!(payloads, [b"a".to_vec(), b"b".to_vec()]);
+
+        let too_large = vec![0; FRAME_SIZE as usize + 1];
+        assert!(matches!(
+            sockets.tx_socket.send_batch(&[b"a", &too_large]),
+            Err(SocketError::PayloadTooLarge {
+                length: 2049,
+                capacity: FRAME_SIZE,
+            })
+        ));
+    }
+
+    #[test]
+    fn busy_poll_options() {
+        let mut sockets = TestSockets::new(FRAME_SIZE, 0, 4);
+        let socket = &mut sockets.rx_socket.socket;
+        assert!(!prefers_busy_poll(socket.as_fd()).unwrap());
+        assert!(!socket.busy_poll());
+
+        let busy_poll = BusyPoll {
+            timeout: 50,
+            budget: 16,
+        };
+        match socket.set_busy_poll(busy_poll) {
+            Ok(()) => {}
+            // Before Linux 5.11 or without CONFIG_NET_RX_BUSY_POLL.
+            Err(error) if error.raw_os_error() == Some(libc::ENOPROTOOPT) => return,
+            Err(error) => panic!("{}", error),
+        }
+        assert!(socket.busy_poll());
+        let fd = socket.as_fd();
+        assert!(prefers_busy_poll(fd).unwrap());
+        // The budget cannot be read back.
+        assert_eq!(get_int_option(fd, SOL_SOCKET, SO_BUSY_POLL).unwrap(), 50);
+    }
+
+    #[test]
+    fn errors_from_create() {
+        assert!(matches!(
+            from_create(libc::EBUSY, false),
+            SocketError::Busy { queue_id: 3, .. }
+        ));
+        assert!(matches!(
+            from_create(libc::EOPNOTSUPP, true),
+            SocketError::ZeroCopyUnsupported { .. }
+        ));
+        // Only zero-copy explains EOPNOTSUPP.
+        assert!(matches!(
+            from_create(libc::EOPNOTSUPP, false),
+            SocketError::Initialize(_)
+        ));
+        assert!(matches!(
+            from_create(libc::EINVAL, false),
+            SocketError::InvalidQueue { queue_id: 3, .. }
+        ));
+        for errno in [libc::ENODEV, libc::ENXIO] {
+            assert!(matches!(
+                from_create(errno, false),
+                SocketError::NoSuchInterface { .. }
+            ));
+        }
+        for errno in [libc::EPERM, libc::EACCES] {
+            assert!(matches!(
+                from_create(errno, false),
+                SocketError::PermissionDenied(_)
+            ));
+        }
+        for errno in [libc::ENOMEM, libc::ENOBUFS] {
+            assert!(matches!(
+                from_create(errno, false),
+                SocketError::Umem(UmemError::Memlock(_))
+            ));
+        }
+        assert!(matches!(
+            from_create(libc::EIO, false),
+            SocketError::Initialize(_)
+        ));
+    }
+
+    #[test]
+    fn messages_end_with_hints() {
+        let message = from_create(libc::EBUSY, false).to_string();
+        assert!(message.starts_with("Queue 3 of eth0 is busy: "));
+        assert!(message.ends_with(". Detach the other program with `xdp-loader unload <interface> --all`, close the other socket or use another queue"));
+
+        let error = from_create(libc::EIO, false);
+        assert_eq!(error.hint(), None);
+        assert!(!error.to_string().contains(". "));
+    }
+}
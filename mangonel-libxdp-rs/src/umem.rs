@@ -1,18 +1,34 @@
+#[cfg(not(feature = "native"))]
+use std::ptr::{null_mut, NonNull};
 use std::{
     ffi::c_void,
-    ptr::{null_mut, NonNull},
-    sync::Arc,
+    os::fd::BorrowedFd,
+    sync::{Arc, Mutex},
+};
+#[cfg(feature = "native")]
+use std::{
+    os::fd::{AsFd, FromRawFd, OwnedFd},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use mangonel_libxdp_sys::{
-    xsk_umem, xsk_umem__create, xsk_umem__delete, xsk_umem__get_data, xsk_umem_config,
+#[cfg(feature = "native")]
+use libc::{
+    socket, xdp_umem_reg, AF_XDP, SOCK_CLOEXEC, SOCK_RAW, XDP_UMEM_COMPLETION_RING,
+    XDP_UMEM_FILL_RING, XDP_UMEM_REG,
 };
+use libc::{XDP_UMEM_PGOFF_COMPLETION_RING, XDP_UMEM_PGOFF_FILL_RING};
+#[cfg(not(feature = "native"))]
+use mangonel_libxdp_sys::{xsk_umem, xsk_umem__create, xsk_umem__delete, xsk_umem_config};
 
+#[cfg(not(feature = "native"))]
+use crate::ring::{ConsumerRingUninit, ProducerRingUninit};
 use crate::{
     buffer::Buffer,
-    mmap::{Mmap, MmapError},
-    ring::{ConsumerRing, ConsumerRingUninit, ProducerRing, ProducerRingUninit, RingError},
+    mmap::{Mmap, MmapBuilder, MmapError},
+    ring::{mmap_offsets, ConsumerRing, ProducerRing, RingError, RingFlags},
 };
+#[cfg(feature = "native")]
+use crate::{util::setsockopt, xsk::xsk_umem_config};
 
 pub struct Umem {
     inner: Arc<UmemInner>,
@@ -20,9 +36,25 @@ pub struct Umem {
 
 struct UmemInner {
     umem_config: xsk_umem_config,
-    umem: NonNull<xsk_umem>,
-    completion_ring: ConsumerRing,
-    fill_ring: ProducerRing,
+    /// [`None`] when the UMEM was adopted from another process.
+    #[cfg(not(feature = "native"))]
+    umem: Option<NonNull<xsk_umem>>,
+    /// The XSK socket the UMEM is registered on. [`None`] when the UMEM was
+    /// adopted from another process.
+    #[cfg(feature = "native")]
+    fd: Option<OwnedFd>,
+    /// Whether a socket is bound on `fd` already. Later sockets share the
+    /// UMEM with it.
+    #[cfg(feature = "native")]
+    bound: AtomicBool,
+    /// The rings are shared by every socket on the UMEM, which may live on
+    /// different threads. [`None`] when the UMEM is not registered with the
+    /// kernel.
+    completion_ring: Option<Mutex<ConsumerRing<u64>>>,
+    fill_ring: Option<Mutex<ProducerRing<u64>>>,
+    /// The flags of the fill ring, read without taking the lock on every
+    /// receive.
+    fill_flags: Option<RingFlags>,
     mmap: Mmap,
 }
 
@@ -30,6 +62,7 @@ unsafe impl Send for UmemInner {}
 
 unsafe impl Sync for UmemInner {}
 
+#[cfg(not(feature = "native"))]
 impl Drop for UmemInner {
     /// # Panics
     ///
@@ -37,10 +70,14 @@ impl Drop for UmemInner {
     /// while it is running and either [`RxSocket`] and [`TxSocket`] is
     /// referring to it. However, we want to see the error when it happens.
     fn drop(&mut self) {
-        let value = unsafe { xsk_umem__delete(self.umem.as_ptr()) };
+        let Some(umem) = self.umem else {
+            return;
+        };
+
+        let value = unsafe { xsk_umem__delete(umem.as_ptr()) };
         if value.is_negative() {
             panic!(
-                "{:?}",
+                "{}",
                 UmemError::Free(std::io::Error::from_raw_os_error(-value))
             );
         }
@@ -61,14 +98,31 @@ impl Umem {
         frame_size: u32,
         frame_headroom_size: u32,
         ring_size: u32,
-        use_hugetlb: bool,
+        mmap_builder: &MmapBuilder,
     ) -> Result<Self, UmemError> {
         let length = frame_size * ring_size;
-        let mmap = Mmap::new(length as usize, use_hugetlb)?;
+        let mmap = mmap_builder.build(length as usize)?;
+
+        Self::from_mmap(mmap, frame_size, frame_headroom_size, ring_size)
+    }
+
+    /// Register an existing mapping as UMEM. A mapping shared with another
+    /// process through [`Mmap::from_fd`] can be registered in both processes,
+    /// each owning a disjoint range of frames.
+    pub fn from_mmap(
+        mmap: Mmap,
+        frame_size: u32,
+        frame_headroom_size: u32,
+        ring_size: u32,
+    ) -> Result<Self, UmemError> {
+        let required = frame_size as usize * ring_size as usize;
+        if mmap.length() < required {
+            return Err(UmemError::MmapTooSmall {
+                required,
+                length: mmap.length(),
+            });
+        }
 
-        let mut umem_ptr = null_mut::<xsk_umem>();
-        let mut fill_ring = ProducerRingUninit::new(ring_size)?;
-        let mut completion_ring = ConsumerRingUninit::new(ring_size)?;
         let umem_config = xsk_umem_config {
             fill_size: ring_size,
             comp_size: ring_size,
@@ -77,6 +131,15 @@ impl Umem {
             flags: 0,
         };
 
+        Self::register(mmap, umem_config)
+    }
+
+    #[cfg(not(feature = "native"))]
+    fn register(mmap: Mmap, umem_config: xsk_umem_config) -> Result<Self, UmemError> {
+        let mut umem_ptr = null_mut::<xsk_umem>();
+        let mut fill_ring = ProducerRingUninit::new(umem_config.fill_size)?;
+        let mut completion_ring = ConsumerRingUninit::new(umem_config.comp_size)?;
+
         let value = unsafe {
             xsk_umem__create(
                 &mut umem_ptr,
@@ -88,16 +151,18 @@ impl Umem {
             )
         };
         if value.is_negative() {
-            return Err(UmemError::Initialize(std::io::Error::from_raw_os_error(
+            return Err(UmemError::from_io(std::io::Error::from_raw_os_error(
                 -value,
             )));
         }
 
+        let fill_ring = fill_ring.init()?;
         let inner = UmemInner {
             umem_config,
-            umem: NonNull::new(umem_ptr).ok_or(UmemError::UmemIsNull)?,
-            fill_ring: fill_ring.init()?,
-            completion_ring: completion_ring.init()?,
+            umem: Some(NonNull::new(umem_ptr).ok_or(UmemError::UmemIsNull)?),
+            fill_flags: fill_ring.flags(),
+            fill_ring: Some(Mutex::new(fill_ring)),
+            completion_ring: Some(Mutex::new(completion_ring.init()?)),
             mmap,
         };
         let umem = Self {
@@ -107,14 +172,197 @@ impl Umem {
         Ok(umem)
     }
 
+    /// Register the UMEM on a new XSK socket and map its rings, as
+    /// `xsk_umem__create()` does.
+    #[cfg(feature = "native")]
+    fn register(mmap: Mmap, umem_config: xsk_umem_config) -> Result<Self, UmemError> {
+        let fd = unsafe { socket(AF_XDP, SOCK_RAW | SOCK_CLOEXEC, 0) };
+        if fd.is_negative() {
+            return Err(UmemError::from_io(std::io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Fields added by later kernels, such as `tx_metadata_len`, stay zero.
+        let mut umem_reg: xdp_umem_reg = unsafe { std::mem::zeroed() };
+        umem_reg.addr = mmap.as_ptr() as u64;
+        umem_reg.len = mmap.length() as u64;
+        umem_reg.chunk_size = umem_config.frame_size;
+        umem_reg.headroom = umem_config.frame_headroom;
+        umem_reg.flags = umem_config.flags;
+        setsockopt(fd.as_fd(), XDP_UMEM_REG, &umem_reg).map_err(UmemError::from_io)?;
+        setsockopt(fd.as_fd(), XDP_UMEM_FILL_RING, &umem_config.fill_size)
+            .map_err(UmemError::from_io)?;
+        setsockopt(fd.as_fd(), XDP_UMEM_COMPLETION_RING, &umem_config.comp_size)
+            .map_err(UmemError::from_io)?;
+
+        let (fill_ring, completion_ring) = map_rings(fd.as_fd(), &umem_config)?;
+        let inner = UmemInner {
+            umem_config,
+            fd: Some(fd),
+            bound: AtomicBool::new(false),
+            fill_flags: fill_ring.flags(),
+            fill_ring: Some(Mutex::new(fill_ring)),
+            completion_ring: Some(Mutex::new(completion_ring)),
+            mmap,
+        };
+
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Adopt a UMEM registered by another process on the XSK socket `fd`,
+    /// typically received through [`crate::handoff::Handoff`]. `mmap` must map
+    /// the same memory, so the UMEM has to be backed by a shared memfd.
+    pub fn adopt(
+        mmap: Mmap,
+        fd: BorrowedFd,
+        frame_size: u32,
+        frame_headroom_size: u32,
+        ring_size: u32,
+    ) -> Result<Self, UmemError> {
+        let umem_config = xsk_umem_config {
+            fill_size: ring_size,
+            comp_size: ring_size,
+            frame_size,
+            frame_headroom: frame_headroom_size,
+            flags: 0,
+        };
+        let (fill_ring, completion_ring) = map_rings(fd, &umem_config)?;
+
+        Ok(Self::from_rings(
+            mmap,
+            umem_config,
+            fill_ring,
+            completion_ring,
+        ))
+    }
+
+    /// Wrap rings mapped elsewhere. No socket can be created on the UMEM.
+    fn from_rings(
+        mmap: Mmap,
+        umem_config: xsk_umem_config,
+        fill_ring: ProducerRing<u64>,
+        completion_ring: ConsumerRing<u64>,
+    ) -> Self {
+        let inner = UmemInner {
+            umem_config,
+            #[cfg(not(feature = "native"))]
+            umem: None,
+            #[cfg(feature = "native")]
+            fd: None,
+            #[cfg(feature = "native")]
+            bound: AtomicBool::new(true),
+            fill_flags: fill_ring.flags(),
+            fill_ring: Some(Mutex::new(fill_ring)),
+            completion_ring: Some(Mutex::new(completion_ring)),
+            mmap,
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Allocate a UMEM on rings in memory, whose other ends a test drives.
+    #[cfg(test)]
+    pub(crate) fn in_memory(
+        frame_size: u32,
+        frame_headroom_size: u32,
+        frame_count: u32,
+        fill_ring: ProducerRing<u64>,
+        completion_ring: ConsumerRing<u64>,
+    ) -> Self {
+        let mmap = MmapBuilder::default()
+            .build(frame_size as usize * frame_count as usize)
+            .unwrap();
+        let umem_config = xsk_umem_config {
+            fill_size: fill_ring.size,
+            comp_size: completion_ring.size,
+            frame_size,
+            frame_headroom: frame_headroom_size,
+            flags: 0,
+        };
+
+        Self::from_rings(mmap, umem_config, fill_ring, completion_ring)
+    }
+
+    /// Allocate a UMEM that is not registered with the kernel. Its frames are
+    /// moved around by [`crate::loopback::Loopback`] instead of the rings.
+    pub(crate) fn detached(
+        frame_size: u32,
+        frame_headroom_size: u32,
+        frame_count: u32,
+    ) -> Result<Self, UmemError> {
+        let length = frame_size as usize * frame_count as usize;
+        let mmap = MmapBuilder::default().build(length)?;
+        let umem_config = xsk_umem_config {
+            fill_size: frame_count,
+            comp_size: frame_count,
+            frame_size,
+            frame_headroom: frame_headroom_size,
+            flags: 0,
+        };
+
+        let inner = UmemInner {
+            umem_config,
+            #[cfg(not(feature = "native"))]
+            umem: None,
+            #[cfg(feature = "native")]
+            fd: None,
+            #[cfg(feature = "native")]
+            bound: AtomicBool::new(true),
+            fill_flags: None,
+            fill_ring: None,
+            completion_ring: None,
+            mmap,
+        };
+
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Return [`None`] when the UMEM was adopted from another process since
+    /// libxdp cannot create new sockets on it.
+    #[cfg(not(feature = "native"))]
+    #[inline(always)]
+    pub(crate) fn as_ptr(&self) -> Option<*mut xsk_umem> {
+        self.inner.umem.map(|umem| umem.as_ptr())
+    }
+
+    /// Return the socket the UMEM is registered on along with whether a
+    /// socket is bound on it already, in which case a new socket has to share
+    /// the UMEM. Return [`None`] when the UMEM was adopted from another
+    /// process.
+    #[cfg(feature = "native")]
+    #[inline(always)]
+    pub(crate) fn bind_fd(&self) -> Option<(BorrowedFd, bool)> {
+        let fd = self.inner.fd.as_ref()?;
+
+        Some((fd.as_fd(), self.inner.bound.load(Ordering::SeqCst)))
+    }
+
+    #[cfg(feature = "native")]
     #[inline(always)]
-    pub(crate) fn as_ptr(&self) -> *mut xsk_umem {
-        self.inner.umem.as_ptr()
+    pub(crate) fn set_bound(&self) {
+        self.inner.bound.store(true, Ordering::SeqCst);
     }
 
     #[inline(always)]
     pub(crate) fn get_data(&self, address: u64) -> *mut c_void {
-        unsafe { xsk_umem__get_data(self.inner.mmap.as_ptr(), address) }
+        self.inner.mmap.offset(address as isize)
+    }
+
+    #[inline(always)]
+    pub fn mmap(&self) -> &Mmap {
+        &self.inner.mmap
+    }
+
+    /// Return the number of frames that fit in the UMEM.
+    #[inline(always)]
+    pub fn frame_count(&self) -> u32 {
+        (self.inner.mmap.length() / self.frame_size() as usize) as u32
     }
 
     #[inline(always)]
@@ -127,74 +375,164 @@ impl Umem {
         self.inner.umem_config.frame_size
     }
 
+    #[inline(always)]
+    pub fn ring_size(&self) -> u32 {
+        self.inner.umem_config.fill_size
+    }
+
     #[inline(always)]
     pub fn needs_wakeup(&self) -> bool {
-        self.inner.fill_ring.needs_wakeup()
+        match self.inner.fill_flags {
+            Some(fill_flags) => fill_flags.needs_wakeup(),
+            None => false,
+        }
     }
 
     #[inline(always)]
     pub fn fill<T: Buffer<u64>>(&self, buffer: &mut T) -> u32 {
-        let mut index: u32 = 0;
-        let size = std::cmp::min(buffer.count(), self.inner.fill_ring.size);
-
-        let available = self.inner.fill_ring.reserve(size, &mut index);
-        if available > 0 {
-            for _ in 0..available {
-                let address = self.inner.fill_ring.fill_address(index);
-                unsafe {
-                    // # Safety
-                    //
-                    // It is safe to call `unwrap()` because the size variable is always smaller
-                    // than the number of descriptors in the buffer.
-                    *address = buffer.pop().unwrap();
-                }
-                index += 1;
-            }
+        let Some(fill_ring) = &self.inner.fill_ring else {
+            return 0;
+        };
+        let mut fill_ring = fill_ring.lock().unwrap();
+        let size = std::cmp::min(buffer.count(), fill_ring.size);
 
-            self.inner.fill_ring.submit(available);
+        let mut reservation = fill_ring.reserve_fill(size);
+        while !reservation.is_full() {
+            // The reservation is never larger than the buffer.
+            let Some(address) = buffer.pop() else {
+                break;
+            };
+            let _ = reservation.push(address);
         }
 
-        available
+        reservation.submit()
     }
 
     #[inline(always)]
     pub fn complete<T: Buffer<u64>>(&self, buffer: &mut T) -> u32 {
-        let mut index: u32 = 0;
-        let size = std::cmp::min(buffer.free(), self.inner.completion_ring.size);
-
-        let available = self.inner.completion_ring.peek(size, &mut index);
-        if available > 0 {
-            for _ in 0..available {
-                let address = self.inner.completion_ring.complete_address(index);
-                unsafe {
-                    buffer.push(*address);
-                }
-                index += 1;
-            }
+        let Some(completion_ring) = &self.inner.completion_ring else {
+            return 0;
+        };
+        let mut completion_ring = completion_ring.lock().unwrap();
+        let size = std::cmp::min(buffer.free(), completion_ring.size);
 
-            self.inner.completion_ring.release(available);
-        }
+        let peek = completion_ring.peek_complete(size);
+        peek.iter().for_each(|address| {
+            buffer.push(*address);
+        });
 
-        available
+        peek.release()
     }
 }
 
+/// Map the fill and completion rings of the UMEM registered on `fd`.
+fn map_rings(
+    fd: BorrowedFd,
+    umem_config: &xsk_umem_config,
+) -> Result<(ProducerRing<u64>, ConsumerRing<u64>), UmemError> {
+    let offsets = mmap_offsets(fd)?;
+    let fill_ring = ProducerRing::map(
+        fd,
+        XDP_UMEM_PGOFF_FILL_RING as i64,
+        &offsets.fr,
+        umem_config.fill_size,
+    )?;
+    let completion_ring = ConsumerRing::map(
+        fd,
+        XDP_UMEM_PGOFF_COMPLETION_RING as i64,
+        &offsets.cr,
+        umem_config.comp_size,
+    )?;
+
+    Ok((fill_ring, completion_ring))
+}
+
 #[derive(Debug)]
 pub enum UmemError {
     Mmap(MmapError),
     Ring(RingError),
+    MmapTooSmall {
+        required: usize,
+        length: usize,
+    },
+    /// The kernel could not pin the UMEM, usually because of `RLIMIT_MEMLOCK`.
+    Memlock(std::io::Error),
+    PermissionDenied(std::io::Error),
     Initialize(std::io::Error),
     UmemIsNull,
     Free(std::io::Error),
 }
 
+impl UmemError {
+    /// Map the error from registering the UMEM to a variant.
+    fn from_io(error: std::io::Error) -> Self {
+        match error.raw_os_error().unwrap_or_default() {
+            libc::ENOMEM | libc::ENOBUFS => Self::Memlock(error),
+            libc::EPERM | libc::EACCES => Self::PermissionDenied(error),
+            _ => Self::Initialize(error),
+        }
+    }
+
+    /// Return how to fix the error when the cause is well known.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Mmap(error) => error.hint(),
+            Self::MmapTooSmall { .. } => Some("Map at least `frame_size * ring_size` bytes"),
+            Self::Memlock(_) => Some(
+                "The UMEM and rings are pinned and count against RLIMIT_MEMLOCK, or the memory \
+                 cgroup since Linux 5.11; raise the limit (`ulimit -l unlimited`), grant \
+                 CAP_IPC_LOCK or lower `ring_size`, `tx_frame_count` or `frame_size`",
+            ),
+            Self::PermissionDenied(_) => Some("Run as root or grant CAP_NET_ADMIN and CAP_NET_RAW"),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for UmemError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            // The wrapped errors print their own hints.
+            Self::Mmap(error) => return write!(f, "Failed to map the UMEM: {}", error),
+            Self::Ring(error) => return write!(f, "Invalid UMEM ring: {}", error),
+            Self::MmapTooSmall { required, length } => write!(
+                f,
+                "The mapping is too small for the UMEM: {} bytes required, {} mapped",
+                required, length
+            )?,
+            Self::Memlock(error) => write!(
+                f,
+                "Not enough lockable memory to register the UMEM: {}",
+                error
+            )?,
+            Self::PermissionDenied(error) => {
+                write!(f, "Not permitted to register the UMEM: {}", error)?
+            }
+            Self::Initialize(error) => write!(f, "Failed to register the UMEM: {}", error)?,
+            Self::UmemIsNull => write!(f, "The UMEM pointer is null")?,
+            Self::Free(error) => write!(f, "Failed to delete the UMEM: {}", error)?,
+        }
+
+        match self.hint() {
+            Some(hint) => write!(f, ". {}", hint),
+            None => Ok(()),
+        }
     }
 }
 
-impl std::error::Error for UmemError {}
+impl std::error::Error for UmemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Mmap(error) => Some(error),
+            Self::Ring(error) => Some(error),
+            Self::Memlock(error)
+            | Self::PermissionDenied(error)
+            | Self::Initialize(error)
+            | Self::Free(error) => Some(error),
+            Self::MmapTooSmall { .. } | Self::UmemIsNull => None,
+        }
+    }
+}
 
 impl From<MmapError> for UmemError {
     fn from(value: MmapError) -> Self {
@@ -207,3 +545,26 @@ impl From<RingError> for UmemError {
         Self::Ring(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error;
+
+    use super::*;
+
+    #[test]
+    fn errors_from_io() {
+        for errno in [libc::ENOMEM, libc::ENOBUFS] {
+            let error = UmemError::from_io(Error::from_raw_os_error(errno));
+            assert!(matches!(error, UmemError::Memlock(_)));
+            assert!(error.hint().unwrap().contains("RLIMIT_MEMLOCK"));
+        }
+        for errno in [libc::EPERM, libc::EACCES] {
+            let error = UmemError::from_io(Error::from_raw_os_error(errno));
+            assert!(matches!(error, UmemError::PermissionDenied(_)));
+        }
+        let error = UmemError::from_io(Error::from_raw_os_error(libc::EINVAL));
+        assert!(matches!(error, UmemError::Initialize(_)));
+        assert_eq!(error.hint(), None);
+    }
+}
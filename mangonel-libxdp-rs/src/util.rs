@@ -6,12 +6,77 @@ pub fn is_power_of_two(size: u32) -> bool {
     (size & (size - 1)) == 0
 }
 
-/// # Panics
+/// Round `value` up to the nearest multiple of `alignment`.
+pub fn round_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Set an `SOL_XDP` option of an XSK socket.
+#[cfg(feature = "native")]
+pub(crate) fn setsockopt<T>(
+    fd: std::os::fd::BorrowedFd,
+    name: libc::c_int,
+    value: &T,
+) -> std::io::Result<()> {
+    set_option(fd, libc::SOL_XDP, name, value)
+}
+
+/// Set an option of a socket at `level`.
+pub(crate) fn set_option<T>(
+    fd: std::os::fd::BorrowedFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            (value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if value.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Read an integer option of a socket at `level`.
+pub(crate) fn get_int_option(
+    fd: std::os::fd::BorrowedFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> std::io::Result<libc::c_int> {
+    use std::os::fd::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut length,
+        )
+    };
+    if result.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+/// Allow unlimited locking of memory, which UMEM registration requires on
+/// kernels accounting it against `RLIMIT_MEMLOCK`.
 ///
-/// The function panics under the following conditions:
-/// - the system fails to allow unlimited locking of memory.
-/// - the program does not have the permission to do so.
-pub fn setrlimit() {
+/// It fails when the program lacks `CAP_SYS_RESOURCE` to raise the hard limit.
+pub fn setrlimit() -> std::io::Result<()> {
     let value = unsafe {
         let rlimit = libc::rlimit {
             rlim_cur: libc::RLIM64_INFINITY,
@@ -21,6 +86,8 @@ pub fn setrlimit() {
         libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlimit)
     };
     if value.is_negative() {
-        panic!("{}", std::io::Error::from_raw_os_error(-value));
+        return Err(std::io::Error::last_os_error());
     }
+
+    Ok(())
 }
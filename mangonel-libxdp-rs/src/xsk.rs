@@ -0,0 +1,224 @@
+//! A Rust implementation of the ring operations in libxdp's `xsk.h`, used in
+//! place of `mangonel_libxdp_sys` when the `native` feature is enabled.
+//!
+//! The names and semantics follow `xsk.h` so that the rest of the crate does
+//! not depend on which implementation is in use.
+#![allow(non_camel_case_types, non_snake_case)]
+
+use std::{
+    ffi::c_void,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use libc::XDP_RING_NEED_WAKEUP;
+
+pub const XSK_RING_PROD__DEFAULT_NUM_DESCS: u32 = 2048;
+pub const XSK_UMEM__DEFAULT_FRAME_SIZE: u32 = 1 << 12;
+pub const XSK_UMEM__DEFAULT_FRAME_HEADROOM: u32 = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct xsk_umem_config {
+    pub fill_size: u32,
+    pub comp_size: u32,
+    pub frame_size: u32,
+    pub frame_headroom: u32,
+    pub flags: u32,
+}
+
+/// The local state of a ring along with pointers into its mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct xsk_ring {
+    pub cached_prod: u32,
+    pub cached_cons: u32,
+    pub mask: u32,
+    pub size: u32,
+    pub producer: *mut u32,
+    pub consumer: *mut u32,
+    pub ring: *mut c_void,
+    pub flags: *mut u32,
+}
+
+pub type xsk_ring_prod = xsk_ring;
+
+pub type xsk_ring_cons = xsk_ring;
+
+/// # Safety
+///
+/// `pointer` must point into a live ring mapping.
+#[inline(always)]
+unsafe fn atomic<'a>(pointer: *mut u32) -> &'a AtomicU32 {
+    &*(pointer as *const AtomicU32)
+}
+
+/// Return the number of free entries, reading the consumer only when fewer
+/// than `nb` are known to be free.
+#[inline(always)]
+unsafe fn xsk_prod_nb_free(r: *mut xsk_ring_prod, nb: u32) -> u32 {
+    let r = &mut *r;
+    let free_entries = r.cached_cons.wrapping_sub(r.cached_prod);
+    if free_entries >= nb {
+        return free_entries;
+    }
+
+    // The consumer is kept `size` ahead so that the subtraction yields the
+    // number of free entries.
+    r.cached_cons = atomic(r.consumer)
+        .load(Ordering::Acquire)
+        .wrapping_add(r.size);
+
+    r.cached_cons.wrapping_sub(r.cached_prod)
+}
+
+/// Return the number of available entries up to `nb`, reading the producer
+/// only when none are known to be available.
+#[inline(always)]
+unsafe fn xsk_cons_nb_avail(r: *mut xsk_ring_cons, nb: u32) -> u32 {
+    let r = &mut *r;
+    let mut entries = r.cached_prod.wrapping_sub(r.cached_cons);
+    if entries == 0 {
+        r.cached_prod = atomic(r.producer).load(Ordering::Acquire);
+        entries = r.cached_prod.wrapping_sub(r.cached_cons);
+    }
+
+    std::cmp::min(entries, nb)
+}
+
+/// Reserve exactly `nb` entries or none at all.
+#[inline(always)]
+pub unsafe fn xsk_ring_prod__reserve(prod: *mut xsk_ring_prod, nb: u32, idx: *mut u32) -> u32 {
+    if xsk_prod_nb_free(prod, nb) < nb {
+        return 0;
+    }
+
+    let prod = &mut *prod;
+    *idx = prod.cached_prod;
+    prod.cached_prod = prod.cached_prod.wrapping_add(nb);
+
+    nb
+}
+
+#[inline(always)]
+pub unsafe fn xsk_ring_prod__submit(prod: *mut xsk_ring_prod, nb: u32) {
+    let producer = atomic((*prod).producer);
+    // Only this process writes the producer.
+    let value = producer.load(Ordering::Relaxed).wrapping_add(nb);
+    producer.store(value, Ordering::Release);
+}
+
+#[inline(always)]
+pub unsafe fn xsk_ring_prod__needs_wakeup(r: *const xsk_ring_prod) -> i32 {
+    (atomic((*r).flags).load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP) as i32
+}
+
+#[inline(always)]
+pub unsafe fn xsk_ring_cons__peek(cons: *mut xsk_ring_cons, nb: u32, idx: *mut u32) -> u32 {
+    let entries = xsk_cons_nb_avail(cons, nb);
+    if entries > 0 {
+        let cons = &mut *cons;
+        *idx = cons.cached_cons;
+        cons.cached_cons = cons.cached_cons.wrapping_add(entries);
+    }
+
+    entries
+}
+
+#[inline(always)]
+pub unsafe fn xsk_ring_cons__cancel(cons: *mut xsk_ring_cons, nb: u32) {
+    (*cons).cached_cons = (*cons).cached_cons.wrapping_sub(nb);
+}
+
+#[inline(always)]
+pub unsafe fn xsk_ring_cons__release(cons: *mut xsk_ring_cons, nb: u32) {
+    let consumer = atomic((*cons).consumer);
+    // Only this process writes the consumer.
+    let value = consumer.load(Ordering::Relaxed).wrapping_add(nb);
+    consumer.store(value, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null_mut;
+
+    use super::*;
+
+    const SIZE: u32 = 4;
+
+    /// Return the producer, consumer and flags of a ring along with the local
+    /// state of a ring whose indices start at `start`, close to wrapping.
+    fn ring(indices: &[AtomicU32; 3], start: u32) -> xsk_ring {
+        indices[0].store(start, Ordering::SeqCst);
+        indices[1].store(start, Ordering::SeqCst);
+
+        xsk_ring {
+            cached_prod: start,
+            cached_cons: start,
+            mask: SIZE - 1,
+            size: SIZE,
+            producer: indices[0].as_ptr(),
+            consumer: indices[1].as_ptr(),
+            ring: null_mut(),
+            flags: indices[2].as_ptr(),
+        }
+    }
+
+    #[test]
+    fn producer_indices_wrap() {
+        let indices = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+        let start = u32::MAX - 1;
+        let mut prod = ring(&indices, start);
+        prod.cached_cons = start.wrapping_add(SIZE);
+
+        let mut index = 0;
+        unsafe {
+            assert_eq!(xsk_ring_prod__reserve(&mut prod, 3, &mut index), 3);
+            assert_eq!(index, start);
+            // Reservations are all or nothing.
+            assert_eq!(xsk_ring_prod__reserve(&mut prod, 2, &mut index), 0);
+            assert_eq!(xsk_ring_prod__reserve(&mut prod, 1, &mut index), 1);
+            assert_eq!(index, 1);
+            xsk_ring_prod__submit(&mut prod, SIZE);
+        }
+        assert_eq!(prod.cached_prod, 2);
+        assert_eq!(indices[0].load(Ordering::SeqCst), 2);
+
+        // The consumer is read again once the cached one leaves too little
+        // room.
+        indices[1].store(start.wrapping_add(3), Ordering::SeqCst);
+        unsafe {
+            assert_eq!(xsk_prod_nb_free(&mut prod, 1), 3);
+            assert_eq!(xsk_ring_prod__reserve(&mut prod, 3, &mut index), 3);
+        }
+        assert_eq!(index, 2);
+    }
+
+    #[test]
+    fn consumer_indices_wrap() {
+        let indices = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+        let start = u32::MAX - 2;
+        let mut cons = ring(&indices, start);
+
+        let mut index = 0;
+        unsafe {
+            assert_eq!(xsk_ring_cons__peek(&mut cons, SIZE, &mut index), 0);
+
+            indices[0].store(start.wrapping_add(SIZE), Ordering::SeqCst);
+            assert_eq!(xsk_ring_cons__peek(&mut cons, 2, &mut index), 2);
+            assert_eq!(index, start);
+            xsk_ring_cons__cancel(&mut cons, 1);
+            assert_eq!(xsk_ring_cons__peek(&mut cons, SIZE, &mut index), 3);
+            assert_eq!(index, start.wrapping_add(1));
+            xsk_ring_cons__release(&mut cons, SIZE);
+        }
+        assert_eq!(cons.cached_cons, 1);
+        assert_eq!(indices[1].load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wakeup_flag() {
+        let indices = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+        let prod = ring(&indices, 0);
+        assert_eq!(unsafe { xsk_ring_prod__needs_wakeup(&prod) }, 0);
+        indices[2].store(XDP_RING_NEED_WAKEUP, Ordering::SeqCst);
+        assert_ne!(unsafe { xsk_ring_prod__needs_wakeup(&prod) }, 0);
+    }
+}
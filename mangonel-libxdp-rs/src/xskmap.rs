@@ -0,0 +1,145 @@
+use std::{
+    ffi::{CString, NulError},
+    mem::size_of,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+#[cfg(feature = "libxdp")]
+use libc::if_nametoindex;
+use libc::{syscall, SYS_bpf};
+#[cfg(feature = "libxdp")]
+use mangonel_libxdp_sys::xsk_setup_xdp_prog;
+
+const BPF_MAP_UPDATE_ELEM: i32 = 2;
+const BPF_OBJ_GET: i32 = 7;
+
+/// The part of `union bpf_attr` used by `BPF_MAP_*_ELEM` commands.
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// The part of `union bpf_attr` used by `BPF_OBJ_*` commands.
+#[repr(C)]
+struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// Call `bpf(2)` directly so that maps can be used without libbpf.
+fn bpf<T>(command: i32, attr: &T) -> std::io::Result<i32> {
+    let value = unsafe { syscall(SYS_bpf, command, attr as *const T, size_of::<T>()) };
+    if value.is_negative() {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(value as i32)
+}
+
+/// The XSKMAP of the XDP program redirecting packets to the sockets.
+///
+/// Sockets created with an [`XskMap`] do not load a program of their own, so
+/// the program and the map outlive the process and can be handed over to
+/// another one.
+#[derive(Debug)]
+pub struct XskMap(OwnedFd);
+
+impl AsFd for XskMap {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl XskMap {
+    /// Load and attach the default libxdp program to the interface unless it
+    /// is already attached, and return its XSKMAP.
+    #[cfg(feature = "libxdp")]
+    pub fn setup(interface_name: impl AsRef<str>) -> Result<Self, XskMapError> {
+        let interface_name =
+            CString::new(interface_name.as_ref()).map_err(XskMapError::InvalidInterfaceName)?;
+        let interface_index = unsafe { if_nametoindex(interface_name.as_ptr()) };
+        if interface_index == 0 {
+            return Err(XskMapError::Setup(std::io::Error::last_os_error()));
+        }
+
+        let mut map_fd = -1;
+        let value = unsafe { xsk_setup_xdp_prog(interface_index as i32, &mut map_fd) };
+        if value.is_negative() {
+            return Err(XskMapError::Setup(std::io::Error::from_raw_os_error(
+                -value,
+            )));
+        }
+
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(map_fd) }))
+    }
+
+    pub fn from_fd(fd: OwnedFd) -> Self {
+        Self(fd)
+    }
+
+    /// Open a map pinned to bpffs, e.g. by `xdp-loader load --pin-path`.
+    pub fn from_pinned(path: impl AsRef<Path>) -> Result<Self, XskMapError> {
+        let path =
+            CString::new(path.as_ref().as_os_str().as_bytes()).map_err(XskMapError::InvalidPath)?;
+        let attr = ObjAttr {
+            pathname: path.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        };
+        let fd = bpf(BPF_OBJ_GET, &attr).map_err(XskMapError::Open)?;
+
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Redirect packets received on the queue to the socket.
+    pub fn insert(&self, queue_id: u32, socket_fd: BorrowedFd) -> Result<(), XskMapError> {
+        let socket_fd = socket_fd.as_raw_fd();
+        let attr = MapElemAttr {
+            map_fd: self.0.as_raw_fd() as u32,
+            key: &queue_id as *const u32 as u64,
+            value: &socket_fd as *const i32 as u64,
+            flags: 0,
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &attr).map_err(XskMapError::Update)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum XskMapError {
+    InvalidInterfaceName(NulError),
+    InvalidPath(NulError),
+    Setup(std::io::Error),
+    Open(std::io::Error),
+    Update(std::io::Error),
+}
+
+impl std::fmt::Display for XskMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidInterfaceName(error) => write!(f, "Invalid interface name: {}", error),
+            Self::InvalidPath(error) => write!(f, "Invalid path: {}", error),
+            Self::Setup(error) => write!(f, "Failed to set up the XDP program: {}", error),
+            Self::Open(error) => write!(f, "Failed to open the pinned XSKMAP: {}", error),
+            Self::Update(error) => write!(f, "Failed to update the XSKMAP: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for XskMapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidInterfaceName(error) | Self::InvalidPath(error) => Some(error),
+            Self::Setup(error) | Self::Open(error) | Self::Update(error) => Some(error),
+        }
+    }
+}
@@ -12,23 +12,25 @@ fn check_os() {
     }
 }
 
-/// Link a library by its name and version.
+/// Link a library by its name and version and return the installed version.
 ///
 /// # Panics
 ///
 /// Panics either when the program fails to find the library in the
 /// default system library path or when the installed library version
 /// does not satisfy the minimum version given by "version" parameter.
-fn link_library(name: &str, version: &str) {
+fn link_library(name: &str, version: &str) -> String {
     pkg_config::Config::new()
         .atleast_version(version)
         .probe(name)
-        .unwrap_or_else(|error| panic!("Failed to link the library: {}", error));
+        .unwrap_or_else(|error| panic!("Failed to link the library: {}", error))
+        .version
 }
 
 fn main() {
     check_os();
-    link_library(LIB_NAME, LIB_VERSION);
+    let version = link_library(LIB_NAME, LIB_VERSION);
+    println!("cargo:rustc-env=LIBXDP_VERSION={}", version);
 
     let bindings = Builder::default()
         .header(WRAPPER)
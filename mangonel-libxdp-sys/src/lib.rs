@@ -2,3 +2,6 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// The version of libxdp the bindings were built against.
+pub const LIBXDP_VERSION: &str = env!("LIBXDP_VERSION");
@@ -0,0 +1,494 @@
+//! Command-line client of the control socket of a running `mangonel`.
+
+use mangonel::control::{Client, Request, CONTROL_PATH};
+use serde_json::{json, Value};
+
+const USAGE: &str = "\
+Usage: mangonelctl [--socket=PATH] [--format=table|json] COMMAND
+
+Commands:
+  stats                          Show the counters of the workers
+  interfaces                     Show the WAN and LAN interfaces
+  sockets                        Show the sockets of the workers
+  rules [list]                   Show the default verdict, the fragment
+                                 policy and the rules
+  rules add NAME pass|drop       Append a rule, or insert it with --position=N
+      [--protocol=tcp|udp|http|https] [--source=NETWORK|set:NAME]
+      [--destination=NETWORK|set:NAME] [--port=PORT] [--position=N]
+      [--packets=PPS] [--packet-burst=N] [--bytes=BPS] [--byte-burst=N]
+      [--ipv4-prefix=LENGTH] [--ipv6-prefix=LENGTH] [--sources=N]
+      [--exceed=drop|mark] [--dscp=DSCP] [--syn-proxy]
+                                 Rules with --packets or --bytes limit the
+                                 rate of every source. Rules with
+                                 --syn-proxy answer SYNs with cookies. Sets
+                                 are those of the configuration
+  rules del NAME                 Remove a rule
+  rules move NAME POSITION       Move a rule, counting positions without it
+  blocks [list]                  Show the sources blocked for sending too
+                                 much, and for how many more seconds
+  blocks del SOURCE              Unblock a source before its time is up
+  conntrack list                 Show the connections spliced by the SYN proxy
+  conntrack flush                Forget the connections of the SYN proxy
+  top                            Show the sources and flows sending the most,
+                                 per second, and the blocked sources
+  capture start [--path=PATH] [--filter=FILTER]
+                                 Start capturing, to the configured path and
+                                 filter unless given
+  capture stop                   Stop capturing
+  reload                         Read the configuration file again
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Table,
+    Json,
+}
+
+fn main() {
+    let mut socket = CONTROL_PATH.to_owned();
+    let mut format = Format::Table;
+    let mut options = Vec::new();
+    let mut words = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if arg == "-h" || arg == "--help" {
+            print!("{}", USAGE);
+            return;
+        } else if let Some(path) = arg.strip_prefix("--socket=") {
+            path.clone_into(&mut socket);
+        } else if let Some(name) = arg.strip_prefix("--format=") {
+            format = match name {
+                "table" => Format::Table,
+                "json" => Format::Json,
+                _ => exit(format!("Unknown format `{}`. Use `table` or `json`", name)),
+            };
+        } else if arg.starts_with("--") {
+            options.push(arg);
+        } else {
+            words.push(arg);
+        }
+    }
+
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let mut options = Options(options);
+    let request = parse_request(&words, &mut options)
+        .and_then(|request| options.finish().map(|()| request))
+        .unwrap_or_else(|error| exit(format!("{}\n\n{}", error, USAGE)));
+    let result = Client::connect(&socket)
+        .and_then(|mut client| client.request(&request))
+        .unwrap_or_else(|error| exit(error));
+
+    match format {
+        Format::Table => print_result(&request, &result),
+        Format::Json => println!("{:#}", result),
+    }
+}
+
+/// The `--name=value` options of a command, taken as they are parsed.
+struct Options(Vec<String>);
+
+impl Options {
+    fn take(&mut self, name: &str) -> Option<String> {
+        let index = self.0.iter().position(|option| {
+            option
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('='))
+        })?;
+
+        Some(self.0.remove(index)[name.len() + 1..].to_owned())
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        let index = self.0.iter().position(|option| option == name);
+        index.map(|index| self.0.remove(index)).is_some()
+    }
+
+    /// Fail on options the command did not take.
+    fn finish(self) -> Result<(), String> {
+        match self.0.first() {
+            Some(option) => Err(format!("Unknown option `{}`", option)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_request(words: &[&str], options: &mut Options) -> Result<Request, String> {
+    let request = match words {
+        ["stats"] => Request::Stats,
+        ["interfaces"] => Request::Interfaces,
+        ["sockets"] => Request::Sockets,
+        ["rules"] | ["rules", "list"] => Request::Rules,
+        ["rules", "add", name, verdict] => {
+            let port = options
+                .take("--port")
+                .map(|port| {
+                    port.parse::<u16>()
+                        .map_err(|_| format!("Invalid port `{}`", port))
+                })
+                .transpose()?;
+            let rate_limit = rate_limit(options)?;
+            // Let the types of the rule validate the values.
+            let rule = serde_json::from_value(json!({
+                "name": name,
+                "verdict": verdict,
+                "protocol": options.take("--protocol"),
+                "source": options.take("--source"),
+                "destination": options.take("--destination"),
+                "destination_port": port,
+                "rate_limit": rate_limit,
+                "syn_proxy": options.flag("--syn-proxy"),
+            }))
+            .map_err(|error| format!("Invalid rule: {}", error))?;
+            Request::AddRule {
+                rule,
+                position: options
+                    .take("--position")
+                    .map(|position| parse_position(&position))
+                    .transpose()?,
+            }
+        }
+        ["rules", "del", name] => Request::RemoveRule {
+            name: name.to_string(),
+        },
+        ["rules", "move", name, position] => Request::MoveRule {
+            name: name.to_string(),
+            position: parse_position(position)?,
+        },
+        ["blocks"] | ["blocks", "list"] => Request::Blocks,
+        ["blocks", "del", source] => Request::Unblock {
+            source: source
+                .parse()
+                .map_err(|_| format!("Invalid address `{}`", source))?,
+        },
+        ["conntrack", "list"] => Request::Connections,
+        ["conntrack", "flush"] => Request::FlushConnections,
+        ["top"] => Request::TopTalkers,
+        ["capture", "start"] => Request::StartCapture {
+            path: options.take("--path").map(Into::into),
+            filter: options.take("--filter"),
+        },
+        ["capture", "stop"] => Request::StopCapture,
+        ["reload"] => Request::Reload,
+        [] => return Err("Missing command".to_owned()),
+        words => return Err(format!("Unknown command `{}`", words.join(" "))),
+    };
+
+    Ok(request)
+}
+
+/// Collect the rate limit options of `rules add`, if there are any.
+fn rate_limit(options: &mut Options) -> Result<Option<Value>, String> {
+    let mut rate_limit = serde_json::Map::new();
+    for name in [
+        "packets",
+        "packet_burst",
+        "bytes",
+        "byte_burst",
+        "ipv4_prefix",
+        "ipv6_prefix",
+        "sources",
+        "dscp",
+    ] {
+        let option = format!("--{}", name.replace('_', "-"));
+        if let Some(value) = options.take(&option) {
+            let value: u64 = value
+                .parse()
+                .map_err(|_| format!("Invalid number `{}` of `{}`", value, option))?;
+            rate_limit.insert(name.to_owned(), value.into());
+        }
+    }
+    if let Some(exceed) = options.take("--exceed") {
+        rate_limit.insert("exceed".to_owned(), exceed.into());
+    }
+
+    Ok((!rate_limit.is_empty()).then_some(Value::Object(rate_limit)))
+}
+
+fn parse_position(position: &str) -> Result<usize, String> {
+    position
+        .parse()
+        .map_err(|_| format!("Invalid position `{}`", position))
+}
+
+/// Print the result of a request for people to read.
+fn print_result(request: &Request, result: &Value) {
+    match request {
+        Request::Stats => {
+            for name in [
+                "received",
+                "passed",
+                "dropped",
+                "marked",
+                "capturing",
+                "capture_skipped",
+            ] {
+                println!("{:15}  {}", name, cell(&result[name]));
+            }
+            println!();
+            print_table(
+                &result["workers"],
+                &[
+                    "interface",
+                    "queue_id",
+                    "received",
+                    "passed",
+                    "dropped",
+                    "marked",
+                ],
+            );
+        }
+        Request::Interfaces => print_table(
+            result,
+            &["side", "name", "index", "mac", "up", "running", "addresses"],
+        ),
+        Request::Sockets => print_table(
+            result,
+            &[
+                "interface",
+                "queue_id",
+                "ring_size",
+                "frame_size",
+                "frame_count",
+            ],
+        ),
+        Request::Rules
+        | Request::AddRule { .. }
+        | Request::RemoveRule { .. }
+        | Request::MoveRule { .. } => {
+            for name in ["default", "fragments"] {
+                println!("{:9}  {}", name, cell(&result[name]));
+            }
+            println!();
+            print_table(
+                &result["rule"],
+                &[
+                    "name",
+                    "verdict",
+                    "protocol",
+                    "source",
+                    "destination",
+                    "destination_port",
+                    "rate_limit",
+                ],
+            );
+        }
+        Request::Blocks | Request::Unblock { .. } => print_table(result, &["source", "expires"]),
+        Request::StartCapture { .. } => println!("Capturing to {}", cell(&result["path"])),
+        Request::Reload => println!("Reloaded {} rules", cell(&result["rules"])),
+        Request::Connections => print_table(result, &["client", "server", "state", "idle"]),
+        Request::FlushConnections => {
+            println!("Flushed {} connections", cell(&result["flushed"]))
+        }
+        Request::TopTalkers => {
+            print_table(&result["sources"], &["source", "packets", "bytes"]);
+            println!();
+            print_table(
+                &result["flows"],
+                &[
+                    "source",
+                    "destination",
+                    "protocol",
+                    "source_port",
+                    "destination_port",
+                    "packets",
+                    "bytes",
+                ],
+            );
+            println!();
+            print_table(&result["blocked"], &["source", "expires"]);
+        }
+        Request::StopCapture => {}
+    }
+}
+
+fn print_table(rows: &Value, columns: &[&str]) {
+    print!("{}", table(rows, columns));
+}
+
+/// Format the `columns` of a list of objects, aligned under a header.
+fn table(rows: &Value, columns: &[&str]) -> String {
+    let rows = rows.as_array().map(Vec::as_slice).unwrap_or_default();
+    let header: Vec<String> = columns.iter().map(|name| name.to_uppercase()).collect();
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| columns.iter().map(|name| cell(&row[*name])).collect())
+        .collect();
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&cells)
+                .map(|row| row[column].len())
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&cells) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_owned(),
+        // A rate limit, e.g. `100pps/24,/64:drop`.
+        Value::Object(limit) => {
+            let mut rates = Vec::new();
+            if let Some(packets) = limit.get("packets").filter(|value| !value.is_null()) {
+                rates.push(format!("{}pps", packets));
+            }
+            if let Some(bytes) = limit.get("bytes").filter(|value| !value.is_null()) {
+                rates.push(format!("{}B/s", bytes));
+            }
+            format!(
+                "{}/{},/{}:{}",
+                rates.join(","),
+                cell(&limit["ipv4_prefix"]),
+                cell(&limit["ipv6_prefix"]),
+                cell(&limit["exceed"])
+            )
+        }
+        Value::String(string) => string.clone(),
+        Value::Array(values) => values.iter().map(cell).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+fn exit(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(command: &str, options: &[&str]) -> Result<Value, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let mut options = Options(options.iter().map(|option| option.to_string()).collect());
+        let request = parse_request(&words, &mut options)?;
+        options.finish()?;
+
+        Ok(serde_json::to_value(request).unwrap())
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("rules", &[]), Ok(json!({"command": "rules"})));
+        assert_eq!(parse("rules list", &[]), Ok(json!({"command": "rules"})));
+        assert_eq!(
+            parse("rules move ssh 0", &[]),
+            Ok(json!({"command": "move-rule", "name": "ssh", "position": 0}))
+        );
+        assert_eq!(
+            parse("capture start", &["--filter=tcp"]),
+            Ok(json!({"command": "start-capture", "path": null, "filter": "tcp"}))
+        );
+        assert_eq!(parse("blocks", &[]), Ok(json!({"command": "blocks"})));
+        assert_eq!(
+            parse("blocks del 2001:db8::1", &[]),
+            Ok(json!({"command": "unblock", "source": "2001:db8::1"}))
+        );
+        assert_eq!(parse("", &[]), Err("Missing command".to_owned()));
+        assert_eq!(
+            parse("rules drop", &[]),
+            Err("Unknown command `rules drop`".to_owned())
+        );
+        assert_eq!(
+            parse("rules move ssh first", &[]),
+            Err("Invalid position `first`".to_owned())
+        );
+        assert_eq!(
+            parse("blocks del 198.51.100", &[]),
+            Err("Invalid address `198.51.100`".to_owned())
+        );
+    }
+
+    #[test]
+    fn options_not_taken_are_rejected() {
+        assert_eq!(
+            parse("stats", &["--port=22"]),
+            Err("Unknown option `--port=22`".to_owned())
+        );
+        // A prefix of an option name does not match it.
+        assert_eq!(
+            parse("capture start", &["--pathname=x"]),
+            Err("Unknown option `--pathname=x`".to_owned())
+        );
+    }
+
+    #[test]
+    fn add_rule() {
+        let request = parse(
+            "rules add web pass",
+            &[
+                "--protocol=tcp",
+                "--port=443",
+                "--packets=100",
+                "--ipv4-prefix=24",
+                "--exceed=mark",
+                "--dscp=8",
+                "--position=1",
+            ],
+        )
+        .unwrap();
+        assert_eq!(request["command"], "add-rule");
+        assert_eq!(request["position"], 1);
+        let rule = &request["rule"];
+        assert_eq!(rule["name"], "web");
+        assert_eq!(rule["destination_port"], 443);
+        assert_eq!(rule["rate_limit"]["packets"], 100);
+        assert_eq!(rule["rate_limit"]["ipv4_prefix"], 24);
+        assert_eq!(rule["rate_limit"]["dscp"], 8);
+
+        assert_eq!(
+            parse("rules add web pass", &["--port=https"]),
+            Err("Invalid port `https`".to_owned())
+        );
+        assert_eq!(
+            parse("rules add web pass", &["--packets=many"]),
+            Err("Invalid number `many` of `--packets`".to_owned())
+        );
+        let error = parse("rules add web allow", &[]).unwrap_err();
+        assert!(error.starts_with("Invalid rule: "), "{}", error);
+    }
+
+    #[test]
+    fn cells() {
+        assert_eq!(cell(&Value::Null), "-");
+        assert_eq!(cell(&json!("eth0")), "eth0");
+        assert_eq!(
+            cell(&json!(["10.0.0.1/32", "fd00::/64"])),
+            "10.0.0.1/32,fd00::/64"
+        );
+        assert_eq!(
+            cell(
+                &json!({"packets": 100, "bytes": null, "ipv4_prefix": 24, "ipv6_prefix": 64, "exceed": "drop"})
+            ),
+            "100pps/24,/64:drop"
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let rows = json!([
+            {"name": "ssh", "verdict": "pass", "destination_port": 22},
+            {"name": "everything-else", "verdict": "drop"},
+        ]);
+        assert_eq!(
+            table(&rows, &["name", "verdict", "destination_port"]),
+            "NAME             VERDICT  DESTINATION_PORT\n\
+             ssh              pass     22\n\
+             everything-else  drop     -\n"
+        );
+        assert_eq!(table(&Value::Null, &["source"]), "SOURCE\n");
+    }
+}
@@ -0,0 +1,346 @@
+//! Capturing the frames seen by the workers to a pcapng file, each annotated
+//! with the port it arrived on, the rule it matched and the verdict.
+//!
+//! A [`Capture`] is shared by all workers and started and stopped while they
+//! run. When no capture is running, recording a frame costs a relaxed load.
+//! Otherwise the workers copy the selected frames into a bounded queue and a
+//! writer thread writes them to the file, so that a slow disk skips frames
+//! instead of stalling the workers.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub use mangonel_libxdp_rs::pcap::Direction;
+use mangonel_libxdp_rs::pcap::{PcapError, PcapngWriter};
+
+use crate::policy::Verdict;
+
+/// How many frames wait for the writer thread before further ones are
+/// skipped.
+const QUEUE_LENGTH: usize = 4096;
+
+/// A frame seen by a worker and the decision taken on it.
+pub struct Frame<'a> {
+    pub interface: &'a str,
+    pub direction: Direction,
+    /// The rule that decided the verdict, if any.
+    pub rule: Option<&'a str>,
+    pub verdict: Verdict,
+    pub data: &'a [u8],
+}
+
+/// Select the frames to capture. Every field that is set has to match.
+///
+/// Parsed from space separated `key=value` terms, e.g.
+/// `interface=wan direction=in verdict=drop`. The keys are `interface`,
+/// `direction` (`in` or `out`), `rule` and `verdict` (`pass` or `drop`). An
+/// empty expression selects every frame.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFilter {
+    pub interface: Option<String>,
+    pub direction: Option<Direction>,
+    pub rule: Option<String>,
+    pub verdict: Option<Verdict>,
+}
+
+impl CaptureFilter {
+    pub fn matches(&self, frame: &Frame) -> bool {
+        self.interface
+            .as_deref()
+            .map_or(true, |interface| interface == frame.interface)
+            && self
+                .direction
+                .map_or(true, |direction| direction == frame.direction)
+            && self
+                .rule
+                .as_deref()
+                .map_or(true, |rule| Some(rule) == frame.rule)
+            && self
+                .verdict
+                .map_or(true, |verdict| verdict == frame.verdict)
+    }
+}
+
+impl FromStr for CaptureFilter {
+    type Err = CaptureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        for term in s.split_whitespace() {
+            let invalid = || CaptureError::Filter(term.to_owned());
+            let (key, value) = term.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "interface" => filter.interface = Some(value.to_owned()),
+                "direction" => {
+                    filter.direction = Some(match value {
+                        "in" => Direction::Inbound,
+                        "out" => Direction::Outbound,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "rule" => filter.rule = Some(value.to_owned()),
+                "verdict" => filter.verdict = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Capture {
+    inner: Arc<CaptureInner>,
+}
+
+#[derive(Default)]
+struct CaptureInner {
+    active: AtomicBool,
+    /// Frames skipped by the running capture because the queue was full.
+    skipped: AtomicU64,
+    session: Mutex<Option<Session>>,
+}
+
+struct Session {
+    filter: CaptureFilter,
+    sender: SyncSender<Record>,
+    writer: JoinHandle<Result<(), PcapError>>,
+}
+
+/// A selected frame, copied for the writer thread.
+struct Record {
+    interface: String,
+    direction: Direction,
+    timestamp: Duration,
+    comment: String,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn new(frame: &Frame) -> Self {
+        Self {
+            interface: frame.interface.to_owned(),
+            direction: frame.direction,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            comment: format!(
+                "port={} rule={} verdict={}",
+                frame.interface,
+                frame.rule.unwrap_or("-"),
+                frame.verdict
+            ),
+            data: frame.data.to_vec(),
+        }
+    }
+}
+
+/// Write the records until every sender is gone, then flush. An error stops
+/// the thread, which drops `receiver` and so tells the workers to stop
+/// recording.
+fn write_records(
+    mut writer: PcapngWriter<BufWriter<File>>,
+    receiver: Receiver<Record>,
+) -> Result<(), PcapError> {
+    // pcapng interface identifiers by interface name, described as frames of
+    // the interface are captured.
+    let mut interfaces: HashMap<String, u32> = HashMap::new();
+    for record in receiver {
+        let interface_id = match interfaces.get(&record.interface) {
+            Some(interface_id) => *interface_id,
+            None => {
+                let interface_id = writer.add_interface(&record.interface)?;
+                interfaces.insert(record.interface, interface_id);
+                interface_id
+            }
+        };
+        writer.write(
+            interface_id,
+            record.timestamp,
+            &record.data,
+            Some(record.direction),
+            Some(&record.comment),
+        )?;
+    }
+
+    writer.flush()
+}
+
+impl Capture {
+    /// Capture the frames selected by `filter` to a new pcapng file at
+    /// `path`, replacing the running capture, which is stopped first.
+    pub fn start(&self, path: impl AsRef<Path>, filter: CaptureFilter) -> Result<(), CaptureError> {
+        let file = File::create(path).map_err(CaptureError::Create)?;
+        let writer = PcapngWriter::new(BufWriter::new(file)).map_err(CaptureError::Write)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let writer = thread::spawn(move || write_records(writer, receiver));
+
+        let mut session = self.inner.session.lock().unwrap();
+        let previous = session.replace(Session {
+            filter,
+            sender,
+            writer,
+        });
+        self.inner.skipped.store(0, Ordering::Relaxed);
+        self.inner.active.store(true, Ordering::Relaxed);
+        drop(session);
+
+        previous.map_or(Ok(()), finish)
+    }
+
+    /// Stop the running capture and wait for the queued frames to be written.
+    /// Return the error that interrupted it, if any.
+    pub fn stop(&self) -> Result<(), CaptureError> {
+        self.inner.active.store(false, Ordering::Relaxed);
+        let session = self.inner.session.lock().unwrap().take();
+
+        session.map_or(Ok(()), finish)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    /// The frames the last capture skipped because the writer fell behind.
+    pub fn skipped(&self) -> u64 {
+        self.inner.skipped.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub fn record(&self, frame: &Frame) {
+        if self.is_active() {
+            self.record_slow(frame);
+        }
+    }
+
+    #[cold]
+    fn record_slow(&self, frame: &Frame) {
+        let session = self.inner.session.lock().unwrap();
+        let Some(session) = session.as_ref() else {
+            return;
+        };
+        if !session.filter.matches(frame) {
+            return;
+        }
+
+        match session.sender.try_send(Record::new(frame)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.inner.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The writer failed. Keep the session so that stopping it reports
+            // the error.
+            Err(TrySendError::Disconnected(_)) => {
+                self.inner.active.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn finish(session: Session) -> Result<(), CaptureError> {
+    let Session { sender, writer, .. } = session;
+    drop(sender);
+
+    match writer.join() {
+        Ok(result) => result.map_err(CaptureError::Write),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Create(std::io::Error),
+    Write(PcapError),
+    Filter(String),
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Create(error) => write!(f, "Failed to create the capture file: {}", error),
+            Self::Write(error) => write!(f, "Capture stopped: {}", error),
+            Self::Filter(term) => write!(
+                f,
+                "Invalid capture filter term `{}`. Use `interface=NAME`, `direction=in|out`, \
+                 `rule=NAME` or `verdict=pass|drop`",
+                term
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Create(error) => Some(error),
+            Self::Write(error) => Some(error),
+            Self::Filter(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mangonel_libxdp_rs::pcap::PcapReader;
+
+    use super::*;
+
+    fn frame<'a>(interface: &'a str, verdict: Verdict, data: &'a [u8]) -> Frame<'a> {
+        Frame {
+            interface,
+            direction: Direction::Inbound,
+            rule: None,
+            verdict,
+            data,
+        }
+    }
+
+    #[test]
+    fn parse_filter() {
+        let filter: CaptureFilter = "interface=wan direction=out rule=web verdict=drop"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.interface.as_deref(), Some("wan"));
+        assert_eq!(filter.direction, Some(Direction::Outbound));
+        assert_eq!(filter.rule.as_deref(), Some("web"));
+        assert_eq!(filter.verdict, Some(Verdict::Drop));
+
+        assert!("direction=up".parse::<CaptureFilter>().is_err());
+        assert!("port=wan".parse::<CaptureFilter>().is_err());
+        assert!("wan".parse::<CaptureFilter>().is_err());
+    }
+
+    #[test]
+    fn record_selected_frames() {
+        let path = std::env::temp_dir().join(format!("mangonel-capture-{}", std::process::id()));
+        let capture = Capture::default();
+        capture
+            .start(&path, "verdict=drop".parse().unwrap())
+            .unwrap();
+        capture.record(&frame("wan", Verdict::Drop, b"first"));
+        capture.record(&frame("wan", Verdict::Pass, b"passed"));
+        capture.record(&frame("lan", Verdict::Drop, b"second"));
+        capture.stop().unwrap();
+        assert!(!capture.is_active());
+        // Stopped, nothing is recorded.
+        capture.record(&frame("wan", Verdict::Drop, b"late"));
+
+        let records: Vec<_> = PcapReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .map(|record| record.unwrap().data)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, [b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(capture.skipped(), 0);
+    }
+}
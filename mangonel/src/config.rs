@@ -0,0 +1,135 @@
+//! The configuration file, read at startup and again on reload.
+//!
+//! ```toml
+//! [policy]
+//! default = "pass"
+//! fragments = "track"
+//!
+//! [policy.set.blocklist]
+//! files = ["/etc/mangonel/blocklist.txt"]
+//! prefixes = ["203.0.113.0/24", "2001:db8:bad::/48"]
+//!
+//! [[policy.rule]]
+//! name = "blocklist"
+//! verdict = "drop"
+//! source = "set:blocklist"
+//!
+//! [[policy.rule]]
+//! name = "no-telnet"
+//! verdict = "drop"
+//! protocol = "tcp"
+//! destination_port = 23
+//!
+//! [[policy.rule]]
+//! name = "dns-flood"
+//! verdict = "pass"
+//! protocol = "udp"
+//! destination_port = 53
+//! rate_limit = { packets = 1000, ipv4_prefix = 24, ipv6_prefix = 64 }
+//!
+//! [[policy.rule]]
+//! name = "web"
+//! verdict = "pass"
+//! protocol = "https"
+//! destination = "192.0.2.0/24"
+//! syn_proxy = true
+//!
+//! [heavy_hitters]
+//! block_packets = 100000
+//! block_seconds = 300
+//! exempt = ["198.51.100.0/24"]
+//! ```
+//!
+//! The files of sets are read along with the configuration.
+
+use std::{path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    heavy_hitters::HeavyHitterConfig,
+    policy::{
+        ip_set::{self, IpSetError, IpSets},
+        Policy, PolicyConfig, PolicyError, SharedPolicy,
+    },
+};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub heavy_hitters: HeavyHitterConfig,
+    /// The sets of the policy, loaded.
+    #[serde(skip)]
+    pub sets: IpSets,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        std::fs::read_to_string(path)
+            .map_err(ConfigError::Read)?
+            .parse()
+    }
+
+    /// Compile the policy and swap it into the workers. The current policy
+    /// stays in place if the configuration is invalid.
+    pub fn apply(&self, policy: &SharedPolicy) -> Result<(), ConfigError> {
+        policy.store(Policy::compile(
+            &self.policy,
+            &self.sets,
+            policy.rate_limiters(),
+        )?);
+
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config: Self = toml::from_str(s).map_err(ConfigError::Parse)?;
+        config.sets = ip_set::load_sets(&config.policy.sets)
+            .map_err(|(name, error)| ConfigError::Set { name, error })?;
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    Policy(PolicyError),
+    Set { name: String, error: IpSetError },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(error) => write!(f, "Failed to read the configuration: {}", error),
+            Self::Parse(error) => write!(f, "Invalid configuration: {}", error),
+            Self::Policy(error) => write!(f, "Invalid policy: {}", error),
+            Self::Set { name, error } => write!(f, "Invalid set `{}`: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read(error) => Some(error),
+            Self::Parse(error) => Some(error),
+            Self::Policy(error) => Some(error),
+            Self::Set { error, .. } => Some(error),
+        }
+    }
+}
+
+impl From<PolicyError> for ConfigError {
+    fn from(value: PolicyError) -> Self {
+        Self::Policy(value)
+    }
+}
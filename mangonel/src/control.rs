@@ -0,0 +1,649 @@
+//! The control socket: a Unix stream socket taking one JSON request per line
+//! and answering each with one JSON line, for inspecting and changing a
+//! running process.
+//!
+//! A request names its command in `command`, e.g.
+//! `{"command": "remove-rule", "name": "no-telnet"}`, and is answered with
+//! `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+//!
+//! [`Client`] sends requests for `mangonelctl`.
+//!
+//! Requests are served on a thread per client, for up to [`MAX_CLIENTS`]
+//! clients at once. They touch the workers only through counters and by
+//! swapping the policy, so the datapath never waits on them.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use pnet::datalink::NetworkInterface;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    capture::{Capture, CaptureError, CaptureFilter},
+    config::{Config, ConfigError},
+    fragments::FragmentTracker,
+    heavy_hitters::HeavyHitters,
+    interface::Port,
+    policy::{Policy, PolicyConfig, RuleConfig, SharedPolicy},
+    syn_proxy::SynProxy,
+    worker::WorkerStats,
+};
+
+/// Where `mangonel` serves and `mangonelctl` connects unless told otherwise.
+pub const CONTROL_PATH: &str = "/run/mangonel/control.sock";
+
+/// How many clients are served at once. Further clients are answered with
+/// an error and disconnected.
+pub const MAX_CLIENTS: usize = 16;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// The WAN and LAN interfaces and their state.
+    Interfaces,
+    /// The sockets the workers run on.
+    Sockets,
+    /// The counters of every worker and their sum.
+    Stats,
+    /// The default verdict, the fragment policy and the rules in order.
+    Rules,
+    /// Insert a rule at `position`, or append it.
+    AddRule {
+        rule: RuleConfig,
+        position: Option<usize>,
+    },
+    RemoveRule {
+        name: String,
+    },
+    /// Move a rule to `position`, counted without it.
+    MoveRule {
+        name: String,
+        position: usize,
+    },
+    /// The connections spliced by the SYN proxy.
+    Connections,
+    FlushConnections,
+    /// The sources and flows sending the most, and the sources blocked for
+    /// it.
+    TopTalkers,
+    /// The sources blocked for sending too much, and for how many more
+    /// seconds.
+    Blocks,
+    /// Unblock a source before its time is up.
+    Unblock {
+        source: IpAddr,
+    },
+    /// Start capturing, to the configured path and filter unless given.
+    StartCapture {
+        path: Option<PathBuf>,
+        filter: Option<String>,
+    },
+    StopCapture,
+    /// Read the configuration file again, discarding rules changed through
+    /// the control socket. Blocked heavy hitters stay blocked.
+    Reload,
+}
+
+/// A socket served by a worker.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueInfo {
+    pub interface: String,
+    pub queue_id: u32,
+    pub ring_size: u32,
+    pub frame_size: u32,
+    pub frame_count: u32,
+}
+
+/// The state of the process shared by the control socket, the signal
+/// handlers and the supervisor.
+#[derive(Clone)]
+pub struct Control {
+    inner: Arc<ControlInner>,
+}
+
+struct ControlInner {
+    port: Port,
+    policy: SharedPolicy,
+    config_path: PathBuf,
+    /// The configuration the current policy was compiled from.
+    config: Mutex<Config>,
+    capture: Capture,
+    capture_path: PathBuf,
+    capture_filter: CaptureFilter,
+    syn_proxy: SynProxy,
+    fragments: FragmentTracker,
+    heavy_hitters: HeavyHitters,
+    queues: Mutex<Vec<(QueueInfo, Arc<WorkerStats>)>>,
+}
+
+#[derive(Debug, Default)]
+pub struct ControlBuilder {
+    /// The configuration file read by [`Control::reload`].
+    pub config_path: PathBuf,
+    /// Where [`Control::start_capture`] captures unless told otherwise.
+    pub capture_path: PathBuf,
+    pub capture_filter: CaptureFilter,
+}
+
+impl ControlBuilder {
+    /// Create the control state of the workers following `policy`,
+    /// recording to `capture`, splicing connections in `syn_proxy`,
+    /// tracking datagrams in `fragments` and counting the top talkers in
+    /// `heavy_hitters`. Nothing is loaded until [`Control::reload`].
+    pub fn build(
+        self,
+        port: Port,
+        policy: SharedPolicy,
+        capture: Capture,
+        syn_proxy: SynProxy,
+        fragments: FragmentTracker,
+        heavy_hitters: HeavyHitters,
+    ) -> Control {
+        Control {
+            inner: Arc::new(ControlInner {
+                port,
+                policy,
+                config_path: self.config_path,
+                config: Mutex::new(Config::default()),
+                capture,
+                capture_path: self.capture_path,
+                capture_filter: self.capture_filter,
+                syn_proxy,
+                fragments,
+                heavy_hitters,
+                queues: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+impl Control {
+    pub fn port(&self) -> &Port {
+        &self.inner.port
+    }
+
+    pub fn policy(&self) -> &SharedPolicy {
+        &self.inner.policy
+    }
+
+    pub fn capture(&self) -> &Capture {
+        &self.inner.capture
+    }
+
+    pub fn syn_proxy(&self) -> &SynProxy {
+        &self.inner.syn_proxy
+    }
+
+    pub fn fragments(&self) -> &FragmentTracker {
+        &self.inner.fragments
+    }
+
+    pub fn heavy_hitters(&self) -> &HeavyHitters {
+        &self.inner.heavy_hitters
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.inner.config_path
+    }
+
+    /// Describe the sockets of the workers along with their counters.
+    pub fn set_queues(&self, queues: Vec<(QueueInfo, Arc<WorkerStats>)>) {
+        *self.inner.queues.lock().unwrap() = queues;
+    }
+
+    /// Read the configuration file and swap its policy into the workers. The
+    /// current policy stays in place on error.
+    pub fn reload(&self) -> Result<(), ControlError> {
+        let config = Config::load(&self.inner.config_path)?;
+        let mut current = self.inner.config.lock().unwrap();
+        self.inner.policy.store(
+            Policy::compile(
+                &config.policy,
+                &config.sets,
+                self.inner.policy.rate_limiters(),
+            )
+            .map_err(ConfigError::from)?,
+        );
+        self.inner
+            .heavy_hitters
+            .configure(config.heavy_hitters.clone());
+        *current = config;
+
+        Ok(())
+    }
+
+    pub fn start_capture(
+        &self,
+        path: Option<&Path>,
+        filter: Option<CaptureFilter>,
+    ) -> Result<PathBuf, ControlError> {
+        let path = path.unwrap_or(&self.inner.capture_path).to_owned();
+        let filter = filter.unwrap_or_else(|| self.inner.capture_filter.clone());
+        self.inner.capture.start(&path, filter)?;
+
+        Ok(path)
+    }
+
+    pub fn stop_capture(&self) -> Result<(), ControlError> {
+        Ok(self.inner.capture.stop()?)
+    }
+
+    /// Serve requests on a socket at `path`, replacing a stale one, until
+    /// the process exits. Only the owner may connect, since requests change
+    /// the datapath.
+    pub fn serve(&self, path: impl AsRef<Path>) -> Result<(), ControlError> {
+        let listener = bind_private(path.as_ref()).map_err(ControlError::Bind)?;
+
+        let control = self.clone();
+        let clients = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    let error = ControlError::TooManyClients.to_string();
+                    let _ = writeln!(stream, "{}", json!({ "ok": false, "error": error }));
+                    continue;
+                }
+
+                let control = control.clone();
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    control.serve_client(stream);
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    fn serve_client(&self, stream: UnixStream) {
+        let Ok(mut writer) = stream.try_clone() else {
+            return;
+        };
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match self.respond(&line) {
+                Ok(result) => json!({ "ok": true, "result": result }),
+                Err(error) => json!({ "ok": false, "error": error.to_string() }),
+            };
+            if writeln!(writer, "{}", response).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Parse and handle a request line.
+    pub fn respond(&self, line: &str) -> Result<Value, ControlError> {
+        let request = serde_json::from_str(line).map_err(ControlError::Request)?;
+
+        self.handle(request)
+    }
+
+    pub fn handle(&self, request: Request) -> Result<Value, ControlError> {
+        match request {
+            Request::Interfaces => Ok(json!([
+                interface_json("wan", &self.inner.port.wan()),
+                interface_json("lan", &self.inner.port.lan()),
+            ])),
+            Request::Sockets => {
+                let queues = self.inner.queues.lock().unwrap();
+                let queues: Vec<_> = queues.iter().map(|(queue, _)| queue).collect();
+                Ok(json!(queues))
+            }
+            Request::Stats => Ok(self.stats()),
+            Request::Rules => Ok(json!(self.inner.config.lock().unwrap().policy)),
+            Request::AddRule { rule, position } => self.edit_rules(|policy| {
+                let position = position.unwrap_or(policy.rules.len());
+                check_position(position, policy.rules.len())?;
+                policy.rules.insert(position, rule);
+                Ok(())
+            }),
+            Request::RemoveRule { name } => self.edit_rules(|policy| {
+                let index = find_rule(policy, &name)?;
+                policy.rules.remove(index);
+                Ok(())
+            }),
+            Request::MoveRule { name, position } => self.edit_rules(|policy| {
+                let index = find_rule(policy, &name)?;
+                let rule = policy.rules.remove(index);
+                if let Err(error) = check_position(position, policy.rules.len()) {
+                    policy.rules.insert(index, rule);
+                    return Err(error);
+                }
+                policy.rules.insert(position, rule);
+                Ok(())
+            }),
+            Request::Connections => Ok(json!(self.inner.syn_proxy.connections())),
+            Request::FlushConnections => Ok(json!({ "flushed": self.inner.syn_proxy.flush() })),
+            Request::TopTalkers => {
+                let report = self.inner.heavy_hitters.report();
+                Ok(json!({
+                    "sources": report.sources,
+                    "flows": report.flows,
+                    "blocked": self.blocks(),
+                }))
+            }
+            Request::Blocks => Ok(self.blocks()),
+            Request::Unblock { source } => match self.inner.heavy_hitters.unblock(source) {
+                true => Ok(self.blocks()),
+                false => Err(ControlError::NotBlocked(source)),
+            },
+            Request::StartCapture { path, filter } => {
+                let filter = filter
+                    .map(|filter| filter.parse::<CaptureFilter>())
+                    .transpose()?;
+                let path = self.start_capture(path.as_deref(), filter)?;
+                Ok(json!({ "path": path }))
+            }
+            Request::StopCapture => {
+                self.stop_capture()?;
+                Ok(Value::Null)
+            }
+            Request::Reload => {
+                self.reload()?;
+                Ok(json!({ "rules": self.inner.policy.load().rule_count() }))
+            }
+        }
+    }
+
+    fn blocks(&self) -> Value {
+        let now = Instant::now();
+        let blocks: Vec<_> = self
+            .inner
+            .heavy_hitters
+            .blocked()
+            .iter()
+            .map(|(source, until)| {
+                json!({
+                    "source": source,
+                    "expires": until.saturating_duration_since(now).as_secs(),
+                })
+            })
+            .collect();
+
+        json!(blocks)
+    }
+
+    fn stats(&self) -> Value {
+        let queues = self.inner.queues.lock().unwrap();
+        let mut total = [0; 4];
+        let workers: Vec<_> = queues
+            .iter()
+            .map(|(queue, stats)| {
+                let counters = [
+                    stats.received.load(Ordering::Relaxed),
+                    stats.passed.load(Ordering::Relaxed),
+                    stats.dropped.load(Ordering::Relaxed),
+                    stats.marked.load(Ordering::Relaxed),
+                ];
+                total
+                    .iter_mut()
+                    .zip(counters)
+                    .for_each(|(total, counter)| *total += counter);
+                json!({
+                    "interface": queue.interface,
+                    "queue_id": queue.queue_id,
+                    "received": counters[0],
+                    "passed": counters[1],
+                    "dropped": counters[2],
+                    "marked": counters[3],
+                })
+            })
+            .collect();
+
+        json!({
+            "received": total[0],
+            "passed": total[1],
+            "dropped": total[2],
+            "marked": total[3],
+            "capturing": self.inner.capture.is_active(),
+            "capture_skipped": self.inner.capture.skipped(),
+            "workers": workers,
+        })
+    }
+
+    /// Change the rules of a copy of the configuration, compile it and swap
+    /// it in. Nothing changes if any step fails. Return the new rules.
+    fn edit_rules(
+        &self,
+        edit: impl FnOnce(&mut PolicyConfig) -> Result<(), ControlError>,
+    ) -> Result<Value, ControlError> {
+        let mut config = self.inner.config.lock().unwrap();
+        let mut policy = config.policy.clone();
+        edit(&mut policy)?;
+        self.inner.policy.store(
+            Policy::compile(&policy, &config.sets, self.inner.policy.rate_limiters())
+                .map_err(ConfigError::from)?,
+        );
+        config.policy = policy;
+
+        Ok(json!(config.policy))
+    }
+}
+
+/// A connection to the control socket of a running process.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, ControlError> {
+        let writer = UnixStream::connect(path).map_err(ControlError::Connect)?;
+        let reader = BufReader::new(writer.try_clone().map_err(ControlError::Connect)?);
+
+        Ok(Self { reader, writer })
+    }
+
+    /// Send a request and wait for its result.
+    pub fn request(&mut self, request: &Request) -> Result<Value, ControlError> {
+        let line = serde_json::to_string(request).map_err(ControlError::Request)?;
+        writeln!(self.writer, "{}", line).map_err(ControlError::Connect)?;
+
+        let mut line = String::new();
+        if self
+            .reader
+            .read_line(&mut line)
+            .map_err(ControlError::Connect)?
+            == 0
+        {
+            return Err(ControlError::Connect(
+                std::io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+        let mut response: Value = serde_json::from_str(&line).map_err(ControlError::Response)?;
+        match response["ok"].as_bool() {
+            Some(true) => Ok(response["result"].take()),
+            _ => Err(ControlError::Remote(
+                response["error"].as_str().unwrap_or_default().to_owned(),
+            )),
+        }
+    }
+}
+
+fn interface_json(side: &str, interface: &NetworkInterface) -> Value {
+    json!({
+        "side": side,
+        "name": interface.name,
+        "index": interface.index,
+        "mac": interface.mac.map(|mac| mac.to_string()),
+        "addresses": interface.ips.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "up": interface.is_up(),
+        "running": interface.is_running(),
+    })
+}
+
+fn find_rule(policy: &PolicyConfig, name: &str) -> Result<usize, ControlError> {
+    policy
+        .rules
+        .iter()
+        .position(|rule| rule.name == name)
+        .ok_or_else(|| ControlError::NoSuchRule(name.to_owned()))
+}
+
+/// Bind a socket at `path` that only the owner can connect to.
+///
+/// The socket is bound in a new directory only the owner can enter, made
+/// private there and then moved into place, so that it is never reachable by
+/// others, whatever the umask.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(directory)?;
+    let private = directory.join(format!(".control.{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("control.sock");
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&private);
+
+    result
+}
+
+fn check_position(position: usize, length: usize) -> Result<(), ControlError> {
+    match position <= length {
+        true => Ok(()),
+        false => Err(ControlError::Position { position, length }),
+    }
+}
+
+#[derive(Debug)]
+pub enum ControlError {
+    Bind(std::io::Error),
+    Connect(std::io::Error),
+    Request(serde_json::Error),
+    Response(serde_json::Error),
+    /// An error the process answered with.
+    Remote(String),
+    Config(ConfigError),
+    Capture(CaptureError),
+    NoSuchRule(String),
+    NotBlocked(IpAddr),
+    Position {
+        position: usize,
+        length: usize,
+    },
+    TooManyClients,
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bind(error) => write!(f, "Failed to create the control socket: {}", error),
+            Self::Connect(error) => write!(
+                f,
+                "Failed to talk to the control socket: {}. Check that mangonel is running, or pass its socket with `--socket`",
+                error
+            ),
+            Self::Request(error) => write!(f, "Invalid request: {}", error),
+            Self::Response(error) => write!(f, "Invalid response: {}", error),
+            Self::Remote(error) => write!(f, "{}", error),
+            // The wrapped errors print their own hints.
+            Self::Config(error) => write!(f, "{}", error),
+            Self::Capture(error) => write!(f, "{}", error),
+            Self::NoSuchRule(name) => write!(
+                f,
+                "No rule is named `{}`. List the rules with the `rules` command",
+                name
+            ),
+            Self::NotBlocked(source) => write!(
+                f,
+                "{} is not blocked. List the blocked sources with the `blocks` command",
+                source
+            ),
+            Self::Position { position, length } => write!(
+                f,
+                "Position {} is past the end of the {} rules",
+                position, length
+            ),
+            Self::TooManyClients => write!(
+                f,
+                "Too many clients. At most {} are served at once",
+                MAX_CLIENTS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bind(error) | Self::Connect(error) => Some(error),
+            Self::Request(error) | Self::Response(error) => Some(error),
+            Self::Config(error) => Some(error),
+            Self::Capture(error) => Some(error),
+            Self::Remote(_)
+            | Self::NoSuchRule(_)
+            | Self::NotBlocked(_)
+            | Self::Position { .. }
+            | Self::TooManyClients => None,
+        }
+    }
+}
+
+impl From<ConfigError> for ControlError {
+    fn from(value: ConfigError) -> Self {
+        Self::Config(value)
+    }
+}
+
+impl From<CaptureError> for ControlError {
+    fn from(value: CaptureError) -> Self {
+        Self::Capture(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::*;
+
+    #[test]
+    fn bind_private_socket() {
+        let directory =
+            std::env::temp_dir().join(format!("mangonel-control-{}", std::process::id()));
+        let path = directory.join("control.sock");
+        std::fs::create_dir_all(&directory).unwrap();
+        // A stale file in the way is replaced.
+        std::fs::write(&path, b"stale").unwrap();
+
+        let _listener = bind_private(&path).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+        // Only the socket is left behind.
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
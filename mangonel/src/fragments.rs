@@ -0,0 +1,528 @@
+//! Deciding on the fragments of a datagram as on its first fragment, the
+//! only one with the transport header the policy matches.
+//!
+//! Datagrams are tracked by source, destination, protocol and
+//! identification, along with the ranges of data their fragments carried.
+//! Fragments that overlap others in part, e.g. to hide a different transport
+//! header from the policy, do not fit the datagram or arrive too many drop
+//! the rest of it. Exact duplicates, as links and retransmissions produce,
+//! pass as the fragment they repeat, unless a repeated first fragment is
+//! decided differently. Fragments arriving before the first one of their
+//! datagram wait for it in their worker.
+//!
+//! Fragments pass unchanged. Only their destination reassembles them.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    packet::{
+        headers::{Fragment, Headers},
+        PROTOCOL_TCP, PROTOCOL_UDP,
+    },
+    policy::{Decision, FragmentPolicy, Policy, Verdict},
+};
+
+const SHARD_COUNT: usize = 64;
+/// The most datagrams tracked at once.
+const DATAGRAM_COUNT: usize = 1 << 16;
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+/// How long the fragments of a datagram are awaited after the first one
+/// that arrived.
+const DATAGRAM_TIMEOUT: u64 = 5 * NANOSECONDS_PER_SECOND;
+/// How often a full shard may be searched for expired datagrams.
+const SWEEP_INTERVAL: u64 = NANOSECONDS_PER_SECOND;
+/// The most fragments of a datagram.
+const FRAGMENT_COUNT: usize = 64;
+/// The most data of a datagram, as IPv4 and IPv6 without jumbograms have
+/// 16 bits of length.
+const DATAGRAM_LENGTH: u32 = u16::MAX as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    id: u32,
+}
+
+impl Key {
+    fn new(headers: &Headers, fragment: &Fragment) -> Self {
+        Self {
+            source: headers.source,
+            destination: headers.destination,
+            protocol: headers.protocol,
+            id: fragment.id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Datagram {
+    /// The verdict and DSCP of the first fragment, once it arrived.
+    decision: Option<(Verdict, Option<u8>)>,
+    /// Set once a fragment did not fit, dropping the rest.
+    invalid: bool,
+    /// The ranges of data that arrived, which never overlap.
+    ranges: Vec<(u32, u32)>,
+    /// The length of the data, known from the last fragment.
+    length: Option<u32>,
+    /// Nanoseconds since the tracker was created.
+    created: u64,
+}
+
+impl Datagram {
+    fn new(now: u64) -> Self {
+        Self {
+            decision: None,
+            invalid: false,
+            ranges: Vec::new(),
+            length: None,
+            created: now,
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created) > DATAGRAM_TIMEOUT
+    }
+
+    fn is_complete(&self) -> bool {
+        self.length.is_some_and(|length| {
+            self.ranges
+                .iter()
+                .map(|(start, end)| end - start)
+                .sum::<u32>()
+                == length
+        })
+    }
+
+    /// Record the data of a fragment and return whether it fits, as a new
+    /// range or the same range with the same flag as before.
+    fn add(&mut self, fragment: &Fragment) -> bool {
+        let (start, end) = (fragment.offset, fragment.offset + fragment.length);
+        if self.ranges.contains(&(start, end)) {
+            return fragment.more != (self.length == Some(end));
+        }
+        let fits = fragment.length > 0
+            // Only the last fragment may end off a multiple of 8 bytes.
+            && (!fragment.more || fragment.length % 8 == 0)
+            && end <= DATAGRAM_LENGTH
+            && self.ranges.len() < FRAGMENT_COUNT
+            && self.length.map_or(true, |length| end <= length)
+            && (fragment.more
+                || (self.length.is_none() && self.ranges.iter().all(|range| range.1 <= end)))
+            && self
+                .ranges
+                .iter()
+                .all(|range| end <= range.0 || range.1 <= start);
+        if fits {
+            self.ranges.push((start, end));
+            if !fragment.more {
+                self.length = Some(end);
+            }
+        }
+
+        fits
+    }
+
+    fn decision(&self) -> Option<Decision<'static>> {
+        match (self.invalid, self.decision) {
+            (true, _) => Some(Decision::new(Verdict::Drop, None)),
+            (false, Some((verdict, dscp))) => Some(Decision {
+                dscp,
+                ..Decision::new(verdict, None)
+            }),
+            (false, None) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[repr(align(64))]
+struct Shard(Mutex<Table>);
+
+#[derive(Debug, Default)]
+struct Table {
+    datagrams: HashMap<Key, Datagram>,
+    swept: u64,
+}
+
+/// The datagrams of the fragments seen, shared by all workers and kept
+/// across policy reloads.
+#[derive(Clone)]
+pub struct FragmentTracker {
+    inner: Arc<FragmentTrackerInner>,
+}
+
+struct FragmentTrackerInner {
+    /// Keyed randomly, as sources choose their addresses and
+    /// identifications.
+    hasher: RandomState,
+    epoch: Instant,
+    /// The number of first fragments seen, for waiting fragments to only
+    /// check on their datagrams after one arrived.
+    first_fragments: AtomicU64,
+    shards: Box<[Shard]>,
+}
+
+impl Default for FragmentTracker {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(FragmentTrackerInner {
+                hasher: RandomState::new(),
+                epoch: Instant::now(),
+                first_fragments: AtomicU64::new(0),
+                shards: (0..SHARD_COUNT).map(|_| Shard(Mutex::default())).collect(),
+            }),
+        }
+    }
+}
+
+impl FragmentTracker {
+    /// Decide on a frame of `length` bytes received at `now`, with
+    /// `policy` for whole datagrams and first fragments. Return `None` for
+    /// fragments whose first fragment has yet to arrive.
+    pub fn inspect<'a>(
+        &self,
+        headers: &Headers,
+        policy: &'a Policy,
+        length: usize,
+        now: Instant,
+    ) -> Option<Decision<'a>> {
+        let Some(fragment) = headers.fragment else {
+            return Some(policy.decide(Some(headers), length, now));
+        };
+        if policy.fragments() == FragmentPolicy::Drop {
+            return Some(Decision::new(Verdict::Drop, None));
+        }
+
+        let first = (fragment.offset == 0).then(|| {
+            let transport = headers.protocol == PROTOCOL_TCP || headers.protocol == PROTOCOL_UDP;
+            let mut decision = policy.decide(Some(headers), length, now);
+            // A first fragment too short for the ports could match
+            // differently than the datagram, and the SYN proxy cannot
+            // splice fragments, which TCP avoids anyway.
+            if (transport && headers.destination_port.is_none()) || decision.syn_proxy {
+                decision.verdict = Verdict::Drop;
+                decision.syn_proxy = false;
+            }
+            decision
+        });
+
+        let key = Key::new(headers, &fragment);
+        let now = now.saturating_duration_since(self.inner.epoch).as_nanos() as u64;
+        let mut table = self.shard(&key).0.lock().unwrap();
+        // Identifications are reused once datagrams completed.
+        if table
+            .datagrams
+            .get(&key)
+            .is_some_and(|datagram| datagram.expired(now) || datagram.is_complete())
+        {
+            table.datagrams.remove(&key);
+        }
+        if !table.datagrams.contains_key(&key) && !Self::make_room(&mut table, now) {
+            return Some(Decision::new(Verdict::Drop, None));
+        }
+
+        let datagram = table
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| Datagram::new(now));
+        if !datagram.add(&fragment) {
+            datagram.invalid = true;
+        }
+        match first {
+            Some(decision) => {
+                let decided = (decision.verdict, decision.dscp);
+                if datagram
+                    .decision
+                    .is_some_and(|previous| previous != decided)
+                {
+                    datagram.invalid = true;
+                }
+                datagram.decision = Some(decided);
+                self.inner.first_fragments.fetch_add(1, Ordering::Release);
+                match datagram.invalid {
+                    true => Some(Decision {
+                        verdict: Verdict::Drop,
+                        ..decision
+                    }),
+                    false => Some(decision),
+                }
+            }
+            None => datagram.decision(),
+        }
+    }
+
+    /// Return the decision on a fragment that [`FragmentTracker::inspect`]
+    /// left waiting, or `None` while it has to wait. Fragments of datagrams
+    /// that expired are dropped.
+    pub fn decide(&self, headers: &Headers, now: Instant) -> Option<Decision<'static>> {
+        let Some(fragment) = headers.fragment else {
+            return Some(Decision::new(Verdict::Drop, None));
+        };
+        let key = Key::new(headers, &fragment);
+        let now = now.saturating_duration_since(self.inner.epoch).as_nanos() as u64;
+        let table = self.shard(&key).0.lock().unwrap();
+
+        match table.datagrams.get(&key) {
+            Some(datagram) if !datagram.expired(now) => datagram.decision(),
+            _ => Some(Decision::new(Verdict::Drop, None)),
+        }
+    }
+
+    /// Return the number of first fragments seen so far, which changes
+    /// whenever a waiting fragment may have been decided.
+    #[inline(always)]
+    pub fn first_fragments(&self) -> u64 {
+        self.inner.first_fragments.load(Ordering::Acquire)
+    }
+
+    fn shard(&self, key: &Key) -> &Shard {
+        &self.inner.shards[self.inner.hasher.hash_one(key) as usize % SHARD_COUNT]
+    }
+
+    /// Make room for a datagram, unless the shard is full of live ones.
+    fn make_room(table: &mut Table, now: u64) -> bool {
+        let capacity = DATAGRAM_COUNT / SHARD_COUNT;
+        if table.datagrams.len() >= capacity && now.saturating_sub(table.swept) >= SWEEP_INTERVAL {
+            table.swept = now;
+            table
+                .datagrams
+                .retain(|_, datagram| !datagram.expired(now) && !datagram.is_complete());
+        }
+
+        table.datagrams.len() < capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::policy::{ip_set::IpSets, rate_limit::RateLimiters};
+
+    const POLICY: &str = r#"
+        default = "drop"
+
+        [[rule]]
+        name = "dns"
+        verdict = "pass"
+        protocol = "udp"
+        destination_port = 53
+        "#;
+
+    fn compile(config: &str) -> Policy {
+        Policy::compile(
+            &toml::from_str(config).unwrap(),
+            &IpSets::new(),
+            &RateLimiters::default(),
+        )
+        .unwrap()
+    }
+
+    /// A UDP fragment of datagram `id` to `port`, with the ports if it is
+    /// the first.
+    fn fragment(id: u32, offset: u32, length: u32, more: bool, port: u16) -> Headers {
+        Headers {
+            source: IpAddr::from([198, 51, 100, 1]),
+            destination: IpAddr::from([192, 0, 2, 1]),
+            protocol: PROTOCOL_UDP,
+            source_port: (offset == 0).then_some(1024),
+            destination_port: (offset == 0).then_some(port),
+            tcp_flags: None,
+            fragment: Some(Fragment {
+                id,
+                offset,
+                length,
+                more,
+            }),
+        }
+    }
+
+    fn verdict(decision: Option<Decision>) -> Option<Verdict> {
+        decision.map(|decision| decision.verdict)
+    }
+
+    #[test]
+    fn first_fragment_decides() {
+        let (tracker, policy, now) = (FragmentTracker::default(), compile(POLICY), Instant::now());
+        let inspect = |headers| verdict(tracker.inspect(&headers, &policy, 64, now));
+
+        // The others wait for the first fragment.
+        assert_eq!(inspect(fragment(1, 16, 16, true, 53)), None);
+        assert_eq!(
+            verdict(tracker.decide(&fragment(1, 16, 16, true, 53), now)),
+            None
+        );
+        assert_eq!(tracker.first_fragments(), 0);
+
+        let decision = tracker.inspect(&fragment(1, 0, 16, true, 53), &policy, 64, now);
+        assert_eq!(decision.unwrap().rule, Some("dns"));
+        assert_eq!(verdict(decision), Some(Verdict::Pass));
+        assert_eq!(tracker.first_fragments(), 1);
+        assert_eq!(
+            verdict(tracker.decide(&fragment(1, 16, 16, true, 53), now)),
+            Some(Verdict::Pass)
+        );
+        assert_eq!(inspect(fragment(1, 32, 4, false, 53)), Some(Verdict::Pass));
+
+        assert_eq!(inspect(fragment(2, 0, 16, true, 80)), Some(Verdict::Drop));
+        assert_eq!(inspect(fragment(2, 16, 4, false, 80)), Some(Verdict::Drop));
+
+        // Whole datagrams are decided by the policy alone.
+        let whole = Headers {
+            fragment: None,
+            ..fragment(3, 0, 0, false, 53)
+        };
+        assert_eq!(inspect(whole), Some(Verdict::Pass));
+    }
+
+    #[test]
+    fn first_fragments_without_ports_are_dropped() {
+        let (tracker, now) = (FragmentTracker::default(), Instant::now());
+        let policy = compile(POLICY);
+        let short = Headers {
+            source_port: None,
+            destination_port: None,
+            ..fragment(1, 0, 8, true, 53)
+        };
+        assert_eq!(
+            verdict(tracker.inspect(&short, &policy, 64, now)),
+            Some(Verdict::Drop)
+        );
+        assert_eq!(
+            verdict(tracker.decide(&fragment(1, 8, 8, false, 53), now)),
+            Some(Verdict::Drop)
+        );
+
+        let policy = compile(
+            r#"
+            [[rule]]
+            name = "web"
+            verdict = "pass"
+            protocol = "tcp"
+            destination_port = 443
+            syn_proxy = true
+            "#,
+        );
+        let first = Headers {
+            protocol: PROTOCOL_TCP,
+            ..fragment(2, 0, 24, true, 443)
+        };
+        let decision = tracker.inspect(&first, &policy, 64, now).unwrap();
+        assert_eq!(decision.verdict, Verdict::Drop);
+        assert!(!decision.syn_proxy);
+    }
+
+    #[test]
+    fn overlapping_fragments_drop_the_datagram() {
+        let (tracker, policy, now) = (FragmentTracker::default(), compile(POLICY), Instant::now());
+        let inspect = |headers| verdict(tracker.inspect(&headers, &policy, 64, now));
+
+        assert_eq!(inspect(fragment(1, 0, 16, true, 53)), Some(Verdict::Pass));
+        assert_eq!(inspect(fragment(1, 8, 16, true, 53)), Some(Verdict::Drop));
+        assert_eq!(inspect(fragment(1, 24, 8, false, 53)), Some(Verdict::Drop));
+
+        // An overlapping first fragment is dropped as well.
+        assert_eq!(inspect(fragment(2, 8, 16, true, 53)), None);
+        assert_eq!(inspect(fragment(2, 0, 16, true, 53)), Some(Verdict::Drop));
+    }
+
+    #[test]
+    fn duplicate_fragments_pass() {
+        let (tracker, policy, now) = (FragmentTracker::default(), compile(POLICY), Instant::now());
+        let inspect = |headers| verdict(tracker.inspect(&headers, &policy, 64, now));
+
+        for _ in 0..2 {
+            assert_eq!(inspect(fragment(1, 0, 16, true, 53)), Some(Verdict::Pass));
+            assert_eq!(inspect(fragment(1, 16, 16, true, 53)), Some(Verdict::Pass));
+        }
+        assert_eq!(inspect(fragment(1, 32, 4, false, 53)), Some(Verdict::Pass));
+
+        // The same range as another but the last.
+        assert_eq!(inspect(fragment(2, 0, 16, true, 53)), Some(Verdict::Pass));
+        assert_eq!(inspect(fragment(2, 32, 16, false, 53)), Some(Verdict::Pass));
+        assert_eq!(inspect(fragment(2, 32, 16, true, 53)), Some(Verdict::Drop));
+        assert_eq!(inspect(fragment(2, 16, 16, true, 53)), Some(Verdict::Drop));
+
+        // A first fragment repeated with another transport header.
+        assert_eq!(inspect(fragment(3, 0, 16, true, 53)), Some(Verdict::Pass));
+        assert_eq!(inspect(fragment(3, 0, 16, true, 80)), Some(Verdict::Drop));
+        assert_eq!(inspect(fragment(3, 16, 4, false, 53)), Some(Verdict::Drop));
+    }
+
+    #[test]
+    fn fragments_that_do_not_fit() {
+        let mut datagram = Datagram::new(0);
+        let add = |datagram: &mut Datagram, offset, length, more| {
+            datagram.add(&Fragment {
+                id: 1,
+                offset,
+                length,
+                more,
+            })
+        };
+
+        assert!(!add(&mut datagram, 0, 0, true));
+        // Only the last fragment may end off a multiple of 8 bytes.
+        assert!(!add(&mut datagram, 0, 12, true));
+        assert!(!add(&mut datagram, DATAGRAM_LENGTH - 4, 8, false));
+        assert!(add(&mut datagram, 64, 12, false));
+        assert!(!add(&mut datagram, 80, 8, true));
+        // A second last fragment.
+        assert!(!add(&mut datagram, 32, 8, false));
+        assert!(!datagram.is_complete());
+
+        for index in 0..FRAGMENT_COUNT as u32 - 1 {
+            assert_eq!(add(&mut datagram, index * 8, 8, true), index < 8);
+        }
+        assert!(datagram.is_complete());
+
+        let mut datagram = Datagram::new(0);
+        for index in 0..FRAGMENT_COUNT as u32 {
+            assert!(add(&mut datagram, index * 8, 8, true));
+        }
+        assert!(!add(&mut datagram, FRAGMENT_COUNT as u32 * 8, 8, false));
+    }
+
+    #[test]
+    fn fragment_policy_drop() {
+        let (tracker, now) = (FragmentTracker::default(), Instant::now());
+        let policy = compile(&format!("fragments = \"drop\"\n{}", POLICY));
+
+        assert_eq!(
+            verdict(tracker.inspect(&fragment(1, 0, 16, true, 53), &policy, 64, now)),
+            Some(Verdict::Drop)
+        );
+    }
+
+    #[test]
+    fn datagrams_expire() {
+        let (tracker, policy, now) = (FragmentTracker::default(), compile(POLICY), Instant::now());
+        let later = now + Duration::from_nanos(DATAGRAM_TIMEOUT + 1);
+
+        assert_eq!(
+            verdict(tracker.inspect(&fragment(1, 0, 16, true, 53), &policy, 64, now)),
+            Some(Verdict::Pass)
+        );
+        assert_eq!(
+            verdict(tracker.decide(&fragment(1, 16, 4, false, 53), later)),
+            Some(Verdict::Drop)
+        );
+        // The identification starts a new datagram.
+        assert_eq!(
+            verdict(tracker.inspect(&fragment(1, 16, 4, false, 53), &policy, 64, later)),
+            None
+        );
+    }
+}
@@ -0,0 +1,496 @@
+//! Generating UDP traffic out of a socket for load testing.
+//!
+//! The frames of a [`Profile`] are built once into UMEM frames and their
+//! descriptors are transmitted over and over, so generating costs no copy.
+//! Rates are measured from the completion ring, i.e. from the frames the
+//! kernel is done transmitting.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use mangonel_libxdp_rs::{descriptor::Descriptor, socket::TxSocket, umem::Umem};
+use pnet::util::MacAddr;
+
+use crate::packet::{
+    builder::{Ipv4, Network, PacketBuilder, Transport, Udp},
+    PacketError,
+};
+
+const BATCH_SIZE: usize = 64;
+/// The most frames built, which bounds the combinations of flows and sizes.
+const MAX_FRAMES: usize = 4096;
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frame lengths, without the FCS, of the simple IMIX: 7 small, 4 medium and
+/// 1 large.
+pub const IMIX: [usize; 12] = [60, 60, 60, 60, 60, 60, 60, 590, 590, 590, 590, 1514];
+
+/// The headers of an Ethernet, IPv4 and UDP frame.
+pub const MIN_FRAME_LENGTH: usize = 42;
+
+/// An inclusive range of IPv4 addresses, parsed from `a.b.c.d` or
+/// `a.b.c.d-e.f.g.h`.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Range {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl Ipv4Range {
+    pub fn len(&self) -> u32 {
+        u32::from(self.end)
+            .saturating_sub(u32::from(self.start))
+            .saturating_add(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        u32::from(self.end) < u32::from(self.start)
+    }
+
+    /// Return the address `index` modulo the length of the range.
+    fn nth(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.start).wrapping_add(index % self.len()))
+    }
+}
+
+impl From<Ipv4Addr> for Ipv4Range {
+    fn from(value: Ipv4Addr) -> Self {
+        Self {
+            start: value,
+            end: value,
+        }
+    }
+}
+
+impl FromStr for Ipv4Range {
+    type Err = GeneratorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GeneratorError::Range(s.to_owned());
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let range = Self {
+            start: start.parse().map_err(|_| invalid())?,
+            end: end.parse().map_err(|_| invalid())?,
+        };
+        if range.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(range)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rate {
+    Unlimited,
+    /// Packets per second.
+    Packets(u64),
+    /// Bits of the frames, without the FCS, per second.
+    Bits(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub source_mac: MacAddr,
+    pub destination_mac: MacAddr,
+    pub source: Ipv4Range,
+    pub destination: Ipv4Range,
+    /// The source port of the first flow; the next flows count up from it.
+    pub source_port: u16,
+    pub destination_port: u16,
+    /// The number of flows, i.e. of distinct address and port tuples.
+    pub flows: u32,
+    /// Frame lengths, without the FCS, cycled through. See [`IMIX`].
+    pub sizes: Vec<usize>,
+    pub rate: Rate,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            source_mac: MacAddr::zero(),
+            destination_mac: MacAddr::broadcast(),
+            source: Ipv4Range::from(Ipv4Addr::new(198, 18, 0, 1)),
+            destination: Ipv4Range::from(Ipv4Addr::new(198, 19, 0, 1)),
+            source_port: 1024,
+            destination_port: 9,
+            flows: 1,
+            sizes: vec![60],
+            rate: Rate::Unlimited,
+        }
+    }
+}
+
+impl Profile {
+    /// Return the number of UMEM frames the profile is built into: enough for
+    /// every combination of flow and size, which has to be at most
+    /// [`MAX_FRAMES`].
+    pub fn frame_count(&self) -> Result<usize, GeneratorError> {
+        let flows = self.flows.max(1) as usize;
+        let sizes = self.sizes.len().max(1);
+
+        match (flows / gcd(flows, sizes)).checked_mul(sizes) {
+            Some(required) if required <= MAX_FRAMES => Ok(required),
+            required => Err(GeneratorError::TooManyFrames {
+                required: required.unwrap_or(usize::MAX),
+            }),
+        }
+    }
+
+    fn builder(&self, flow: u32) -> PacketBuilder {
+        PacketBuilder {
+            source: self.source_mac,
+            destination: self.destination_mac,
+            vlan: None,
+            network: Network::Ipv4(Ipv4 {
+                source: self.source.nth(flow),
+                destination: self.destination.nth(flow / self.source.len()),
+                transport: Transport::Udp(Udp {
+                    source_port: self.source_port.wrapping_add(flow as u16),
+                    destination_port: self.destination_port,
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// The frames and bytes transmitted over an interval.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub elapsed: Duration,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Stats {
+    pub fn packets_per_second(&self) -> f64 {
+        self.packets as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn bits_per_second(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets, {} bytes in {:.1}s: {:.0} pps, {:.3} Mbps",
+            self.packets,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.packets_per_second(),
+            self.bits_per_second() / 1e6
+        )
+    }
+}
+
+pub struct Generator {
+    socket: TxSocket,
+    umem: Umem,
+    /// Addresses and lengths of the frames built, transmitted in order.
+    frames: Vec<(u64, u32)>,
+    lengths: HashMap<u64, u32>,
+    next: usize,
+    rate: Rate,
+    in_flight: u32,
+    sender_buffer: VecDeque<Descriptor>,
+    completed: VecDeque<u64>,
+    start: Instant,
+    /// Frames and bytes handed to the socket.
+    sent: Stats,
+    /// Frames and bytes transmitted.
+    transmitted: Stats,
+}
+
+impl Generator {
+    /// Build the frames of the profile into the frames held by the socket for
+    /// [`TxSocket::send`], which needs at least [`Profile::frame_count`] of
+    /// them. See [`mangonel_libxdp_rs::socket::SocketBuilder::tx_frame_count`].
+    pub fn new(mut socket: TxSocket, profile: &Profile) -> Result<Self, GeneratorError> {
+        let umem = socket.umem();
+        let count = profile.frame_count()?;
+        let addresses = socket.take_frames();
+        if addresses.len() < count {
+            return Err(GeneratorError::Frames {
+                required: count,
+                available: addresses.len(),
+            });
+        }
+        let sizes = match profile.sizes.is_empty() {
+            true => &[60][..],
+            false => &profile.sizes[..],
+        };
+        if let Some(&length) = sizes.iter().find(|&&size| size < MIN_FRAME_LENGTH) {
+            return Err(GeneratorError::FrameTooSmall { length });
+        }
+
+        let payload = vec![0u8; sizes.iter().max().unwrap() - MIN_FRAME_LENGTH];
+        let headroom_size = umem.headroom_size() as u64;
+        let mut frames = Vec::with_capacity(count);
+        for (index, frame) in addresses.iter().take(count).enumerate() {
+            let flow = index as u32 % profile.flows.max(1);
+            let size = sizes[index % sizes.len()];
+            let address = frame + headroom_size;
+            let mut descriptor = Descriptor::new(address, 0, umem.clone());
+            let length = profile
+                .builder(flow)
+                .write(descriptor.get_buffer(), &payload[..size - MIN_FRAME_LENGTH])?;
+            frames.push((address, length as u32));
+        }
+        // Frames not built into remain available to `TxSocket::send`.
+        socket.add_frames(addresses.into_iter().skip(count));
+
+        Ok(Self {
+            socket,
+            lengths: frames.iter().copied().collect(),
+            frames,
+            next: 0,
+            rate: profile.rate,
+            in_flight: 0,
+            sender_buffer: VecDeque::with_capacity(BATCH_SIZE),
+            completed: VecDeque::with_capacity(umem.ring_size() as usize),
+            umem,
+            start: Instant::now(),
+            sent: Stats::default(),
+            transmitted: Stats::default(),
+        })
+    }
+
+    /// Generate until the flag is cleared or `duration` elapses, passing the
+    /// rate of every interval to `report`, and return the overall rate.
+    pub fn run(
+        &mut self,
+        flag: &AtomicBool,
+        duration: Option<Duration>,
+        mut report: impl FnMut(&Stats),
+    ) -> Stats {
+        self.start = Instant::now();
+        let mut last_report = (self.start, self.transmitted);
+        while flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if duration.is_some_and(|duration| now - self.start >= duration) {
+                break;
+            }
+
+            if self.poll(now) == 0 && !matches!(self.rate, Rate::Unlimited) {
+                std::thread::sleep(Duration::from_micros(50));
+            }
+
+            if now - last_report.0 >= REPORT_INTERVAL {
+                report(&Stats {
+                    elapsed: now - last_report.0,
+                    packets: self.transmitted.packets - last_report.1.packets,
+                    bytes: self.transmitted.bytes - last_report.1.bytes,
+                });
+                last_report = (now, self.transmitted);
+            }
+        }
+
+        // Wait briefly for the frames in flight.
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while self.in_flight > 0 && Instant::now() < deadline {
+            self.complete();
+        }
+
+        Stats {
+            elapsed: self.start.elapsed(),
+            ..self.transmitted
+        }
+    }
+
+    /// Collect the transmitted frames, then transmit the next batch allowed
+    /// by the rate and return its length.
+    pub fn poll(&mut self, now: Instant) -> u32 {
+        self.complete();
+
+        let elapsed = now - self.start;
+        let room = self.umem.ring_size().saturating_sub(self.in_flight) as usize;
+        let mut packets = 0;
+        let mut bytes = 0;
+        while self.sender_buffer.len() < std::cmp::min(BATCH_SIZE, room) {
+            let (address, length) = self.frames[self.next];
+            let allowed = match self.rate {
+                Rate::Unlimited => true,
+                Rate::Packets(rate) => {
+                    (self.sent.packets + packets + 1) as u128 * 1_000_000_000
+                        <= rate as u128 * elapsed.as_nanos()
+                }
+                Rate::Bits(rate) => {
+                    (self.sent.bytes + bytes + length as u64) as u128 * 8 * 1_000_000_000
+                        <= rate as u128 * elapsed.as_nanos()
+                }
+            };
+            if !allowed {
+                break;
+            }
+
+            self.sender_buffer
+                .push_back(Descriptor::new(address, length, self.umem.clone()));
+            self.next = (self.next + 1) % self.frames.len();
+            packets += 1;
+            bytes += length as u64;
+        }
+
+        let sent = match self.sender_buffer.len() {
+            0 => 0,
+            _ => self.socket.tx_burst(&mut self.sender_buffer),
+        };
+        let unsent = self.sender_buffer.len();
+        if unsent > 0 {
+            // The TX ring is full: take the rest of the batch back and retry
+            // it.
+            let length = self.frames.len();
+            self.next = (self.next + length - unsent % length) % length;
+            packets -= unsent as u64;
+            bytes -= self
+                .sender_buffer
+                .drain(..)
+                .map(|descriptor| descriptor.length() as u64)
+                .sum::<u64>();
+        }
+
+        self.in_flight += sent;
+        self.sent.packets += packets;
+        self.sent.bytes += bytes;
+
+        sent
+    }
+
+    fn complete(&mut self) {
+        let completed = self.umem.complete(&mut self.completed);
+        self.in_flight = self.in_flight.saturating_sub(completed);
+        for address in self.completed.drain(..) {
+            self.transmitted.packets += 1;
+            self.transmitted.bytes += self.lengths.get(&address).copied().unwrap_or(0) as u64;
+        }
+    }
+
+    /// Return the socket along with the frames built into, which it can send
+    /// into again.
+    pub fn into_socket(mut self) -> TxSocket {
+        let frame_size = self.umem.frame_size() as u64;
+        let frames = self
+            .frames
+            .iter()
+            .map(|(address, _)| address - address % frame_size);
+        self.socket.add_frames(frames);
+
+        self.socket
+    }
+}
+
+#[derive(Debug)]
+pub enum GeneratorError {
+    Frames { required: usize, available: usize },
+    TooManyFrames { required: usize },
+    FrameTooSmall { length: usize },
+    Range(String),
+    Packet(PacketError),
+}
+
+impl std::fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Frames {
+                required,
+                available,
+            } => write!(
+                f,
+                "The profile needs {} frames but the socket holds {}. Raise \
+                 `SocketBuilder::tx_frame_count`",
+                required, available
+            ),
+            Self::TooManyFrames { required } => write!(
+                f,
+                "The profile needs {} frames, one for every combination of flow and size, \
+                 but at most {} are built. Use fewer flows or sizes",
+                required, MAX_FRAMES
+            ),
+            Self::FrameTooSmall { length } => write!(
+                f,
+                "A frame of {} bytes cannot hold the headers of {} bytes",
+                length, MIN_FRAME_LENGTH
+            ),
+            Self::Range(range) => write!(
+                f,
+                "Invalid address range `{}`. Use `a.b.c.d` or `a.b.c.d-e.f.g.h`",
+                range
+            ),
+            Self::Packet(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for GeneratorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Packet(error) => Some(error),
+            Self::Frames { .. }
+            | Self::TooManyFrames { .. }
+            | Self::FrameTooSmall { .. }
+            | Self::Range(_) => None,
+        }
+    }
+}
+
+impl From<PacketError> for GeneratorError {
+    fn from(value: PacketError) -> Self {
+        Self::Packet(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_count() {
+        let profile = |flows, sizes: &[usize]| Profile {
+            flows,
+            sizes: sizes.to_vec(),
+            ..Default::default()
+        };
+
+        assert_eq!(profile(1, &[60]).frame_count().unwrap(), 1);
+        assert_eq!(profile(0, &[]).frame_count().unwrap(), 1);
+        // Every combination of the 4 flows and 6 sizes comes round in 12
+        // frames.
+        assert_eq!(profile(4, &[60; 6]).frame_count().unwrap(), 12);
+        assert_eq!(profile(1000, &IMIX).frame_count().unwrap(), 3000);
+        assert!(matches!(
+            profile(4097, &[60]).frame_count(),
+            Err(GeneratorError::TooManyFrames { required: 4097 })
+        ));
+        assert!(profile(u32::MAX, &IMIX).frame_count().is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let range: Ipv4Range = "10.0.0.254-10.0.1.1".parse().unwrap();
+        assert_eq!(range.len(), 4);
+        assert_eq!(range.nth(1), Ipv4Addr::new(10, 0, 0, 255));
+        assert_eq!(range.nth(5), Ipv4Addr::new(10, 0, 0, 255));
+
+        let range: Ipv4Range = "192.0.2.1".parse().unwrap();
+        assert_eq!(range.len(), 1);
+        assert_eq!(range.nth(7), Ipv4Addr::new(192, 0, 2, 1));
+
+        assert!("10.0.0.2-10.0.0.1".parse::<Ipv4Range>().is_err());
+        assert!("10.0.0.1-".parse::<Ipv4Range>().is_err());
+    }
+}
@@ -0,0 +1,763 @@
+//! Finding the sources and flows sending the most, in memory that does not
+//! grow with their number.
+//!
+//! Every worker counts the frames it receives in count-min sketches of its
+//! own, one by source address and one by flow, and keeps the addresses and
+//! flows with the highest estimates as candidates. The counts belong to a
+//! generation, which [`HeavyHitters::collect`] ends once per [`INTERVAL`].
+//! When a worker sees a new generation, busy or idle, it adds its sketches
+//! and candidates to those of the generation that ended, shared by all
+//! workers. The next collection ranks them into the top talkers, so that
+//! every generation is ranked over exactly the time it lasted.
+//!
+//! Sources over the configured rates are blocked by the workers themselves,
+//! in a set they pick up with each generation, without touching the policy.
+
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashSet},
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use pnet::ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::headers::Headers;
+
+/// How long a generation of counts lasts.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+/// The number of sources and flows ranked.
+pub const TOP_COUNT: usize = 16;
+/// The rule named in the decisions on frames from blocked sources.
+pub const BLOCK_RULE: &str = "heavy-hitter";
+/// Rows of a sketch, each with its own hash function.
+const DEPTH: usize = 4;
+/// Counters of a row. A power of two.
+const WIDTH: usize = 1024;
+
+/// Detecting and blocking heavy hitters as configured, the
+/// `[heavy_hitters]` table.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeavyHitterConfig {
+    /// Block sources sending more packets per second.
+    pub block_packets: Option<u64>,
+    /// Block sources sending more bytes per second.
+    pub block_bytes: Option<u64>,
+    /// How long a source stays blocked after it was last over a rate.
+    #[serde(default = "default_block_seconds")]
+    pub block_seconds: u64,
+    /// Networks that are never blocked, e.g. of monitoring or backups.
+    #[serde(default)]
+    pub exempt: Vec<IpNetwork>,
+}
+
+fn default_block_seconds() -> u64 {
+    60
+}
+
+impl Default for HeavyHitterConfig {
+    fn default() -> Self {
+        Self {
+            block_packets: None,
+            block_bytes: None,
+            block_seconds: default_block_seconds(),
+            exempt: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Count {
+    packets: u64,
+    bytes: u64,
+}
+
+/// A count-min sketch of packets and bytes.
+#[derive(Debug, Clone)]
+struct Sketch {
+    counts: Box<[Count]>,
+}
+
+impl Sketch {
+    fn new() -> Self {
+        Self {
+            counts: vec![Count::default(); DEPTH * WIDTH].into_boxed_slice(),
+        }
+    }
+
+    /// Return the counter of each row for a hash, derived from its halves.
+    #[inline(always)]
+    fn indices(hash: u64) -> impl Iterator<Item = usize> {
+        let (low, high) = (hash as usize, (hash >> 32) as usize | 1);
+
+        (0..DEPTH).map(move |row| row * WIDTH + (low.wrapping_add(row * high) & (WIDTH - 1)))
+    }
+
+    /// Count a frame and return the new estimate of its key.
+    #[inline(always)]
+    fn add(&mut self, hash: u64, bytes: u64) -> Count {
+        let mut estimate = Count {
+            packets: u64::MAX,
+            bytes: u64::MAX,
+        };
+        for index in Self::indices(hash) {
+            let count = &mut self.counts[index];
+            count.packets += 1;
+            count.bytes += bytes;
+            estimate.packets = estimate.packets.min(count.packets);
+            estimate.bytes = estimate.bytes.min(count.bytes);
+        }
+
+        estimate
+    }
+
+    fn estimate(&self, hash: u64) -> Count {
+        Self::indices(hash).fold(
+            Count {
+                packets: u64::MAX,
+                bytes: u64::MAX,
+            },
+            |estimate, index| Count {
+                packets: estimate.packets.min(self.counts[index].packets),
+                bytes: estimate.bytes.min(self.counts[index].bytes),
+            },
+        )
+    }
+
+    /// Add the counts of a sketch with the same hash functions and clear
+    /// it.
+    fn drain_into(&mut self, other: &mut Self) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter_mut()) {
+            other.packets += count.packets;
+            other.bytes += count.bytes;
+            *count = Count::default();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counts.fill(Count::default());
+    }
+}
+
+/// The keys with the most packets seen by a worker, by their estimates.
+#[derive(Debug)]
+struct TopK<K> {
+    entries: Vec<(K, u64)>,
+}
+
+impl<K: Copy + Eq> TopK<K> {
+    fn new() -> Self {
+        Self {
+            entries: Vec::with_capacity(TOP_COUNT),
+        }
+    }
+
+    #[inline(always)]
+    fn offer(&mut self, key: K, packets: u64) {
+        if let Some(entry) = self.entries.iter_mut().find(|(other, _)| *other == key) {
+            entry.1 = packets;
+        } else if self.entries.len() < TOP_COUNT {
+            self.entries.push((key, packets));
+        } else {
+            let smallest = self
+                .entries
+                .iter_mut()
+                .min_by_key(|(_, packets)| *packets)
+                .unwrap();
+            if packets > smallest.1 {
+                *smallest = (key, packets);
+            }
+        }
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = K> + '_ {
+        self.entries.drain(..).map(|(key, _)| key)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// The traffic between two endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct Flow {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: u8,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+}
+
+impl From<&Headers> for Flow {
+    fn from(headers: &Headers) -> Self {
+        Self {
+            source: headers.source,
+            destination: headers.destination,
+            protocol: headers.protocol,
+            source_port: headers.source_port,
+            destination_port: headers.destination_port,
+        }
+    }
+}
+
+/// A source or flow among the top talkers of an interval, with its rates.
+#[derive(Debug, Clone, Serialize)]
+pub struct Talker<K> {
+    #[serde(flatten)]
+    pub key: K,
+    /// Packets per second.
+    pub packets: u64,
+    /// Bytes per second.
+    pub bytes: u64,
+}
+
+/// A source address, as a [`Talker`] key.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Source {
+    pub source: IpAddr,
+}
+
+/// The top talkers of the last interval.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub sources: Vec<Talker<Source>>,
+    pub flows: Vec<Talker<Flow>>,
+}
+
+/// The counts of all workers in a generation.
+#[derive(Debug)]
+struct Interval {
+    generation: u64,
+    /// When the generation started and ended, the same until it did.
+    started: Instant,
+    ended: Instant,
+    sources: Sketch,
+    flows: Sketch,
+    source_candidates: HashSet<IpAddr>,
+    flow_candidates: HashSet<Flow>,
+}
+
+impl Interval {
+    fn new(generation: u64, started: Instant) -> Self {
+        Self {
+            generation,
+            started,
+            ended: started,
+            sources: Sketch::new(),
+            flows: Sketch::new(),
+            source_candidates: HashSet::new(),
+            flow_candidates: HashSet::new(),
+        }
+    }
+}
+
+/// The sources blocked, until when, and the set of them the workers check.
+#[derive(Debug, Default)]
+struct Blocks {
+    until: BTreeMap<IpAddr, Instant>,
+    sources: Arc<HashSet<IpAddr>>,
+}
+
+/// The counts of the workers, the top talkers found in them and the sources
+/// blocked for it.
+#[derive(Clone)]
+pub struct HeavyHitters {
+    inner: Arc<HeavyHittersInner>,
+}
+
+struct HeavyHittersInner {
+    /// Shared by the sketches of all workers, so that they can be added.
+    /// Keyed randomly, as sources choose their addresses.
+    hasher: RandomState,
+    config: Mutex<HeavyHitterConfig>,
+    /// Incremented by every collection. Workers merge their counts when they
+    /// see it change.
+    generation: AtomicU64,
+    /// The counts of the current generation and of the one before, by
+    /// generation modulo 2.
+    intervals: [Mutex<Interval>; 2],
+    /// Locked before `intervals`.
+    blocks: Mutex<Blocks>,
+    report: Mutex<Report>,
+}
+
+impl Default for HeavyHitters {
+    fn default() -> Self {
+        let now = Instant::now();
+
+        Self {
+            inner: Arc::new(HeavyHittersInner {
+                hasher: RandomState::new(),
+                config: Mutex::default(),
+                generation: AtomicU64::new(0),
+                intervals: [
+                    Mutex::new(Interval::new(0, now)),
+                    Mutex::new(Interval::new(1, now)),
+                ],
+                blocks: Mutex::default(),
+                report: Mutex::default(),
+            }),
+        }
+    }
+}
+
+impl HeavyHitters {
+    /// Apply the rates and exemptions from the next collection on.
+    pub fn configure(&self, config: HeavyHitterConfig) {
+        *self.inner.config.lock().unwrap() = config;
+    }
+
+    pub fn config(&self) -> HeavyHitterConfig {
+        self.inner.config.lock().unwrap().clone()
+    }
+
+    /// Create the counter of a worker.
+    pub fn counter(&self) -> Counter {
+        let blocks = self.inner.blocks.lock().unwrap();
+
+        Counter {
+            shared: self.clone(),
+            sources: Sketch::new(),
+            flows: Sketch::new(),
+            top_sources: TopK::new(),
+            top_flows: TopK::new(),
+            generation: self.inner.generation.load(Ordering::Acquire),
+            blocked: blocks.sources.clone(),
+        }
+    }
+
+    /// End the current generation at `now` and rank the one before, whose
+    /// counts the workers merged since. Block the sources over the
+    /// configured rates for the configured time from `now`, or longer if
+    /// they already are, and unblock the sources whose time is up. Return
+    /// the sources newly blocked.
+    pub fn collect(&self, now: Instant) -> Vec<IpAddr> {
+        let config = self.config();
+        let mut blocks = self.inner.blocks.lock().unwrap();
+        let generation = self.inner.generation.load(Ordering::Acquire);
+        self.inner.intervals[(generation % 2) as usize]
+            .lock()
+            .unwrap()
+            .ended = now;
+        let interval = std::mem::replace(
+            &mut *self.inner.intervals[((generation + 1) % 2) as usize]
+                .lock()
+                .unwrap(),
+            Interval::new(generation + 1, now),
+        );
+
+        let (sources, flows) = self.rank(&interval);
+        let heavy: Vec<_> = sources
+            .iter()
+            .filter(|talker| {
+                config
+                    .block_packets
+                    .is_some_and(|limit| talker.packets > limit)
+                    || config.block_bytes.is_some_and(|limit| talker.bytes > limit)
+            })
+            .map(|talker| talker.key.source)
+            .filter(|source| {
+                !config
+                    .exempt
+                    .iter()
+                    .any(|network| network.contains(*source))
+            })
+            .collect();
+        *self.inner.report.lock().unwrap() = Report { sources, flows };
+
+        let length = blocks.until.len();
+        blocks.until.retain(|_, until| *until > now);
+        let duration = Duration::from_secs(config.block_seconds);
+        let blocked: Vec<_> = heavy
+            .into_iter()
+            .filter(|source| blocks.until.insert(*source, now + duration).is_none())
+            .collect();
+        if !blocked.is_empty() || blocks.until.len() != length {
+            blocks.sources = Arc::new(blocks.until.keys().copied().collect());
+        }
+        // After the blocks, so that workers seeing the new generation pick
+        // them up.
+        self.inner
+            .generation
+            .store(generation + 1, Ordering::Release);
+
+        blocked
+    }
+
+    /// Return the top sources and flows of an interval with their rates.
+    fn rank(&self, interval: &Interval) -> (Vec<Talker<Source>>, Vec<Talker<Flow>>) {
+        let hasher = &self.inner.hasher;
+        let seconds = (interval.ended - interval.started)
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let per_second = |count: Count| {
+            (
+                (count.packets as f64 / seconds) as u64,
+                (count.bytes as f64 / seconds) as u64,
+            )
+        };
+
+        let mut sources: Vec<_> = interval
+            .source_candidates
+            .iter()
+            .map(|source| {
+                let (packets, bytes) =
+                    per_second(interval.sources.estimate(hasher.hash_one(source)));
+                Talker {
+                    key: Source { source: *source },
+                    packets,
+                    bytes,
+                }
+            })
+            .collect();
+        let mut flows: Vec<_> = interval
+            .flow_candidates
+            .iter()
+            .map(|flow| {
+                let (packets, bytes) = per_second(interval.flows.estimate(hasher.hash_one(flow)));
+                Talker {
+                    key: *flow,
+                    packets,
+                    bytes,
+                }
+            })
+            .collect();
+        sources.sort_unstable_by_key(|talker| std::cmp::Reverse(talker.packets));
+        sources.truncate(TOP_COUNT);
+        flows.sort_unstable_by_key(|talker| std::cmp::Reverse(talker.packets));
+        flows.truncate(TOP_COUNT);
+
+        (sources, flows)
+    }
+
+    /// Return the top talkers of the last generation ranked.
+    pub fn report(&self) -> Report {
+        self.inner.report.lock().unwrap().clone()
+    }
+
+    /// Return the sources blocked and until when.
+    pub fn blocked(&self) -> BTreeMap<IpAddr, Instant> {
+        self.inner.blocks.lock().unwrap().until.clone()
+    }
+
+    /// Unblock a source before its time is up and return whether it was
+    /// blocked. The workers pick it up with the next generation, and it is
+    /// blocked again if it is still over a rate.
+    pub fn unblock(&self, source: IpAddr) -> bool {
+        let mut blocks = self.inner.blocks.lock().unwrap();
+        if blocks.until.remove(&source).is_none() {
+            return false;
+        }
+        blocks.sources = Arc::new(blocks.until.keys().copied().collect());
+
+        true
+    }
+}
+
+/// The counts of a worker, merged into its [`HeavyHitters`] once per
+/// generation.
+pub struct Counter {
+    shared: HeavyHitters,
+    sources: Sketch,
+    flows: Sketch,
+    top_sources: TopK<IpAddr>,
+    top_flows: TopK<Flow>,
+    /// The generation the counts belong to.
+    generation: u64,
+    /// The sources blocked as of `generation`.
+    blocked: Arc<HashSet<IpAddr>>,
+}
+
+impl Counter {
+    /// Count a received frame of `length` bytes.
+    #[inline(always)]
+    pub fn count(&mut self, headers: &Headers, length: usize) {
+        let hasher = &self.shared.inner.hasher;
+        let flow = Flow::from(headers);
+
+        let estimate = self
+            .sources
+            .add(hasher.hash_one(headers.source), length as u64);
+        self.top_sources.offer(headers.source, estimate.packets);
+        let estimate = self.flows.add(hasher.hash_one(flow), length as u64);
+        self.top_flows.offer(flow, estimate.packets);
+    }
+
+    /// Whether frames from `source` are dropped for sending too much.
+    #[inline(always)]
+    pub fn is_blocked(&self, source: IpAddr) -> bool {
+        !self.blocked.is_empty() && self.blocked.contains(&source)
+    }
+
+    /// Merge the counts into their generation and pick up the blocked
+    /// sources if a new generation started. Costs an acquire load unless one
+    /// did.
+    #[inline(always)]
+    pub fn follow(&mut self) {
+        let generation = self.shared.inner.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            self.merge(generation);
+        }
+    }
+
+    #[cold]
+    fn merge(&mut self, generation: u64) {
+        self.blocked = self.shared.inner.blocks.lock().unwrap().sources.clone();
+        let mut interval = self.shared.inner.intervals[(self.generation % 2) as usize]
+            .lock()
+            .unwrap();
+        let current = interval.generation == self.generation;
+        let Interval {
+            sources,
+            flows,
+            source_candidates,
+            flow_candidates,
+            ..
+        } = &mut *interval;
+
+        // Counts of a generation already ranked, from a worker that did not
+        // follow for longer than an interval, are dropped.
+        match current {
+            true => {
+                self.sources.drain_into(sources);
+                self.flows.drain_into(flows);
+                source_candidates.extend(self.top_sources.drain());
+                flow_candidates.extend(self.top_flows.drain());
+            }
+            false => {
+                self.sources.clear();
+                self.flows.clear();
+                self.top_sources.clear();
+                self.top_flows.clear();
+            }
+        }
+        self.generation = generation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(source: [u8; 4]) -> Headers {
+        Headers {
+            source: IpAddr::from(source),
+            destination: IpAddr::from([192, 0, 2, 1]),
+            protocol: 17,
+            source_port: Some(1024),
+            destination_port: Some(53),
+            tcp_flags: None,
+            fragment: None,
+        }
+    }
+
+    /// Count `count` frames of 100 bytes from `source`.
+    fn count(counter: &mut Counter, source: [u8; 4], count: usize) {
+        (0..count).for_each(|_| counter.count(&headers(source), 100));
+    }
+
+    #[test]
+    fn sketch_estimates() {
+        let hasher = RandomState::new();
+        let mut sketch = Sketch::new();
+        for key in 0..2000u32 {
+            for _ in 0..key % 5 + 1 {
+                sketch.add(hasher.hash_one(key), 10);
+            }
+        }
+
+        // Estimates never fall below the counts.
+        for key in 0..2000u32 {
+            let estimate = sketch.estimate(hasher.hash_one(key));
+            assert!(estimate.packets > (key % 5) as u64);
+            assert_eq!(estimate.bytes, estimate.packets * 10);
+        }
+
+        let mut other = Sketch::new();
+        sketch.drain_into(&mut other);
+        sketch.drain_into(&mut other);
+        assert_eq!(sketch.estimate(hasher.hash_one(7u32)), Count::default());
+        assert!(other.estimate(hasher.hash_one(7u32)).packets >= 3);
+
+        other.clear();
+        assert!(other.counts.iter().all(|count| *count == Count::default()));
+    }
+
+    #[test]
+    fn sketch_is_exact_for_few_keys() {
+        let hasher = RandomState::new();
+        let mut sketch = Sketch::new();
+        for key in 0..8u32 {
+            for _ in 0..=key {
+                sketch.add(hasher.hash_one(key), 100);
+            }
+        }
+
+        for key in 0..8u32 {
+            assert_eq!(
+                sketch.estimate(hasher.hash_one(key)),
+                Count {
+                    packets: key as u64 + 1,
+                    bytes: (key as u64 + 1) * 100,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn top_k() {
+        let mut top = TopK::new();
+        for key in 0..TOP_COUNT as u64 * 2 {
+            top.offer(key, key);
+        }
+        // Smaller than every entry.
+        top.offer(100, 1);
+        // Raises an entry rather than adding another.
+        top.offer(TOP_COUNT as u64 * 2 - 1, 1000);
+        assert_eq!(top.entries.len(), TOP_COUNT);
+
+        let mut keys: Vec<_> = top.drain().collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            (TOP_COUNT as u64..TOP_COUNT as u64 * 2).collect::<Vec<_>>()
+        );
+        assert!(top.entries.is_empty());
+    }
+
+    #[test]
+    fn generations() {
+        let heavy_hitters = HeavyHitters::default();
+        let mut counter = heavy_hitters.counter();
+        let start = Instant::now();
+
+        heavy_hitters.collect(start);
+        counter.follow();
+        count(&mut counter, [198, 51, 100, 1], 300);
+        count(&mut counter, [198, 51, 100, 2], 100);
+        // The counts of a generation are only merged once it ended.
+        counter.follow();
+        heavy_hitters.collect(start + INTERVAL);
+        assert!(heavy_hitters.report().sources.is_empty());
+
+        counter.follow();
+        heavy_hitters.collect(start + INTERVAL * 2);
+        let report = heavy_hitters.report();
+        let sources: Vec<_> = report
+            .sources
+            .iter()
+            .map(|talker| (talker.key.source, talker.packets, talker.bytes))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                (IpAddr::from([198, 51, 100, 1]), 300, 30000),
+                (IpAddr::from([198, 51, 100, 2]), 100, 10000),
+            ]
+        );
+        assert_eq!(report.flows.len(), 2);
+        assert_eq!(report.flows[0].key, Flow::from(&headers([198, 51, 100, 1])));
+
+        // An idle worker follows as well, leaving nothing to rank.
+        counter.follow();
+        heavy_hitters.collect(start + INTERVAL * 3);
+        assert!(heavy_hitters.report().sources.is_empty());
+    }
+
+    #[test]
+    fn stale_counts_are_dropped() {
+        let heavy_hitters = HeavyHitters::default();
+        let mut counter = heavy_hitters.counter();
+        let start = Instant::now();
+
+        count(&mut counter, [198, 51, 100, 1], 10);
+        heavy_hitters.collect(start);
+        heavy_hitters.collect(start + INTERVAL);
+        // Merged into a generation already ranked.
+        counter.follow();
+        heavy_hitters.collect(start + INTERVAL * 2);
+        heavy_hitters.collect(start + INTERVAL * 3);
+
+        assert!(heavy_hitters.report().sources.is_empty());
+    }
+
+    #[test]
+    fn blocking() {
+        let heavy_hitters = HeavyHitters::default();
+        heavy_hitters.configure(HeavyHitterConfig {
+            block_packets: Some(50),
+            block_seconds: 10,
+            exempt: vec!["198.51.100.128/25".parse().unwrap()],
+            ..Default::default()
+        });
+        let mut counter = heavy_hitters.counter();
+        let start = Instant::now();
+
+        heavy_hitters.collect(start);
+        counter.follow();
+        count(&mut counter, [198, 51, 100, 1], 100);
+        count(&mut counter, [198, 51, 100, 2], 10);
+        count(&mut counter, [198, 51, 100, 200], 100);
+        heavy_hitters.collect(start + INTERVAL);
+        counter.follow();
+        assert_eq!(
+            heavy_hitters.collect(start + INTERVAL * 2),
+            [IpAddr::from([198, 51, 100, 1])]
+        );
+        assert!(!counter.is_blocked(IpAddr::from([198, 51, 100, 1])));
+
+        // Picked up with the next generation.
+        counter.follow();
+        assert!(counter.is_blocked(IpAddr::from([198, 51, 100, 1])));
+        assert!(!counter.is_blocked(IpAddr::from([198, 51, 100, 2])));
+        assert!(!counter.is_blocked(IpAddr::from([198, 51, 100, 200])));
+        assert_eq!(
+            heavy_hitters.blocked().into_iter().collect::<Vec<_>>(),
+            [(IpAddr::from([198, 51, 100, 1]), start + INTERVAL * 12)]
+        );
+
+        assert!(heavy_hitters.collect(start + INTERVAL * 12).is_empty());
+        counter.follow();
+        assert!(!counter.is_blocked(IpAddr::from([198, 51, 100, 1])));
+        assert!(heavy_hitters.blocked().is_empty());
+    }
+
+    #[test]
+    fn unblocking() {
+        let heavy_hitters = HeavyHitters::default();
+        heavy_hitters.configure(HeavyHitterConfig {
+            block_packets: Some(50),
+            block_seconds: 10,
+            ..Default::default()
+        });
+        let mut counter = heavy_hitters.counter();
+        let start = Instant::now();
+        let source = IpAddr::from([198, 51, 100, 1]);
+
+        heavy_hitters.collect(start);
+        counter.follow();
+        count(&mut counter, [198, 51, 100, 1], 100);
+        heavy_hitters.collect(start + INTERVAL);
+        counter.follow();
+        heavy_hitters.collect(start + INTERVAL * 2);
+        counter.follow();
+        assert!(counter.is_blocked(source));
+
+        assert!(heavy_hitters.unblock(source));
+        assert!(!heavy_hitters.unblock(source));
+        assert!(heavy_hitters.blocked().is_empty());
+        // Picked up with the next generation, which does not block it again
+        // as it sent nothing meanwhile.
+        assert!(counter.is_blocked(source));
+        assert!(heavy_hitters.collect(start + INTERVAL * 3).is_empty());
+        counter.follow();
+        assert!(!counter.is_blocked(source));
+    }
+}
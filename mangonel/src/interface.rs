@@ -1,14 +1,18 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use pnet::datalink::{self, NetworkInterface};
 
+use crate::netlink::{Event, Link};
+
+/// Both ends of the port. Their state follows the events of
+/// [`crate::netlink::Monitor`] applied with [`Port::apply`].
 pub struct Port {
-    inner: Arc<PortInner>,
+    inner: Arc<RwLock<PortInner>>,
 }
 
 struct PortInner {
-    wan: NetworkInterface,
-    lan: NetworkInterface,
+    wan: State,
+    lan: State,
 }
 
 impl Clone for Port {
@@ -24,33 +28,224 @@ impl Port {
         wan_interface_name: impl AsRef<str>,
         lan_interface_name: impl AsRef<str>,
     ) -> Result<Self, NetworkInterfaceError> {
-        let wan = datalink::interfaces()
-            .into_iter()
-            .find(|interface| interface.name == wan_interface_name.as_ref())
-            .ok_or(NetworkInterfaceError::DeviceDoesNotExist(
-                wan_interface_name.as_ref().to_owned(),
-            ))?;
-
-        let lan = datalink::interfaces()
-            .into_iter()
-            .find(|interface| interface.name == lan_interface_name.as_ref())
-            .ok_or(NetworkInterfaceError::DeviceDoesNotExist(
-                wan_interface_name.as_ref().to_owned(),
-            ))?;
+        let interfaces = datalink::interfaces();
+        let find = |name: &str| {
+            interfaces
+                .iter()
+                .find(|interface| interface.name == name)
+                .cloned()
+                .ok_or_else(|| NetworkInterfaceError::DeviceDoesNotExist(name.to_owned()))
+        };
 
+        let wan = State::new(
+            wan_interface_name.as_ref(),
+            find(wan_interface_name.as_ref())?,
+        );
+        let lan = State::new(
+            lan_interface_name.as_ref(),
+            find(lan_interface_name.as_ref())?,
+        );
         let inner = PortInner { wan, lan };
 
         Ok(Self {
-            inner: Arc::new(inner),
+            inner: Arc::new(RwLock::new(inner)),
         })
     }
 
-    pub fn wan(&self) -> &NetworkInterface {
-        &self.inner.wan
+    pub fn wan(&self) -> NetworkInterface {
+        self.inner.read().unwrap().wan.interface.clone()
+    }
+
+    pub fn lan(&self) -> NetworkInterface {
+        self.inner.read().unwrap().lan.interface.clone()
+    }
+
+    /// Update the state of the interfaces and return how they changed. An
+    /// [`Event::Overrun`] reads the state of every interface again.
+    pub fn apply(&self, event: &Event) -> Vec<(Side, Change)> {
+        let interfaces = match event {
+            Event::Overrun => Some(datalink::interfaces()),
+            _ => None,
+        };
+
+        let mut inner = self.inner.write().unwrap();
+        let PortInner { wan, lan } = &mut *inner;
+        [(Side::Wan, wan), (Side::Lan, lan)]
+            .into_iter()
+            .filter_map(|(side, state)| {
+                let change = match &interfaces {
+                    Some(interfaces) => state.refresh(interfaces),
+                    None => state.apply(event),
+                };
+                change.map(|change| (side, change))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Wan,
+    Lan,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Wan => write!(f, "WAN"),
+            Self::Lan => write!(f, "LAN"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The link came up, or an interface with the configured name appeared
+    /// after the previous one was removed.
+    Up,
+    /// The link went down or was removed.
+    Down,
+    /// The XDP program was detached from the link.
+    XdpDetached,
+    Renamed {
+        name: String,
+    },
+    Addresses,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Up => write!(f, "came up"),
+            Self::Down => write!(f, "went down"),
+            Self::XdpDetached => write!(f, "lost its XDP program"),
+            Self::Renamed { name } => write!(f, "was renamed to {}", name),
+            Self::Addresses => write!(f, "changed addresses"),
+        }
+    }
+}
+
+/// An end of the port, followed by interface index so that renames keep it.
+struct State {
+    /// The name the interface was configured with, used to find it again
+    /// after it was removed and created anew.
+    configured_name: String,
+    interface: NetworkInterface,
+    present: bool,
+    xdp_attached: Option<bool>,
+}
+
+impl State {
+    fn new(configured_name: &str, interface: NetworkInterface) -> Self {
+        Self {
+            configured_name: configured_name.to_owned(),
+            interface,
+            present: true,
+            xdp_attached: None,
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        let flags = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        self.present && self.interface.flags & flags == flags
+    }
+
+    fn apply(&mut self, event: &Event) -> Option<Change> {
+        match event {
+            Event::Link(link) => self.update(link),
+            Event::LinkRemoved { index, .. } if self.present && *index == self.interface.index => {
+                let was_up = self.is_up();
+                self.present = false;
+                self.xdp_attached = None;
+                was_up.then_some(Change::Down)
+            }
+            Event::AddressAdded { index, network } if *index == self.interface.index => {
+                if self.interface.ips.contains(network) {
+                    return None;
+                }
+                self.interface.ips.push(*network);
+                Some(Change::Addresses)
+            }
+            Event::AddressRemoved { index, network } if *index == self.interface.index => {
+                let length = self.interface.ips.len();
+                self.interface.ips.retain(|ip| ip != network);
+                (self.interface.ips.len() != length).then_some(Change::Addresses)
+            }
+            _ => None,
+        }
     }
 
-    pub fn lan(&self) -> &NetworkInterface {
-        &self.inner.lan
+    fn update(&mut self, link: &Link) -> Option<Change> {
+        let replaced = !self.present && link.name == self.configured_name;
+        if link.index != self.interface.index && !replaced {
+            return None;
+        }
+
+        let was_up = self.is_up();
+        let renamed = !link.name.is_empty() && link.name != self.interface.name;
+        if replaced {
+            self.interface.index = link.index;
+            self.interface.ips.clear();
+            self.xdp_attached = None;
+        }
+        if replaced || renamed {
+            self.interface.name = link.name.clone();
+        }
+        self.interface.flags = link.flags;
+        self.interface.mac = link.mac.or(self.interface.mac);
+        self.present = true;
+        let was_attached = self.xdp_attached;
+        self.xdp_attached = link.xdp_attached.or(self.xdp_attached);
+
+        if self.is_up() && !was_up {
+            Some(Change::Up)
+        } else if was_attached == Some(true) && self.xdp_attached == Some(false) {
+            Some(Change::XdpDetached)
+        } else if !self.is_up() && was_up {
+            Some(Change::Down)
+        } else if renamed && !replaced {
+            Some(Change::Renamed {
+                name: link.name.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Update the state from a snapshot of the interfaces.
+    fn refresh(&mut self, interfaces: &[NetworkInterface]) -> Option<Change> {
+        let interface = interfaces
+            .iter()
+            .find(|interface| self.present && interface.index == self.interface.index)
+            .or_else(|| {
+                interfaces
+                    .iter()
+                    .find(|interface| interface.name == self.configured_name)
+            });
+        let Some(interface) = interface else {
+            return self.apply(&Event::LinkRemoved {
+                index: self.interface.index,
+                name: self.interface.name.clone(),
+            });
+        };
+
+        if interface.index != self.interface.index {
+            self.present = false;
+        }
+        let change = self.update(&Link {
+            index: interface.index,
+            name: interface.name.clone(),
+            flags: interface.flags,
+            mac: interface.mac,
+            xdp_attached: None,
+        });
+        if change.is_none() && self.interface.ips != interface.ips {
+            self.interface.ips = interface.ips.clone();
+            return Some(Change::Addresses);
+        }
+        self.interface.ips = interface.ips.clone();
+
+        change
     }
 }
 
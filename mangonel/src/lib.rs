@@ -1,3 +1,12 @@
+pub mod capture;
+pub mod config;
+pub mod control;
+pub mod fragments;
+pub mod generator;
+pub mod heavy_hitters;
 pub mod interface;
+pub mod netlink;
 pub mod packet;
 pub mod policy;
+pub mod syn_proxy;
+pub mod worker;
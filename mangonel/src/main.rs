@@ -1,16 +1,75 @@
 use std::{
-    collections::VecDeque,
+    fmt::Display,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
         Arc,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use mangonel::{interface::Port, packet::Packet};
-use mangonel_libxdp_rs::{descriptor::Descriptor, socket::SocketBuilder};
+use core_affinity::CoreId;
+use mangonel::{
+    capture::{Capture, CaptureFilter},
+    config::ConfigError,
+    control::{Control, ControlBuilder, ControlError, QueueInfo, CONTROL_PATH},
+    fragments::FragmentTracker,
+    generator::{Generator, Profile, Rate, IMIX},
+    heavy_hitters::{self, HeavyHitters},
+    interface::{Change, Port, Side},
+    netlink::Monitor,
+    policy::SharedPolicy,
+    syn_proxy::SynProxy,
+    worker::{Worker, WorkerStats},
+};
+use mangonel_libxdp_rs::{
+    backend::SocketPair,
+    handoff::{Handoff, HandoffSocket},
+    mmap::MmapBuilder,
+    numa,
+    preflight::Report,
+    socket::{BusyPoll, SocketBuilder, SocketError, TxSocket},
+    xskmap::XskMap,
+};
+use pnet::{datalink, util::MacAddr};
+
+const HANDOFF_PATH: &str = "/run/mangonel/handoff.sock";
+const CAPTURE_PATH: &str = "/run/mangonel/capture.pcapng";
+const CONFIG_PATH: &str = "/etc/mangonel/mangonel.toml";
+const WAN_INTERFACE: &str = "wan";
+const LAN_INTERFACE: &str = "lan";
+const QUEUE_ID: u32 = 0;
+/// How often the supervisor checks whether to stop.
+const SUPERVISOR_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait before retrying to rebind the sockets.
+const REBIND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the supervisor does with the workers after the port changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Restart the workers on the same sockets.
+    Restart,
+    /// Recreate the sockets, e.g. once the link came back.
+    Rebind,
+}
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--check") {
+        let report = Report::new(WAN_INTERFACE, QUEUE_ID, &socket_builder());
+        print!("{}", report);
+        std::process::exit(match report.is_ok() {
+            true => 0,
+            false => 1,
+        });
+    }
+
+    // Before any thread is spawned, so that every thread inherits the mask.
+    let signals = block_signals();
+
     let flag = Arc::new(AtomicBool::new(true));
     ctrlc::set_handler({
         let flag = flag.clone();
@@ -20,42 +79,522 @@ fn main() {
     })
     .unwrap();
 
-    let port = Port::new("wan", "lan").unwrap();
-    let worker = thread::spawn({
-        let port = port.clone();
-        let flag = flag.clone();
-        move || worker(flag, port)
+    if std::env::args().nth(1).as_deref() == Some("gen") {
+        generate(&flag);
+        return;
+    }
+
+    let capture_filter: CaptureFilter = argument("--capture-filter")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or_else(|error| exit(error));
+    let capture_path = argument("--capture").unwrap_or_else(|| CAPTURE_PATH.to_owned());
+    let config_path = argument("--config");
+
+    let port = Port::new(WAN_INTERFACE, LAN_INTERFACE).unwrap();
+    let control = ControlBuilder {
+        config_path: config_path.as_deref().unwrap_or(CONFIG_PATH).into(),
+        capture_path: capture_path.into(),
+        capture_filter,
+    }
+    .build(
+        port.clone(),
+        SharedPolicy::default(),
+        Capture::default(),
+        SynProxy::default(),
+        FragmentTracker::default(),
+        HeavyHitters::default(),
+    );
+    match control.reload() {
+        Ok(()) => {}
+        // Without a configuration every frame passes.
+        Err(ControlError::Config(ConfigError::Read(error)))
+            if config_path.is_none() && error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => exit(error),
+    }
+    handle_signals(signals, control.clone());
+    block_heavy_hitters(control.clone());
+    control
+        .serve(argument("--control").unwrap_or_else(|| CONTROL_PATH.to_owned()))
+        .unwrap_or_else(|error| exit(error));
+
+    let action_receiver = monitor_port(port.clone());
+    let mut handoff = match std::env::args().any(|arg| arg == "--takeover") {
+        true => take_over(),
+        false => start(&port).unwrap_or_else(|error| exit(error)),
+    };
+    let mut handoff_receiver = listen_for_handoff(flag.clone()).unwrap_or_else(|error| exit(error));
+
+    // Counters by socket, kept across restarts.
+    let mut stats: Vec<Arc<WorkerStats>> = Vec::new();
+    loop {
+        stats.resize_with(handoff.sockets.len(), Default::default);
+        control.set_queues(
+            handoff
+                .sockets
+                .iter()
+                .zip(&stats)
+                .map(|(socket, stats)| (queue_info(&port, socket), stats.clone()))
+                .collect(),
+        );
+
+        let running = Arc::new(AtomicBool::new(true));
+        let workers = spawn_workers(&running, handoff.sockets, &control, &stats);
+        let action = wait_for_action(&flag, &action_receiver);
+        running.store(false, Ordering::SeqCst);
+
+        handoff.sockets = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect();
+
+        match action {
+            Some(Action::Restart) => continue,
+            Some(Action::Rebind) => {
+                // Close the sockets before binding new ones to the queue.
+                handoff.sockets.clear();
+                if let Some(rebound) = rebind(&port, &flag) {
+                    handoff = rebound;
+                    eprintln!("Rebound the sockets to {}", port.wan().name);
+                    // The changes that arrived meanwhile are covered.
+                    action_receiver.try_iter().for_each(drop);
+                    continue;
+                }
+            }
+            None => {}
+        }
+
+        if let Err(error) = control.stop_capture() {
+            eprintln!("{}", error);
+        }
+
+        let Ok(stream) = handoff_receiver.try_recv() else {
+            break;
+        };
+        let Err(error) = handoff.send(&stream) else {
+            break;
+        };
+        // The sockets are still owned here, so keep serving them.
+        eprintln!("Failed to hand over the sockets: {}", error);
+        flag.store(true, Ordering::SeqCst);
+        handoff_receiver = listen_for_handoff(flag.clone()).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            mpsc::channel().1
+        });
+    }
+}
+
+fn queue_info(port: &Port, socket: &HandoffSocket) -> QueueInfo {
+    let umem = socket.rx_socket.umem();
+
+    QueueInfo {
+        interface: port.wan().name,
+        queue_id: socket.queue_id,
+        ring_size: umem.ring_size(),
+        frame_size: umem.frame_size(),
+        frame_count: umem.frame_count(),
+    }
+}
+
+fn spawn_workers(
+    running: &Arc<AtomicBool>,
+    sockets: Vec<HandoffSocket>,
+    control: &Control,
+    stats: &[Arc<WorkerStats>],
+) -> Vec<JoinHandle<HandoffSocket>> {
+    let interface_name = control.port().wan().name;
+    let core_ids = worker_cores(&interface_name);
+
+    sockets
+        .into_iter()
+        .enumerate()
+        .map(|(index, socket)| {
+            let core_id = core_ids.get(index).copied();
+            let running = running.clone();
+            let interface_name = interface_name.clone();
+            let control = control.clone();
+            let stats = stats[index].clone();
+            thread::spawn(move || {
+                if let Some(core_id) = core_id {
+                    core_affinity::set_for_current(core_id);
+                }
+                worker(running, socket, interface_name, control, stats)
+            })
+        })
+        .collect()
+}
+
+/// Wait until the flag is cleared, returning `None`, or until the workers
+/// have to act on a change of the port.
+fn wait_for_action(flag: &AtomicBool, receiver: &Receiver<Action>) -> Option<Action> {
+    while flag.load(Ordering::SeqCst) {
+        match receiver.recv_timeout(SUPERVISOR_INTERVAL) {
+            Ok(action) => return Some(action),
+            Err(RecvTimeoutError::Timeout) => {}
+            // The port is no longer monitored.
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(SUPERVISOR_INTERVAL),
+        }
+    }
+
+    None
+}
+
+/// Create the sockets again until it succeeds or the flag is cleared.
+fn rebind(port: &Port, flag: &AtomicBool) -> Option<Handoff> {
+    while flag.load(Ordering::SeqCst) {
+        match start(port) {
+            Ok(handoff) => return Some(handoff),
+            Err(error) => {
+                eprintln!("Failed to rebind the sockets: {}", error);
+                thread::sleep(REBIND_INTERVAL);
+            }
+        }
+    }
+
+    None
+}
+
+/// Follow the links and addresses of the port and tell the supervisor how
+/// the workers have to react.
+fn monitor_port(port: Port) -> Receiver<Action> {
+    let mut monitor = Monitor::new().unwrap_or_else(|error| exit(error));
+    let (sender, receiver): (Sender<Action>, _) = mpsc::channel();
+    thread::spawn(move || loop {
+        let events = match monitor.receive() {
+            Ok(events) => events,
+            Err(error) => {
+                eprintln!("{}", error);
+                return;
+            }
+        };
+
+        for (side, change) in events.iter().flat_map(|event| port.apply(event)) {
+            eprintln!("The {} interface {}", side, change);
+            let action = match (side, change) {
+                (Side::Wan, Change::Up | Change::XdpDetached) => Action::Rebind,
+                (Side::Wan, Change::Renamed { .. }) => Action::Restart,
+                _ => continue,
+            };
+            if sender.send(action).is_err() {
+                return;
+            }
+        }
     });
 
-    worker.join().unwrap();
+    receiver
+}
+
+/// Return the value of a `--name=value` argument.
+fn argument(name: &str) -> Option<String> {
+    std::env::args().find_map(|arg| {
+        arg.strip_prefix(name)?
+            .strip_prefix('=')
+            .map(ToOwned::to_owned)
+    })
 }
 
-pub fn worker(flag: Arc<AtomicBool>, port: Port) {
+/// Parse the value of a `--name=value` argument, exiting if it is invalid.
+fn parse_argument<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    argument(name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|error| exit(format!("Invalid {} `{}`: {}", name, value, error)))
+    })
+}
+
+/// Transmit the traffic described by the arguments until interrupted or
+/// `--duration` seconds elapse:
+///
+/// `mangonel gen [--interface=NAME] [--queue=ID] [--size=LENGTH | --imix]
+/// [--flows=COUNT] [--src=RANGE] [--dst=RANGE] [--src-port=PORT]
+/// [--dst-port=PORT] [--src-mac=MAC] [--dst-mac=MAC] [--pps=RATE | --bps=RATE]
+/// [--duration=SECONDS]`
+fn generate(flag: &AtomicBool) {
+    let interface_name = argument("--interface").unwrap_or_else(|| WAN_INTERFACE.to_owned());
+    let queue_id = parse_argument("--queue").unwrap_or(QUEUE_ID);
+    let interface_mac = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == interface_name)
+        .and_then(|interface| interface.mac);
+
+    let default = Profile::default();
+    let sizes = match (
+        parse_argument("--size"),
+        std::env::args().any(|arg| arg == "--imix"),
+    ) {
+        (Some(size), _) => vec![size],
+        (None, true) => IMIX.to_vec(),
+        (None, false) => default.sizes,
+    };
+    let rate = match (parse_argument("--pps"), parse_argument("--bps")) {
+        (Some(rate), _) => Rate::Packets(rate),
+        (None, Some(rate)) => Rate::Bits(rate),
+        (None, None) => Rate::Unlimited,
+    };
+    let profile = Profile {
+        source_mac: parse_argument::<MacAddr>("--src-mac")
+            .or(interface_mac)
+            .unwrap_or(default.source_mac),
+        destination_mac: parse_argument("--dst-mac").unwrap_or(default.destination_mac),
+        source: parse_argument("--src").unwrap_or(default.source),
+        destination: parse_argument("--dst").unwrap_or(default.destination),
+        source_port: parse_argument("--src-port").unwrap_or(default.source_port),
+        destination_port: parse_argument("--dst-port").unwrap_or(default.destination_port),
+        flows: parse_argument("--flows").unwrap_or(default.flows),
+        sizes,
+        rate,
+    };
+    let duration = parse_argument("--duration").map(|seconds: f64| {
+        Duration::try_from_secs_f64(seconds)
+            .unwrap_or_else(|error| exit(format!("Invalid --duration `{}`: {}", seconds, error)))
+    });
+    let frame_count = profile.frame_count().unwrap_or_else(|error| exit(error));
+
+    let config = SocketBuilder {
+        tx_frame_count: frame_count as u32,
+        ..socket_builder()
+    };
+    let (_rx_socket, tx_socket) = config
+        .build(&interface_name, queue_id)
+        .unwrap_or_else(|error| exit(error));
+    let mut generator = Generator::new(tx_socket, &profile).unwrap_or_else(|error| exit(error));
+
+    let total = generator.run(flag, duration, |stats| println!("{}", stats));
+    println!("Total: {}", total);
+}
+
+fn socket_builder() -> SocketBuilder {
+    SocketBuilder {
+        frame_headroom_size: 6 + 16,
+        mmap: MmapBuilder {
+            shared: true,
+            ..Default::default()
+        },
+        busy_poll: std::env::args()
+            .any(|arg| arg == "--busy-poll")
+            .then(BusyPoll::default),
+        ..Default::default()
+    }
+}
+
+/// Create the socket the SYN proxy sends the handshakes it replays to the
+/// servers from. It only transmits, so the LAN port keeps receiving into the
+/// kernel, and is created again rather than handed over.
+fn lan_socket(interface_name: &str, queue_id: u32) -> Result<TxSocket, SocketError> {
+    let config = socket_builder();
+
+    SocketBuilder {
+        tx_frame_count: config.ring_size,
+        ..config
+    }
+    .build_tx(interface_name, queue_id)
+}
+
+fn start(port: &Port) -> Result<Handoff, SocketError> {
     let interface_name = &port.wan().name;
-    let queue_id = 0;
-    let mut config = SocketBuilder::default();
-    config.frame_headroom_size = 6 + 16;
-    let (mut receiver, mut sender) = config.build(interface_name, queue_id).unwrap();
+    let queue_id = QUEUE_ID;
+    let xskmap = Arc::new(XskMap::setup(interface_name)?);
+    let config = SocketBuilder {
+        xskmap: Some(xskmap.clone()),
+        ..socket_builder()
+    };
+    let (rx_socket, tx_socket) = config.build(interface_name, queue_id)?;
+    if let Some(error) = rx_socket.umem().mmap().hugepage_fallback() {
+        eprintln!("Falling back to regular pages: {}", error);
+    }
 
-    let mut descriptor_address_buffer = VecDeque::<u64>::with_capacity(64);
-    let mut receiver_buffer = VecDeque::<Descriptor>::with_capacity(64);
-    let mut sender_buffer = VecDeque::<Descriptor>::with_capacity(64);
+    let socket = HandoffSocket {
+        queue_id,
+        rx_socket,
+        tx_socket,
+        free_frames: Vec::new(),
+    };
 
-    while flag.load(Ordering::SeqCst) {
-        receiver.umem().fill(&mut descriptor_address_buffer);
-
-        let received = receiver.rx_burst(&mut receiver_buffer);
-        if received > 0 {
-            for _ in 0..received {
-                let mut descriptor = receiver_buffer.pop_front().unwrap();
-                let mut packet: Packet = descriptor.get_data().into();
-                println!("{:?}", packet);
-                sender_buffer.push_back(descriptor);
+    Ok(Handoff {
+        xskmap,
+        sockets: vec![socket],
+    })
+}
+
+/// Print the error along with its remediation hint and exit.
+fn exit(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
+/// Adopt the sockets of the running process, which exits afterwards.
+fn take_over() -> Handoff {
+    let stream = UnixStream::connect(HANDOFF_PATH).unwrap_or_else(|error| {
+        exit(format!(
+            "Failed to connect to the running process at {}: {}",
+            HANDOFF_PATH, error
+        ))
+    });
+
+    Handoff::receive(&stream).unwrap_or_else(|error| exit(error))
+}
+
+/// Wait for a new process to take over, then stop the workers so that their
+/// sockets can be handed over.
+fn listen_for_handoff(flag: Arc<AtomicBool>) -> Result<Receiver<UnixStream>, String> {
+    let path = Path::new(HANDOFF_PATH);
+    let message = |error| {
+        format!(
+            "Failed to listen for a hand-off at {}: {}",
+            HANDOFF_PATH, error
+        )
+    };
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory).map_err(message)?;
+    }
+    // The previous process keeps listening on the unlinked socket until it
+    // exits.
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).map_err(message)?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Ok((stream, _)) = listener.accept() {
+            // Without the receiver the process is exiting anyway.
+            if sender.send(stream).is_ok() {
+                flag.store(false, Ordering::SeqCst);
             }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Block SIGHUP and SIGUSR1 in the calling thread, and so in the threads it
+/// spawns later, to leave them to [`handle_signals`]. Return the blocked
+/// signals.
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::sigaddset(&mut signals, libc::SIGUSR1);
+        let result = libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        assert_eq!(result, 0, "Failed to block signals");
 
-            sender.tx_burst(&mut sender_buffer);
+        signals
+    }
+}
+
+/// Wait for the blocked `signals`:
+///
+/// - On SIGHUP, read the configuration again and swap its policy into the
+///   running workers. An invalid configuration leaves the policy unchanged.
+/// - On SIGUSR1, start capturing, and stop on the next one.
+fn handle_signals(signals: libc::sigset_t, control: Control) {
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            continue;
+        }
+
+        match signal {
+            libc::SIGHUP => match control.reload() {
+                Ok(()) => eprintln!(
+                    "Reloaded {} rules from {}",
+                    control.policy().load().rule_count(),
+                    control.config_path().display()
+                ),
+                Err(error) => eprintln!("{}. Keeping the current policy", error),
+            },
+            libc::SIGUSR1 => {
+                let result = match control.capture().is_active() {
+                    true => control
+                        .stop_capture()
+                        .map(|()| eprintln!("Stopped capturing")),
+                    false => control
+                        .start_capture(None, None)
+                        .map(|path| eprintln!("Capturing to {}", path.display())),
+                };
+                if let Err(error) = result {
+                    eprintln!("{}", error);
+                }
+            }
+            _ => {}
         }
+    });
+}
+
+/// Collect the top talkers the workers counted once per interval, and block
+/// the sources sending more than configured.
+fn block_heavy_hitters(control: Control) {
+    thread::spawn(move || loop {
+        thread::sleep(heavy_hitters::INTERVAL);
+        control
+            .heavy_hitters()
+            .collect(Instant::now())
+            .iter()
+            .for_each(|source| eprintln!("Blocking heavy hitter {}", source));
+    });
+}
+
+/// Return the cores with the ones on the NUMA node of the interface first.
+fn worker_cores(interface_name: &str) -> Vec<CoreId> {
+    let mut core_ids = core_affinity::get_core_ids().unwrap_or_default();
+    if let Some(node) = numa::interface_node(interface_name) {
+        numa::prefer_local(&mut core_ids, &numa::node_cpus(node), |core_id| core_id.id);
+    }
+
+    core_ids
+}
+
+pub fn worker(
+    flag: Arc<AtomicBool>,
+    socket: HandoffSocket,
+    interface_name: String,
+    control: Control,
+    stats: Arc<WorkerStats>,
+) -> HandoffSocket {
+    let HandoffSocket {
+        queue_id,
+        rx_socket,
+        tx_socket,
+        free_frames,
+    } = socket;
+
+    let mut worker = Worker::new(
+        SocketPair {
+            rx_socket,
+            tx_socket,
+        },
+        free_frames,
+    )
+    .with_capture(interface_name, control.capture().clone())
+    .with_policy(control.policy())
+    .with_syn_proxy(control.syn_proxy().clone())
+    .with_fragments(control.fragments().clone())
+    .with_heavy_hitters(control.heavy_hitters())
+    .with_stats(stats);
+    let lan_name = control.port().lan().name;
+    match lan_socket(&lan_name, queue_id) {
+        Ok(lan_socket) => worker = worker.with_lan(lan_name, lan_socket),
+        Err(error) => eprintln!("Failed to create the socket on {}: {}", lan_name, error),
+    }
+    worker.run(&flag);
+
+    // Frames that are not in any ring belong to the worker and have to be
+    // handed over explicitly.
+    let (
+        SocketPair {
+            rx_socket,
+            tx_socket,
+        },
+        free_frames,
+    ) = worker.into_parts();
 
-        sender.umem().complete(&mut descriptor_address_buffer);
+    HandoffSocket {
+        queue_id,
+        rx_socket,
+        tx_socket,
+        free_frames,
     }
 }
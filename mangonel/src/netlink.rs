@@ -0,0 +1,411 @@
+//! Monitoring links and addresses over rtnetlink, so that mangonel follows
+//! interfaces that flap, get renamed, change addresses or lose their XDP
+//! program while it runs.
+//!
+//! Only the attributes mangonel uses are parsed; the rest of each message is
+//! skipped.
+
+use std::{
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::{
+    bind, recv, sockaddr_nl, socket, socklen_t, AF_INET, AF_INET6, AF_NETLINK, IFA_ADDRESS,
+    IFA_LOCAL, IFLA_ADDRESS, IFLA_IFNAME, IFLA_XDP, NETLINK_ROUTE, RTMGRP_IPV4_IFADDR,
+    RTMGRP_IPV6_IFADDR, RTMGRP_LINK, RTM_DELADDR, RTM_DELLINK, RTM_NEWADDR, RTM_NEWLINK,
+    SOCK_CLOEXEC, SOCK_RAW,
+};
+use pnet::{ipnetwork::IpNetwork, util::MacAddr};
+
+// Not defined by the libc crate yet.
+const IFLA_XDP_ATTACHED: u16 = 2;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const HEADER_LENGTH: usize = 16;
+/// Length of `struct ifinfomsg`.
+const LINK_HEADER_LENGTH: usize = 16;
+/// Length of `struct ifaddrmsg`.
+const ADDRESS_HEADER_LENGTH: usize = 8;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The state of a link announced by the kernel.
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    /// `IFF_*` flags of the link.
+    pub flags: u32,
+    pub mac: Option<MacAddr>,
+    /// Whether an XDP program is attached, if the message says.
+    pub xdp_attached: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A link was added or changed.
+    Link(Link),
+    LinkRemoved {
+        index: u32,
+        name: String,
+    },
+    AddressAdded {
+        index: u32,
+        network: IpNetwork,
+    },
+    AddressRemoved {
+        index: u32,
+        network: IpNetwork,
+    },
+    /// The kernel dropped events because the receive buffer overflowed. The
+    /// state has to be read again.
+    Overrun,
+}
+
+/// A socket subscribed to link and IPv4 and IPv6 address changes.
+pub struct Monitor {
+    fd: OwnedFd,
+    buffer: Vec<u8>,
+}
+
+impl Monitor {
+    pub fn new() -> Result<Self, NetlinkError> {
+        let fd = unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) };
+        if fd.is_negative() {
+            return Err(NetlinkError::Socket(std::io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = AF_NETLINK as u16;
+        address.nl_groups = (RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR) as u32;
+        let value = unsafe {
+            bind(
+                fd.as_raw_fd(),
+                (&address as *const sockaddr_nl).cast(),
+                size_of::<sockaddr_nl>() as socklen_t,
+            )
+        };
+        if value.is_negative() {
+            return Err(NetlinkError::Socket(std::io::Error::last_os_error()));
+        }
+
+        Ok(Self {
+            fd,
+            buffer: vec![0; BUFFER_SIZE],
+        })
+    }
+
+    /// Wait for the next batch of messages and return the events they carry.
+    pub fn receive(&mut self) -> Result<Vec<Event>, NetlinkError> {
+        let length = unsafe {
+            recv(
+                self.fd.as_raw_fd(),
+                self.buffer.as_mut_ptr().cast(),
+                self.buffer.len(),
+                0,
+            )
+        };
+        if length.is_negative() {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::ENOBUFS) => Ok(vec![Event::Overrun]),
+                Some(libc::EINTR) => Ok(Vec::new()),
+                _ => Err(NetlinkError::Receive(error)),
+            };
+        }
+
+        Ok(parse_messages(&self.buffer[..length as usize]))
+    }
+}
+
+fn parse_messages(mut data: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    while data.len() >= HEADER_LENGTH {
+        let length = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+        if length < HEADER_LENGTH || length > data.len() {
+            break;
+        }
+
+        let payload = &data[HEADER_LENGTH..length];
+        let event = match kind {
+            RTM_NEWLINK | RTM_DELLINK => parse_link(payload).map(|link| match kind {
+                RTM_NEWLINK => Event::Link(link),
+                _ => Event::LinkRemoved {
+                    index: link.index,
+                    name: link.name,
+                },
+            }),
+            RTM_NEWADDR | RTM_DELADDR => {
+                parse_address(payload).map(|(index, network)| match kind {
+                    RTM_NEWADDR => Event::AddressAdded { index, network },
+                    _ => Event::AddressRemoved { index, network },
+                })
+            }
+            _ if kind == libc::NLMSG_OVERRUN as u16 => Some(Event::Overrun),
+            _ => None,
+        };
+        events.extend(event);
+
+        data = &data[align(length).min(data.len())..];
+    }
+
+    events
+}
+
+fn parse_link(payload: &[u8]) -> Option<Link> {
+    let header = payload.get(..LINK_HEADER_LENGTH)?;
+    let mut link = Link {
+        index: u32::from_ne_bytes(header[4..8].try_into().unwrap()),
+        name: String::new(),
+        flags: u32::from_ne_bytes(header[8..12].try_into().unwrap()),
+        mac: None,
+        xdp_attached: None,
+    };
+
+    for (kind, value) in attributes(&payload[LINK_HEADER_LENGTH..]) {
+        match kind {
+            IFLA_IFNAME => {
+                let value = value.split(|byte| *byte == 0).next().unwrap_or_default();
+                link.name = String::from_utf8_lossy(value).into_owned();
+            }
+            IFLA_ADDRESS if value.len() == 6 => {
+                link.mac = Some(MacAddr::new(
+                    value[0], value[1], value[2], value[3], value[4], value[5],
+                ));
+            }
+            IFLA_XDP => {
+                link.xdp_attached = attributes(value)
+                    .find(|(kind, _)| *kind == IFLA_XDP_ATTACHED)
+                    .and_then(|(_, value)| value.first())
+                    .map(|mode| *mode != 0);
+            }
+            _ => {}
+        }
+    }
+
+    Some(link)
+}
+
+fn parse_address(payload: &[u8]) -> Option<(u32, IpNetwork)> {
+    let header = payload.get(..ADDRESS_HEADER_LENGTH)?;
+    let family = header[0] as i32;
+    let prefix = header[1];
+    let index = u32::from_ne_bytes(header[4..8].try_into().unwrap());
+
+    // On point-to-point links `IFA_ADDRESS` is the peer and `IFA_LOCAL` the
+    // address of the link.
+    let mut address = None;
+    for (kind, value) in attributes(&payload[ADDRESS_HEADER_LENGTH..]) {
+        let value = match (family, value.len()) {
+            (AF_INET, 4) => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(value).unwrap())),
+            (AF_INET6, 16) => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(value).unwrap())),
+            _ => continue,
+        };
+        match kind {
+            IFA_LOCAL => address = Some(value),
+            IFA_ADDRESS => address = address.or(Some(value)),
+            _ => {}
+        }
+    }
+
+    let network = IpNetwork::new(address?, prefix).ok()?;
+
+    Some((index, network))
+}
+
+/// Iterate over the `struct rtattr` in `data` as their types and values.
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let length = u16::from_ne_bytes(data[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data[2..4].try_into().unwrap()) & NLA_TYPE_MASK;
+        if length < 4 || length > data.len() {
+            return None;
+        }
+
+        let value = &data[4..length];
+        data = &data[align(length).min(data.len())..];
+
+        Some((kind, value))
+    })
+}
+
+/// Round up to the 4 byte alignment of netlink messages and attributes.
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+#[derive(Debug)]
+pub enum NetlinkError {
+    Socket(std::io::Error),
+    Receive(std::io::Error),
+}
+
+impl std::fmt::Display for NetlinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Socket(error) => write!(
+                f,
+                "Failed to subscribe to link and address changes: {}",
+                error
+            ),
+            Self::Receive(error) => {
+                write!(f, "Failed to receive link and address changes: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetlinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Socket(error) | Self::Receive(error) => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+        data.extend_from_slice(&kind.to_ne_bytes());
+        data.extend_from_slice(value);
+        data.resize(align(data.len()), 0);
+
+        data
+    }
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&((HEADER_LENGTH + payload.len()) as u32).to_ne_bytes());
+        data.extend_from_slice(&kind.to_ne_bytes());
+        data.resize(HEADER_LENGTH, 0);
+        data.extend_from_slice(payload);
+        data.resize(align(data.len()), 0);
+
+        data
+    }
+
+    fn link(index: u32, flags: u32, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = vec![0; LINK_HEADER_LENGTH];
+        payload[4..8].copy_from_slice(&index.to_ne_bytes());
+        payload[8..12].copy_from_slice(&flags.to_ne_bytes());
+        attributes
+            .iter()
+            .for_each(|attribute| payload.extend_from_slice(attribute));
+
+        payload
+    }
+
+    fn address(family: i32, prefix: u8, index: u32, attributes: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = vec![family as u8, prefix, 0, 0];
+        payload.extend_from_slice(&index.to_ne_bytes());
+        attributes
+            .iter()
+            .for_each(|attribute| payload.extend_from_slice(attribute));
+
+        payload
+    }
+
+    #[test]
+    fn new_link() {
+        let payload = link(
+            3,
+            libc::IFF_UP as u32,
+            &[
+                attribute(IFLA_IFNAME, b"eth0\0"),
+                attribute(IFLA_ADDRESS, &[2, 0, 0, 0, 0, 1]),
+                attribute(IFLA_XDP, &attribute(IFLA_XDP_ATTACHED, &[1])),
+            ],
+        );
+        let events = parse_messages(&message(RTM_NEWLINK, &payload));
+
+        let [Event::Link(link)] = events.as_slice() else {
+            panic!("{:?}", events);
+        };
+        assert_eq!(link.index, 3);
+        assert_eq!(link.name, "eth0");
+        assert_eq!(link.flags, libc::IFF_UP as u32);
+        assert_eq!(link.mac, Some(MacAddr::new(2, 0, 0, 0, 0, 1)));
+        assert_eq!(link.xdp_attached, Some(true));
+    }
+
+    #[test]
+    fn removed_link_without_xdp() {
+        let payload = link(4, 0, &[attribute(IFLA_IFNAME, b"veth1\0")]);
+        let events = parse_messages(&message(RTM_DELLINK, &payload));
+
+        assert!(matches!(
+            events.as_slice(),
+            [Event::LinkRemoved { index: 4, name }] if name == "veth1"
+        ));
+    }
+
+    #[test]
+    fn local_address_wins_over_the_peer() {
+        let payload = address(
+            AF_INET,
+            32,
+            5,
+            &[
+                attribute(IFA_ADDRESS, &[203, 0, 113, 1]),
+                attribute(IFA_LOCAL, &[192, 0, 2, 1]),
+            ],
+        );
+        let events = parse_messages(&message(RTM_NEWADDR, &payload));
+
+        assert!(matches!(
+            events.as_slice(),
+            [Event::AddressAdded { index: 5, network }]
+                if *network == "192.0.2.1/32".parse::<IpNetwork>().unwrap()
+        ));
+    }
+
+    #[test]
+    fn several_messages() {
+        let address = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets();
+        let mut data = message(
+            RTM_DELADDR,
+            &self::address(AF_INET6, 64, 2, &[attribute(IFA_ADDRESS, &address)]),
+        );
+        data.extend(message(libc::NLMSG_OVERRUN as u16, &[]));
+        // Messages of other types are skipped.
+        data.extend(message(libc::RTM_NEWROUTE, &[0; 12]));
+        data.extend(message(RTM_NEWLINK, &link(1, 0, &[])));
+
+        let events = parse_messages(&data);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            events[0],
+            Event::AddressRemoved { index: 2, network } if network.prefix() == 64
+        ));
+        assert!(matches!(events[1], Event::Overrun));
+        assert!(matches!(&events[2], Event::Link(link) if link.index == 1));
+    }
+
+    #[test]
+    fn truncated_data() {
+        let payload = link(3, 0, &[attribute(IFLA_IFNAME, b"eth0\0")]);
+        let data = message(RTM_NEWLINK, &payload);
+        // A message longer than the data is dropped.
+        assert!(parse_messages(&data[..data.len() - 4]).is_empty());
+
+        // So is an attribute longer than the message, along with the rest.
+        let mut payload = link(3, 0, &[attribute(IFLA_IFNAME, b"eth0\0")]);
+        payload[LINK_HEADER_LENGTH..LINK_HEADER_LENGTH + 2].copy_from_slice(&100u16.to_ne_bytes());
+        let events = parse_messages(&message(RTM_NEWLINK, &payload));
+        assert!(matches!(events.as_slice(), [Event::Link(link)] if link.name.is_empty()));
+
+        // An address without a valid prefix length is dropped.
+        let payload = address(AF_INET, 33, 1, &[attribute(IFA_ADDRESS, &[192, 0, 2, 1])]);
+        assert!(parse_messages(&message(RTM_NEWADDR, &payload)).is_empty());
+    }
+}
@@ -1,3 +1,22 @@
+pub mod builder;
+pub mod headers;
+
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_ARP: u16 = 0x0806;
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+pub(crate) const ETHERTYPE_QINQ: u16 = 0x88a8;
+pub(crate) const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+pub(crate) const PROTOCOL_ICMP: u8 = 1;
+pub(crate) const PROTOCOL_TCP: u8 = 6;
+pub(crate) const PROTOCOL_UDP: u8 = 17;
+pub(crate) const PROTOCOL_ICMPV6: u8 = 58;
+
+pub(crate) const ETHERNET_HEADER_LENGTH: usize = 14;
+pub(crate) const VLAN_HEADER_LENGTH: usize = 4;
+pub(crate) const IPV4_HEADER_LENGTH: usize = 20;
+pub(crate) const IPV6_HEADER_LENGTH: usize = 40;
+
 #[derive(Debug)]
 pub struct Packet<'a>(&'a mut [u8]);
 
@@ -7,4 +26,116 @@ impl<'a> From<&'a mut [u8]> for Packet<'a> {
     }
 }
 
-impl<'a> Packet<'a> {}
+impl<'a> Packet<'a> {
+    /// Set the DSCP of an IPv4 or IPv6 frame, keeping the ECN bits, and
+    /// update the IPv4 header checksum. Return whether the frame is IP.
+    pub fn set_dscp(&mut self, dscp: u8) -> bool {
+        let Some((ethertype, offset)) = headers::network_header(self.0) else {
+            return false;
+        };
+
+        match (ethertype, self.0.get_mut(offset..)) {
+            (ETHERTYPE_IPV4, Some(header)) if header.len() >= IPV4_HEADER_LENGTH => {
+                let old = [header[0], header[1]];
+                header[1] = dscp << 2 | header[1] & 0x03;
+                let checksum = u16::from_be_bytes([header[10], header[11]]);
+                let checksum = builder::update_checksum(checksum, &old, &header[0..2]);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                true
+            }
+            (ETHERTYPE_IPV6, Some(header)) if header.len() >= IPV6_HEADER_LENGTH => {
+                // The traffic class straddles the first two bytes.
+                let traffic_class = dscp << 2 | (header[1] >> 4) & 0x03;
+                header[0] = header[0] & 0xf0 | traffic_class >> 4;
+                header[1] = traffic_class << 4 | header[1] & 0x0f;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+    /// The frame does not fit in the buffer.
+    BufferTooSmall { length: usize, capacity: usize },
+    /// The payload exceeds the maximum length of an IP packet.
+    PayloadTooLarge { length: usize },
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BufferTooSmall { length, capacity } => write!(
+                f,
+                "A frame of {} bytes does not fit in a buffer of {} bytes",
+                length, capacity
+            ),
+            Self::PayloadTooLarge { length } => write!(
+                f,
+                "A payload of {} bytes exceeds the maximum length of an IP packet",
+                length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::{
+        builder::{checksum, Ipv4, Ipv6, Network, PacketBuilder},
+        *,
+    };
+
+    fn build(network: Network) -> Vec<u8> {
+        let builder = PacketBuilder {
+            network,
+            ..Default::default()
+        };
+        let mut frame = vec![0; 128];
+        let length = builder.write(&mut frame, b"data").unwrap();
+        frame.truncate(length);
+
+        frame
+    }
+
+    #[test]
+    fn set_dscp_ipv4() {
+        let mut frame = build(Network::Ipv4(Ipv4 {
+            // ECN capable.
+            tos: 0x02,
+            ..Default::default()
+        }));
+
+        assert!(Packet::from(frame.as_mut_slice()).set_dscp(46));
+        let header = &frame[ETHERNET_HEADER_LENGTH..ETHERNET_HEADER_LENGTH + IPV4_HEADER_LENGTH];
+        assert_eq!(header[1], 46 << 2 | 0x02);
+        assert_eq!(checksum(header, 0), 0);
+    }
+
+    #[test]
+    fn set_dscp_ipv6() {
+        let mut frame = build(Network::Ipv6(Ipv6 {
+            destination: Ipv6Addr::LOCALHOST,
+            traffic_class: 0x01,
+            flow_label: 0xfffff,
+            ..Default::default()
+        }));
+
+        assert!(Packet::from(frame.as_mut_slice()).set_dscp(10));
+        let word = u32::from_be_bytes(frame[14..18].try_into().unwrap());
+        assert_eq!(word >> 28, 6);
+        assert_eq!((word >> 20) as u8, 10 << 2 | 0x01);
+        assert_eq!(word & 0xfffff, 0xfffff);
+    }
+
+    #[test]
+    fn set_dscp_other() {
+        let mut frame = build(Network::Arp(Default::default()));
+        assert!(!Packet::from(frame.as_mut_slice()).set_dscp(10));
+    }
+}
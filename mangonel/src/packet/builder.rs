@@ -0,0 +1,613 @@
+//! Writing the headers of a frame and its payload directly into a buffer,
+//! with lengths and checksums filled in.
+//!
+//! The buffer is either a fresh one sent with
+//! [`mangonel_libxdp_rs::socket::TxSocket::send`] or the frame of a received
+//! descriptor, from [`mangonel_libxdp_rs::descriptor::Descriptor::get_buffer`]
+//! or past the headroom of
+//! [`mangonel_libxdp_rs::descriptor::Descriptor::get_data`].
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use pnet::util::MacAddr;
+
+use crate::packet::{
+    PacketError, ETHERNET_HEADER_LENGTH, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+    ETHERTYPE_VLAN, IPV4_HEADER_LENGTH, IPV6_HEADER_LENGTH, PROTOCOL_ICMP, PROTOCOL_ICMPV6,
+    PROTOCOL_TCP, PROTOCOL_UDP, VLAN_HEADER_LENGTH,
+};
+
+const ARP_LENGTH: usize = 28;
+const TCP_HEADER_LENGTH: usize = 20;
+const TCP_MSS_LENGTH: usize = 4;
+const UDP_HEADER_LENGTH: usize = 8;
+const ICMP_HEADER_LENGTH: usize = 8;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+pub const TCP_OPTION_END: u8 = 0;
+pub const TCP_OPTION_NOP: u8 = 1;
+pub const TCP_OPTION_MSS: u8 = 2;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// The headers of a frame, written by [`PacketBuilder::write`] in front of a
+/// payload.
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    pub source: MacAddr,
+    pub destination: MacAddr,
+    pub vlan: Option<Vlan>,
+    pub network: Network,
+}
+
+impl Default for PacketBuilder {
+    fn default() -> Self {
+        Self {
+            source: MacAddr::zero(),
+            destination: MacAddr::broadcast(),
+            vlan: None,
+            network: Network::Ipv4(Ipv4::default()),
+        }
+    }
+}
+
+/// An 802.1Q tag.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vlan {
+    pub id: u16,
+    pub priority: u8,
+}
+
+#[derive(Debug, Clone)]
+pub enum Network {
+    Arp(Arp),
+    Ipv4(Ipv4),
+    Ipv6(Ipv6),
+}
+
+/// An ARP message for IPv4 over Ethernet.
+#[derive(Debug, Clone)]
+pub struct Arp {
+    /// [`ARP_REQUEST`] or [`ARP_REPLY`].
+    pub operation: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Default for Arp {
+    fn default() -> Self {
+        Self {
+            operation: ARP_REQUEST,
+            sender_mac: MacAddr::zero(),
+            sender_ip: Ipv4Addr::UNSPECIFIED,
+            target_mac: MacAddr::zero(),
+            target_ip: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ipv4 {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub ttl: u8,
+    pub identification: u16,
+    /// The DSCP and ECN bits.
+    pub tos: u8,
+    pub dont_fragment: bool,
+    pub transport: Transport,
+}
+
+impl Default for Ipv4 {
+    fn default() -> Self {
+        Self {
+            source: Ipv4Addr::UNSPECIFIED,
+            destination: Ipv4Addr::UNSPECIFIED,
+            ttl: 64,
+            identification: 0,
+            tos: 0,
+            dont_fragment: true,
+            transport: Transport::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ipv6 {
+    pub source: Ipv6Addr,
+    pub destination: Ipv6Addr,
+    pub hop_limit: u8,
+    pub traffic_class: u8,
+    /// Only the lower 20 bits are used.
+    pub flow_label: u32,
+    pub transport: Transport,
+}
+
+impl Default for Ipv6 {
+    fn default() -> Self {
+        Self {
+            source: Ipv6Addr::UNSPECIFIED,
+            destination: Ipv6Addr::UNSPECIFIED,
+            hop_limit: 64,
+            traffic_class: 0,
+            flow_label: 0,
+            transport: Transport::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Tcp(Tcp),
+    Udp(Udp),
+    /// ICMP over IPv4 and ICMPv6 over IPv6.
+    Icmp(Icmp),
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::Udp(Udp::default())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Tcp {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    /// A combination of [`TCP_SYN`], [`TCP_ACK`] and the other flags.
+    pub flags: u8,
+    pub window: u16,
+    /// The maximum segment size option, the only option written.
+    pub mss: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Udp {
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Icmp {
+    pub kind: u8,
+    pub code: u8,
+    /// The rest of the header, e.g. the identifier and sequence number of an
+    /// echo or the next-hop MTU of a fragmentation needed message.
+    pub rest: [u8; 4],
+}
+
+impl PacketBuilder {
+    /// Return the length of the frame carrying `payload_length` bytes.
+    pub fn length(&self, payload_length: usize) -> usize {
+        let vlan_length = match self.vlan {
+            Some(_) => VLAN_HEADER_LENGTH,
+            None => 0,
+        };
+        let network_length = match &self.network {
+            Network::Arp(_) => ARP_LENGTH,
+            Network::Ipv4(ipv4) => IPV4_HEADER_LENGTH + ipv4.transport.header_length(),
+            Network::Ipv6(ipv6) => IPV6_HEADER_LENGTH + ipv6.transport.header_length(),
+        };
+
+        ETHERNET_HEADER_LENGTH + vlan_length + network_length + payload_length
+    }
+
+    /// Write the frame to the start of `buffer` and return its length.
+    pub fn write(&self, buffer: &mut [u8], payload: &[u8]) -> Result<usize, PacketError> {
+        let length = self.length(payload.len());
+        if length > buffer.len() {
+            return Err(PacketError::BufferTooSmall {
+                length,
+                capacity: buffer.len(),
+            });
+        }
+        let buffer = &mut buffer[..length];
+
+        buffer[0..6].copy_from_slice(&self.destination.octets());
+        buffer[6..12].copy_from_slice(&self.source.octets());
+        let mut offset = 12;
+        if let Some(vlan) = self.vlan {
+            let tci = (vlan.priority as u16 & 0x7) << 13 | vlan.id & 0x0fff;
+            put_u16(buffer, offset, ETHERTYPE_VLAN);
+            put_u16(buffer, offset + 2, tci);
+            offset += VLAN_HEADER_LENGTH;
+        }
+
+        let ethertype = match &self.network {
+            Network::Arp(_) => ETHERTYPE_ARP,
+            Network::Ipv4(_) => ETHERTYPE_IPV4,
+            Network::Ipv6(_) => ETHERTYPE_IPV6,
+        };
+        put_u16(buffer, offset, ethertype);
+        offset += 2;
+
+        let network = &mut buffer[offset..];
+        match &self.network {
+            Network::Arp(arp) => {
+                write_arp(network, arp);
+                network[ARP_LENGTH..].copy_from_slice(payload);
+            }
+            Network::Ipv4(ipv4) => write_ipv4(network, ipv4, payload)?,
+            Network::Ipv6(ipv6) => write_ipv6(network, ipv6, payload)?,
+        }
+
+        Ok(length)
+    }
+}
+
+impl Transport {
+    fn header_length(&self) -> usize {
+        match self {
+            Self::Tcp(tcp) => match tcp.mss {
+                Some(_) => TCP_HEADER_LENGTH + TCP_MSS_LENGTH,
+                None => TCP_HEADER_LENGTH,
+            },
+            Self::Udp(_) => UDP_HEADER_LENGTH,
+            Self::Icmp(_) => ICMP_HEADER_LENGTH,
+        }
+    }
+
+    /// Write the header and the payload with a zero checksum and return the
+    /// offset of the checksum.
+    fn write(&self, buffer: &mut [u8], payload: &[u8]) -> usize {
+        let header_length = self.header_length();
+        buffer[header_length..].copy_from_slice(payload);
+        let buffer = &mut buffer[..header_length];
+        buffer.fill(0);
+
+        match self {
+            Self::Tcp(tcp) => {
+                put_u16(buffer, 0, tcp.source_port);
+                put_u16(buffer, 2, tcp.destination_port);
+                buffer[4..8].copy_from_slice(&tcp.sequence.to_be_bytes());
+                buffer[8..12].copy_from_slice(&tcp.acknowledgement.to_be_bytes());
+                buffer[12] = (header_length as u8 / 4) << 4;
+                buffer[13] = tcp.flags;
+                put_u16(buffer, 14, tcp.window);
+                if let Some(mss) = tcp.mss {
+                    buffer[20] = TCP_OPTION_MSS;
+                    buffer[21] = TCP_MSS_LENGTH as u8;
+                    put_u16(buffer, 22, mss);
+                }
+
+                16
+            }
+            Self::Udp(udp) => {
+                put_u16(buffer, 0, udp.source_port);
+                put_u16(buffer, 2, udp.destination_port);
+                put_u16(buffer, 4, (header_length + payload.len()) as u16);
+
+                6
+            }
+            Self::Icmp(icmp) => {
+                buffer[0] = icmp.kind;
+                buffer[1] = icmp.code;
+                buffer[4..8].copy_from_slice(&icmp.rest);
+
+                2
+            }
+        }
+    }
+
+    fn protocol(&self, ipv6: bool) -> u8 {
+        match (self, ipv6) {
+            (Self::Tcp(_), _) => PROTOCOL_TCP,
+            (Self::Udp(_), _) => PROTOCOL_UDP,
+            (Self::Icmp(_), false) => PROTOCOL_ICMP,
+            (Self::Icmp(_), true) => PROTOCOL_ICMPV6,
+        }
+    }
+}
+
+fn write_arp(buffer: &mut [u8], arp: &Arp) {
+    put_u16(buffer, 0, 1);
+    put_u16(buffer, 2, ETHERTYPE_IPV4);
+    buffer[4] = 6;
+    buffer[5] = 4;
+    put_u16(buffer, 6, arp.operation);
+    buffer[8..14].copy_from_slice(&arp.sender_mac.octets());
+    buffer[14..18].copy_from_slice(&arp.sender_ip.octets());
+    buffer[18..24].copy_from_slice(&arp.target_mac.octets());
+    buffer[24..28].copy_from_slice(&arp.target_ip.octets());
+}
+
+fn write_ipv4(buffer: &mut [u8], ipv4: &Ipv4, payload: &[u8]) -> Result<(), PacketError> {
+    let total_length = buffer.len();
+    if total_length > u16::MAX as usize {
+        return Err(PacketError::PayloadTooLarge {
+            length: payload.len(),
+        });
+    }
+
+    let (header, transport) = buffer.split_at_mut(IPV4_HEADER_LENGTH);
+    header[0] = 0x45;
+    header[1] = ipv4.tos;
+    put_u16(header, 2, total_length as u16);
+    put_u16(header, 4, ipv4.identification);
+    put_u16(header, 6, if ipv4.dont_fragment { 0x4000 } else { 0 });
+    header[8] = ipv4.ttl;
+    header[9] = ipv4.transport.protocol(false);
+    put_u16(header, 10, 0);
+    header[12..16].copy_from_slice(&ipv4.source.octets());
+    header[16..20].copy_from_slice(&ipv4.destination.octets());
+    put_u16(header, 10, checksum(header, 0));
+
+    let checksum_offset = ipv4.transport.write(transport, payload);
+    let pseudo_header = match ipv4.transport {
+        // The ICMP checksum covers the message only.
+        Transport::Icmp(_) => 0,
+        _ => {
+            sum(&ipv4.source.octets())
+                + sum(&ipv4.destination.octets())
+                + ipv4.transport.protocol(false) as u32
+                + transport.len() as u32
+        }
+    };
+    finish_transport(transport, &ipv4.transport, checksum_offset, pseudo_header);
+
+    Ok(())
+}
+
+fn write_ipv6(buffer: &mut [u8], ipv6: &Ipv6, payload: &[u8]) -> Result<(), PacketError> {
+    let payload_length = buffer.len() - IPV6_HEADER_LENGTH;
+    if payload_length > u16::MAX as usize {
+        return Err(PacketError::PayloadTooLarge {
+            length: payload.len(),
+        });
+    }
+
+    let (header, transport) = buffer.split_at_mut(IPV6_HEADER_LENGTH);
+    let first_word = 6 << 28 | (ipv6.traffic_class as u32) << 20 | ipv6.flow_label & 0x000f_ffff;
+    header[0..4].copy_from_slice(&first_word.to_be_bytes());
+    put_u16(header, 4, payload_length as u16);
+    header[6] = ipv6.transport.protocol(true);
+    header[7] = ipv6.hop_limit;
+    header[8..24].copy_from_slice(&ipv6.source.octets());
+    header[24..40].copy_from_slice(&ipv6.destination.octets());
+
+    let checksum_offset = ipv6.transport.write(transport, payload);
+    // Unlike ICMP, ICMPv6 covers the pseudo-header too.
+    let pseudo_header = sum(&ipv6.source.octets())
+        + sum(&ipv6.destination.octets())
+        + ipv6.transport.protocol(true) as u32
+        + payload_length as u32;
+    finish_transport(transport, &ipv6.transport, checksum_offset, pseudo_header);
+
+    Ok(())
+}
+
+fn finish_transport(buffer: &mut [u8], transport: &Transport, offset: usize, pseudo_header: u32) {
+    let value = match (checksum(buffer, pseudo_header), transport) {
+        // A zero UDP checksum means that there is none.
+        (0, Transport::Udp(_)) => 0xffff,
+        (value, _) => value,
+    };
+    put_u16(buffer, offset, value);
+}
+
+#[inline(always)]
+fn put_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Return the ones' complement sum of the big endian 16-bit words of `data`,
+/// padded with a zero byte to an even length, with the carries folded in.
+fn sum(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum: u64 = chunks
+        .by_ref()
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]) as u64)
+        .sum();
+    if let [last] = chunks.remainder() {
+        sum += (*last as u64) << 8;
+    }
+
+    fold(sum) as u32
+}
+
+#[inline(always)]
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+/// Return the Internet checksum of `data`, starting from `initial`.
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    !fold(sum(data) as u64 + initial as u64)
+}
+
+/// Return `checksum` updated for 16-bit aligned `old` data replaced by `new`
+/// of the same length, as in RFC 1624: HC' = ~(~HC + ~m + m').
+pub fn update_checksum(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    !fold(!checksum as u64 + !sum(old) as u16 as u64 + sum(new) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::{
+        ethernet::EthernetPacket, icmp::IcmpPacket, icmpv6::Icmpv6Packet, ipv4::Ipv4Packet,
+        ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket, Packet,
+    };
+
+    use super::*;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 2);
+    const SOURCE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DESTINATION_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    fn build(builder: &PacketBuilder, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; 2048];
+        let length = builder.write(&mut buffer, payload).unwrap();
+        assert_eq!(length, builder.length(payload.len()));
+        buffer.truncate(length);
+
+        buffer
+    }
+
+    fn ipv4(transport: Transport) -> PacketBuilder {
+        PacketBuilder {
+            network: Network::Ipv4(Ipv4 {
+                source: SOURCE,
+                destination: DESTINATION,
+                transport,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ipv6(transport: Transport) -> PacketBuilder {
+        PacketBuilder {
+            network: Network::Ipv6(Ipv6 {
+                source: SOURCE_V6,
+                destination: DESTINATION_V6,
+                transport,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ipv4_tcp_checksums() {
+        let builder = ipv4(Transport::Tcp(Tcp {
+            source_port: 40000,
+            destination_port: 443,
+            sequence: 1,
+            flags: TCP_SYN,
+            mss: Some(1460),
+            ..Default::default()
+        }));
+        let frame = build(&builder, b"odd");
+
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        let ip = Ipv4Packet::new(ethernet.payload()).unwrap();
+        assert_eq!(ip.get_checksum(), pnet::packet::ipv4::checksum(&ip));
+        assert_eq!(ip.get_total_length() as usize, frame.len() - 14);
+        let tcp = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!(tcp.get_data_offset(), 6);
+        assert_eq!(tcp.payload(), b"odd");
+        assert_eq!(
+            tcp.get_checksum(),
+            pnet::packet::tcp::ipv4_checksum(&tcp, &SOURCE, &DESTINATION)
+        );
+    }
+
+    #[test]
+    fn ipv6_udp_checksum() {
+        let builder = ipv6(Transport::Udp(Udp {
+            source_port: 5353,
+            destination_port: 53,
+        }));
+        let frame = build(&builder, &[0xab; 33]);
+
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        let ip = Ipv6Packet::new(ethernet.payload()).unwrap();
+        assert_eq!(ip.get_payload_length(), 8 + 33);
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!(udp.get_length(), 8 + 33);
+        assert_eq!(
+            udp.get_checksum(),
+            pnet::packet::udp::ipv6_checksum(&udp, &SOURCE_V6, &DESTINATION_V6)
+        );
+    }
+
+    #[test]
+    fn icmp_checksums() {
+        let icmp = Transport::Icmp(Icmp {
+            kind: 8,
+            rest: [0, 1, 0, 2],
+            ..Default::default()
+        });
+
+        let frame = build(&ipv4(icmp.clone()), b"ping");
+        let ip = Ipv4Packet::new(&frame[14..]).unwrap();
+        let message = IcmpPacket::new(ip.payload()).unwrap();
+        assert_eq!(
+            message.get_checksum(),
+            pnet::packet::icmp::checksum(&message)
+        );
+
+        // ICMPv6 also covers the pseudo-header.
+        let frame = build(&ipv6(icmp), b"ping");
+        let ip = Ipv6Packet::new(&frame[14..]).unwrap();
+        let message = Icmpv6Packet::new(ip.payload()).unwrap();
+        assert_eq!(
+            message.get_checksum(),
+            pnet::packet::icmpv6::checksum(&message, &SOURCE_V6, &DESTINATION_V6)
+        );
+    }
+
+    #[test]
+    fn vlan_tag() {
+        let builder = PacketBuilder {
+            vlan: Some(Vlan {
+                id: 100,
+                priority: 5,
+            }),
+            ..ipv4(Transport::default())
+        };
+        let frame = build(&builder, &[]);
+
+        assert_eq!(frame[12..18], [0x81, 0x00, 0xa0, 100, 0x08, 0x00]);
+        assert_eq!(frame.len(), 14 + 4 + 20 + 8);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let builder = ipv4(Transport::default());
+        let mut buffer = [0; 50];
+        assert!(matches!(
+            builder.write(&mut buffer, &[0; 10]),
+            Err(PacketError::BufferTooSmall {
+                length: 52,
+                capacity: 50
+            })
+        ));
+    }
+
+    #[test]
+    fn checksum_verifies_to_zero() {
+        let data = [0x45, 0x00, 0x00, 0x1c, 0xab, 0xcd, 0x40, 0x00, 0x40, 0x11];
+        let value = checksum(&data, 0);
+        let mut data = data.to_vec();
+        data.extend_from_slice(&value.to_be_bytes());
+        assert_eq!(checksum(&data, 0), 0);
+    }
+
+    #[test]
+    fn update_checksum_matches_recomputing() {
+        let frame = build(&ipv4(Transport::default()), b"payload");
+        let mut header = frame[14..34].to_vec();
+        let old = u16::from_be_bytes([header[10], header[11]]);
+
+        for (offset, new) in [(0, [0x45, 0xb8]), (8, [0x01, 0x11]), (12, [0xff, 0xff])] {
+            let updated = update_checksum(
+                u16::from_be_bytes([header[10], header[11]]),
+                &header[offset..offset + 2],
+                &new,
+            );
+            header[offset..offset + 2].copy_from_slice(&new);
+            header[10..12].fill(0);
+            let recomputed = checksum(&header, 0);
+            assert_eq!(updated, recomputed, "offset {}", offset);
+            header[10..12].copy_from_slice(&recomputed.to_be_bytes());
+        }
+        assert_ne!(old, u16::from_be_bytes([header[10], header[11]]));
+    }
+}
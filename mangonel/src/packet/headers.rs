@@ -0,0 +1,192 @@
+//! Reading the addresses, protocol and ports of a received frame, the fields
+//! policies match on.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::packet::{
+    ETHERNET_HEADER_LENGTH, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_QINQ, ETHERTYPE_VLAN,
+    IPV4_HEADER_LENGTH, IPV6_HEADER_LENGTH, PROTOCOL_TCP, PROTOCOL_UDP, VLAN_HEADER_LENGTH,
+};
+
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+
+const TCP_HEADER_LENGTH: usize = 20;
+const UDP_HEADER_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headers {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    /// IP protocol number of the transport header, after any IPv6 extension
+    /// headers.
+    pub protocol: u8,
+    /// Ports of TCP and UDP. Unknown for fragments other than the first.
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Flags of TCP, e.g. [`crate::packet::builder::TCP_SYN`].
+    pub tcp_flags: Option<u8>,
+    /// Set for fragments of a larger datagram, including the first.
+    pub fragment: Option<Fragment>,
+}
+
+/// Where a fragment belongs in its datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    /// The identification of the datagram, 16 bits for IPv4.
+    pub id: u32,
+    /// The offset of the data of the fragment in the datagram, in bytes.
+    pub offset: u32,
+    /// The bytes of data of the fragment.
+    pub length: u32,
+    /// Whether fragments follow, unset for the last one.
+    pub more: bool,
+}
+
+impl Headers {
+    /// Parse an Ethernet frame, possibly VLAN tagged. Return `None` for
+    /// frames other than IPv4 and IPv6, and truncated ones.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let (ethertype, offset) = network_header(frame)?;
+        let network = &frame[offset..];
+
+        match ethertype {
+            ETHERTYPE_IPV4 => parse_ipv4(network),
+            ETHERTYPE_IPV6 => parse_ipv6(network),
+            _ => None,
+        }
+    }
+}
+
+/// Return the EtherType of the network header and its offset, after any VLAN
+/// tags.
+pub(crate) fn network_header(frame: &[u8]) -> Option<(u16, usize)> {
+    let mut offset = ETHERNET_HEADER_LENGTH - 2;
+    let mut ethertype = read_u16(frame, offset)?;
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        offset += VLAN_HEADER_LENGTH;
+        ethertype = read_u16(frame, offset)?;
+    }
+
+    Some((ethertype, offset + 2))
+}
+
+fn parse_ipv4(packet: &[u8]) -> Option<Headers> {
+    let header = packet.get(..IPV4_HEADER_LENGTH)?;
+    let header_length = (header[0] & 0x0f) as usize * 4;
+    if header[0] >> 4 != 4 || header_length < IPV4_HEADER_LENGTH {
+        return None;
+    }
+
+    let flags = read_u16(header, 6)?;
+    let fragment_offset = flags & 0x1fff;
+    let mut headers = Headers {
+        source: IpAddr::from(Ipv4Addr::from(
+            <[u8; 4]>::try_from(&header[12..16]).unwrap(),
+        )),
+        destination: IpAddr::from(Ipv4Addr::from(
+            <[u8; 4]>::try_from(&header[16..20]).unwrap(),
+        )),
+        protocol: header[9],
+        source_port: None,
+        destination_port: None,
+        tcp_flags: None,
+        fragment: None,
+    };
+    if flags & IPV4_MORE_FRAGMENTS != 0 || fragment_offset != 0 {
+        headers.fragment = Some(Fragment {
+            id: read_u16(header, 4)? as u32,
+            offset: fragment_offset as u32 * 8,
+            length: read_u16(header, 2)?.saturating_sub(header_length as u16) as u32,
+            more: flags & IPV4_MORE_FRAGMENTS != 0,
+        });
+    }
+    if fragment_offset == 0 {
+        parse_transport(&mut headers, packet.get(header_length..)?);
+    }
+
+    Some(headers)
+}
+
+fn parse_ipv6(packet: &[u8]) -> Option<Headers> {
+    let header = packet.get(..IPV6_HEADER_LENGTH)?;
+    if header[0] >> 4 != 6 {
+        return None;
+    }
+
+    let mut headers = Headers {
+        source: IpAddr::from(Ipv6Addr::from(
+            <[u8; 16]>::try_from(&header[8..24]).unwrap(),
+        )),
+        destination: IpAddr::from(Ipv6Addr::from(
+            <[u8; 16]>::try_from(&header[24..40]).unwrap(),
+        )),
+        protocol: header[6],
+        source_port: None,
+        destination_port: None,
+        tcp_flags: None,
+        fragment: None,
+    };
+
+    let mut offset = IPV6_HEADER_LENGTH;
+    loop {
+        match headers.protocol {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION_OPTIONS => {
+                let extension = packet.get(offset..offset + 2)?;
+                headers.protocol = extension[0];
+                offset += (extension[1] as usize + 1) * 8;
+            }
+            IPV6_FRAGMENT => {
+                let extension = packet.get(offset..offset + 8)?;
+                headers.protocol = extension[0];
+                offset += 8;
+                let flags = read_u16(extension, 2)?;
+                let (fragment_offset, more) = (flags >> 3, flags & 1 != 0);
+                // Atomic fragments, of a single fragment, are whole datagrams.
+                if fragment_offset != 0 || more {
+                    let length = IPV6_HEADER_LENGTH + read_u16(header, 4)? as usize;
+                    headers.fragment = Some(Fragment {
+                        id: u32::from_be_bytes(extension[4..8].try_into().unwrap()),
+                        offset: fragment_offset as u32 * 8,
+                        length: length.saturating_sub(offset) as u32,
+                        more,
+                    });
+                }
+                if fragment_offset != 0 {
+                    return Some(headers);
+                }
+            }
+            _ => break,
+        }
+    }
+    parse_transport(&mut headers, packet.get(offset..)?);
+
+    Some(headers)
+}
+
+fn parse_transport(headers: &mut Headers, segment: &[u8]) {
+    let length = match headers.protocol {
+        PROTOCOL_TCP => TCP_HEADER_LENGTH,
+        PROTOCOL_UDP => UDP_HEADER_LENGTH,
+        _ => return,
+    };
+    let Some(header) = segment.get(..length) else {
+        return;
+    };
+
+    headers.source_port = read_u16(header, 0);
+    headers.destination_port = read_u16(header, 2);
+    if headers.protocol == PROTOCOL_TCP {
+        headers.tcp_flags = Some(header[13]);
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
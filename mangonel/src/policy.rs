@@ -1,3 +1,37 @@
+//! Deciding the verdict of received frames.
+//!
+//! A [`Policy`] is compiled from the configuration and never changes. To
+//! change the rules, a new policy is stored into the [`SharedPolicy`] of the
+//! workers, which pick it up between bursts through their [`PolicyReader`].
+//! The previous policy is freed once the last worker moved on. The rate
+//! limits of its rules live on in the [`RateLimiters`] of the shared policy
+//! and carry over to the rules of the same name in the new one.
+
+pub mod ip_set;
+pub mod rate_limit;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use pnet::ipnetwork::{IpNetwork, IpNetworkError};
+use serde::{Deserialize, Serialize};
+
+use self::{
+    ip_set::{IpSet, IpSetConfig, IpSets},
+    rate_limit::{Exceed, RateLimitConfig, RateLimiter, RateLimiters},
+};
+use crate::packet::{headers::Headers, PROTOCOL_TCP, PROTOCOL_UDP};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Protocol {
     Tcp,
     Udp,
@@ -5,4 +39,750 @@ pub enum Protocol {
     Https,
 }
 
-pub struct WhiteList;
+impl Protocol {
+    /// Return the IP protocol number and the destination port the protocol
+    /// implies, if any.
+    fn resolve(self) -> (u8, Option<u16>) {
+        match self {
+            Self::Tcp => (PROTOCOL_TCP, None),
+            Self::Udp => (PROTOCOL_UDP, None),
+            Self::Http => (PROTOCOL_TCP, Some(80)),
+            Self::Https => (PROTOCOL_TCP, Some(443)),
+        }
+    }
+}
+
+/// The addresses a rule matches as source or destination: a network, e.g.
+/// `192.0.2.0/24`, or the set of a `[policy.set.NAME]` table, as `set:NAME`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Addresses {
+    Network(IpNetwork),
+    Set(String),
+}
+
+impl FromStr for Addresses {
+    type Err = IpNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("set:") {
+            Some(name) => Ok(Self::Set(name.to_owned())),
+            None => s.parse().map(Self::Network),
+        }
+    }
+}
+
+impl TryFrom<String> for Addresses {
+    type Error = IpNetworkError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::fmt::Display for Addresses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(network) => write!(f, "{}", network),
+            Self::Set(name) => write!(f, "set:{}", name),
+        }
+    }
+}
+
+impl From<Addresses> for String {
+    fn from(value: Addresses) -> Self {
+        value.to_string()
+    }
+}
+
+impl From<IpNetwork> for Addresses {
+    fn from(value: IpNetwork) -> Self {
+        Self::Network(value)
+    }
+}
+
+/// What happens to fragments of IP datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FragmentPolicy {
+    /// Decide on the first fragment of a datagram and apply the decision to
+    /// the others, see [`crate::fragments`].
+    #[default]
+    Track,
+    /// Drop every fragment, e.g. where the path MTU is known to be large
+    /// enough.
+    Drop,
+}
+
+/// What a worker does with a received frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// Transmit the frame.
+    Pass,
+    /// Return the frame to the UMEM.
+    Drop,
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pass => write!(f, "pass"),
+            Self::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+impl std::str::FromStr for Verdict {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(Self::Pass),
+            "drop" => Ok(Self::Drop),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A rule as configured. Every field that is set has to match.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    pub verdict: Verdict,
+    pub protocol: Option<Protocol>,
+    pub source: Option<Addresses>,
+    pub destination: Option<Addresses>,
+    /// Needs `protocol` to be `tcp` or `udp`.
+    pub destination_port: Option<u16>,
+    /// Limit the rate of every source. Frames within the limit get the
+    /// verdict of the rule, which has to be `pass`.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Answer the SYNs of matching TCP connections with SYN cookies, and
+    /// only hand the handshakes of verified clients to the servers. Needs a
+    /// `pass` verdict.
+    #[serde(default)]
+    pub syn_proxy: bool,
+}
+
+/// The policy as configured, in the `[policy]` table.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// The verdict of IP frames no rule matches.
+    #[serde(default = "default_verdict")]
+    pub default: Verdict,
+    /// Rules in the order they are tried, the `[[policy.rule]]` tables.
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub fragments: FragmentPolicy,
+    /// Sets of prefixes rules match addresses against, the
+    /// `[policy.set.NAME]` tables.
+    #[serde(default, rename = "set")]
+    pub sets: BTreeMap<String, IpSetConfig>,
+}
+
+fn default_verdict() -> Verdict {
+    Verdict::Pass
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            default: default_verdict(),
+            rules: Vec::new(),
+            fragments: FragmentPolicy::default(),
+            sets: BTreeMap::new(),
+        }
+    }
+}
+
+/// The compiled [`Addresses`] of a rule.
+#[derive(Debug)]
+enum AddressMatcher {
+    Network(IpNetwork),
+    Set(Arc<IpSet>),
+}
+
+impl AddressMatcher {
+    fn new(addresses: &Addresses, sets: &IpSets) -> Option<Self> {
+        match addresses {
+            Addresses::Network(network) => Some(Self::Network(*network)),
+            Addresses::Set(name) => sets.get(name).cloned().map(Self::Set),
+        }
+    }
+
+    #[inline(always)]
+    fn contains(&self, address: IpAddr) -> bool {
+        match self {
+            Self::Network(network) => network.contains(address),
+            Self::Set(set) => set.contains(address),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    verdict: Verdict,
+    protocol: Option<u8>,
+    source: Option<AddressMatcher>,
+    destination: Option<AddressMatcher>,
+    destination_port: Option<u16>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    syn_proxy: bool,
+}
+
+impl Rule {
+    #[inline(always)]
+    fn matches(&self, headers: &Headers) -> bool {
+        self.protocol
+            .map_or(true, |protocol| protocol == headers.protocol)
+            && self
+                .source
+                .as_ref()
+                .map_or(true, |source| source.contains(headers.source))
+            && self.destination.as_ref().map_or(true, |destination| {
+                destination.contains(headers.destination)
+            })
+            && self
+                .destination_port
+                .map_or(true, |port| Some(port) == headers.destination_port)
+    }
+}
+
+/// The rules compiled from a [`PolicyConfig`].
+#[derive(Debug)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Verdict,
+    fragments: FragmentPolicy,
+}
+
+impl Default for Policy {
+    /// Pass every frame.
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: Verdict::Pass,
+            fragments: FragmentPolicy::default(),
+        }
+    }
+}
+
+impl Policy {
+    /// Validate the configuration and compile it, with the `sets` loaded
+    /// from its set tables. Rate limited rules take their limiter from
+    /// `rate_limiters`, which keeps the limiters of this policy only once it
+    /// is stored with [`SharedPolicy::store`].
+    pub fn compile(
+        config: &PolicyConfig,
+        sets: &IpSets,
+        rate_limiters: &RateLimiters,
+    ) -> Result<Self, PolicyError> {
+        let mut rules: Vec<Rule> = Vec::with_capacity(config.rules.len());
+        for rule in &config.rules {
+            let invalid = |reason| PolicyError::InvalidRule {
+                name: rule.name.clone(),
+                reason,
+            };
+            if rule.name.is_empty() {
+                return Err(PolicyError::UnnamedRule);
+            }
+            if rules.iter().any(|compiled| compiled.name == rule.name) {
+                return Err(PolicyError::DuplicateRule(rule.name.clone()));
+            }
+
+            let (protocol, implied_port) = rule.protocol.map(Protocol::resolve).unzip();
+            let implied_port = implied_port.flatten();
+            let destination_port = match (implied_port, rule.destination_port) {
+                (Some(_), Some(_)) => return Err(invalid("`http` and `https` imply the port")),
+                (None, Some(_)) if protocol.is_none() => {
+                    return Err(invalid(
+                        "`destination_port` needs `protocol` `tcp` or `udp`",
+                    ))
+                }
+                (implied_port, port) => implied_port.or(port),
+            };
+            if let (Some(Addresses::Network(source)), Some(Addresses::Network(destination))) =
+                (&rule.source, &rule.destination)
+            {
+                if source.is_ipv4() != destination.is_ipv4() {
+                    return Err(invalid(
+                        "`source` and `destination` are of different families",
+                    ));
+                }
+            }
+            if rule.rate_limit.is_some() && rule.verdict == Verdict::Drop {
+                return Err(invalid("a rule dropping every frame has nothing to limit"));
+            }
+            if rule.syn_proxy && (protocol != Some(PROTOCOL_TCP) || rule.verdict == Verdict::Drop) {
+                return Err(invalid(
+                    "`syn_proxy` needs `protocol` `tcp`, `http` or `https` and a `pass` verdict",
+                ));
+            }
+            let addresses = |addresses: &Option<Addresses>| {
+                addresses
+                    .as_ref()
+                    .map(|addresses| {
+                        AddressMatcher::new(addresses, sets).ok_or_else(|| PolicyError::NoSuchSet {
+                            rule: rule.name.clone(),
+                            set: addresses.to_string(),
+                        })
+                    })
+                    .transpose()
+            };
+            let source = addresses(&rule.source)?;
+            let destination = addresses(&rule.destination)?;
+            let rate_limit = match &rule.rate_limit {
+                Some(config) => Some(rate_limiters.get(&rule.name, config).map_err(invalid)?),
+                None => None,
+            };
+
+            rules.push(Rule {
+                name: rule.name.clone(),
+                verdict: rule.verdict,
+                protocol,
+                source,
+                destination,
+                destination_port,
+                rate_limit,
+                syn_proxy: rule.syn_proxy,
+            });
+        }
+
+        Ok(Self {
+            rules,
+            default: config.default,
+            fragments: config.fragments,
+        })
+    }
+
+    /// Decide on a frame received at `now`. Frames other than IPv4 and IPv6,
+    /// e.g. ARP, pass.
+    #[inline(always)]
+    pub fn evaluate(&self, frame: &[u8], now: Instant) -> Decision<'_> {
+        self.decide(Headers::parse(frame).as_ref(), frame.len(), now)
+    }
+
+    /// Decide on a frame of `length` bytes with `headers` already parsed,
+    /// `None` for frames other than IPv4 and IPv6.
+    #[inline(always)]
+    pub fn decide(&self, headers: Option<&Headers>, length: usize, now: Instant) -> Decision<'_> {
+        let Some(headers) = headers else {
+            return Decision::new(Verdict::Pass, None);
+        };
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(headers)) else {
+            return Decision::new(self.default, None);
+        };
+
+        let mut decision = Decision::new(rule.verdict, Some(&rule.name));
+        decision.syn_proxy = rule.syn_proxy;
+        if let Some(limiter) = &rule.rate_limit {
+            if !limiter.take(headers.source, length, now) {
+                match limiter.exceed() {
+                    Exceed::Drop => decision.verdict = Verdict::Drop,
+                    Exceed::Mark => decision.dscp = Some(limiter.dscp()),
+                }
+            }
+        }
+
+        decision
+    }
+
+    /// Return the limiters of the rate limited rules by rule name.
+    fn limiters(&self) -> HashMap<String, Arc<RateLimiter>> {
+        self.rules
+            .iter()
+            .filter_map(|rule| Some((rule.name.clone(), rule.rate_limit.clone()?)))
+            .collect()
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn fragments(&self) -> FragmentPolicy {
+        self.fragments
+    }
+}
+
+/// The decision of a [`Policy`] on a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision<'a> {
+    pub verdict: Verdict,
+    /// The rule that decided the verdict, if any.
+    pub rule: Option<&'a str>,
+    /// The DSCP to mark the frame with before passing it.
+    pub dscp: Option<u8>,
+    /// Whether the frame goes through [`crate::syn_proxy::SynProxy`] as
+    /// from a client.
+    pub syn_proxy: bool,
+}
+
+impl<'a> Decision<'a> {
+    pub(crate) fn new(verdict: Verdict, rule: Option<&'a str>) -> Self {
+        Self {
+            verdict,
+            rule,
+            dscp: None,
+            syn_proxy: false,
+        }
+    }
+}
+
+/// The current policy of the workers, replaced as a whole while they run.
+#[derive(Clone, Default)]
+pub struct SharedPolicy {
+    inner: Arc<SharedPolicyInner>,
+}
+
+#[derive(Default)]
+struct SharedPolicyInner {
+    /// Incremented on every store so that readers only lock on changes.
+    version: AtomicU64,
+    policy: Mutex<Arc<Policy>>,
+    rate_limiters: RateLimiters,
+}
+
+impl SharedPolicy {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            inner: Arc::new(SharedPolicyInner {
+                version: AtomicU64::new(0),
+                policy: Mutex::new(Arc::new(policy)),
+                rate_limiters: RateLimiters::default(),
+            }),
+        }
+    }
+
+    /// Replace the policy. Readers pick it up on their next
+    /// [`PolicyReader::refresh`]. The rate limiters of its rules are kept for
+    /// the policies compiled next.
+    pub fn store(&self, policy: Policy) {
+        let mut current = self.inner.policy.lock().unwrap();
+        self.inner.rate_limiters.replace(policy.limiters());
+        *current = Arc::new(policy);
+        self.inner.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// The rate limiters to compile the policies stored here with.
+    pub fn rate_limiters(&self) -> &RateLimiters {
+        &self.inner.rate_limiters
+    }
+
+    pub fn load(&self) -> Arc<Policy> {
+        self.inner.policy.lock().unwrap().clone()
+    }
+
+    pub fn reader(&self) -> PolicyReader {
+        let (version, policy) = self.snapshot();
+
+        PolicyReader {
+            shared: self.clone(),
+            version,
+            policy,
+        }
+    }
+
+    fn snapshot(&self) -> (u64, Arc<Policy>) {
+        let policy = self.inner.policy.lock().unwrap();
+
+        (self.inner.version.load(Ordering::Acquire), policy.clone())
+    }
+}
+
+/// A worker's reference to the policy of a [`SharedPolicy`], which stays the
+/// same until refreshed.
+pub struct PolicyReader {
+    shared: SharedPolicy,
+    version: u64,
+    policy: Arc<Policy>,
+}
+
+impl PolicyReader {
+    /// Switch to the latest policy. Costs an acquire load unless the policy
+    /// was replaced.
+    #[inline(always)]
+    pub fn refresh(&mut self) {
+        if self.shared.inner.version.load(Ordering::Acquire) != self.version {
+            self.refresh_slow();
+        }
+    }
+
+    #[cold]
+    fn refresh_slow(&mut self) {
+        (self.version, self.policy) = self.shared.snapshot();
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> &Policy {
+        &self.policy
+    }
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    UnnamedRule,
+    DuplicateRule(String),
+    InvalidRule { name: String, reason: &'static str },
+    NoSuchSet { rule: String, set: String },
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnnamedRule => write!(f, "A rule has an empty name"),
+            Self::DuplicateRule(name) => write!(
+                f,
+                "More than one rule is named `{}`. Give every rule a unique name",
+                name
+            ),
+            Self::InvalidRule { name, reason } => write!(f, "Invalid rule `{}`: {}", name, reason),
+            Self::NoSuchSet { rule, set } => write!(
+                f,
+                "Rule `{}` matches `{}`, which is not defined. Add a `[policy.{}]` table with its `files` or `prefixes`",
+                rule, set, set.replacen(':', ".", 1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::packet::builder::{Arp, Ipv4, Network, PacketBuilder, Tcp, Transport, Udp};
+
+    fn compile(config: &str) -> Result<Policy, PolicyError> {
+        Policy::compile(
+            &toml::from_str(config).unwrap(),
+            &IpSets::new(),
+            &RateLimiters::default(),
+        )
+    }
+
+    fn frame(source: [u8; 4], destination: [u8; 4], transport: Transport) -> Vec<u8> {
+        let builder = PacketBuilder {
+            network: Network::Ipv4(Ipv4 {
+                source: Ipv4Addr::from(source),
+                destination: Ipv4Addr::from(destination),
+                transport,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut frame = vec![0; 128];
+        let length = builder.write(&mut frame, &[]).unwrap();
+        frame.truncate(length);
+
+        frame
+    }
+
+    fn tcp(port: u16) -> Transport {
+        Transport::Tcp(Tcp {
+            destination_port: port,
+            ..Default::default()
+        })
+    }
+
+    fn udp(port: u16) -> Transport {
+        Transport::Udp(Udp {
+            source_port: 1024,
+            destination_port: port,
+        })
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = compile(
+            r#"
+            default = "drop"
+
+            [[rule]]
+            name = "no-telnet"
+            verdict = "drop"
+            protocol = "tcp"
+            destination_port = 23
+
+            [[rule]]
+            name = "web"
+            verdict = "pass"
+            protocol = "http"
+            destination = "192.0.2.0/24"
+
+            [[rule]]
+            name = "office"
+            verdict = "pass"
+            source = "198.51.100.0/24"
+            "#,
+        )
+        .unwrap();
+        let now = Instant::now();
+        let evaluate = |source, destination, transport| {
+            let decision = policy.evaluate(&frame(source, destination, transport), now);
+            (decision.verdict, decision.rule)
+        };
+
+        let (office, server) = ([198, 51, 100, 7], [192, 0, 2, 1]);
+        assert_eq!(
+            evaluate(office, server, tcp(23)),
+            (Verdict::Drop, Some("no-telnet"))
+        );
+        assert_eq!(
+            evaluate([203, 0, 113, 1], server, tcp(80)),
+            (Verdict::Pass, Some("web"))
+        );
+        // `http` is TCP to port 80 only.
+        assert_eq!(
+            evaluate([203, 0, 113, 1], server, udp(80)),
+            (Verdict::Drop, None)
+        );
+        assert_eq!(
+            evaluate(office, [203, 0, 113, 1], udp(53)),
+            (Verdict::Pass, Some("office"))
+        );
+        assert_eq!(policy.rule_count(), 3);
+    }
+
+    #[test]
+    fn frames_other_than_ip_pass() {
+        let policy = compile(r#"default = "drop""#).unwrap();
+        let builder = PacketBuilder {
+            network: Network::Arp(Arp::default()),
+            ..Default::default()
+        };
+        let mut frame = vec![0; 64];
+        let length = builder.write(&mut frame, &[]).unwrap();
+
+        let decision = policy.evaluate(&frame[..length], Instant::now());
+        assert_eq!(decision, Decision::new(Verdict::Pass, None));
+    }
+
+    #[test]
+    fn sets_match_addresses() {
+        let config: PolicyConfig = toml::from_str(
+            r#"
+            [set.blocklist]
+            prefixes = ["203.0.113.0/24"]
+
+            [[rule]]
+            name = "blocklist"
+            verdict = "drop"
+            source = "set:blocklist"
+            "#,
+        )
+        .unwrap();
+        let sets = ip_set::load_sets(&config.sets).unwrap();
+        let policy = Policy::compile(&config, &sets, &RateLimiters::default()).unwrap();
+        let now = Instant::now();
+
+        let decision = policy.evaluate(&frame([203, 0, 113, 9], [192, 0, 2, 1], udp(53)), now);
+        assert_eq!(decision.verdict, Verdict::Drop);
+        let decision = policy.evaluate(&frame([203, 0, 114, 9], [192, 0, 2, 1], udp(53)), now);
+        assert_eq!(decision.verdict, Verdict::Pass);
+
+        assert!(matches!(
+            Policy::compile(&config, &IpSets::new(), &RateLimiters::default()),
+            Err(PolicyError::NoSuchSet { set, .. }) if set == "set:blocklist"
+        ));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let invalid = |rule: &str| match compile(&format!("[[rule]]\n{}", rule)) {
+            Err(PolicyError::InvalidRule { reason, .. }) => reason,
+            other => panic!("{:?}", other),
+        };
+
+        invalid("name = 'a'\nverdict = 'pass'\nprotocol = 'https'\ndestination_port = 8443");
+        invalid("name = 'a'\nverdict = 'pass'\ndestination_port = 53");
+        invalid(
+            "name = 'a'\nverdict = 'pass'\nsource = '192.0.2.0/24'\ndestination = '2001:db8::/32'",
+        );
+        invalid("name = 'a'\nverdict = 'drop'\nrate_limit = { packets = 10 }");
+        invalid("name = 'a'\nverdict = 'pass'\nprotocol = 'udp'\nsyn_proxy = true");
+        invalid("name = 'a'\nverdict = 'drop'\nprotocol = 'tcp'\nsyn_proxy = true");
+
+        assert!(matches!(
+            compile("[[rule]]\nname = ''\nverdict = 'pass'"),
+            Err(PolicyError::UnnamedRule)
+        ));
+        assert!(matches!(
+            compile("[[rule]]\nname = 'a'\nverdict = 'pass'\n[[rule]]\nname = 'a'\nverdict = 'drop'"),
+            Err(PolicyError::DuplicateRule(name)) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        for text in ["192.0.2.0/24", "2001:db8::/32", "set:blocklist"] {
+            assert_eq!(text.parse::<Addresses>().unwrap().to_string(), text);
+        }
+        assert!("set".parse::<Addresses>().is_err());
+    }
+
+    #[test]
+    fn readers_pick_up_stored_policies() {
+        let shared = SharedPolicy::default();
+        let mut reader = shared.reader();
+        assert_eq!(reader.get().rule_count(), 0);
+
+        shared.store(compile("[[rule]]\nname = 'a'\nverdict = 'drop'").unwrap());
+        assert_eq!(reader.get().rule_count(), 0);
+        reader.refresh();
+        assert_eq!(reader.get().rule_count(), 1);
+    }
+
+    #[test]
+    fn rate_limits_carry_over() {
+        let shared = SharedPolicy::default();
+        let compile = |config: &str| {
+            Policy::compile(
+                &toml::from_str(config).unwrap(),
+                &IpSets::new(),
+                shared.rate_limiters(),
+            )
+        };
+        let limited = "[[rule]]\nname = 'dns'\nverdict = 'pass'\nprotocol = 'udp'\n\
+                       rate_limit = { packets = 1 }\n";
+        let frame = frame([192, 0, 2, 1], [198, 51, 100, 1], udp(53));
+        let now = Instant::now();
+
+        let policy = compile(limited).unwrap();
+        assert_eq!(policy.evaluate(&frame, now).verdict, Verdict::Pass);
+        assert_eq!(policy.evaluate(&frame, now).verdict, Verdict::Drop);
+        // Nothing is kept until the policy is stored.
+        assert!(shared.rate_limiters().is_empty());
+        shared.store(policy);
+        assert_eq!(shared.rate_limiters().len(), 1);
+
+        // Another rule changes, the bucket of `dns` stays empty.
+        let policy = compile(&format!(
+            "[[rule]]\nname = 'telnet'\nverdict = 'drop'\nprotocol = 'tcp'\n\
+             destination_port = 23\n{}",
+            limited
+        ))
+        .unwrap();
+        assert_eq!(policy.evaluate(&frame, now).verdict, Verdict::Drop);
+
+        // Neither a policy that is only validated nor an invalid one changes
+        // the limiters.
+        compile("").unwrap();
+        let duplicate = format!("{}{}", limited, limited);
+        assert!(compile(&duplicate).is_err());
+        assert_eq!(shared.rate_limiters().len(), 1);
+
+        // A changed limit starts over.
+        let policy = compile(&limited.replace("packets = 1", "packets = 2")).unwrap();
+        assert_eq!(policy.evaluate(&frame, now).verdict, Verdict::Pass);
+
+        shared.store(compile("").unwrap());
+        assert!(shared.rate_limiters().is_empty());
+    }
+}
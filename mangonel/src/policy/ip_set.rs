@@ -0,0 +1,634 @@
+//! Sets of IPv4 and IPv6 prefixes, e.g. threat feeds, that rules match
+//! addresses against as `set:NAME`.
+//!
+//! IPv4 prefixes are laid out like a DIR-24-8 table with bits for entries: a
+//! bit per /24 network in the set as a whole, and a bitmap of its 256
+//! addresses for every /24 network in the set in part, found by counting the
+//! partial networks before it. A lookup reads at most two cache lines, and
+//! the table takes 6 MiB plus 32 bytes per partial network.
+//!
+//! IPv6 prefixes are kept in a trie with a stride of a byte, whose nodes
+//! have a bit per value of their byte for the values in the set as a whole
+//! and one for the values with a child node, the children of a node being
+//! stored in order and found by counting the bits before. A lookup visits a
+//! node per byte of the longest prefix on the path of the address, at most
+//! 16, and a node takes 72 bytes.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use pnet::ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+/// The /24 networks of IPv4.
+const NETWORK_COUNT: usize = 1 << 24;
+
+/// A set as configured, a `[policy.set.NAME]` table.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IpSetConfig {
+    /// Files of a prefix or address per line. Lines may be CSV with the
+    /// prefix in the first column, and `#` or `;` start comments. A first
+    /// line that is not a prefix, e.g. a CSV header, is skipped.
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    #[serde(default)]
+    pub prefixes: Vec<IpNetwork>,
+}
+
+/// The sets of a policy by name, loaded once and shared by the policies
+/// compiled from the same configuration.
+pub type IpSets = HashMap<String, Arc<IpSet>>;
+
+/// Load every set of `configs`, failing with the name of the first invalid
+/// one.
+pub fn load_sets(configs: &BTreeMap<String, IpSetConfig>) -> Result<IpSets, (String, IpSetError)> {
+    configs
+        .iter()
+        .map(|(name, config)| match IpSet::load(config) {
+            Ok(set) => Ok((name.clone(), Arc::new(set))),
+            Err(error) => Err((name.clone(), error)),
+        })
+        .collect()
+}
+
+/// A set of prefixes, looked up by address.
+pub struct IpSet {
+    ipv4: Ipv4Table,
+    ipv6: Ipv6Table,
+    /// The number of prefixes the set was built from.
+    len: usize,
+}
+
+impl IpSet {
+    /// Read the prefixes of the files and the configuration.
+    pub fn load(config: &IpSetConfig) -> Result<Self, IpSetError> {
+        let mut builder = IpSetBuilder::default();
+        config
+            .prefixes
+            .iter()
+            .for_each(|prefix| builder.insert(*prefix));
+        for path in &config.files {
+            let read = |error| IpSetError::Read {
+                path: path.clone(),
+                error,
+            };
+            let file = File::open(path).map_err(read)?;
+
+            let mut header = true;
+            for (index, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(read)?;
+                let Some(field) = first_field(&line) else {
+                    continue;
+                };
+                match field.parse::<IpNetwork>() {
+                    Ok(prefix) => builder.insert(prefix),
+                    Err(_) if header => {}
+                    Err(_) => {
+                        return Err(IpSetError::Parse {
+                            path: path.clone(),
+                            line: index + 1,
+                            text: field.to_owned(),
+                        })
+                    }
+                }
+                header = false;
+            }
+        }
+
+        Ok(builder.build())
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match address {
+            IpAddr::V4(address) => self.ipv4.contains(address),
+            IpAddr::V6(address) => self.ipv6.contains(address),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl std::fmt::Debug for IpSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IpSet")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Return the first column of a line without its comment, if any.
+fn first_field(line: &str) -> Option<&str> {
+    line.split(['#', ';'])
+        .next()
+        .unwrap()
+        .split([',', ' ', '\t'])
+        .map(|field| field.trim().trim_matches('"'))
+        .find(|field| !field.is_empty())
+}
+
+#[derive(Default)]
+struct IpSetBuilder {
+    /// A bit per /24 network in the set as a whole, allocated on the first
+    /// IPv4 prefix.
+    networks: Vec<u64>,
+    /// The addresses of /24 networks in part.
+    partial: BTreeMap<u32, [u64; 4]>,
+    /// The trie of IPv6 prefixes, rooted at the first node, allocated on the
+    /// first IPv6 prefix.
+    ipv6: Vec<TrieNode>,
+    len: usize,
+}
+
+/// A node of the IPv6 trie while it is built.
+#[derive(Debug, Default)]
+struct TrieNode {
+    covered: [u64; 4],
+    children: BTreeMap<u8, usize>,
+}
+
+impl IpSetBuilder {
+    fn insert(&mut self, prefix: IpNetwork) {
+        self.len += 1;
+        let length = prefix.prefix();
+        match prefix.network() {
+            IpAddr::V4(network) => {
+                let network = u32::from(network);
+                if self.networks.is_empty() {
+                    self.networks = vec![0; NETWORK_COUNT / 64];
+                }
+                match length {
+                    0..=24 => set_bits(
+                        &mut self.networks,
+                        (network >> 8) as usize,
+                        1 << (24 - length),
+                    ),
+                    _ => set_bits(
+                        self.partial.entry(network >> 8).or_default(),
+                        (network & 0xff) as usize,
+                        1 << (32 - length),
+                    ),
+                }
+            }
+            IpAddr::V6(network) => {
+                if self.ipv6.is_empty() {
+                    self.ipv6.push(TrieNode::default());
+                }
+                let octets = network.octets();
+                let mut node = 0;
+                let mut depth = 0;
+                while length as usize > (depth + 1) * 8 {
+                    let byte = octets[depth];
+                    if self.ipv6[node].covered[byte as usize / 64] & 1 << (byte % 64) != 0 {
+                        return;
+                    }
+                    node = match self.ipv6[node].children.get(&byte) {
+                        Some(child) => *child,
+                        None => {
+                            self.ipv6.push(TrieNode::default());
+                            let child = self.ipv6.len() - 1;
+                            self.ipv6[node].children.insert(byte, child);
+                            child
+                        }
+                    };
+                    depth += 1;
+                }
+                let bits = length as usize - depth * 8;
+                set_bits(
+                    &mut self.ipv6[node].covered,
+                    octets[depth] as usize,
+                    1 << (8 - bits),
+                );
+            }
+        }
+    }
+
+    fn build(self) -> IpSet {
+        let mut blocks: Vec<Block> = self
+            .networks
+            .iter()
+            .map(|networks| Block {
+                networks: *networks,
+                ..Default::default()
+            })
+            .collect();
+        let mut addresses = Vec::new();
+        for (network, bitmap) in self.partial {
+            let block = &mut blocks[network as usize / 64];
+            let bit = 1 << (network % 64);
+            if block.networks & bit != 0 {
+                continue;
+            }
+            match bitmap == [u64::MAX; 4] {
+                true => block.networks |= bit,
+                false => {
+                    block.partial |= bit;
+                    addresses.push(bitmap);
+                }
+            }
+        }
+        let mut rank = 0;
+        for block in &mut blocks {
+            block.rank = rank;
+            rank += block.partial.count_ones();
+        }
+
+        IpSet {
+            ipv4: Ipv4Table {
+                blocks: blocks.into_boxed_slice(),
+                addresses: addresses.into_boxed_slice(),
+            },
+            ipv6: Ipv6Table::new(self.ipv6),
+            len: self.len,
+        }
+    }
+}
+
+/// Set `count` bits of a bitmap from `start`.
+fn set_bits(bitmap: &mut [u64], start: usize, count: usize) {
+    let mut bit = start;
+    while bit < start + count {
+        let (word, offset) = (bit / 64, bit % 64);
+        let width = (64 - offset).min(start + count - bit);
+        bitmap[word] |= (u64::MAX >> (64 - width)) << offset;
+        bit += width;
+    }
+}
+
+/// The state of 64 consecutive /24 networks.
+#[derive(Debug, Clone, Copy, Default)]
+struct Block {
+    /// The networks in the set as a whole.
+    networks: u64,
+    /// The networks in the set in part.
+    partial: u64,
+    /// The partial networks in the blocks before, the index of the bitmap
+    /// of the first one of the block.
+    rank: u32,
+}
+
+struct Ipv4Table {
+    /// Empty without IPv4 prefixes.
+    blocks: Box<[Block]>,
+    /// The addresses of every partial network, in order of the networks.
+    addresses: Box<[[u64; 4]]>,
+}
+
+impl Ipv4Table {
+    #[inline(always)]
+    fn contains(&self, address: Ipv4Addr) -> bool {
+        let address = u32::from(address);
+        let network = (address >> 8) as usize;
+        let Some(block) = self.blocks.get(network / 64) else {
+            return false;
+        };
+        let bit = 1 << (network % 64);
+        if block.networks & bit != 0 {
+            return true;
+        }
+        if block.partial & bit == 0 {
+            return false;
+        }
+
+        let index = block.rank + (block.partial & (bit - 1)).count_ones();
+        let host = (address & 0xff) as usize;
+        self.addresses[index as usize][host / 64] & 1 << (host % 64) != 0
+    }
+}
+
+/// A node of the IPv6 trie, for a byte of the address.
+#[derive(Debug, Clone, Copy, Default)]
+struct Node {
+    /// The values of the byte in the set as a whole.
+    covered: [u64; 4],
+    /// The values of the byte with a child node for the next byte.
+    children: [u64; 4],
+    /// The index of the child of the lowest value, followed by the others.
+    first_child: u32,
+}
+
+struct Ipv6Table {
+    /// Empty without IPv6 prefixes, rooted at the first node otherwise.
+    nodes: Box<[Node]>,
+}
+
+impl Ipv6Table {
+    /// Lay out the nodes of a trie breadth first, so that the children of a
+    /// node follow each other. Children within covered values are left out.
+    fn new(trie: Vec<TrieNode>) -> Self {
+        let mut nodes = Vec::with_capacity(trie.len());
+        let mut queue = VecDeque::from_iter((!trie.is_empty()).then_some(0));
+        while let Some(index) = queue.pop_front() {
+            let TrieNode { covered, children } = &trie[index];
+            let mut node = Node {
+                covered: *covered,
+                first_child: (nodes.len() + queue.len() + 1) as u32,
+                ..Default::default()
+            };
+            for (byte, child) in children {
+                let (word, bit) = (*byte as usize / 64, 1 << (byte % 64));
+                if covered[word] & bit == 0 {
+                    node.children[word] |= bit;
+                    queue.push_back(*child);
+                }
+            }
+            nodes.push(node);
+        }
+
+        Self {
+            nodes: nodes.into_boxed_slice(),
+        }
+    }
+
+    #[inline(always)]
+    fn contains(&self, address: Ipv6Addr) -> bool {
+        let Some(mut node) = self.nodes.first() else {
+            return false;
+        };
+        for byte in address.octets() {
+            let (word, bit) = (byte as usize / 64, 1 << (byte % 64));
+            if node.covered[word] & bit != 0 {
+                return true;
+            }
+            if node.children[word] & bit == 0 {
+                return false;
+            }
+            let rank = node.children[..word]
+                .iter()
+                .map(|children| children.count_ones())
+                .sum::<u32>()
+                + (node.children[word] & (bit - 1)).count_ones();
+            node = &self.nodes[(node.first_child + rank) as usize];
+        }
+
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum IpSetError {
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        text: String,
+    },
+}
+
+impl std::fmt::Display for IpSetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { path, error } => {
+                write!(f, "Failed to read {}: {}", path.display(), error)
+            }
+            Self::Parse { path, line, text } => write!(
+                f,
+                "`{}` on line {} of {} is not a prefix. Write one prefix per line, e.g. `192.0.2.0/24`",
+                text,
+                line,
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IpSetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read { error, .. } => Some(error),
+            Self::Parse { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn set(prefixes: &[&str]) -> IpSet {
+        let mut builder = IpSetBuilder::default();
+        prefixes
+            .iter()
+            .for_each(|prefix| builder.insert(prefix.parse().unwrap()));
+
+        builder.build()
+    }
+
+    fn contains(set: &IpSet, address: &str) -> bool {
+        set.contains(address.parse().unwrap())
+    }
+
+    #[test]
+    fn empty_set() {
+        let set = set(&[]);
+        assert!(set.is_empty());
+        assert!(!contains(&set, "192.0.2.1"));
+        assert!(!contains(&set, "2001:db8::1"));
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let everything = set(&["0.0.0.0/0"]);
+        assert!(contains(&everything, "0.0.0.0"));
+        assert!(contains(&everything, "255.255.255.255"));
+        assert!(!contains(&everything, "::1"));
+
+        let set = set(&["192.0.2.0/24", "10.0.0.0/8", "198.51.100.128/25"]);
+        assert_eq!(set.len(), 3);
+        assert!(contains(&set, "192.0.2.0"));
+        assert!(contains(&set, "192.0.2.255"));
+        assert!(!contains(&set, "192.0.1.255"));
+        assert!(!contains(&set, "192.0.3.0"));
+        assert!(contains(&set, "10.255.255.255"));
+        assert!(!contains(&set, "11.0.0.0"));
+        assert!(contains(&set, "198.51.100.128"));
+        assert!(contains(&set, "198.51.100.255"));
+        assert!(!contains(&set, "198.51.100.127"));
+    }
+
+    #[test]
+    fn partial_networks() {
+        let set = set(&[
+            "203.0.113.1/32",
+            "203.0.113.64/26",
+            "203.0.113.8/30",
+            "198.51.100.0/25",
+            "198.51.100.128/25",
+        ]);
+        for (address, expected) in [
+            ("203.0.113.0", false),
+            ("203.0.113.1", true),
+            ("203.0.113.2", false),
+            ("203.0.113.7", false),
+            ("203.0.113.8", true),
+            ("203.0.113.11", true),
+            ("203.0.113.12", false),
+            ("203.0.113.63", false),
+            ("203.0.113.64", true),
+            ("203.0.113.127", true),
+            ("203.0.113.128", false),
+        ] {
+            assert_eq!(contains(&set, address), expected, "{}", address);
+        }
+        // Two halves make a whole network, without a bitmap.
+        assert!(contains(&set, "198.51.100.0"));
+        assert!(contains(&set, "198.51.100.255"));
+        assert_eq!(set.ipv4.addresses.len(), 1);
+    }
+
+    #[test]
+    fn partial_networks_covered_by_wider_prefixes() {
+        let set = set(&["192.0.2.1/32", "192.0.2.0/24", "198.51.100.7/32"]);
+        assert!(contains(&set, "192.0.2.200"));
+        assert!(contains(&set, "198.51.100.7"));
+        assert!(!contains(&set, "198.51.100.8"));
+        assert_eq!(set.ipv4.addresses.len(), 1);
+    }
+
+    #[test]
+    fn block_boundaries() {
+        // The /24 networks 10.0.63.0 and 10.0.64.0 are the last of a block
+        // and the first of the next.
+        let set = set(&[
+            "10.0.0.1/32",
+            "10.0.63.2/32",
+            "10.0.64.3/32",
+            "10.0.127.0/24",
+            "10.0.128.4/32",
+            "255.255.255.255/32",
+        ]);
+        for (address, expected) in [
+            ("10.0.0.1", true),
+            ("10.0.63.2", true),
+            ("10.0.63.3", false),
+            ("10.0.64.3", true),
+            ("10.0.64.2", false),
+            ("10.0.127.255", true),
+            ("10.0.128.4", true),
+            ("10.0.128.1", false),
+            ("10.0.62.2", false),
+            ("255.255.255.255", true),
+            ("255.255.255.254", false),
+        ] {
+            assert_eq!(contains(&set, address), expected, "{}", address);
+        }
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let everything = set(&["::/0"]);
+        assert!(contains(&everything, "::"));
+        assert!(contains(
+            &everything,
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        ));
+        assert!(!contains(&everything, "192.0.2.1"));
+
+        let set = set(&[
+            "2001:db8::/32",
+            "2001:db8::1/128",
+            "2a00:1450::/29",
+            "2600:1f00:8000::/33",
+            "fe80::/10",
+            "2001:db9:0:1::/64",
+            "2001:db9:0:1::/127",
+        ]);
+        for (address, expected) in [
+            ("2001:db8::", true),
+            ("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff", true),
+            ("2001:db7:ffff:ffff:ffff:ffff:ffff:ffff", false),
+            ("2001:db9::", false),
+            ("2a00:1450::", true),
+            ("2a00:1457:ffff::", true),
+            ("2a00:1458::", false),
+            ("2600:1f00:8000::", true),
+            ("2600:1f00:ffff:ffff::", true),
+            ("2600:1f00:7fff:ffff::", false),
+            ("fe80::1", true),
+            ("febf:ffff::", true),
+            ("fec0::", false),
+            ("2001:db9:0:1:ffff:ffff:ffff:ffff", true),
+            ("2001:db9:0:2::", false),
+            ("::1", false),
+        ] {
+            assert_eq!(contains(&set, address), expected, "{}", address);
+        }
+
+        let set = self::set(&["2001:db8::1/128", "2001:db8::2/127"]);
+        assert!(contains(&set, "2001:db8::1"));
+        assert!(contains(&set, "2001:db8::2"));
+        assert!(contains(&set, "2001:db8::3"));
+        assert!(!contains(&set, "2001:db8::"));
+        assert!(!contains(&set, "2001:db8::4"));
+    }
+
+    #[test]
+    fn nodes_within_wider_prefixes_are_left_out() {
+        let set = set(&["2001:db8:1::/48", "2001:db8::/32", "2001:db8:2::1/128"]);
+        assert!(contains(&set, "2001:db8:1::1"));
+        assert!(contains(&set, "2001:db8:2::2"));
+        // The nodes of the first four bytes, the last covering db8.
+        assert_eq!(set.ipv6.nodes.len(), 4);
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(first_field("192.0.2.0/24"), Some("192.0.2.0/24"));
+        assert_eq!(first_field("  192.0.2.1 # host"), Some("192.0.2.1"));
+        assert_eq!(first_field("\"192.0.2.0/24\",spam,1"), Some("192.0.2.0/24"));
+        assert_eq!(first_field("192.0.2.0/24\tspam"), Some("192.0.2.0/24"));
+        assert_eq!(first_field("; comment"), None);
+        assert_eq!(first_field("# comment"), None);
+        assert_eq!(first_field(""), None);
+        assert_eq!(first_field(" , 192.0.2.0/24"), Some("192.0.2.0/24"));
+    }
+
+    #[test]
+    fn load_files() {
+        let path = std::env::temp_dir().join(format!("mangonel-ip-set-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# A feed\n\"network\",\"score\"\n192.0.2.0/24,10\n\n2001:db8::/32 ; documentation\n",
+        )
+        .unwrap();
+        let config = IpSetConfig {
+            files: vec![path.clone()],
+            prefixes: vec!["198.51.100.1/32".parse().unwrap()],
+        };
+        let set = IpSet::load(&config).unwrap();
+        assert_eq!(set.len(), 3);
+        assert!(contains(&set, "192.0.2.1"));
+        assert!(contains(&set, "2001:db8::1"));
+        assert!(contains(&set, "198.51.100.1"));
+
+        // Only the first line may be a header.
+        fs::write(&path, "network\n192.0.2.0/24\nnetwork\n").unwrap();
+        let error = IpSet::load(&config).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        match error {
+            IpSetError::Parse { line, text, .. } => {
+                assert_eq!((line, text.as_str()), (3, "network"))
+            }
+            error => panic!("{}", error),
+        }
+        assert!(matches!(IpSet::load(&config), Err(IpSetError::Read { .. })));
+    }
+}
@@ -0,0 +1,523 @@
+//! Limiting the rate of every source address, or of every source prefix,
+//! with a token bucket each.
+//!
+//! The buckets live in a table of a bounded number of sources, split into
+//! shards that the workers lock independently. Once a shard is full, sources
+//! whose bucket refilled make room for new ones, and the sources that still
+//! do not fit share [`OVERFLOW_COUNT`] buckets of the shard by hash, so that
+//! a flood of new sources only slows the few sources it collides with.
+//!
+//! The limiters are kept in [`RateLimiters`] by rule name, so that the
+//! buckets of a rule outlive the policies it is compiled into.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+const SHARD_COUNT: usize = 64;
+/// The buckets of a shard shared by the sources that do not fit.
+const OVERFLOW_COUNT: usize = 64;
+/// The longest frame, with a VLAN tag and without the FCS, which a byte
+/// burst has to hold.
+const MAX_FRAME_LENGTH: u64 = 1518;
+const NANOSECONDS_PER_SECOND: f64 = 1e9;
+/// How often a full shard may be searched for sources to forget.
+const SWEEP_INTERVAL: u64 = 1_000_000_000;
+
+/// What happens to frames over the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exceed {
+    #[default]
+    Drop,
+    /// Pass the frame with the DSCP set to `dscp`, for the network to treat
+    /// it as less important.
+    Mark,
+}
+
+/// A rate limit as configured, the `rate_limit` table of a rule. At least one
+/// of `packets` and `bytes` has to be set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Packets per second of every source.
+    pub packets: Option<u64>,
+    /// Packets a source may send at once. Defaults to a second's worth.
+    pub packet_burst: Option<u64>,
+    /// Bytes per second of every source, counting Ethernet headers.
+    pub bytes: Option<u64>,
+    /// Bytes a source may send at once. Defaults to a second's worth. Has to
+    /// hold a frame of 1518 bytes.
+    pub byte_burst: Option<u64>,
+    /// Count IPv4 sources by prefix of this length, e.g. 24.
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    /// Count IPv6 sources by prefix of this length, e.g. 64.
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// The most sources tracked at once.
+    #[serde(default = "default_sources")]
+    pub sources: usize,
+    #[serde(default)]
+    pub exceed: Exceed,
+    /// The DSCP of marked frames, CS1 (lower effort) unless set.
+    #[serde(default = "default_dscp")]
+    pub dscp: u8,
+}
+
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix() -> u8 {
+    128
+}
+
+fn default_sources() -> usize {
+    65536
+}
+
+fn default_dscp() -> u8 {
+    8
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    /// Tokens per nanosecond.
+    rate: f64,
+    burst: f64,
+}
+
+impl Limit {
+    fn new(rate: Option<u64>, burst: Option<u64>) -> Result<Option<Self>, &'static str> {
+        match (rate, burst) {
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err("a burst needs the rate it belongs to"),
+            (Some(0), _) | (_, Some(0)) => Err("rates and bursts have to be positive"),
+            (Some(rate), burst) => Ok(Some(Self {
+                rate: rate as f64 / NANOSECONDS_PER_SECOND,
+                burst: burst.unwrap_or(rate) as f64,
+            })),
+        }
+    }
+
+    /// Return the tokens after `elapsed` nanoseconds.
+    #[inline(always)]
+    fn refill(&self, tokens: f64, elapsed: u64) -> f64 {
+        (tokens + self.rate * elapsed as f64).min(self.burst)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    packets: f64,
+    bytes: f64,
+    /// Nanoseconds since the limiter was created.
+    updated: u64,
+}
+
+/// The buckets of a shard, aligned to keep shards off each other's cache
+/// lines.
+#[derive(Debug)]
+#[repr(align(64))]
+struct Shard(Mutex<Table>);
+
+#[derive(Debug)]
+struct Table {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Shared by the sources that do not fit, by hash.
+    overflow: [Bucket; OVERFLOW_COUNT],
+    swept: u64,
+}
+
+/// The token buckets of a rate limited rule, shared by all workers.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    packets: Option<Limit>,
+    bytes: Option<Limit>,
+    ipv4_mask: u32,
+    ipv6_mask: u128,
+    shard_capacity: usize,
+    exceed: Exceed,
+    dscp: u8,
+    /// Keyed randomly, as sources choose their addresses.
+    hasher: RandomState,
+    epoch: Instant,
+    shards: Box<[Shard]>,
+}
+
+impl RateLimiter {
+    /// Validate the configuration and create a limiter with empty buckets.
+    pub fn new(config: &RateLimitConfig) -> Result<Self, &'static str> {
+        let packets = Limit::new(config.packets, config.packet_burst)?;
+        let bytes = Limit::new(config.bytes, config.byte_burst)?;
+        if bytes.is_some_and(|limit| limit.burst < MAX_FRAME_LENGTH as f64) {
+            return Err("the byte burst has to hold a frame of 1518 bytes");
+        }
+        if packets.is_none() && bytes.is_none() {
+            return Err("a rate limit needs `packets` or `bytes`");
+        }
+        if config.ipv4_prefix > 32 || config.ipv6_prefix > 128 {
+            return Err("prefixes are at most 32 bits for IPv4 and 128 bits for IPv6");
+        }
+        if config.sources == 0 {
+            return Err("a rate limit has to track at least one source");
+        }
+        if config.dscp > 63 {
+            return Err("a DSCP is at most 63");
+        }
+
+        let shard_capacity = config.sources.div_ceil(SHARD_COUNT);
+        let now = Instant::now();
+        let limiter = Self {
+            config: config.clone(),
+            packets,
+            bytes,
+            ipv4_mask: u32::MAX
+                .checked_shl(32 - config.ipv4_prefix as u32)
+                .unwrap_or(0),
+            ipv6_mask: u128::MAX
+                .checked_shl(128 - config.ipv6_prefix as u32)
+                .unwrap_or(0),
+            shard_capacity,
+            exceed: config.exceed,
+            dscp: config.dscp,
+            hasher: RandomState::new(),
+            epoch: now,
+            shards: Vec::new().into_boxed_slice(),
+        };
+        let shards = (0..SHARD_COUNT)
+            .map(|_| {
+                Shard(Mutex::new(Table {
+                    buckets: HashMap::with_capacity(shard_capacity.min(1024)),
+                    overflow: [limiter.full_bucket(0); OVERFLOW_COUNT],
+                    swept: 0,
+                }))
+            })
+            .collect();
+
+        Ok(Self { shards, ..limiter })
+    }
+
+    pub fn exceed(&self) -> Exceed {
+        self.exceed
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Take the tokens of a frame of `length` bytes from the bucket of
+    /// `source` and return whether there were enough.
+    #[inline]
+    pub fn take(&self, source: IpAddr, length: usize, now: Instant) -> bool {
+        let now = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+        let key = self.key(source);
+        let hash = self.hasher.hash_one(key);
+        let shard = &self.shards[hash as usize % SHARD_COUNT];
+        let mut table = shard.0.lock().unwrap();
+
+        let known = table.buckets.contains_key(&key);
+        if !known && table.buckets.len() >= self.shard_capacity {
+            self.sweep(&mut table, now);
+        }
+        let Table {
+            buckets, overflow, ..
+        } = &mut *table;
+        let bucket = match known || buckets.len() < self.shard_capacity {
+            true => buckets.entry(key).or_insert_with(|| self.full_bucket(now)),
+            false => &mut overflow[(hash >> 32) as usize % OVERFLOW_COUNT],
+        };
+
+        self.take_from(bucket, length as f64, now)
+    }
+
+    #[inline(always)]
+    fn take_from(&self, bucket: &mut Bucket, length: f64, now: u64) -> bool {
+        let elapsed = now.saturating_sub(bucket.updated);
+        bucket.updated = bucket.updated.max(now);
+        if let Some(packets) = &self.packets {
+            bucket.packets = packets.refill(bucket.packets, elapsed);
+        }
+        if let Some(bytes) = &self.bytes {
+            bucket.bytes = bytes.refill(bucket.bytes, elapsed);
+        }
+
+        let conforming = (self.packets.is_none() || bucket.packets >= 1.0)
+            && (self.bytes.is_none() || bucket.bytes >= length);
+        if conforming {
+            bucket.packets -= 1.0;
+            bucket.bytes -= length;
+        }
+
+        conforming
+    }
+
+    /// Forget the sources whose bucket refilled, at most once per
+    /// [`SWEEP_INTERVAL`] so that a flood of new sources cannot keep the
+    /// worker scanning.
+    #[cold]
+    fn sweep(&self, table: &mut Table, now: u64) {
+        if now.saturating_sub(table.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        table.swept = now;
+
+        let refilled = |bucket: &Bucket| {
+            let elapsed = now.saturating_sub(bucket.updated);
+            self.packets.map_or(true, |limit| {
+                limit.refill(bucket.packets, elapsed) >= limit.burst
+            }) && self.bytes.map_or(true, |limit| {
+                limit.refill(bucket.bytes, elapsed) >= limit.burst
+            })
+        };
+        table.buckets.retain(|_, bucket| !refilled(bucket));
+    }
+
+    fn full_bucket(&self, now: u64) -> Bucket {
+        Bucket {
+            packets: self.packets.map_or(0.0, |limit| limit.burst),
+            bytes: self.bytes.map_or(0.0, |limit| limit.burst),
+            updated: now,
+        }
+    }
+
+    /// Return the prefix of `source` that is limited as one.
+    #[inline(always)]
+    fn key(&self, source: IpAddr) -> IpAddr {
+        match source {
+            IpAddr::V4(address) => IpAddr::V4(Ipv4Addr::from(u32::from(address) & self.ipv4_mask)),
+            IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from(u128::from(address) & self.ipv6_mask)),
+        }
+    }
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("packets", &self.packets)
+            .field("bytes", &self.bytes)
+            .field("exceed", &self.exceed)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The rate limiters of the rules, by rule name, shared by the policies
+/// compiled one after the other.
+///
+/// A rule keeps its buckets when the policy is compiled again, e.g. on a
+/// reload or when other rules change, as long as its rate limit stays the
+/// same.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiters {
+    inner: Arc<Mutex<HashMap<String, Arc<RateLimiter>>>>,
+}
+
+impl RateLimiters {
+    /// Return the limiter of rule `name`: the current one if it is limited
+    /// by the same `config`, or a new one.
+    pub(crate) fn get(
+        &self,
+        name: &str,
+        config: &RateLimitConfig,
+    ) -> Result<Arc<RateLimiter>, &'static str> {
+        let limiters = self.inner.lock().unwrap();
+        match limiters.get(name) {
+            Some(limiter) if limiter.config == *config => Ok(limiter.clone()),
+            _ => RateLimiter::new(config).map(Arc::new),
+        }
+    }
+
+    /// Keep exactly the limiters of the policy compiled last.
+    pub(crate) fn replace(&self, limiters: HashMap<String, Arc<RateLimiter>>) {
+        *self.inner.lock().unwrap() = limiters;
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config(packets: Option<u64>, bytes: Option<u64>) -> RateLimitConfig {
+        RateLimitConfig {
+            packets,
+            packet_burst: None,
+            bytes,
+            byte_burst: None,
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            sources: default_sources(),
+            exceed: Exceed::Drop,
+            dscp: default_dscp(),
+        }
+    }
+
+    fn source(index: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + index))
+    }
+
+    #[test]
+    fn packet_bucket() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            packet_burst: Some(2),
+            ..config(Some(10), None)
+        })
+        .unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.take(source(1), 60, start));
+        assert!(limiter.take(source(1), 60, start));
+        assert!(!limiter.take(source(1), 60, start));
+        // Every source has a bucket of its own.
+        assert!(limiter.take(source(2), 60, start));
+
+        // A token every 100 ms, up to the burst.
+        assert!(limiter.take(source(1), 60, start + Duration::from_millis(100)));
+        assert!(!limiter.take(source(1), 60, start + Duration::from_millis(150)));
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.take(source(1), 60, later));
+        assert!(limiter.take(source(1), 60, later));
+        assert!(!limiter.take(source(1), 60, later));
+    }
+
+    #[test]
+    fn byte_bucket() {
+        let limiter = RateLimiter::new(&config(None, Some(3000))).unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.take(source(1), 1500, start));
+        assert!(limiter.take(source(1), 1000, start));
+        assert!(!limiter.take(source(1), 1000, start));
+        // Frames that do not conform take no tokens.
+        assert!(limiter.take(source(1), 500, start));
+        assert!(limiter.take(source(1), 1000, start + Duration::from_millis(334)));
+    }
+
+    #[test]
+    fn both_buckets_have_to_conform() {
+        let now = Instant::now();
+
+        let limiter = RateLimiter::new(&config(Some(1), Some(2000))).unwrap();
+        assert!(limiter.take(source(1), 1500, now));
+        assert!(!limiter.take(source(1), 60, now));
+
+        let limiter = RateLimiter::new(&config(Some(10), Some(2000))).unwrap();
+        assert!(limiter.take(source(1), 1500, now));
+        assert!(!limiter.take(source(1), 1500, now));
+    }
+
+    #[test]
+    fn prefixes_share_a_bucket() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            ..config(Some(1), None)
+        })
+        .unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.take("192.0.2.1".parse().unwrap(), 60, now));
+        assert!(!limiter.take("192.0.2.200".parse().unwrap(), 60, now));
+        assert!(limiter.take("192.0.3.1".parse().unwrap(), 60, now));
+        assert!(limiter.take("2001:db8::1".parse().unwrap(), 60, now));
+        assert!(!limiter.take("2001:db8::ffff:1".parse().unwrap(), 60, now));
+        assert!(limiter.take("2001:db8:0:1::1".parse().unwrap(), 60, now));
+    }
+
+    #[test]
+    fn sources_that_do_not_fit_share_buckets_by_hash() {
+        // One source per shard.
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            sources: SHARD_COUNT,
+            ..config(Some(1), None)
+        })
+        .unwrap();
+        let now = Instant::now();
+
+        // A single bucket for all sources that do not fit would let through
+        // one frame per shard beyond the tracked sources.
+        let conforming = (0..500)
+            .filter(|&index| limiter.take(source(index), 60, now))
+            .count();
+        assert!(conforming > 300, "{} frames conformed", conforming);
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let invalid = |config: RateLimitConfig| RateLimiter::new(&config).unwrap_err();
+
+        invalid(config(None, None));
+        invalid(config(Some(0), None));
+        invalid(RateLimitConfig {
+            packet_burst: Some(0),
+            ..config(Some(10), None)
+        });
+        invalid(RateLimitConfig {
+            byte_burst: Some(100_000),
+            ..config(Some(10), None)
+        });
+        // Bursts that do not hold a frame.
+        invalid(config(None, Some(1000)));
+        invalid(RateLimitConfig {
+            byte_burst: Some(1517),
+            ..config(None, Some(1_000_000))
+        });
+        invalid(RateLimitConfig {
+            ipv4_prefix: 33,
+            ..config(Some(10), None)
+        });
+        invalid(RateLimitConfig {
+            sources: 0,
+            ..config(Some(10), None)
+        });
+        invalid(RateLimitConfig {
+            dscp: 64,
+            ..config(Some(10), None)
+        });
+
+        assert!(RateLimiter::new(&RateLimitConfig {
+            byte_burst: Some(MAX_FRAME_LENGTH),
+            ..config(None, Some(100))
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn limiters_are_kept_by_name() {
+        let limiters = RateLimiters::default();
+        let first = limiters.get("a", &config(Some(10), None)).unwrap();
+        limiters.replace(HashMap::from([("a".to_owned(), first.clone())]));
+
+        assert!(Arc::ptr_eq(
+            &first,
+            &limiters.get("a", &config(Some(10), None)).unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            &first,
+            &limiters.get("a", &config(Some(20), None)).unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            &first,
+            &limiters.get("b", &config(Some(10), None)).unwrap()
+        ));
+
+        limiters.replace(HashMap::new());
+        assert!(limiters.is_empty());
+    }
+}
@@ -0,0 +1,1019 @@
+//! Protecting servers from SYN floods by completing the handshakes of
+//! clients before the servers see them.
+//!
+//! The SYN of a client is answered with a SYN-ACK whose sequence number is a
+//! cookie encoding the connection, the time and the MSS of the client, so
+//! that SYNs create no state. Only once the client returns a valid cookie in
+//! its ACK is the connection tracked and the handshake replayed to the
+//! server. The server's SYN-ACK is answered in place of the client, and
+//! from then on the sequence numbers of the server are shifted to those of
+//! the cookie in both directions. Data the client sends along with the ACK of
+//! the cookie, before the server answered, is held and handed to the server
+//! with the ACK completing its handshake.
+//!
+//! Segments to protected servers that the proxy cannot parse, e.g. with IPv4
+//! options, of fragments, with IPv6 extension headers or stacked VLAN tags,
+//! are dropped rather than let through unchecked.
+//!
+//! The answers to clients are sent back out of the port their segments came
+//! in on, and the handshakes replayed to the servers out of the LAN port,
+//! both in frames of their own rather than over the received ones.
+//!
+//! Neither side gets to negotiate window scaling, selective
+//! acknowledgements or timestamps, as the cookie cannot carry them. Frames
+//! of both directions have to reach the workers.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use pnet::util::MacAddr;
+use serde::Serialize;
+
+use crate::{
+    packet::{
+        builder::{
+            update_checksum, Ipv4, Ipv6, Network, PacketBuilder, Tcp, Transport, Vlan, TCP_ACK,
+            TCP_FIN, TCP_OPTION_END, TCP_OPTION_MSS, TCP_OPTION_NOP, TCP_RST, TCP_SYN,
+        },
+        headers, ETHERNET_HEADER_LENGTH, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_VLAN,
+        IPV4_HEADER_LENGTH, IPV6_HEADER_LENGTH, PROTOCOL_TCP,
+    },
+    policy::Verdict,
+};
+
+const SHARD_COUNT: usize = 64;
+/// The most connections tracked at once.
+const CONNECTION_COUNT: usize = 1 << 18;
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+/// How long a server has to answer the replayed SYN.
+const HANDSHAKE_TIMEOUT: u64 = 5 * NANOSECONDS_PER_SECOND;
+/// How long an idle connection is tracked.
+const IDLE_TIMEOUT: u64 = 600 * NANOSECONDS_PER_SECOND;
+/// How often a full shard may be searched for expired connections.
+const SWEEP_INTERVAL: u64 = NANOSECONDS_PER_SECOND;
+/// Cookies are valid for one to two of these periods.
+const COOKIE_PERIOD: u64 = 64 * NANOSECONDS_PER_SECOND;
+/// The segment sizes a cookie can encode, in three bits.
+const MSS_TABLE: [u16; 8] = [536, 1200, 1220, 1360, 1400, 1440, 1452, 1460];
+/// The window of the SYN-ACK to clients. The window of the server applies
+/// once it answered.
+const SYN_ACK_WINDOW: u16 = u16::MAX;
+
+const TCP_HEADER_LENGTH: usize = 20;
+
+/// What a worker does with a TCP segment seen by the proxy.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Transmit the frame unchanged.
+    Forward,
+    Drop,
+    /// Transmit this frame instead, written over the received one.
+    Rewrite(PacketBuilder),
+    /// Drop the frame and send this one back to the client, out of the port
+    /// it came in on.
+    Reply(PacketBuilder),
+    /// Drop the frame and send this one of the handshake replayed to the
+    /// server, carrying the payload, out of the LAN port.
+    Replay(PacketBuilder, Vec<u8>),
+    /// Add `delta` to the sequence or acknowledgement number at `offset` of
+    /// the frame, and transmit it.
+    Shift {
+        offset: usize,
+        checksum_offset: usize,
+        delta: u32,
+    },
+}
+
+impl Action {
+    pub fn verdict(&self) -> Verdict {
+        match self {
+            Self::Drop | Self::Reply(_) | Self::Replay(..) => Verdict::Drop,
+            _ => Verdict::Pass,
+        }
+    }
+
+    /// Change the frame in `buffer`, which spans to the end of the UMEM
+    /// frame, and return its new length, or `None` if it stays the same.
+    pub fn apply(&self, buffer: &mut [u8]) -> Option<usize> {
+        match self {
+            Self::Forward | Self::Drop | Self::Reply(_) | Self::Replay(..) => None,
+            // The frame a segment is rewritten to is a minimal TCP segment
+            // and always fits.
+            Self::Rewrite(builder) => builder.write(buffer, &[]).ok(),
+            Self::Shift {
+                offset,
+                checksum_offset,
+                delta,
+            } => {
+                let old = <[u8; 4]>::try_from(&buffer[*offset..offset + 4]).unwrap();
+                let new = u32::from_be_bytes(old).wrapping_add(*delta).to_be_bytes();
+                buffer[*offset..offset + 4].copy_from_slice(&new);
+                let checksum_bytes = &mut buffer[*checksum_offset..checksum_offset + 2];
+                let checksum = u16::from_be_bytes([checksum_bytes[0], checksum_bytes[1]]);
+                checksum_bytes
+                    .copy_from_slice(&update_checksum(checksum, &old, &new).to_be_bytes());
+                None
+            }
+        }
+    }
+}
+
+/// The fields of a TCP segment the proxy reads or answers with.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    source_mac: MacAddr,
+    destination_mac: MacAddr,
+    vlan: Option<Vlan>,
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    /// The offset of the TCP header in the frame.
+    offset: usize,
+    /// The offset of the payload in the frame.
+    payload_offset: usize,
+    payload_length: usize,
+}
+
+impl Segment {
+    /// Parse a TCP segment in an Ethernet frame with at most one VLAN tag,
+    /// and IPv4 without fragmentation or IPv6 without extension headers.
+    fn parse(frame: &[u8]) -> Option<Self> {
+        let (ethertype, network) = headers::network_header(frame)?;
+        let vlan = match network - ETHERNET_HEADER_LENGTH {
+            0 => None,
+            4 if read_u16(frame, 12)? == ETHERTYPE_VLAN => {
+                let tci = read_u16(frame, 14)?;
+                Some(Vlan {
+                    id: tci & 0x0fff,
+                    priority: (tci >> 13) as u8,
+                })
+            }
+            _ => return None,
+        };
+
+        let (source, destination, offset, end) = match ethertype {
+            ETHERTYPE_IPV4 => {
+                let header = frame.get(network..network + IPV4_HEADER_LENGTH)?;
+                // Options, fragments and other protocols.
+                if header[0] != 0x45
+                    || read_u16(header, 6)? & 0x3fff != 0
+                    || header[9] != PROTOCOL_TCP
+                {
+                    return None;
+                }
+                let source = Ipv4Addr::from(<[u8; 4]>::try_from(&header[12..16]).unwrap());
+                let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&header[16..20]).unwrap());
+                (
+                    IpAddr::from(source),
+                    IpAddr::from(destination),
+                    network + IPV4_HEADER_LENGTH,
+                    network + read_u16(header, 2)? as usize,
+                )
+            }
+            ETHERTYPE_IPV6 => {
+                let header = frame.get(network..network + IPV6_HEADER_LENGTH)?;
+                if header[0] >> 4 != 6 || header[6] != PROTOCOL_TCP {
+                    return None;
+                }
+                let source = Ipv6Addr::from(<[u8; 16]>::try_from(&header[8..24]).unwrap());
+                let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&header[24..40]).unwrap());
+                (
+                    IpAddr::from(source),
+                    IpAddr::from(destination),
+                    network + IPV6_HEADER_LENGTH,
+                    network + IPV6_HEADER_LENGTH + read_u16(header, 4)? as usize,
+                )
+            }
+            _ => return None,
+        };
+
+        let header = frame.get(offset..offset + TCP_HEADER_LENGTH)?;
+        let header_length = (header[12] >> 4) as usize * 4;
+        let options = frame.get(offset + TCP_HEADER_LENGTH..offset + header_length)?;
+        let payload_offset = offset + header_length;
+        if end < payload_offset || end > frame.len() {
+            return None;
+        }
+
+        Some(Self {
+            source_mac: MacAddr::from(<[u8; 6]>::try_from(&frame[6..12]).unwrap()),
+            destination_mac: MacAddr::from(<[u8; 6]>::try_from(&frame[0..6]).unwrap()),
+            vlan,
+            source: SocketAddr::new(source, read_u16(header, 0)?),
+            destination: SocketAddr::new(destination, read_u16(header, 2)?),
+            sequence: read_u32(header, 4)?,
+            acknowledgement: read_u32(header, 8)?,
+            flags: header[13],
+            window: read_u16(header, 14)?,
+            mss: parse_mss(options),
+            offset,
+            payload_offset,
+            payload_length: end - payload_offset,
+        })
+    }
+
+    /// Return a segment of the same connection with the addresses and ports
+    /// of `source` and `destination`.
+    fn build(&self, source: &Self, tcp: Tcp) -> PacketBuilder {
+        let tcp = Tcp {
+            source_port: source.source.port(),
+            destination_port: source.destination.port(),
+            ..tcp
+        };
+        let network = match (source.source.ip(), source.destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => Network::Ipv4(Ipv4 {
+                source,
+                destination,
+                transport: Transport::Tcp(tcp),
+                ..Default::default()
+            }),
+            (IpAddr::V6(source), IpAddr::V6(destination)) => Network::Ipv6(Ipv6 {
+                source,
+                destination,
+                transport: Transport::Tcp(tcp),
+                ..Default::default()
+            }),
+            _ => unreachable!("the addresses of a segment are of one family"),
+        };
+
+        PacketBuilder {
+            source: source.source_mac,
+            destination: source.destination_mac,
+            vlan: self.vlan,
+            network,
+        }
+    }
+
+    /// Return a segment on the way of this one.
+    fn forward(&self, tcp: Tcp) -> PacketBuilder {
+        self.build(self, tcp)
+    }
+
+    /// Return a segment back to the sender of this one.
+    fn reply(&self, tcp: Tcp) -> PacketBuilder {
+        let reversed = Self {
+            source_mac: self.destination_mac,
+            destination_mac: self.source_mac,
+            source: self.destination,
+            destination: self.source,
+            ..*self
+        };
+
+        self.build(&reversed, tcp)
+    }
+
+    fn has_flags(&self, flags: u8) -> bool {
+        self.flags & (TCP_SYN | TCP_ACK | TCP_RST | TCP_FIN) == flags
+    }
+
+    fn payload<'a>(&self, frame: &'a [u8]) -> &'a [u8] {
+        &frame[self.payload_offset..self.payload_offset + self.payload_length]
+    }
+}
+
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    loop {
+        match *options {
+            [] | [TCP_OPTION_END, ..] => return None,
+            [TCP_OPTION_NOP, ref rest @ ..] => options = rest,
+            [TCP_OPTION_MSS, 4, high, low, ..] => return Some(u16::from_be_bytes([high, low])),
+            [_, length, ..] if length >= 2 => options = options.get(length as usize..)?,
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    client: SocketAddr,
+    server: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    /// The handshake was replayed to the server, which has yet to answer.
+    SynSent,
+    Established,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    state: State,
+    /// The initial sequence number of the client.
+    client_sequence: u32,
+    /// The initial sequence number the client got, the cookie.
+    cookie: u32,
+    /// The initial sequence number of the server less the cookie.
+    delta: u32,
+    mss: u16,
+    window: u16,
+    /// Nanoseconds since the proxy was created.
+    updated: u64,
+}
+
+impl Connection {
+    fn expired(&self, now: u64) -> bool {
+        let timeout = match self.state {
+            State::SynSent => HANDSHAKE_TIMEOUT,
+            State::Established => IDLE_TIMEOUT,
+        };
+
+        now.saturating_sub(self.updated) > timeout
+    }
+}
+
+/// A tracked connection, as listed on the control socket.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub state: State,
+    /// Seconds since the last segment.
+    pub idle: u64,
+}
+
+#[derive(Debug)]
+#[repr(align(64))]
+struct Shard(Mutex<Table>);
+
+#[derive(Debug, Default)]
+struct Table {
+    connections: HashMap<Key, Connection>,
+    /// The first data of clients in [`State::SynSent`], for the server once
+    /// it answered.
+    held: HashMap<Key, Vec<u8>>,
+    swept: u64,
+}
+
+/// The connections spliced by the proxy, shared by all workers and kept
+/// across policy reloads.
+#[derive(Clone)]
+pub struct SynProxy {
+    inner: Arc<SynProxyInner>,
+}
+
+struct SynProxyInner {
+    /// Keys the cookies.
+    secret: RandomState,
+    /// Keyed randomly, as clients choose their addresses and ports.
+    hasher: RandomState,
+    epoch: Instant,
+    /// The number of tracked connections, to skip the table while empty.
+    count: AtomicUsize,
+    shards: Box<[Shard]>,
+}
+
+impl Default for SynProxy {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(SynProxyInner {
+                secret: RandomState::new(),
+                hasher: RandomState::new(),
+                epoch: Instant::now(),
+                count: AtomicUsize::new(0),
+                shards: (0..SHARD_COUNT).map(|_| Shard(Mutex::default())).collect(),
+            }),
+        }
+    }
+}
+
+impl SynProxy {
+    /// Return whether any connection is tracked, costing a relaxed load.
+    #[inline(always)]
+    pub fn is_tracking(&self) -> bool {
+        self.inner.count.load(Ordering::Relaxed) > 0
+    }
+
+    /// Decide on a frame received at `now`. Segments from clients to the
+    /// servers a rule protects have `protected` set, and are dropped if they
+    /// cannot be parsed. Segments of other TCP connections are only changed
+    /// if they are from a server to a client of a tracked connection, and
+    /// other frames are forwarded.
+    pub fn inspect(&self, frame: &[u8], protected: bool, now: Instant) -> Action {
+        let Some(segment) = Segment::parse(frame) else {
+            return match protected {
+                true => Action::Drop,
+                false => Action::Forward,
+            };
+        };
+        let now = now.saturating_duration_since(self.inner.epoch).as_nanos() as u64;
+
+        match protected {
+            true => self.inspect_client(&segment, segment.payload(frame), now),
+            false => self.inspect_server(&segment, now),
+        }
+    }
+
+    fn inspect_client(&self, segment: &Segment, payload: &[u8], now: u64) -> Action {
+        let key = Key {
+            client: segment.source,
+            server: segment.destination,
+        };
+        if segment.has_flags(TCP_SYN) {
+            return self.answer_syn(segment, &key, now);
+        }
+
+        let mut table = self.shard(&key).0.lock().unwrap();
+        let Some(connection) = table.connections.get_mut(&key) else {
+            // Floods of ACKs and anything else without a handshake end here.
+            if !segment.has_flags(TCP_ACK) {
+                return Action::Drop;
+            }
+            let client_sequence = segment.sequence.wrapping_sub(1);
+            let cookie = segment.acknowledgement.wrapping_sub(1);
+            let Some(mss) = self.check_cookie(&key, client_sequence, cookie, now) else {
+                return Action::Drop;
+            };
+            let connection = Connection {
+                state: State::SynSent,
+                client_sequence,
+                cookie,
+                delta: 0,
+                mss,
+                window: segment.window,
+                updated: now,
+            };
+            if !self.insert(&mut table, key, connection, now) {
+                return Action::Drop;
+            }
+            hold(&mut table, key, &connection, segment, payload);
+
+            return Action::Replay(replayed_syn(segment, &connection), Vec::new());
+        };
+
+        connection.updated = now;
+        match connection.state {
+            // The client may ACK the cookie again before the server answers,
+            // in case the SYN got lost, or send data.
+            State::SynSent if segment.has_flags(TCP_ACK) => {
+                let connection = *connection;
+                hold(&mut table, key, &connection, segment, payload);
+                Action::Replay(replayed_syn(segment, &connection), Vec::new())
+            }
+            State::SynSent => Action::Drop,
+            State::Established => {
+                let action = match segment.flags & TCP_ACK != 0 {
+                    true => Action::Shift {
+                        offset: segment.offset + 8,
+                        checksum_offset: segment.offset + 16,
+                        delta: connection.delta,
+                    },
+                    false => Action::Forward,
+                };
+                if segment.flags & TCP_RST != 0 {
+                    self.remove(&mut table, &key);
+                }
+                action
+            }
+        }
+    }
+
+    fn inspect_server(&self, segment: &Segment, now: u64) -> Action {
+        if !self.is_tracking() {
+            return Action::Forward;
+        }
+        let key = Key {
+            client: segment.destination,
+            server: segment.source,
+        };
+        let mut table = self.shard(&key).0.lock().unwrap();
+        let Some(connection) = table.connections.get_mut(&key) else {
+            return Action::Forward;
+        };
+        connection.updated = now;
+
+        if segment.has_flags(TCP_SYN | TCP_ACK) {
+            if segment.acknowledgement != connection.client_sequence.wrapping_add(1) {
+                return Action::Drop;
+            }
+            connection.delta = segment.sequence.wrapping_sub(connection.cookie);
+            connection.state = State::Established;
+
+            // Complete the handshake with the server as the client, along
+            // with the data the client sent meanwhile.
+            let reply = segment.reply(Tcp {
+                sequence: connection.client_sequence.wrapping_add(1),
+                acknowledgement: segment.sequence.wrapping_add(1),
+                flags: TCP_ACK,
+                window: connection.window,
+                ..Default::default()
+            });
+            return Action::Replay(reply, table.held.remove(&key).unwrap_or_default());
+        }
+
+        match connection.state {
+            // The server refused the connection the client believes is
+            // established.
+            State::SynSent if segment.flags & TCP_RST != 0 => {
+                let cookie = connection.cookie;
+                self.remove(&mut table, &key);
+                Action::Rewrite(segment.forward(Tcp {
+                    sequence: cookie.wrapping_add(1),
+                    flags: TCP_RST,
+                    ..Default::default()
+                }))
+            }
+            State::SynSent => Action::Drop,
+            State::Established => {
+                let action = Action::Shift {
+                    offset: segment.offset + 4,
+                    checksum_offset: segment.offset + 16,
+                    delta: connection.delta.wrapping_neg(),
+                };
+                if segment.flags & TCP_RST != 0 {
+                    self.remove(&mut table, &key);
+                }
+                action
+            }
+        }
+    }
+
+    /// Answer a SYN with a SYN-ACK carrying a cookie, tracking nothing.
+    fn answer_syn(&self, segment: &Segment, key: &Key, now: u64) -> Action {
+        let client_mss = segment.mss.unwrap_or(MSS_TABLE[0]);
+        let mss_index = MSS_TABLE
+            .iter()
+            .rposition(|&mss| mss <= client_mss)
+            .unwrap_or(0);
+        let cookie = self.cookie(key, segment.sequence, now / COOKIE_PERIOD, mss_index);
+
+        Action::Reply(segment.reply(Tcp {
+            sequence: cookie,
+            acknowledgement: segment.sequence.wrapping_add(1),
+            flags: TCP_SYN | TCP_ACK,
+            window: SYN_ACK_WINDOW,
+            mss: Some(MSS_TABLE[mss_index]),
+            ..Default::default()
+        }))
+    }
+
+    /// Return the cookie of a connection, made of five bits of the period it
+    /// was issued in, three bits of the MSS and 24 bits of a keyed hash.
+    fn cookie(&self, key: &Key, client_sequence: u32, period: u64, mss_index: usize) -> u32 {
+        let hash = self
+            .inner
+            .secret
+            .hash_one((key, client_sequence, period, mss_index)) as u32;
+
+        (period as u32 & 0x1f) << 27 | (mss_index as u32) << 24 | hash & 0x00ff_ffff
+    }
+
+    /// Return the MSS encoded in a cookie issued in this or the previous
+    /// period, or `None` if it is invalid.
+    fn check_cookie(&self, key: &Key, client_sequence: u32, cookie: u32, now: u64) -> Option<u16> {
+        let current = now / COOKIE_PERIOD;
+        let mss_index = (cookie >> 24 & 0x07) as usize;
+
+        [Some(current), current.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .filter(|period| *period as u32 & 0x1f == cookie >> 27)
+            .any(|period| self.cookie(key, client_sequence, period, mss_index) == cookie)
+            .then_some(MSS_TABLE[mss_index])
+    }
+
+    fn shard(&self, key: &Key) -> &Shard {
+        &self.inner.shards[self.inner.hasher.hash_one(key) as usize % SHARD_COUNT]
+    }
+
+    /// Track a connection, unless its shard is full of live ones.
+    fn insert(&self, table: &mut Table, key: Key, connection: Connection, now: u64) -> bool {
+        let capacity = CONNECTION_COUNT / SHARD_COUNT;
+        if table.connections.len() >= capacity && now.saturating_sub(table.swept) >= SWEEP_INTERVAL
+        {
+            table.swept = now;
+            let before = table.connections.len();
+            table
+                .connections
+                .retain(|_, connection| !connection.expired(now));
+            let Table {
+                connections, held, ..
+            } = table;
+            held.retain(|key, _| connections.contains_key(key));
+            self.inner
+                .count
+                .fetch_sub(before - table.connections.len(), Ordering::Relaxed);
+        }
+        if table.connections.len() >= capacity {
+            return false;
+        }
+
+        if table.connections.insert(key, connection).is_none() {
+            self.inner.count.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    fn remove(&self, table: &mut Table, key: &Key) {
+        table.held.remove(key);
+        if table.connections.remove(key).is_some() {
+            self.inner.count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the tracked connections that did not expire.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let now = self.inner.epoch.elapsed().as_nanos() as u64;
+
+        self.inner
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let table = shard.0.lock().unwrap();
+                table
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| !connection.expired(now))
+                    .map(|(key, connection)| ConnectionInfo {
+                        client: key.client,
+                        server: key.server,
+                        state: connection.state,
+                        idle: now.saturating_sub(connection.updated) / NANOSECONDS_PER_SECOND,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Forget every connection and return their number. Established ones
+    /// stall, as their sequence numbers are no longer shifted.
+    pub fn flush(&self) -> usize {
+        self.inner
+            .shards
+            .iter()
+            .map(|shard| {
+                let mut table = shard.0.lock().unwrap();
+                let count = table.connections.len();
+                table.connections.clear();
+                table.held.clear();
+                self.inner.count.fetch_sub(count, Ordering::Relaxed);
+                count
+            })
+            .sum()
+    }
+}
+
+/// Hold the payload of a segment a client sent before the server answered, if
+/// it is the first data of the connection and fits in a segment. Later data
+/// is dropped and sent again by the client once the server acknowledged the
+/// held data.
+fn hold(table: &mut Table, key: Key, connection: &Connection, segment: &Segment, payload: &[u8]) {
+    if !payload.is_empty()
+        && payload.len() <= connection.mss as usize
+        && segment.sequence == connection.client_sequence.wrapping_add(1)
+    {
+        table.held.entry(key).or_insert_with(|| payload.to_vec());
+    }
+}
+
+/// Return the SYN of the client that `segment` acknowledges the cookie of.
+fn replayed_syn(segment: &Segment, connection: &Connection) -> PacketBuilder {
+    segment.forward(Tcp {
+        sequence: connection.client_sequence,
+        flags: TCP_SYN,
+        window: connection.window,
+        mss: Some(connection.mss),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pnet::packet::{ipv4::Ipv4Packet, tcp::TcpPacket};
+
+    use super::*;
+    use crate::packet::builder::TCP_PSH;
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 40000);
+    const SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2)), 443);
+    const KEY: Key = Key {
+        client: CLIENT,
+        server: SERVER,
+    };
+
+    fn segment(source: SocketAddr, destination: SocketAddr, tcp: Tcp, payload: &[u8]) -> Vec<u8> {
+        let (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) = (source.ip(), destination.ip())
+        else {
+            unreachable!();
+        };
+        let builder = PacketBuilder {
+            network: Network::Ipv4(Ipv4 {
+                source: source_ip,
+                destination: destination_ip,
+                transport: Transport::Tcp(Tcp {
+                    source_port: source.port(),
+                    destination_port: destination.port(),
+                    window: 1000,
+                    ..tcp
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut frame = vec![0; 2048];
+        let length = builder.write(&mut frame, payload).unwrap();
+        frame.truncate(length);
+
+        frame
+    }
+
+    fn from_client(tcp: Tcp, payload: &[u8]) -> Vec<u8> {
+        segment(CLIENT, SERVER, tcp, payload)
+    }
+
+    fn from_server(tcp: Tcp, payload: &[u8]) -> Vec<u8> {
+        segment(SERVER, CLIENT, tcp, payload)
+    }
+
+    /// Apply the action to the frame and return the frame transmitted.
+    fn transmitted(action: &Action, mut frame: Vec<u8>) -> Vec<u8> {
+        let length = frame.len();
+        frame.resize(2048, 0);
+        let length = action.apply(&mut frame).unwrap_or(length);
+        frame.truncate(length);
+
+        frame
+    }
+
+    /// Return the frame the action sends in place of the received one.
+    fn sent(action: &Action) -> Vec<u8> {
+        let (builder, payload) = match action {
+            Action::Reply(builder) => (builder, &[][..]),
+            Action::Replay(builder, payload) => (builder, &payload[..]),
+            _ => panic!("{:?} sends no frame", action),
+        };
+        let mut frame = vec![0; builder.length(payload.len())];
+        builder.write(&mut frame, payload).unwrap();
+
+        frame
+    }
+
+    fn assert_checksum(frame: &[u8]) {
+        let ip = Ipv4Packet::new(&frame[ETHERNET_HEADER_LENGTH..]).unwrap();
+        let tcp = TcpPacket::new(&frame[ETHERNET_HEADER_LENGTH + IPV4_HEADER_LENGTH..]).unwrap();
+        assert_eq!(
+            tcp.get_checksum(),
+            pnet::packet::tcp::ipv4_checksum(&tcp, &ip.get_source(), &ip.get_destination())
+        );
+    }
+
+    #[test]
+    fn cookie_encoding() {
+        let proxy = SynProxy::default();
+        let period = 37;
+        let cookie = proxy.cookie(&KEY, 1000, period, 5);
+        assert_eq!(cookie >> 27, period as u32 & 0x1f);
+        assert_eq!(cookie >> 24 & 0x07, 5);
+
+        // Valid in its period and the next one.
+        let now = period * COOKIE_PERIOD;
+        assert_eq!(proxy.check_cookie(&KEY, 1000, cookie, now), Some(1440));
+        assert_eq!(
+            proxy.check_cookie(&KEY, 1000, cookie, now + COOKIE_PERIOD),
+            Some(1440)
+        );
+        assert_eq!(
+            proxy.check_cookie(&KEY, 1000, cookie, now + 2 * COOKIE_PERIOD),
+            None
+        );
+        assert_eq!(
+            proxy.check_cookie(&KEY, 1000, cookie, now - COOKIE_PERIOD),
+            None
+        );
+        // Also once the five bits of the period wrapped around.
+        assert_eq!(
+            proxy.check_cookie(&KEY, 1000, cookie, now + 32 * COOKIE_PERIOD),
+            None
+        );
+
+        // The hash covers the connection, the client's sequence number and
+        // the MSS.
+        let other = Key {
+            client: SocketAddr::new(CLIENT.ip(), 40001),
+            ..KEY
+        };
+        assert_eq!(proxy.check_cookie(&other, 1000, cookie, now), None);
+        assert_eq!(proxy.check_cookie(&KEY, 1001, cookie, now), None);
+        assert_eq!(proxy.check_cookie(&KEY, 1000, cookie ^ 1 << 24, now), None);
+        assert_eq!(proxy.check_cookie(&KEY, 1000, cookie ^ 1, now), None);
+        // Cookies are keyed by a secret of the proxy.
+        assert_eq!(
+            SynProxy::default().check_cookie(&KEY, 1000, cookie, now),
+            None
+        );
+    }
+
+    #[test]
+    fn syn_answered_with_cookie() {
+        let proxy = SynProxy::default();
+        let syn = from_client(
+            Tcp {
+                sequence: 1000,
+                flags: TCP_SYN,
+                mss: Some(1300),
+                ..Default::default()
+            },
+            &[],
+        );
+
+        let action = proxy.inspect(&syn, true, Instant::now());
+        assert!(matches!(action, Action::Reply(_)));
+        assert_eq!(action.verdict(), Verdict::Drop);
+        let syn_ack = Segment::parse(&sent(&action)).unwrap();
+        assert!(syn_ack.has_flags(TCP_SYN | TCP_ACK));
+        assert_eq!(syn_ack.source, SERVER);
+        assert_eq!(syn_ack.destination, CLIENT);
+        assert_eq!(syn_ack.acknowledgement, 1001);
+        // The largest encodable MSS within the client's.
+        assert_eq!(syn_ack.mss, Some(1220));
+        assert!(!proxy.is_tracking());
+    }
+
+    #[test]
+    fn handshake_with_data() {
+        let proxy = SynProxy::default();
+        let now = Instant::now();
+        let cookie = proxy.cookie(&KEY, 1000, 0, 7);
+
+        // Without a valid cookie, nothing reaches the server.
+        let ack = |acknowledgement| {
+            from_client(
+                Tcp {
+                    sequence: 1001,
+                    acknowledgement,
+                    flags: TCP_ACK | TCP_PSH,
+                    ..Default::default()
+                },
+                b"request",
+            )
+        };
+        assert!(matches!(
+            proxy.inspect(&ack(cookie), true, now),
+            Action::Drop
+        ));
+
+        // The ACK of the cookie is replayed as the client's SYN, and its data
+        // held.
+        let action = proxy.inspect(&ack(cookie.wrapping_add(1)), true, now);
+        assert!(matches!(action, Action::Replay(..)));
+        let syn = Segment::parse(&sent(&action)).unwrap();
+        assert!(syn.has_flags(TCP_SYN));
+        assert_eq!((syn.source, syn.destination), (CLIENT, SERVER));
+        assert_eq!(syn.sequence, 1000);
+        assert_eq!(syn.mss, Some(1460));
+        assert_eq!(syn.payload_length, 0);
+        assert!(proxy.is_tracking());
+
+        // The server's SYN-ACK is answered in place of the client, with the
+        // held data.
+        let frame = from_server(
+            Tcp {
+                sequence: 5000,
+                acknowledgement: 1001,
+                flags: TCP_SYN | TCP_ACK,
+                ..Default::default()
+            },
+            &[],
+        );
+        let action = proxy.inspect(&frame, false, now);
+        assert!(matches!(action, Action::Replay(..)));
+        let frame = sent(&action);
+        let reply = Segment::parse(&frame).unwrap();
+        assert!(reply.has_flags(TCP_ACK));
+        assert_eq!((reply.source, reply.destination), (CLIENT, SERVER));
+        assert_eq!(reply.sequence, 1001);
+        assert_eq!(reply.acknowledgement, 5001);
+        assert_eq!(reply.payload(&frame), b"request");
+        assert_checksum(&frame);
+
+        let connections = proxy.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].state, State::Established);
+    }
+
+    #[test]
+    fn sequence_numbers_are_spliced() {
+        let proxy = SynProxy::default();
+        let now = Instant::now();
+        let cookie = proxy.cookie(&KEY, 1000, 0, 0);
+        let ack = from_client(
+            Tcp {
+                sequence: 1001,
+                acknowledgement: cookie.wrapping_add(1),
+                flags: TCP_ACK,
+                ..Default::default()
+            },
+            &[],
+        );
+        proxy.inspect(&ack, true, now);
+        let server_sequence = cookie.wrapping_add(0x8000_0000);
+        let syn_ack = from_server(
+            Tcp {
+                sequence: server_sequence,
+                acknowledgement: 1001,
+                flags: TCP_SYN | TCP_ACK,
+                ..Default::default()
+            },
+            &[],
+        );
+        proxy.inspect(&syn_ack, false, now);
+
+        // The server's sequence numbers are shifted to the cookie's.
+        let data = from_server(
+            Tcp {
+                sequence: server_sequence.wrapping_add(1),
+                acknowledgement: 1001,
+                flags: TCP_ACK,
+                ..Default::default()
+            },
+            b"response",
+        );
+        let action = proxy.inspect(&data, false, now);
+        assert!(matches!(action, Action::Shift { .. }));
+        let frame = transmitted(&action, data);
+        let segment = Segment::parse(&frame).unwrap();
+        assert_eq!(segment.sequence, cookie.wrapping_add(1));
+        assert_eq!(segment.acknowledgement, 1001);
+        assert_eq!(segment.payload(&frame), b"response");
+        assert_checksum(&frame);
+
+        // And the client's acknowledgements back to the server's.
+        let ack = from_client(
+            Tcp {
+                sequence: 1001,
+                acknowledgement: cookie.wrapping_add(9),
+                flags: TCP_ACK,
+                ..Default::default()
+            },
+            &[],
+        );
+        let frame = transmitted(&proxy.inspect(&ack, true, now), ack);
+        let segment = Segment::parse(&frame).unwrap();
+        assert_eq!(segment.sequence, 1001);
+        assert_eq!(segment.acknowledgement, server_sequence.wrapping_add(9));
+        assert_checksum(&frame);
+
+        // A reset ends the connection.
+        let reset = from_client(
+            Tcp {
+                sequence: 1001,
+                acknowledgement: cookie.wrapping_add(9),
+                flags: TCP_RST | TCP_ACK,
+                ..Default::default()
+            },
+            &[],
+        );
+        proxy.inspect(&reset, true, now + Duration::from_secs(1));
+        assert!(!proxy.is_tracking());
+    }
+
+    #[test]
+    fn unparsed_segments_to_protected_servers_are_dropped() {
+        let proxy = SynProxy::default();
+        let mut frame = from_client(
+            Tcp {
+                flags: TCP_SYN,
+                ..Default::default()
+            },
+            &[],
+        );
+        // A fragment.
+        frame[ETHERNET_HEADER_LENGTH + 6] = 0x20;
+
+        assert!(matches!(
+            proxy.inspect(&frame, true, Instant::now()),
+            Action::Drop
+        ));
+        assert!(matches!(
+            proxy.inspect(&frame, false, Instant::now()),
+            Action::Forward
+        ));
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use mangonel_libxdp_rs::{
+    backend::{Backend, Sender},
+    descriptor::Descriptor,
+};
+
+use crate::{
+    capture::{Capture, Direction, Frame},
+    fragments::FragmentTracker,
+    heavy_hitters::{Counter, HeavyHitters, BLOCK_RULE},
+    packet::{builder::PacketBuilder, headers::Headers, Packet},
+    policy::{Decision, PolicyReader, SharedPolicy, Verdict},
+    syn_proxy::{Action, SynProxy},
+};
+
+const BATCH_SIZE: usize = 64;
+/// The most fragments waiting for the first fragment of their datagram.
+const HELD_COUNT: usize = 64;
+/// How long a fragment waits for the first fragment of its datagram.
+const HOLD_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Counters of a worker, read by the control plane while it runs.
+#[derive(Debug, Default)]
+pub struct WorkerStats {
+    pub received: AtomicU64,
+    pub passed: AtomicU64,
+    pub dropped: AtomicU64,
+    /// Passed with their DSCP changed by a rate limit.
+    pub marked: AtomicU64,
+}
+
+/// The frames of a burst by what happened to them, added to the
+/// [`WorkerStats`] at once.
+#[derive(Debug, Default)]
+struct Tally {
+    passed: u64,
+    dropped: u64,
+    marked: u64,
+}
+
+/// The packet processing loop of a queue. It is generic over the backend so
+/// that it can run on a [`mangonel_libxdp_rs::loopback::Loopback`] in tests.
+pub struct Worker<B> {
+    backend: B,
+    interface: String,
+    capture: Capture,
+    policy: PolicyReader,
+    syn_proxy: SynProxy,
+    /// The LAN port and the sender of the handshakes replayed to the servers.
+    lan: Option<(String, Box<dyn Sender>)>,
+    /// The frames built by the SYN proxy are written here before they are
+    /// sent.
+    frame: Vec<u8>,
+    fragments: FragmentTracker,
+    heavy_hitters: Option<Counter>,
+    stats: Arc<WorkerStats>,
+    free_frames: VecDeque<u64>,
+    /// Fragments waiting for the first fragment of their datagram, with
+    /// when they arrived.
+    held: VecDeque<(Descriptor, Headers, Instant)>,
+    /// The first fragments seen by the tracker when `held` was last
+    /// checked.
+    first_fragments: u64,
+    receiver_buffer: VecDeque<Descriptor>,
+    sender_buffer: VecDeque<Descriptor>,
+}
+
+impl<B: Backend> Worker<B> {
+    /// Create a worker owning `free_frames`, which are handed to the backend
+    /// to receive into.
+    pub fn new(backend: B, free_frames: Vec<u64>) -> Self {
+        let mut free_frames = VecDeque::from(free_frames);
+        free_frames.reserve(BATCH_SIZE);
+
+        Self {
+            backend,
+            interface: String::new(),
+            capture: Capture::default(),
+            policy: SharedPolicy::default().reader(),
+            syn_proxy: SynProxy::default(),
+            lan: None,
+            frame: Vec::new(),
+            fragments: FragmentTracker::default(),
+            heavy_hitters: None,
+            stats: Arc::default(),
+            free_frames,
+            held: VecDeque::with_capacity(HELD_COUNT),
+            first_fragments: 0,
+            receiver_buffer: VecDeque::with_capacity(BATCH_SIZE),
+            sender_buffer: VecDeque::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Record the frames received on `interface` and transmitted to
+    /// `capture` while it runs.
+    pub fn with_capture(mut self, interface: impl Into<String>, capture: Capture) -> Self {
+        self.interface = interface.into();
+        self.capture = capture;
+
+        self
+    }
+
+    /// Decide the verdicts with the policy of `policy`, following it as it
+    /// is replaced.
+    pub fn with_policy(mut self, policy: &SharedPolicy) -> Self {
+        self.policy = policy.reader();
+
+        self
+    }
+
+    /// Splice the connections of rules with `syn_proxy` in `syn_proxy`,
+    /// which the other workers share.
+    pub fn with_syn_proxy(mut self, syn_proxy: SynProxy) -> Self {
+        self.syn_proxy = syn_proxy;
+
+        self
+    }
+
+    /// Send the handshakes the SYN proxy replays to the servers with
+    /// `sender`, out of the LAN port `interface`. Without it the servers
+    /// cannot be reached.
+    pub fn with_lan(mut self, interface: impl Into<String>, sender: impl Sender + 'static) -> Self {
+        self.lan = Some((interface.into(), Box::new(sender)));
+
+        self
+    }
+
+    /// Track the datagrams of fragments in `fragments`, which the other
+    /// workers share.
+    pub fn with_fragments(mut self, fragments: FragmentTracker) -> Self {
+        self.fragments = fragments;
+
+        self
+    }
+
+    /// Count the frames by source and flow for `heavy_hitters` to find the
+    /// top talkers, and drop the frames of the sources it blocked.
+    pub fn with_heavy_hitters(mut self, heavy_hitters: &HeavyHitters) -> Self {
+        self.heavy_hitters = Some(heavy_hitters.counter());
+
+        self
+    }
+
+    /// Count the frames in `stats`, e.g. to keep counting across restarts.
+    pub fn with_stats(mut self, stats: Arc<WorkerStats>) -> Self {
+        self.stats = stats;
+
+        self
+    }
+
+    #[inline(always)]
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Run [`Worker::poll`] until the flag is cleared.
+    pub fn run(&mut self, flag: &AtomicBool) {
+        while flag.load(Ordering::SeqCst) {
+            self.poll();
+        }
+    }
+
+    /// Receive, process and transmit a batch of packets and return the
+    /// number of packets received.
+    pub fn poll(&mut self) -> u32 {
+        // Between bursts, so that a burst is decided by a single policy.
+        self.policy.refresh();
+        self.backend.fill(&mut self.free_frames);
+
+        let received = self.backend.rx_burst(&mut self.receiver_buffer);
+        if received > 0 || !self.held.is_empty() {
+            let now = Instant::now();
+            let mut tally = Tally::default();
+            for _ in 0..received {
+                let descriptor = self.receiver_buffer.pop_front().unwrap();
+                let payload = descriptor.payload();
+                let headers = Headers::parse(payload);
+                let blocked = match (&mut self.heavy_hitters, &headers) {
+                    (Some(counter), Some(headers)) => {
+                        counter.count(headers, payload.len());
+                        counter.is_blocked(headers.source)
+                    }
+                    _ => false,
+                };
+                let policy = self.policy.get();
+                let decision = match headers.filter(|headers| headers.fragment.is_some()) {
+                    _ if blocked => Decision::new(Verdict::Drop, Some(BLOCK_RULE)),
+                    Some(headers) => {
+                        match self.fragments.inspect(&headers, policy, payload.len(), now) {
+                            Some(decision) => decision,
+                            None => {
+                                self.hold(descriptor, headers, now, &mut tally);
+                                continue;
+                            }
+                        }
+                    }
+                    None => policy.decide(headers.as_ref(), payload.len(), now),
+                };
+                let action = match decision.verdict == Verdict::Pass
+                    && (decision.syn_proxy || self.syn_proxy.is_tracking())
+                {
+                    true => self
+                        .syn_proxy
+                        .inspect(descriptor.payload(), decision.syn_proxy, now),
+                    false => Action::Forward,
+                };
+                let decision = Decision {
+                    verdict: match decision.verdict {
+                        Verdict::Pass => action.verdict(),
+                        Verdict::Drop => Verdict::Drop,
+                    },
+                    ..decision
+                };
+                self.capture.record(&Frame {
+                    interface: &self.interface,
+                    direction: Direction::Inbound,
+                    rule: decision.rule,
+                    verdict: decision.verdict,
+                    data: descriptor.payload(),
+                });
+                let (verdict, dscp) = (decision.verdict, decision.dscp);
+                match &action {
+                    Action::Reply(builder) => self.send(builder, &[], false),
+                    Action::Replay(builder, payload) => self.send(builder, payload, true),
+                    _ => {}
+                }
+                self.dispatch(descriptor, verdict, dscp, &action, &mut tally);
+            }
+            if !self.held.is_empty() {
+                self.release(now, &mut tally);
+            }
+            self.stats
+                .received
+                .fetch_add(received as u64, Ordering::Relaxed);
+            self.stats.passed.fetch_add(tally.passed, Ordering::Relaxed);
+            self.stats
+                .dropped
+                .fetch_add(tally.dropped, Ordering::Relaxed);
+            self.stats.marked.fetch_add(tally.marked, Ordering::Relaxed);
+
+            if self.capture.is_active() {
+                for descriptor in &self.sender_buffer {
+                    self.capture.record(&Frame {
+                        interface: &self.interface,
+                        direction: Direction::Outbound,
+                        rule: None,
+                        verdict: Verdict::Pass,
+                        data: descriptor.payload(),
+                    });
+                }
+            }
+            self.backend.tx_burst(&mut self.sender_buffer);
+        }
+
+        self.backend.complete(&mut self.free_frames);
+        if let Some(counter) = &mut self.heavy_hitters {
+            counter.follow();
+        }
+
+        received
+    }
+
+    /// Change a decided frame as the SYN proxy asked, and queue it for
+    /// transmission or return it to the UMEM.
+    #[inline(always)]
+    fn dispatch(
+        &mut self,
+        mut descriptor: Descriptor,
+        verdict: Verdict,
+        dscp: Option<u8>,
+        action: &Action,
+        tally: &mut Tally,
+    ) {
+        if let Some(length) = action.apply(descriptor.get_buffer()) {
+            descriptor.set_length(length as u32);
+        }
+        match verdict {
+            Verdict::Pass => {
+                if let Some(dscp) = dscp {
+                    let length = descriptor.length() as usize;
+                    Packet::from(&mut descriptor.get_buffer()[..length]).set_dscp(dscp);
+                    tally.marked += 1;
+                }
+                self.sender_buffer.push_back(descriptor);
+                tally.passed += 1;
+            }
+            Verdict::Drop => {
+                self.free_frames.push_back(descriptor.address());
+                tally.dropped += 1;
+            }
+        }
+    }
+
+    /// Send a frame built by the SYN proxy back out of the port receiving,
+    /// or out of the LAN port. A frame that finds no free frame to be sent
+    /// from is lost, and sent again once the client retransmits.
+    #[cold]
+    fn send(&mut self, builder: &PacketBuilder, payload: &[u8], lan: bool) {
+        self.frame.resize(builder.length(payload.len()), 0);
+        if builder.write(&mut self.frame, payload).is_err() {
+            return;
+        }
+        let (interface, sent) = match (lan, &mut self.lan) {
+            (false, _) => (&self.interface, self.backend.send(&self.frame)),
+            (true, Some((interface, sender))) => (&*interface, sender.send(&self.frame)),
+            (true, None) => return,
+        };
+
+        if sent.is_ok() {
+            self.capture.record(&Frame {
+                interface,
+                direction: Direction::Outbound,
+                rule: None,
+                verdict: Verdict::Pass,
+                data: &self.frame,
+            });
+        }
+    }
+
+    /// Keep a fragment until the first fragment of its datagram arrives, or
+    /// drop it if too many wait already.
+    #[cold]
+    fn hold(&mut self, descriptor: Descriptor, headers: Headers, now: Instant, tally: &mut Tally) {
+        if self.held.len() < HELD_COUNT {
+            self.held.push_back((descriptor, headers, now));
+            return;
+        }
+
+        self.capture.record(&Frame {
+            interface: &self.interface,
+            direction: Direction::Inbound,
+            rule: None,
+            verdict: Verdict::Drop,
+            data: descriptor.payload(),
+        });
+        self.dispatch(descriptor, Verdict::Drop, None, &Action::Forward, tally);
+    }
+
+    /// Decide on the held fragments whose first fragment arrived meanwhile,
+    /// and drop the ones that waited too long.
+    #[cold]
+    fn release(&mut self, now: Instant, tally: &mut Tally) {
+        let first_fragments = self.fragments.first_fragments();
+        let timed_out = self
+            .held
+            .front()
+            .is_some_and(|(_, _, held)| now.saturating_duration_since(*held) >= HOLD_TIMEOUT);
+        if first_fragments == self.first_fragments && !timed_out {
+            return;
+        }
+        self.first_fragments = first_fragments;
+
+        for _ in 0..self.held.len() {
+            let (descriptor, headers, held) = self.held.pop_front().unwrap();
+            let decision = match self.fragments.decide(&headers, now) {
+                Some(decision) => decision,
+                None if now.saturating_duration_since(held) < HOLD_TIMEOUT => {
+                    self.held.push_back((descriptor, headers, held));
+                    continue;
+                }
+                None => Decision::new(Verdict::Drop, None),
+            };
+            self.capture.record(&Frame {
+                interface: &self.interface,
+                direction: Direction::Inbound,
+                rule: decision.rule,
+                verdict: decision.verdict,
+                data: descriptor.payload(),
+            });
+            self.dispatch(
+                descriptor,
+                decision.verdict,
+                decision.dscp,
+                &Action::Forward,
+                tally,
+            );
+        }
+    }
+
+    /// Return the backend along with the frames held by the worker rather
+    /// than by the backend, which would be lost otherwise.
+    pub fn into_parts(self) -> (B, Vec<u64>) {
+        let free_frames = self
+            .free_frames
+            .into_iter()
+            .chain(
+                self.receiver_buffer
+                    .into_iter()
+                    .chain(self.sender_buffer)
+                    .chain(self.held.into_iter().map(|(descriptor, _, _)| descriptor))
+                    .map(|descriptor| descriptor.address()),
+            )
+            .collect();
+
+        (self.backend, free_frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Mutex, thread};
+
+    use mangonel_libxdp_rs::{loopback::Loopback, socket::SocketError};
+    use pnet::packet::tcp::TcpPacket;
+
+    use super::*;
+    use crate::{
+        packet::builder::{Ipv4, Network, Tcp, Transport, Udp, TCP_ACK, TCP_SYN},
+        policy::{ip_set::IpSets, rate_limit::RateLimiters, Policy},
+    };
+
+    const FRAME_SIZE: u32 = 2048;
+    const FRAME_COUNT: u32 = 256;
+
+    /// Collects the frames sent out of the LAN port.
+    #[derive(Clone, Default)]
+    struct Lan(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Sender for Lan {
+        fn send(&mut self, payload: &[u8]) -> Result<(), SocketError> {
+            self.0.lock().unwrap().push(payload.to_vec());
+
+            Ok(())
+        }
+    }
+
+    fn worker() -> Worker<Loopback> {
+        worker_with(
+            r#"
+            default = "drop"
+
+            [[rule]]
+            name = "dns"
+            verdict = "pass"
+            protocol = "udp"
+            destination_port = 53
+            "#,
+        )
+    }
+
+    fn worker_with(config: &str) -> Worker<Loopback> {
+        let policy = Policy::compile(
+            &toml::from_str(config).unwrap(),
+            &IpSets::new(),
+            &RateLimiters::default(),
+        )
+        .unwrap();
+        let free_frames = (0..FRAME_COUNT as u64)
+            .map(|index| index * FRAME_SIZE as u64)
+            .collect();
+        let mut worker = Worker::new(
+            Loopback::new(FRAME_SIZE, 256, FRAME_COUNT).unwrap(),
+            free_frames,
+        )
+        .with_policy(&SharedPolicy::new(policy));
+        // Fill the frames to receive into.
+        assert_eq!(worker.poll(), 0);
+
+        worker
+    }
+
+    /// A TCP segment from a client to port 80 of a server.
+    fn segment(tcp: Tcp) -> Vec<u8> {
+        let builder = PacketBuilder {
+            network: Network::Ipv4(Ipv4 {
+                source: Ipv4Addr::new(192, 0, 2, 1),
+                destination: Ipv4Addr::new(198, 51, 100, 2),
+                transport: Transport::Tcp(Tcp {
+                    source_port: 40000,
+                    destination_port: 80,
+                    window: 1000,
+                    ..tcp
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut frame = vec![0; 128];
+        let length = builder.write(&mut frame, &[]).unwrap();
+        frame.truncate(length);
+
+        frame
+    }
+
+    /// A UDP fragment of datagram `id` to `port` with 16 bytes of data at
+    /// `offset`, the UDP header and 8 bytes of payload.
+    fn fragment(id: u16, offset: u16, more: bool, port: u16) -> Vec<u8> {
+        let builder = PacketBuilder {
+            network: Network::Ipv4(Ipv4 {
+                source: Ipv4Addr::new(198, 51, 100, 1),
+                destination: Ipv4Addr::new(192, 0, 2, 1),
+                dont_fragment: false,
+                transport: Transport::Udp(Udp {
+                    source_port: 1024,
+                    destination_port: port,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut frame = vec![0; 128];
+        let length = builder.write(&mut frame, &[0; 8]).unwrap();
+        frame.truncate(length);
+        frame[18..20].copy_from_slice(&id.to_be_bytes());
+        let flags = (offset / 8) | if more { 0x2000 } else { 0 };
+        frame[20..22].copy_from_slice(&flags.to_be_bytes());
+
+        frame
+    }
+
+    #[test]
+    fn held_fragments_follow_the_first() {
+        let mut worker = worker();
+
+        let last = fragment(1, 16, false, 53);
+        worker.backend_mut().inject(&last).unwrap();
+        worker
+            .backend_mut()
+            .inject(&fragment(2, 16, false, 80))
+            .unwrap();
+        assert_eq!(worker.poll(), 2);
+        assert_eq!(worker.held.len(), 2);
+        assert!(worker.backend_mut().transmitted().is_empty());
+
+        let first = fragment(1, 0, true, 53);
+        worker.backend_mut().inject(&first).unwrap();
+        worker
+            .backend_mut()
+            .inject(&fragment(2, 0, true, 80))
+            .unwrap();
+        assert_eq!(worker.poll(), 2);
+        assert!(worker.held.is_empty());
+        assert_eq!(worker.backend_mut().transmitted(), [first, last]);
+        assert_eq!(worker.stats.passed.load(Ordering::Relaxed), 2);
+        assert_eq!(worker.stats.dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn held_fragments_time_out() {
+        let mut worker = worker();
+
+        worker
+            .backend_mut()
+            .inject(&fragment(1, 16, false, 53))
+            .unwrap();
+        worker.poll();
+        assert_eq!(worker.held.len(), 1);
+        // Waiting, as nothing arrived.
+        worker.poll();
+        assert_eq!(worker.held.len(), 1);
+
+        thread::sleep(HOLD_TIMEOUT);
+        worker.poll();
+        assert!(worker.held.is_empty());
+        assert_eq!(worker.stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            worker.free_frames.len() + worker.backend_mut().filled(),
+            FRAME_COUNT as usize
+        );
+    }
+
+    #[test]
+    fn held_fragments_are_bounded() {
+        let mut worker = worker();
+
+        for id in 0..HELD_COUNT as u16 + 4 {
+            worker
+                .backend_mut()
+                .inject(&fragment(id, 16, false, 53))
+                .unwrap();
+        }
+        while worker.poll() > 0 {}
+        assert_eq!(worker.held.len(), HELD_COUNT);
+        assert_eq!(worker.stats.dropped.load(Ordering::Relaxed), 4);
+
+        // Along with the frames held.
+        let (loopback, free_frames) = worker.into_parts();
+        assert_eq!(free_frames.len() + loopback.filled(), FRAME_COUNT as usize);
+    }
+
+    #[test]
+    fn syn_proxy_sends_frames_of_its_own() {
+        let lan = Lan::default();
+        let mut worker = worker_with(
+            r#"
+            [[rule]]
+            name = "web"
+            verdict = "pass"
+            protocol = "tcp"
+            destination_port = 80
+            syn_proxy = true
+            "#,
+        )
+        .with_lan("lan", lan.clone());
+        let tcp = |frame: &[u8]| {
+            let tcp = TcpPacket::new(&frame[34..]).unwrap();
+            (
+                tcp.get_flags(),
+                tcp.get_sequence(),
+                tcp.get_acknowledgement(),
+            )
+        };
+
+        // The SYN is answered out of the port it came in on, in place of the
+        // SYN itself.
+        worker
+            .backend_mut()
+            .inject(&segment(Tcp {
+                sequence: 1000,
+                flags: TCP_SYN,
+                mss: Some(1460),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(worker.poll(), 1);
+        let transmitted = worker.backend_mut().transmitted();
+        assert_eq!(transmitted.len(), 1);
+        let (flags, cookie, acknowledgement) = tcp(&transmitted[0]);
+        assert_eq!((flags, acknowledgement), (TCP_SYN | TCP_ACK, 1001));
+        assert!(lan.0.lock().unwrap().is_empty());
+        assert_eq!(worker.stats.dropped.load(Ordering::Relaxed), 1);
+
+        // The ACK of the cookie is replayed to the server as the SYN, out of
+        // the LAN port.
+        worker
+            .backend_mut()
+            .inject(&segment(Tcp {
+                sequence: 1001,
+                acknowledgement: cookie.wrapping_add(1),
+                flags: TCP_ACK,
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(worker.poll(), 1);
+        assert!(worker.backend_mut().transmitted().is_empty());
+        let sent = lan.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(tcp(&sent[0]), (TCP_SYN, 1000, 0));
+    }
+}